@@ -0,0 +1,18 @@
+#!/bin/sh
+# Regenerates schema.json, the introspection result of AniList's GraphQL API that the queries in
+# src/anilist_graphql.rs are checked against when the crate compiles, and records where and when it
+# was taken in SCHEMA_SOURCE. Rerun it when AniList changes its API and commit both files, the build
+# then fails on every query the change broke. Needs graphql_client_cli:
+#
+#     cargo install graphql_client_cli
+#
+# ANILIST_URL points it at another endpoint.
+
+set -eu
+
+url="${ANILIST_URL:-https://graphql.anilist.co}"
+dir="$(dirname "$0")"
+
+graphql-client introspect-schema "$url" --output "$dir/schema.json"
+printf 'Introspected from %s on %s with %s\n' \
+    "$url" "$(date -u +%Y-%m-%dT%H:%M:%SZ)" "$(graphql-client --version)" > "$dir/SCHEMA_SOURCE"
@@ -0,0 +1,94 @@
+-- Users, anime and the lists joining them. Everything is IF NOT EXISTS so databases that were set
+-- up by hand before migrations existed are brought in line instead of failing.
+
+CREATE TABLE IF NOT EXISTS users (
+    user_id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    avatar_s3 TEXT NOT NULL,
+    avatar_anilist TEXT NOT NULL
+);
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS sync_needs_confirmation BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS last_synced_at TIMESTAMPTZ;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS last_sync_attempt_at TIMESTAMPTZ;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS slug TEXT;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS restricted BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS takedown_requested_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS users_name_idx ON users (name);
+CREATE UNIQUE INDEX IF NOT EXISTS users_slug_idx ON users (slug);
+
+CREATE TABLE IF NOT EXISTS anime (
+    anime_id INTEGER PRIMARY KEY,
+    description TEXT NOT NULL,
+    cover_s3 TEXT NOT NULL,
+    cover_anilist TEXT NOT NULL,
+    average SMALLINT,
+    native TEXT,
+    romaji TEXT,
+    english TEXT
+);
+
+ALTER TABLE anime ADD COLUMN IF NOT EXISTS search_title TEXT NOT NULL DEFAULT '';
+ALTER TABLE anime ADD COLUMN IF NOT EXISTS slug TEXT;
+ALTER TABLE anime ADD COLUMN IF NOT EXISTS genres TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE anime ADD COLUMN IF NOT EXISTS tags TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE anime ADD COLUMN IF NOT EXISTS episodes INTEGER;
+ALTER TABLE anime ADD COLUMN IF NOT EXISTS season TEXT;
+ALTER TABLE anime ADD COLUMN IF NOT EXISTS season_year INTEGER;
+ALTER TABLE anime ADD COLUMN IF NOT EXISTS format TEXT;
+ALTER TABLE anime ADD COLUMN IF NOT EXISTS studio TEXT;
+ALTER TABLE anime ADD COLUMN IF NOT EXISTS cover_version INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE anime ADD COLUMN IF NOT EXISTS mal_id INTEGER;
+ALTER TABLE anime ADD COLUMN IF NOT EXISTS is_adult BOOLEAN NOT NULL DEFAULT false;
+
+CREATE INDEX IF NOT EXISTS anime_slug_idx ON anime (slug);
+
+CREATE TABLE IF NOT EXISTS lists (
+    user_id INTEGER NOT NULL REFERENCES users (user_id),
+    anime_id INTEGER NOT NULL REFERENCES anime (anime_id),
+    user_title TEXT,
+    start_day DATE,
+    end_day DATE,
+    score SMALLINT,
+    PRIMARY KEY (user_id, anime_id)
+);
+
+ALTER TABLE lists ADD COLUMN IF NOT EXISTS status TEXT;
+ALTER TABLE lists ADD COLUMN IF NOT EXISTS updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
+ALTER TABLE lists ADD COLUMN IF NOT EXISTS anilist_updated_at BIGINT;
+ALTER TABLE lists ADD COLUMN IF NOT EXISTS progress INTEGER;
+ALTER TABLE lists ADD COLUMN IF NOT EXISTS repeat INTEGER;
+
+-- The default list order and the date range routes.
+CREATE INDEX IF NOT EXISTS lists_user_end_day_idx ON lists (user_id, end_day);
+-- Incremental responses ask for entries changed since a point in time.
+CREATE INDEX IF NOT EXISTS lists_user_updated_at_idx ON lists (user_id, updated_at);
+CREATE INDEX IF NOT EXISTS lists_anime_idx ON lists (anime_id);
+
+CREATE TABLE IF NOT EXISTS list_history (
+    history_id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users (user_id),
+    anime_id INTEGER NOT NULL REFERENCES anime (anime_id),
+    score SMALLINT,
+    status TEXT,
+    start_day DATE,
+    end_day DATE,
+    replaced_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS list_history_user_anime_idx ON list_history (user_id, anime_id);
+
+CREATE TABLE IF NOT EXISTS list_tombstones (
+    user_id INTEGER NOT NULL REFERENCES users (user_id),
+    anime_id INTEGER NOT NULL,
+    deleted_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, anime_id)
+);
+
+CREATE TABLE IF NOT EXISTS hidden_entries (
+    user_id INTEGER NOT NULL REFERENCES users (user_id),
+    anime_id INTEGER NOT NULL,
+    hidden_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, anime_id)
+);
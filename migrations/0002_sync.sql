@@ -0,0 +1,55 @@
+-- The job queue and what syncs leave behind: warnings, stats and response snapshots.
+
+CREATE TABLE IF NOT EXISTS job_batches (
+    batch_id SERIAL PRIMARY KEY,
+    kind TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS jobs (
+    job_id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users (user_id),
+    kind TEXT NOT NULL,
+    state TEXT NOT NULL DEFAULT 'queued',
+    force BOOLEAN NOT NULL DEFAULT false,
+    error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    started_at TIMESTAMPTZ,
+    finished_at TIMESTAMPTZ
+);
+
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS claimed_by TEXT;
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS batch_id INTEGER REFERENCES job_batches (batch_id);
+
+-- Workers claim the oldest queued job, queueing looks for a user's active sync.
+CREATE INDEX IF NOT EXISTS jobs_queued_idx ON jobs (job_id) WHERE state = 'queued';
+CREATE INDEX IF NOT EXISTS jobs_user_state_idx ON jobs (user_id, state);
+CREATE INDEX IF NOT EXISTS jobs_batch_idx ON jobs (batch_id) WHERE batch_id IS NOT NULL;
+
+-- anime_id has no foreign key, a warning may be about an anime that could not be saved.
+CREATE TABLE IF NOT EXISTS sync_warnings (
+    user_id INTEGER NOT NULL REFERENCES users (user_id),
+    anime_id INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    detail TEXT NOT NULL,
+    job_id INTEGER NOT NULL REFERENCES jobs (job_id),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, anime_id, kind)
+);
+
+CREATE INDEX IF NOT EXISTS sync_warnings_job_idx ON sync_warnings (job_id);
+
+CREATE TABLE IF NOT EXISTS user_stats (
+    user_id INTEGER PRIMARY KEY REFERENCES users (user_id),
+    entries BIGINT NOT NULL,
+    completed BIGINT NOT NULL,
+    scored BIGINT NOT NULL,
+    score_sum BIGINT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS response_cache (
+    user_id INTEGER PRIMARY KEY REFERENCES users (user_id),
+    body BYTEA NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
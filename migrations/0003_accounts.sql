@@ -0,0 +1,50 @@
+-- Signing in and everything a signed in user can set up.
+
+CREATE TABLE IF NOT EXISTS sessions (
+    token TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users (user_id),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS anilist_tokens (
+    user_id INTEGER PRIMARY KEY REFERENCES users (user_id),
+    access_token TEXT NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS subscriptions (
+    subscriber_id INTEGER NOT NULL REFERENCES users (user_id),
+    target_id INTEGER NOT NULL REFERENCES users (user_id),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (subscriber_id, target_id)
+);
+
+CREATE INDEX IF NOT EXISTS subscriptions_target_idx ON subscriptions (target_id);
+
+CREATE TABLE IF NOT EXISTS user_preferences (
+    user_id INTEGER PRIMARY KEY REFERENCES users (user_id),
+    title_language TEXT NOT NULL,
+    date_format TEXT NOT NULL,
+    show_adult BOOLEAN NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS webhooks (
+    webhook_id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users (user_id),
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS webhooks_user_idx ON webhooks (user_id);
+
+CREATE TABLE IF NOT EXISTS profile_hits (
+    user_id INTEGER NOT NULL REFERENCES users (user_id),
+    hour TIMESTAMPTZ NOT NULL,
+    hits INTEGER NOT NULL,
+    PRIMARY KEY (user_id, hour)
+);
+
+CREATE INDEX IF NOT EXISTS profile_hits_hour_idx ON profile_hits (hour);
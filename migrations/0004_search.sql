@@ -0,0 +1,22 @@
+-- Anime search: prefix matches on the normalized title, full text over title and description,
+-- and trigram similarity for misspelled queries.
+
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+ALTER TABLE anime ADD COLUMN IF NOT EXISTS search_document TSVECTOR NOT NULL DEFAULT ''::tsvector;
+
+-- Rows stored before the column existed, new ones get their document when they are saved.
+UPDATE anime
+SET search_document = setweight(to_tsvector('simple', search_title), 'A')
+    || setweight(to_tsvector('english', description), 'B')
+WHERE search_document = ''::tsvector;
+
+CREATE INDEX IF NOT EXISTS anime_search_title_prefix_idx ON anime (search_title text_pattern_ops);
+CREATE INDEX IF NOT EXISTS anime_search_title_trgm_idx ON anime USING gin (search_title gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS anime_search_document_idx ON anime USING gin (search_document);
+
+CREATE TABLE IF NOT EXISTS remote_search_cache (
+    query TEXT PRIMARY KEY,
+    body TEXT NOT NULL,
+    fetched_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,11 @@
+-- Taste embeddings, one dimension per genre in taste::GENRES.
+
+CREATE EXTENSION IF NOT EXISTS vector;
+
+CREATE TABLE IF NOT EXISTS user_embeddings (
+    user_id INTEGER PRIMARY KEY REFERENCES users (user_id),
+    embedding vector(19) NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS user_embeddings_embedding_idx ON user_embeddings USING hnsw (embedding vector_cosine_ops);
@@ -0,0 +1,3 @@
+-- Extra large copies of anime covers, NULL until the anime's next sync or when AniList has none.
+
+ALTER TABLE anime ADD COLUMN IF NOT EXISTS cover_xl_s3 TEXT;
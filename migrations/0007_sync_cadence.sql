@@ -0,0 +1,3 @@
+-- How often the scheduler syncs each user, a models::SyncCadence.
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS sync_cadence TEXT NOT NULL DEFAULT 'daily';
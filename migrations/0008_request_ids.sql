@@ -0,0 +1,3 @@
+-- X-Request-Id of the request that queued a job, so a failed sync can be traced back to it.
+
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS request_id TEXT;
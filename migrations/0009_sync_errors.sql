@@ -0,0 +1,16 @@
+-- Entries a sync left out because they couldn't be written. payload is AniList's JSON of the entry
+-- as text, jsonb would reject the NUL characters that get entries quarantined. Like warnings,
+-- anime_id has no foreign key.
+
+CREATE TABLE IF NOT EXISTS sync_errors (
+    user_id INTEGER NOT NULL REFERENCES users (user_id),
+    anime_id INTEGER NOT NULL,
+    job_id INTEGER NOT NULL REFERENCES jobs (job_id),
+    payload TEXT NOT NULL,
+    error TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, anime_id)
+);
+
+CREATE INDEX IF NOT EXISTS sync_errors_job_idx ON sync_errors (job_id);
+CREATE INDEX IF NOT EXISTS sync_errors_created_idx ON sync_errors (created_at);
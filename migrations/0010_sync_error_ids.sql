@@ -0,0 +1,6 @@
+-- Stable id of a quarantined entry, so it can be retried on its own. It stays the same when later
+-- syncs quarantine the entry again.
+
+ALTER TABLE sync_errors ADD COLUMN IF NOT EXISTS sync_error_id SERIAL;
+
+CREATE UNIQUE INDEX IF NOT EXISTS sync_errors_id_idx ON sync_errors (sync_error_id);
@@ -0,0 +1,3 @@
+-- Small WebP copies of anime covers for the list grid, NULL until the anime's next sync.
+
+ALTER TABLE anime ADD COLUMN IF NOT EXISTS cover_thumb_s3 TEXT;
@@ -0,0 +1,16 @@
+-- Covers and avatars whose upload to the image store failed, retried until they are stored. Each
+-- retry downloads source_url from AniList again. id is the anime_id or user_id the image belongs
+-- to and has no foreign key, retries drop rows whose anime or user is gone.
+
+CREATE TABLE IF NOT EXISTS pending_uploads (
+    image_type TEXT NOT NULL,
+    id INTEGER NOT NULL,
+    source_url TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT NOT NULL,
+    next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (image_type, id)
+);
+
+CREATE INDEX IF NOT EXISTS pending_uploads_next_attempt_idx ON pending_uploads (next_attempt_at);
@@ -0,0 +1,9 @@
+-- Where each user's list is synced from, and the id that site knows the user by. Users from before
+-- are all AniList users stored under their AniList id.
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS provider TEXT NOT NULL DEFAULT 'anilist';
+ALTER TABLE users ADD COLUMN IF NOT EXISTS external_id INTEGER;
+UPDATE users SET external_id = user_id WHERE external_id IS NULL;
+ALTER TABLE users ALTER COLUMN external_id SET NOT NULL;
+
+CREATE UNIQUE INDEX IF NOT EXISTS users_provider_external_id_idx ON users (provider, external_id);
@@ -0,0 +1,15 @@
+-- AniList's list activity of each user, one row per update like "watched episode 4 - 6", for a
+-- day by day history. Like warnings, anime_id has no foreign key, activity stays when the entry
+-- goes.
+
+CREATE TABLE IF NOT EXISTS activities (
+    activity_id INTEGER PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users (user_id),
+    anime_id INTEGER NOT NULL,
+    status TEXT NOT NULL,
+    episode_from INTEGER,
+    episode_to INTEGER,
+    created_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS activities_user_created_idx ON activities (user_id, created_at);
@@ -0,0 +1,7 @@
+-- The user's notes on each entry and the custom lists it is on. Entries synced before are written
+-- again on their user's next sync, which otherwise skips entries AniList reports as unchanged.
+
+ALTER TABLE lists ADD COLUMN IF NOT EXISTS notes TEXT;
+ALTER TABLE lists ADD COLUMN IF NOT EXISTS custom_lists TEXT[] NOT NULL DEFAULT '{}';
+
+UPDATE lists SET anilist_updated_at = NULL;
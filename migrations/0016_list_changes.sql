@@ -0,0 +1,15 @@
+-- What each sync did to a user's list: the entries it added, updated and removed, with the values
+-- before and after as JSON. Like warnings, anime_id has no foreign key, removals outlive the anime.
+
+CREATE TABLE IF NOT EXISTS list_changes (
+    change_id BIGSERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users (user_id),
+    anime_id INTEGER NOT NULL,
+    job_id INTEGER NOT NULL REFERENCES jobs (job_id),
+    operation TEXT NOT NULL,
+    before TEXT,
+    after TEXT,
+    changed_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS list_changes_user_changed_idx ON list_changes (user_id, changed_at);
@@ -0,0 +1,4 @@
+-- MyAnimeList users are stored under "mal:" and their name, the same name can belong to different
+-- people on AniList and MyAnimeList and every lookup by name has to find one of them.
+
+UPDATE users SET name = 'mal:' || name WHERE provider = 'mal' AND name NOT LIKE 'mal:%';
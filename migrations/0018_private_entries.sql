@@ -0,0 +1,19 @@
+-- Entries users keep private on AniList, synced with their access token. They are stored so syncs
+-- can compare against them but never served: public reads go through public_lists instead of lists.
+
+ALTER TABLE lists ADD COLUMN IF NOT EXISTS private BOOLEAN NOT NULL DEFAULT false;
+
+-- A view's columns are fixed when it is created, it has to be recreated when lists gains a column.
+CREATE OR REPLACE VIEW public_lists AS SELECT * FROM lists WHERE NOT private;
+
+-- The next sync of each user writes every entry again, which brings its private flag.
+UPDATE lists SET anilist_updated_at = NULL;
+
+-- Cached responses may hold entries that are private.
+DELETE FROM response_cache;
+
+-- Access tokens are sealed with TOKEN_ENCRYPTION_KEY from now on. Tokens stored in the clear can't
+-- be sealed here, those users sign in again.
+DELETE FROM anilist_tokens;
+ALTER TABLE anilist_tokens DROP COLUMN IF EXISTS access_token;
+ALTER TABLE anilist_tokens ADD COLUMN IF NOT EXISTS sealed_token BYTEA NOT NULL;
@@ -0,0 +1,5 @@
+-- Users whose data may show up anywhere public: neither restricted because their AniList list is
+-- private, nor waiting for a takedown. Everything that aggregates across users reads them from here.
+
+CREATE OR REPLACE VIEW visible_users AS
+    SELECT * FROM users WHERE NOT restricted AND takedown_requested_at IS NULL;
@@ -0,0 +1,6 @@
+-- Batch updates queue a lookup of each name on AniList instead of looking them up in the request.
+-- A lookup job has the name but no user yet, and points at the sync it queued once it has run.
+
+ALTER TABLE jobs ALTER COLUMN user_id DROP NOT NULL;
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS username TEXT;
+ALTER TABLE jobs ADD COLUMN IF NOT EXISTS sync_job_id INTEGER REFERENCES jobs (job_id);
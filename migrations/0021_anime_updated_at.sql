@@ -0,0 +1,4 @@
+-- When an anime's stored details last changed, so cached list responses showing them can tell
+-- they are out of date.
+
+ALTER TABLE anime ADD COLUMN IF NOT EXISTS updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
@@ -0,0 +1,11 @@
+-- Dates AniList only knows the year or the month of, as 2023 or 2023-05. start_day and end_day stay
+-- unset for them.
+
+ALTER TABLE lists ADD COLUMN IF NOT EXISTS start_partial TEXT;
+ALTER TABLE lists ADD COLUMN IF NOT EXISTS end_partial TEXT;
+
+-- A view's columns are fixed when it is created, it has to be recreated when lists gains a column.
+CREATE OR REPLACE VIEW public_lists AS SELECT * FROM lists WHERE NOT private;
+
+-- The next sync of each user writes every entry again, which brings its partial dates.
+UPDATE lists SET anilist_updated_at = NULL;
@@ -0,0 +1,13 @@
+-- The jobs of each batch. A user whose sync is already queued when a batch reaches them joins the
+-- batch with that job, which can belong to an earlier batch as well, so jobs.batch_id can't hold
+-- it. jobs.batch_id is no longer written.
+
+CREATE TABLE IF NOT EXISTS batch_jobs (
+    batch_id INTEGER NOT NULL REFERENCES job_batches (batch_id) ON DELETE CASCADE,
+    job_id INTEGER NOT NULL REFERENCES jobs (job_id) ON DELETE CASCADE,
+    PRIMARY KEY (batch_id, job_id)
+);
+
+INSERT INTO batch_jobs (batch_id, job_id)
+SELECT batch_id, job_id FROM jobs WHERE batch_id IS NOT NULL
+ON CONFLICT DO NOTHING;
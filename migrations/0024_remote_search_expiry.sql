@@ -0,0 +1,3 @@
+-- Expired remote searches are deleted as new ones are cached.
+
+CREATE INDEX IF NOT EXISTS remote_search_cache_fetched_idx ON remote_search_cache (fetched_at);
@@ -0,0 +1,12 @@
+-- Sessions are found by the SHA-256 of their token, so a copy of the table opens no accounts, and
+-- they run out. Sessions from before get the default 30 days from when they were created.
+
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ;
+
+UPDATE sessions SET token = encode(sha256(convert_to(token, 'UTF8')), 'hex'), expires_at = created_at + interval '30 days';
+
+ALTER TABLE sessions ALTER COLUMN expires_at SET NOT NULL;
+
+ALTER TABLE sessions RENAME COLUMN token TO token_hash;
+
+CREATE INDEX IF NOT EXISTS sessions_user_idx ON sessions (user_id);
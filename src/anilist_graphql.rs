@@ -0,0 +1,424 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Typed queries to AniList, generated from the .graphql files in graphql/anilist. Queries are
+// checked against AniList's own schema when the crate compiles, so a misspelt field or a variable of
+// the wrong type fails the build instead of coming back from AniList as an error. The schema is the
+// introspection dump graphql/anilist/update_schema.sh writes to schema.json. Responses are
+// turned into anilist_models here, which lists from MyAnimeList and quarantined entries share.
+
+use crate::anilist_models;
+use graphql_client::GraphQLQuery;
+use serde::Serialize;
+
+// AniList's Json scalar, customLists comes back as one.
+type Json = serde_json::Value;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "graphql/anilist/schema.json",
+    query_path = "graphql/anilist/list.graphql"
+)]
+pub struct ListQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "graphql/anilist/schema.json",
+    query_path = "graphql/anilist/media.graphql"
+)]
+pub struct MediaQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "graphql/anilist/schema.json",
+    query_path = "graphql/anilist/mal_media.graphql"
+)]
+pub struct MalMediaQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "graphql/anilist/schema.json",
+    query_path = "graphql/anilist/user.graphql"
+)]
+pub struct UserQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "graphql/anilist/schema.json",
+    query_path = "graphql/anilist/viewer.graphql"
+)]
+pub struct ViewerQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "graphql/anilist/schema.json",
+    query_path = "graphql/anilist/activities.graphql"
+)]
+pub struct ActivitiesQuery;
+
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "graphql/anilist/schema.json",
+    query_path = "graphql/anilist/search.graphql"
+)]
+pub struct SearchQuery;
+
+// The list, media and MyAnimeList queries select the same fields of an anime, each into a struct
+// of its own.
+macro_rules! media {
+    ($media:expr) => {{
+        let media = $media;
+        anilist_models::Media {
+            id: media.id as i32,
+            id_mal: media.id_mal.map(|id| id as i32),
+            is_adult: media.is_adult,
+            title: media
+                .title
+                .map_or_else(empty_title, |title| anilist_models::Title {
+                    user_preferred: title.user_preferred,
+                    english: title.english,
+                    romaji: title.romaji,
+                    native: title.native,
+                }),
+            description: media.description.unwrap_or_default(),
+            cover_image: media.cover_image.map_or_else(empty_image, |image| {
+                anilist_models::Image {
+                    large: image.large.unwrap_or_default(),
+                    extra_large: image.extra_large,
+                }
+            }),
+            average_score: media.average_score.map(|score| score as i16),
+            site_url: media.site_url.unwrap_or_default(),
+            genres: media
+                .genres
+                .map(|genres| genres.into_iter().flatten().collect()),
+            tags: media.tags.map(|tags| {
+                tags.into_iter()
+                    .flatten()
+                    .map(|tag| anilist_models::Tag {
+                        name: tag.name,
+                        is_media_spoiler: tag.is_media_spoiler,
+                    })
+                    .collect()
+            }),
+            episodes: media.episodes.map(|episodes| episodes as i32),
+            season: media.season.as_ref().and_then(name),
+            season_year: media.season_year.map(|year| year as i32),
+            format: media.format.as_ref().and_then(name),
+            studios: media
+                .studios
+                .map(|studios| anilist_models::StudioConnection {
+                    nodes: studios
+                        .nodes
+                        .unwrap_or_default()
+                        .into_iter()
+                        .flatten()
+                        .map(|studio| anilist_models::Studio { name: studio.name })
+                        .collect(),
+                }),
+        }
+    }};
+}
+
+macro_rules! date {
+    ($date:expr) => {
+        match $date {
+            Some(date) => anilist_models::Date {
+                year: date.year.map(|year| year as i32),
+                month: date.month.map(|month| month as i32),
+                day: date.day.map(|day| day as i32),
+            },
+            None => anilist_models::Date {
+                year: None,
+                month: None,
+                day: None,
+            },
+        }
+    };
+}
+
+macro_rules! user {
+    ($user:expr) => {{
+        let user = $user;
+        anilist_models::User {
+            id: user.id as i32,
+            name: user.name,
+            avatar: anilist_models::Avatar {
+                large: user
+                    .avatar
+                    .and_then(|avatar| avatar.large)
+                    .unwrap_or_default(),
+            },
+        }
+    }};
+}
+
+// Enums are stored by AniList's names for them.
+fn name<T: Serialize>(value: &T) -> Option<String> {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(name)) => Some(name),
+        _ => None,
+    }
+}
+
+fn empty_title() -> anilist_models::Title {
+    anilist_models::Title {
+        user_preferred: None,
+        english: None,
+        romaji: None,
+        native: None,
+    }
+}
+
+fn empty_image() -> anilist_models::Image {
+    anilist_models::Image {
+        large: String::new(),
+        extra_large: None,
+    }
+}
+
+// The lists in a chunk and whether another chunk follows, None without a collection.
+pub fn lists(data: list_query::ResponseData) -> Option<(Vec<anilist_models::MediaList>, bool)> {
+    let collection = data.media_list_collection?;
+    let lists = collection
+        .lists
+        .unwrap_or_default()
+        .into_iter()
+        .flatten()
+        .map(|list| anilist_models::MediaList {
+            name: list.name.unwrap_or_default(),
+            is_custom_list: list.is_custom_list.unwrap_or(false),
+            entries: list
+                .entries
+                .unwrap_or_default()
+                .into_iter()
+                .flatten()
+                .filter_map(|entry| {
+                    Some(anilist_models::Entry {
+                        score_raw: entry.score_raw.map(|score| score.round() as i16),
+                        status: entry.status.as_ref().and_then(name),
+                        progress: entry.progress.map(|progress| progress as i32),
+                        repeat: entry.repeat.map(|repeat| repeat as i32),
+                        updated_at: entry.updated_at,
+                        started_at: date!(entry.started_at),
+                        completed_at: date!(entry.completed_at),
+                        notes: entry.notes,
+                        custom_lists: entry
+                            .custom_lists
+                            .and_then(|lists| serde_json::from_value(lists).ok()),
+                        private: entry.private,
+                        hidden_from_status_lists: entry.hidden_from_status_lists,
+                        media: media!(entry.media?),
+                    })
+                })
+                .collect(),
+        })
+        .collect();
+    Some((lists, collection.has_next_chunk.unwrap_or(false)))
+}
+
+pub fn media(data: media_query::ResponseData) -> Option<anilist_models::Media> {
+    data.media.map(|media| media!(media))
+}
+
+pub fn mal_media(data: mal_media_query::ResponseData) -> Vec<anilist_models::Media> {
+    data.page
+        .and_then(|page| page.media)
+        .unwrap_or_default()
+        .into_iter()
+        .flatten()
+        .map(|media| media!(media))
+        .collect()
+}
+
+pub fn user(data: user_query::ResponseData) -> Option<anilist_models::User> {
+    data.user.map(|user| user!(user))
+}
+
+pub fn viewer(data: viewer_query::ResponseData) -> Option<anilist_models::User> {
+    data.viewer.map(|user| user!(user))
+}
+
+// The list activity on a page and whether another page follows. Text and message activity isn't
+// requested, so it never shows up.
+pub fn activities(data: activities_query::ResponseData) -> (Vec<anilist_models::Activity>, bool) {
+    let page = match data.page {
+        Some(page) => page,
+        None => return (Vec::new(), false),
+    };
+    let activities = page
+        .activities
+        .unwrap_or_default()
+        .into_iter()
+        .flatten()
+        .filter_map(|activity| match activity {
+            activities_query::ActivitiesQueryPageActivities::ListActivity(activity) => {
+                Some(anilist_models::Activity {
+                    id: activity.id as i32,
+                    status: activity.status,
+                    progress: activity.progress,
+                    created_at: activity.created_at,
+                    media: activity.media.map(|media| anilist_models::ActivityMedia {
+                        id: media.id as i32,
+                    }),
+                })
+            }
+            _ => None,
+        })
+        .collect();
+    let has_next_page = page
+        .page_info
+        .and_then(|info| info.has_next_page)
+        .unwrap_or(false);
+    (activities, has_next_page)
+}
+
+pub fn search(data: search_query::ResponseData) -> Vec<anilist_models::SearchMedia> {
+    data.page
+        .and_then(|page| page.media)
+        .unwrap_or_default()
+        .into_iter()
+        .flatten()
+        .map(|media| anilist_models::SearchMedia {
+            id: media.id as i32,
+            title: media
+                .title
+                .map_or_else(empty_title, |title| anilist_models::Title {
+                    user_preferred: title.user_preferred,
+                    english: title.english,
+                    romaji: title.romaji,
+                    native: title.native,
+                }),
+            cover_image: anilist_models::Image {
+                large: media
+                    .cover_image
+                    .and_then(|image| image.large)
+                    .unwrap_or_default(),
+                extra_large: None,
+            },
+            format: media.format.as_ref().and_then(name),
+            season_year: media.season_year.map(|year| year as i32),
+            average_score: media.average_score.map(|score| score as i16),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graphql_client::GraphQLQuery;
+    use serde_json::json;
+
+    #[test]
+    fn usernames_travel_as_variables() {
+        let name = "kumiko\") { id } Viewer { id }";
+        let body = UserQuery::build_query(user_query::Variables {
+            name: name.to_owned(),
+        });
+        assert!(!body.query.contains(name));
+
+        let body = serde_json::to_value(&body).unwrap();
+        assert_eq!(body["operationName"], "UserQuery");
+        assert_eq!(body["variables"], json!({ "name": name }));
+    }
+
+    #[test]
+    fn list_variables_use_graphql_names() {
+        let body = ListQuery::build_query(list_query::Variables {
+            user_id: 1,
+            chunk: 2,
+            per_chunk: 500,
+        });
+        assert_eq!(
+            serde_json::to_value(&body).unwrap()["variables"],
+            json!({"userId": 1, "chunk": 2, "perChunk": 500})
+        );
+    }
+
+    #[test]
+    fn lists_are_read_into_models() {
+        let response: graphql_client::Response<list_query::ResponseData> =
+            serde_json::from_value(json!({
+                "data": {
+                    "MediaListCollection": {
+                        "hasNextChunk": true,
+                        "lists": [{
+                            "name": "Completed",
+                            "isCustomList": false,
+                            "entries": [{
+                                "scoreRaw": 84.6,
+                                "status": "COMPLETED",
+                                "progress": 13,
+                                "customLists": [{"name": "Favourites", "enabled": true}],
+                                "startedAt": {"year": 2023, "month": 5, "day": null},
+                                "completedAt": null,
+                                "media": {
+                                    "id": 5680,
+                                    "format": "TV",
+                                    "title": {"romaji": "K-On!"},
+                                    "genres": ["Music", null],
+                                },
+                            }, {
+                                "status": "PLANNING",
+                                "media": null,
+                            }],
+                        }],
+                    },
+                },
+            }))
+            .unwrap();
+
+        let (lists, has_next_chunk) = lists(response.data.unwrap()).unwrap();
+        assert!(has_next_chunk);
+        assert_eq!(lists.len(), 1);
+        assert_eq!(lists[0].name, "Completed");
+        // Entries without their anime are dropped.
+        assert_eq!(lists[0].entries.len(), 1);
+
+        let entry = &lists[0].entries[0];
+        assert_eq!(entry.score_raw, Some(85));
+        assert_eq!(entry.status.as_ref().map(String::as_str), Some("COMPLETED"));
+        assert_eq!(entry.progress, Some(13));
+        assert_eq!(
+            (
+                entry.started_at.year,
+                entry.started_at.month,
+                entry.started_at.day
+            ),
+            (Some(2023), Some(5), None)
+        );
+        assert_eq!(entry.completed_at.year, None);
+        assert_eq!(entry.custom_lists.as_ref().map(Vec::len), Some(1));
+        assert_eq!(entry.media.id, 5680);
+        assert_eq!(entry.media.format.as_ref().map(String::as_str), Some("TV"));
+        assert_eq!(
+            entry.media.title.romaji.as_ref().map(String::as_str),
+            Some("K-On!")
+        );
+        assert_eq!(entry.media.genres, Some(vec!["Music".to_owned()]));
+    }
+
+    #[test]
+    fn private_lists_come_back_as_errors() {
+        let response: graphql_client::Response<list_query::ResponseData> =
+            serde_json::from_value(json!({
+                "data": null,
+                "errors": [{"message": "Private User", "status": 404}],
+            }))
+            .unwrap();
+        assert!(response.data.is_none());
+        assert_eq!(response.errors.unwrap()[0].message, "Private User");
+    }
+
+    #[test]
+    fn missing_users_have_no_data() {
+        let response: graphql_client::Response<user_query::ResponseData> =
+            serde_json::from_value(json!({"data": {"User": null}})).unwrap();
+        assert!(response.data.and_then(user).is_none());
+    }
+}
@@ -8,32 +8,28 @@
 
 use serde_derive::{Deserialize, Serialize};
 
-// User Structs
-#[derive(Serialize, Deserialize, Clone)]
-pub struct UserResponse {
-    pub data: UserData,
-    pub errors: Option<Vec<Error>>,
-}
-
-#[derive(Serialize, Deserialize, Clone)]
-pub struct Error {
-    pub message: String,
-    pub status: i32,
-    pub locations: Vec<Location>,
-}
-
-#[derive(Serialize, Deserialize, Clone)]
-pub struct Location {
-    pub line: i32,
-    pub column: i32,
+// AniList's users, lists and anime as anihistory works with them. Query responses are converted
+// into these in anilist_graphql, lists from MyAnimeList are built from them as well.
+
+// OAuth Structs
+#[derive(Serialize)]
+pub struct TokenRequest<'a> {
+    pub grant_type: &'a str,
+    pub client_id: &'a str,
+    pub client_secret: &'a str,
+    pub redirect_uri: &'a str,
+    pub code: &'a str,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
-pub struct UserData {
-    #[serde(rename = "User")]
-    pub user: Option<User>,
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    // Seconds until the access token expires.
+    pub expires_in: i64,
 }
 
+// User Structs
 #[derive(Serialize, Deserialize, Clone)]
 pub struct User {
     pub id: i32,
@@ -41,21 +37,34 @@ pub struct User {
     pub avatar: Avatar,
 }
 
-// List Structs
+// A list update, like "watched episode" with progress "4 - 6".
 #[derive(Serialize, Deserialize, Clone)]
-pub struct ListResponse {
-    pub data: MediaListCollectionData,
+pub struct Activity {
+    pub id: i32,
+    pub status: Option<String>,
+    pub progress: Option<String>,
+    // Unix timestamp.
+    #[serde(rename = "createdAt")]
+    pub created_at: i64,
+    pub media: Option<ActivityMedia>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
-pub struct MediaListCollectionData {
-    #[serde(rename = "MediaListCollection")]
-    pub media_list_collection: MediaListCollection,
+pub struct ActivityMedia {
+    pub id: i32,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
-pub struct MediaListCollection {
-    pub lists: Vec<MediaList>,
+pub struct SearchMedia {
+    pub id: i32,
+    pub title: Title,
+    #[serde(rename = "coverImage")]
+    pub cover_image: Image,
+    pub format: Option<String>,
+    #[serde(rename = "seasonYear")]
+    pub season_year: Option<i32>,
+    #[serde(rename = "averageScore")]
+    pub average_score: Option<i16>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -66,6 +75,8 @@ pub struct Avatar {
 #[derive(Serialize, Deserialize, Clone)]
 pub struct MediaList {
     pub name: String,
+    #[serde(rename = "isCustomList")]
+    pub is_custom_list: bool,
     pub entries: Vec<Entry>,
 }
 
@@ -73,13 +84,36 @@ pub struct MediaList {
 pub struct Entry {
     #[serde(rename = "scoreRaw")]
     pub score_raw: Option<i16>,
+    pub status: Option<String>,
+    // Episodes watched, counting from the start again on a rewatch.
+    pub progress: Option<i32>,
+    // Times the user rewatched it.
+    pub repeat: Option<i32>,
+    // Unix timestamp of the user's last change to the entry.
+    #[serde(rename = "updatedAt")]
+    pub updated_at: Option<i64>,
     #[serde(rename = "startedAt")]
     pub started_at: Date,
     #[serde(rename = "completedAt")]
     pub completed_at: Date,
+    pub notes: Option<String>,
+    // Every custom list the user has, with whether the entry is on it.
+    #[serde(rename = "customLists")]
+    pub custom_lists: Option<Vec<CustomList>>,
+    // Only the user sees it on AniList, it comes back with their access token.
+    pub private: Option<bool>,
+    // On custom lists only, AniList leaves it out of the user's status lists.
+    #[serde(rename = "hiddenFromStatusLists")]
+    pub hidden_from_status_lists: Option<bool>,
     pub media: Media,
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CustomList {
+    pub name: String,
+    pub enabled: bool,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Date {
     pub year: Option<i32>,
@@ -90,6 +124,10 @@ pub struct Date {
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Media {
     pub id: i32,
+    #[serde(rename = "idMal")]
+    pub id_mal: Option<i32>,
+    #[serde(rename = "isAdult")]
+    pub is_adult: Option<bool>,
     pub title: Title,
     pub description: String,
     #[serde(rename = "coverImage")]
@@ -98,6 +136,31 @@ pub struct Media {
     pub average_score: Option<i16>,
     #[serde(rename = "siteUrl")]
     pub site_url: String,
+    pub genres: Option<Vec<String>>,
+    pub tags: Option<Vec<Tag>>,
+    pub episodes: Option<i32>,
+    pub season: Option<String>,
+    #[serde(rename = "seasonYear")]
+    pub season_year: Option<i32>,
+    pub format: Option<String>,
+    pub studios: Option<StudioConnection>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Tag {
+    pub name: String,
+    #[serde(rename = "isMediaSpoiler")]
+    pub is_media_spoiler: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StudioConnection {
+    pub nodes: Vec<Studio>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Studio {
+    pub name: String,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -112,4 +175,7 @@ pub struct Title {
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Image {
     pub large: String,
+    // Only requested for anime we store, search results leave it out.
+    #[serde(rename = "extraLarge")]
+    pub extra_large: Option<String>,
 }
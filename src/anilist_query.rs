@@ -6,95 +6,512 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use crate::anilist_models;
-use log::error;
-use reqwest::blocking::Client;
+// Queries to AniList. Every request goes through one client with ANILIST_CONNECT_TIMEOUT_SECS to
+// connect and ANILIST_TIMEOUT_SECS for the whole request. After ANILIST_BREAKER_FAILURES queries
+// in a row have failed, AniList is left alone for ANILIST_BREAKER_COOLDOWN_SECS and queries fail
+// right away, so syncs don't queue up behind an AniList that doesn't answer. Once the cooldown is
+// over a single query goes out to find out whether AniList is back.
+
+use crate::anilist_graphql::{
+    self, activities_query, list_query, mal_media_query, media_query, search_query, user_query,
+    viewer_query, ActivitiesQuery, ListQuery, MalMediaQuery, MediaQuery, SearchQuery, UserQuery,
+    ViewerQuery,
+};
+use crate::{anilist_models, config, models};
+use chrono::Utc;
+use graphql_client::GraphQLQuery;
+use log::{error, info, warn};
+use reqwest::blocking::{Client, Response};
+use reqwest::StatusCode;
 use serde_json::from_str;
-use std::collections::HashMap;
-
-pub fn get_id(username: &str) -> Option<anilist_models::User> {
-    // Construct query to anilist GraphQL to find corresponding id for username
-    let query = USER_QUERY.replace("{}", username.as_ref());
-    let mut body = HashMap::new();
-    body.insert("query", query);
-    let client = Client::new();
-    let res = client.post(ANILSIT_URL).json(&body).send().unwrap();
-    let res_text = res.text().unwrap();
-    let json: anilist_models::UserResponse = from_str(res_text.as_ref()).unwrap();
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use std::{fmt, thread};
+
+// Consecutive failed requests after which AniList is reported as down instead of degraded.
+const DOWN_AFTER_FAILURES: usize = 3;
+
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+const DEFAULT_BREAKER_FAILURES: usize = 5;
+
+const DEFAULT_BREAKER_COOLDOWN_SECS: u64 = 60;
+
+// Requests sent for a single query before giving up on rate limits and server errors.
+const MAX_ATTEMPTS: u32 = 5;
+
+const BASE_BACKOFF_SECS: u64 = 1;
+
+// Upper bound for any single wait, whatever AniList asks for.
+const MAX_BACKOFF_SECS: u64 = 60;
+
+// AniList counts requests per minute.
+const RATE_LIMIT_WINDOW_SECS: u64 = 60;
+
+// Entries requested per chunk, the most AniList allows.
+const PER_CHUNK: i32 = 500;
+
+// Guards against a response that keeps claiming there is another chunk.
+const MAX_CHUNKS: i32 = 100;
+
+const SEARCH_PER_PAGE: i32 = 25;
+
+// The most AniList returns per page.
+const MAL_IDS_PER_PAGE: usize = 50;
+
+const ACTIVITIES_PER_PAGE: i32 = 50;
+
+// Activity fetched by a single sync, the first sync of a user only goes back this far.
+const MAX_ACTIVITY_PAGES: i32 = 20;
+
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+static BREAKER: Mutex<Breaker> = Mutex::new(Breaker {
+    failures: 0,
+    open_until: None,
+    probing: false,
+});
+
+#[derive(Debug)]
+pub enum AnilistError {
+    Unreachable(reqwest::Error),
+    InvalidResponse(serde_json::Error),
+    RetriesExhausted(u16),
+    // The user's list is private on AniList.
+    PrivateList,
+    QueryFailed(String),
+    // The caller's deadline passed before AniList answered.
+    DeadlineExceeded,
+    // AniList no longer accepts the access token, usually because the user revoked it.
+    InvalidToken,
+    // The authorization code could not be exchanged for an access token.
+    AuthorizationFailed(u16),
+    // AniList kept failing, so queries aren't sent for the given number of seconds.
+    CircuitOpen(u64),
+    // Lists from MyAnimeList come with AniList's data of each anime, see mal_query.
+    Mal(String),
+}
+
+impl fmt::Display for AnilistError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AnilistError::Unreachable(error) => write!(f, "AniList unreachable: {}", error),
+            AnilistError::InvalidResponse(error) => {
+                write!(f, "invalid response from AniList: {}", error)
+            }
+            AnilistError::RetriesExhausted(status) => write!(
+                f,
+                "AniList still responded with status {} after {} attempts",
+                status, MAX_ATTEMPTS
+            ),
+            AnilistError::PrivateList => write!(f, "list is private on AniList"),
+            AnilistError::QueryFailed(message) => write!(f, "AniList query failed: {}", message),
+            AnilistError::DeadlineExceeded => write!(f, "deadline passed while querying AniList"),
+            AnilistError::InvalidToken => write!(f, "AniList rejected the access token"),
+            AnilistError::AuthorizationFailed(status) => write!(
+                f,
+                "AniList refused the authorization code with status {}",
+                status
+            ),
+            AnilistError::CircuitOpen(secs) => write!(
+                f,
+                "AniList is failing, queries are paused for another {}s",
+                secs
+            ),
+            AnilistError::Mal(message) => write!(f, "MyAnimeList request failed: {}", message),
+        }
+    }
+}
+
+struct Breaker {
+    failures: usize,
+    // Queries fail right away until then.
+    open_until: Option<Instant>,
+    // A query is out to test whether AniList is back, the others still fail right away.
+    probing: bool,
+}
+
+impl Breaker {
+    fn admit(&mut self, now: Instant) -> Result<(), AnilistError> {
+        match self.open_until {
+            Some(until) if now < until => Err(AnilistError::CircuitOpen(
+                until.duration_since(now).as_secs().max(1),
+            )),
+            Some(_) if self.probing => Err(AnilistError::CircuitOpen(1)),
+            Some(_) => {
+                self.probing = true;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn succeeded(&mut self) {
+        if self.open_until.is_some() {
+            info!("AniList answered again, resuming queries");
+        }
+        self.failures = 0;
+        self.open_until = None;
+        self.probing = false;
+    }
+
+    fn failed(&mut self, now: Instant) {
+        self.failures += 1;
+        if self.probing || self.failures >= breaker_failures() {
+            let cooldown = config::env_value(
+                "ANILIST_BREAKER_COOLDOWN_SECS",
+                DEFAULT_BREAKER_COOLDOWN_SECS,
+            );
+            warn!(
+                "AniList failed {} times in a row, pausing queries for {}s",
+                self.failures, cooldown
+            );
+            self.open_until = Some(now + Duration::from_secs(cooldown));
+            self.probing = false;
+        }
+    }
+}
+
+// Also downloads covers and avatars from AniList's CDN, which shouldn't hang a sync either.
+pub fn client() -> &'static Client {
+    CLIENT.get_or_init(|| {
+        Client::builder()
+            .connect_timeout(Duration::from_secs(config::env_value(
+                "ANILIST_CONNECT_TIMEOUT_SECS",
+                DEFAULT_CONNECT_TIMEOUT_SECS,
+            )))
+            .timeout(request_timeout())
+            .build()
+            .expect("the AniList client could not be built")
+    })
+}
+
+fn request_timeout() -> Duration {
+    Duration::from_secs(config::env_value(
+        "ANILIST_TIMEOUT_SECS",
+        DEFAULT_TIMEOUT_SECS,
+    ))
+}
+
+fn breaker_failures() -> usize {
+    config::env_value("ANILIST_BREAKER_FAILURES", DEFAULT_BREAKER_FAILURES).max(1)
+}
+
+// The user the access token belongs to.
+pub fn get_viewer(token: &str) -> Result<anilist_models::User, AnilistError> {
+    let response = post_query_until::<ViewerQuery>(viewer_query::Variables, None, Some(token))?;
+
+    response
+        .data
+        .and_then(anilist_graphql::viewer)
+        .ok_or(AnilistError::InvalidToken)
+}
+
+// Trades the code from the OAuth callback for an access token. Codes are single use, so this is
+// never retried.
+pub fn exchange_code(
+    request: &anilist_models::TokenRequest,
+) -> Result<anilist_models::TokenResponse, AnilistError> {
+    let response = client()
+        .post(TOKEN_URL)
+        .json(request)
+        .send()
+        .map_err(AnilistError::Unreachable)?;
+
+    if !response.status().is_success() {
+        return Err(AnilistError::AuthorizationFailed(
+            response.status().as_u16(),
+        ));
+    }
+    let res_text = response.text().map_err(AnilistError::Unreachable)?;
+    from_str(res_text.as_ref()).map_err(AnilistError::InvalidResponse)
+}
+
+pub fn get_id(username: &str) -> Result<Option<anilist_models::User>, AnilistError> {
+    // Query anilist GraphQL to find corresponding id for username
+    let response = post_query::<UserQuery>(user_query::Variables {
+        name: username.to_owned(),
+    })?;
 
     // If the username was valid, there will be some data, else there will be errors
-    match json.data.user {
-        Some(user) => Some(user),
+    match response.data.and_then(anilist_graphql::user) {
+        Some(user) => Ok(Some(user)),
         None => {
             error!(
                 "user_name={} was not found in anilist/external database",
                 username
             );
-            None
+            Ok(None)
         }
     }
 }
 
-pub fn get_lists(id: i32) -> Vec<anilist_models::MediaList> {
-    let query = LIST_QUERY.replace("{}", id.to_string().as_ref());
-    let mut body = HashMap::new();
-    body.insert("query", query);
+// Large lists come back in chunks, each holding a slice of every list. Entries are merged back
+// into one list per name so callers never see the chunking.
+// Retries and rate limit pauses stop at the deadline, if there is one. With the user's own access
+// token, private lists and scores come back as well.
+pub fn get_lists(
+    id: i32,
+    deadline: Option<Instant>,
+    token: Option<&str>,
+) -> Result<Vec<anilist_models::MediaList>, AnilistError> {
+    let mut lists: Vec<anilist_models::MediaList> = Vec::new();
 
-    let client = Client::new();
-    let res = client.post(ANILSIT_URL).json(&body).send().unwrap();
-    let res_text = res.text().unwrap();
-    let json: anilist_models::ListResponse = from_str(res_text.as_ref()).unwrap();
-    json.data.media_list_collection.lists.clone()
+    for chunk in 1..=MAX_CHUNKS {
+        let response = post_query_until::<ListQuery>(
+            list_query::Variables {
+                user_id: i64::from(id),
+                chunk: i64::from(chunk),
+                per_chunk: i64::from(PER_CHUNK),
+            },
+            deadline,
+            token,
+        )?;
+        let errors = response.errors.unwrap_or_default();
+        let (chunk_lists, has_next_chunk) = match response.data.and_then(anilist_graphql::lists) {
+            Some(chunk) => chunk,
+            // Private lists come back as a "Private User" error without any data.
+            None if errors.iter().any(|error| error.message == "Private User") => {
+                return Err(AnilistError::PrivateList)
+            }
+            None if errors.iter().any(|error| error.message == "Invalid token") => {
+                return Err(AnilistError::InvalidToken)
+            }
+            None => return Err(query_failed(errors)),
+        };
+
+        for list in chunk_lists {
+            match lists.iter_mut().find(|existing| existing.name == list.name) {
+                Some(existing) => existing.entries.extend(list.entries),
+                None => lists.push(list),
+            }
+        }
+
+        if !has_next_chunk {
+            return Ok(lists);
+        }
+    }
+
+    warn!(
+        "user_id={} has more than {} chunks of entries, the rest is ignored",
+        id, MAX_CHUNKS
+    );
+    Ok(lists)
 }
 
-static ANILSIT_URL: &'static str = "https://graphql.anilist.co";
+// None when AniList has no anime with the id.
+pub fn get_media(id: i32) -> Result<Option<anilist_models::Media>, AnilistError> {
+    let response = post_query::<MediaQuery>(media_query::Variables { id: i64::from(id) })?;
+
+    Ok(response.data.and_then(anilist_graphql::media))
+}
 
-static LIST_QUERY: &'static str = "query {
-    MediaListCollection(userId: {}, type: ANIME) {
-      lists {
-        name
-        entries {
-          ...mediaListEntry
+// The user's public list activity after since, newest first.
+pub fn get_activities(
+    user_id: i32,
+    since: Option<i64>,
+    deadline: Option<Instant>,
+) -> Result<Vec<anilist_models::Activity>, AnilistError> {
+    let mut activities = Vec::new();
+    for page in 1..=MAX_ACTIVITY_PAGES {
+        let response = post_query_until::<ActivitiesQuery>(
+            activities_query::Variables {
+                user_id: i64::from(user_id),
+                page: i64::from(page),
+                per_page: i64::from(ACTIVITIES_PER_PAGE),
+                since,
+            },
+            deadline,
+            None,
+        )?;
+        let (page, has_next_page) = match response.data {
+            Some(data) => anilist_graphql::activities(data),
+            None => return Err(query_failed(response.errors.unwrap_or_default())),
+        };
+        activities.extend(page);
+        if !has_next_page {
+            break;
         }
-      }
     }
-  }
-
-  fragment mediaListEntry on MediaList {
-    scoreRaw: score(format: POINT_100)
-    startedAt {
-      year
-      month
-      day
+    Ok(activities)
+}
+
+// The anime AniList knows among the MyAnimeList ids, in no particular order.
+pub fn get_media_by_mal_ids(
+    mal_ids: &[i32],
+    deadline: Option<Instant>,
+) -> Result<Vec<anilist_models::Media>, AnilistError> {
+    let mut media = Vec::with_capacity(mal_ids.len());
+    for chunk in mal_ids.chunks(MAL_IDS_PER_PAGE) {
+        let response = post_query_until::<MalMediaQuery>(
+            mal_media_query::Variables {
+                id_mal: chunk.iter().map(|&id| i64::from(id)).collect(),
+                per_page: MAL_IDS_PER_PAGE as i64,
+            },
+            deadline,
+            None,
+        )?;
+        match response.data {
+            Some(data) => media.extend(anilist_graphql::mal_media(data)),
+            None => return Err(query_failed(response.errors.unwrap_or_default())),
+        }
+    }
+    Ok(media)
+}
+
+pub fn search_media(search: &str) -> Result<Vec<anilist_models::SearchMedia>, AnilistError> {
+    let response = post_query::<SearchQuery>(search_query::Variables {
+        search: search.to_owned(),
+        per_page: i64::from(SEARCH_PER_PAGE),
+    })?;
+
+    match response.data {
+        Some(data) => Ok(anilist_graphql::search(data)),
+        None => Err(query_failed(response.errors.unwrap_or_default())),
+    }
+}
+
+fn query_failed(errors: Vec<graphql_client::Error>) -> AnilistError {
+    let messages: Vec<String> = errors.into_iter().map(|error| error.message).collect();
+    AnilistError::QueryFailed(messages.join(", "))
+}
+
+pub fn upstream_status() -> models::UpstreamStatus {
+    let breaker = BREAKER.lock().unwrap();
+    if breaker.open_until.is_some() {
+        return models::UpstreamStatus::Down;
+    }
+    match breaker.failures {
+        0 => models::UpstreamStatus::Up,
+        failures if failures < DOWN_AFTER_FAILURES => models::UpstreamStatus::Degraded,
+        _ => models::UpstreamStatus::Down,
+    }
+}
+
+// User input only ever travels in the variables object, never inside the query text.
+fn post_query<Q: GraphQLQuery>(
+    variables: Q::Variables,
+) -> Result<graphql_client::Response<Q::ResponseData>, AnilistError> {
+    post_query_until::<Q>(variables, None, None)
+}
+
+fn post_query_until<Q: GraphQLQuery>(
+    variables: Q::Variables,
+    deadline: Option<Instant>,
+    token: Option<&str>,
+) -> Result<graphql_client::Response<Q::ResponseData>, AnilistError> {
+    let body = Q::build_query(variables);
+    BREAKER.lock().unwrap().admit(Instant::now())?;
+
+    let res_text = match send_with_retries(&body, deadline, token) {
+        Ok(text) => {
+            BREAKER.lock().unwrap().succeeded();
+            text
+        }
+        // Running out of time says nothing about AniList's health. A probe that ran out of time
+        // gives the next query the chance to find out.
+        Err(AnilistError::DeadlineExceeded) => {
+            BREAKER.lock().unwrap().probing = false;
+            return Err(AnilistError::DeadlineExceeded);
+        }
+        Err(error) => {
+            BREAKER.lock().unwrap().failed(Instant::now());
+            error!("error querying AniList. Error: {}", error);
+            return Err(error);
+        }
+    };
+    from_str(res_text.as_ref()).map_err(AnilistError::InvalidResponse)
+}
+
+// Rate limited requests wait for as long as AniList asks, server errors back off exponentially.
+// Anything else, including GraphQL errors for unknown users, is handed back to the caller.
+fn send_with_retries<T: serde::Serialize>(
+    body: &T,
+    deadline: Option<Instant>,
+    token: Option<&str>,
+) -> Result<String, AnilistError> {
+    let client = client();
+    let mut attempt = 0;
+
+    loop {
+        let mut request = client.post(&config::settings().anilist_url).json(body);
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        // The deadline can only shorten the configured timeout.
+        if let Some(deadline) = deadline {
+            request = request.timeout(remaining(deadline)?.min(request_timeout()));
+        }
+        let response = request.send().map_err(|error| {
+            if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                AnilistError::DeadlineExceeded
+            } else {
+                AnilistError::Unreachable(error)
+            }
+        })?;
+        let status = response.status();
+
+        let wait = if status == StatusCode::TOO_MANY_REQUESTS {
+            header_secs(&response, "Retry-After").unwrap_or_else(|| backoff(attempt))
+        } else if status.is_server_error() {
+            backoff(attempt)
+        } else {
+            // Out of requests for this window, so hold off before the next one goes out.
+            if header_secs(&response, "X-RateLimit-Remaining") == Some(0) {
+                let reset = header_secs(&response, "X-RateLimit-Reset")
+                    .map(|reset| reset.saturating_sub(Utc::now().timestamp() as u64))
+                    .unwrap_or(RATE_LIMIT_WINDOW_SECS);
+                warn!("AniList rate limit reached, pausing for {}s", reset);
+                let mut pause = Duration::from_secs(reset.min(MAX_BACKOFF_SECS));
+                if let Some(deadline) = deadline {
+                    pause = pause.min(deadline.saturating_duration_since(Instant::now()));
+                }
+                thread::sleep(pause);
+            }
+            return response.text().map_err(AnilistError::Unreachable);
+        };
+
+        attempt += 1;
+        if attempt >= MAX_ATTEMPTS {
+            return Err(AnilistError::RetriesExhausted(status.as_u16()));
+        }
+        // No point waiting for a retry that can't be sent in time.
+        if let Some(deadline) = deadline {
+            if remaining(deadline)? <= Duration::from_secs(wait.min(MAX_BACKOFF_SECS)) {
+                return Err(AnilistError::DeadlineExceeded);
+            }
+        }
+
+        warn!(
+            "AniList responded with status={}, retrying in {}s ({}/{})",
+            status,
+            wait.min(MAX_BACKOFF_SECS),
+            attempt,
+            MAX_ATTEMPTS - 1
+        );
+        thread::sleep(Duration::from_secs(wait.min(MAX_BACKOFF_SECS)));
     }
-    completedAt {
-      year
-      month
-      day
+}
+
+fn remaining(deadline: Instant) -> Result<Duration, AnilistError> {
+    match deadline.checked_duration_since(Instant::now()) {
+        Some(remaining) if remaining > Duration::from_secs(0) => Ok(remaining),
+        _ => Err(AnilistError::DeadlineExceeded),
     }
-    media {
-	  id
-      title {
-        userPreferred
-        english
-        romaji
-        native
-      }
-      description(asHtml: true)
-      coverImage {
-        large
-      }
-      averageScore
-      siteUrl
-      }
-    }";
-
-static USER_QUERY: &'static str = "query {
-  	User(name: \"{}\") {
-	  id
-      name
-      avatar {
-        large
-      }
-	}
-  }";
+}
+
+fn backoff(attempt: u32) -> u64 {
+    BASE_BACKOFF_SECS << attempt.min(6)
+}
+
+fn header_secs(response: &Response, name: &str) -> Option<u64> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+static TOKEN_URL: &'static str = "https://anilist.co/api/v2/oauth/token";
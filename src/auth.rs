@@ -0,0 +1,75 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::{config, database, PgDbConn};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::Outcome;
+
+// Request guard for routes that act on behalf of a user. The caller has to send the token of one
+// of their sessions as "Authorization: Bearer <token>".
+pub struct AuthenticatedUser {
+    pub user_id: i32,
+    // Hash of the token the request came with, see database::session_hash.
+    pub session: String,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for AuthenticatedUser {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let token = match bearer_token(request) {
+            Some(token) => token,
+            None => return Outcome::Failure((Status::Unauthorized, ())),
+        };
+
+        let database_conn = request.guard::<PgDbConn>()?;
+        let session = database::session_hash(token);
+        match database::get_session_user(&session, &database_conn) {
+            Some(user_id) => Outcome::Success(AuthenticatedUser { user_id, session }),
+            None => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+// Request guard for operator routes. The caller has to send ADMIN_TOKEN as a bearer token, and
+// without ADMIN_TOKEN set nobody gets in.
+pub struct Admin;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let expected = match config::env_parsed::<String>("ADMIN_TOKEN") {
+            Some(token) => token,
+            None => return Outcome::Failure((Status::Forbidden, ())),
+        };
+
+        match bearer_token(request) {
+            Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => {
+                Outcome::Success(Admin)
+            }
+            Some(_) => Outcome::Failure((Status::Forbidden, ())),
+            None => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+// Compares without returning early, so response times don't give the token away byte by byte.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn bearer_token<'a>(request: &'a Request) -> Option<&'a str> {
+    let header = request.headers().get_one("Authorization")?;
+    if header.starts_with("Bearer ") {
+        Some(header["Bearer ".len()..].trim())
+    } else {
+        None
+    }
+}
@@ -0,0 +1,218 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// `anihistory_server bench`, built with --features bench. Seeds synthetic users with lists of a
+// given size, sends requests to the list and stats routes through the full server in process and
+// prints p50/p99 latencies, so regressions in building responses show up before a release. It
+// writes to and deletes from its database, so it only runs against BENCH_DATABASE_URL, which has to
+// name a database other than DATABASE_URL.
+
+use crate::stats;
+use dotenv::dotenv;
+use rocket::local::Client;
+use rocket::Rocket;
+use rocket_contrib::databases::postgres::Connection;
+use std::env;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+// Users and anime get ids from here on.
+const ID_BASE: i32 = 1_900_000_000;
+
+const DEFAULT_USERS: i32 = 10;
+
+const DEFAULT_ENTRIES: i32 = 500;
+
+const DEFAULT_REQUESTS: usize = 200;
+
+// Tables holding rows of the synthetic users, emptied before the users themselves.
+const USER_TABLES: &[&str] = &[
+    "sync_warnings",
+    "sync_errors",
+    "list_history",
+    "lists",
+    "user_stats",
+    "user_preferences",
+    "response_cache",
+    "profile_hits",
+];
+
+#[derive(Debug)]
+pub enum BenchError {
+    // BENCH_DATABASE_URL is missing or names the service's own database.
+    NoScratchDatabase,
+    Database(postgres::Error),
+    // The server didn't start or a route answered with an error.
+    Server(String),
+}
+
+impl fmt::Display for BenchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BenchError::NoScratchDatabase => write!(
+                f,
+                "BENCH_DATABASE_URL must be set to a scratch database other than DATABASE_URL"
+            ),
+            BenchError::Database(error) => write!(f, "database error: {}", error),
+            BenchError::Server(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<postgres::Error> for BenchError {
+    fn from(error: postgres::Error) -> Self {
+        BenchError::Database(error)
+    }
+}
+
+// Points DATABASE_URL at the scratch database before the settings are read, so migrations, the
+// seeded rows and the server's pool all end up there.
+pub fn use_scratch_database() -> Result<(), BenchError> {
+    dotenv().ok();
+    let scratch = env::var("BENCH_DATABASE_URL")
+        .ok()
+        .filter(|url| !url.trim().is_empty())
+        .ok_or(BenchError::NoScratchDatabase)?;
+    if env::var("DATABASE_URL").ok().as_ref() == Some(&scratch) {
+        return Err(BenchError::NoScratchDatabase);
+    }
+    env::set_var("DATABASE_URL", scratch);
+    Ok(())
+}
+
+pub struct Options {
+    // Synthetic users, requests go round them.
+    pub users: i32,
+    // Entries on each user's list.
+    pub entries: i32,
+    // Requests per route.
+    pub requests: usize,
+}
+
+impl Options {
+    pub fn from_args(args: &[String]) -> Options {
+        let value = |flag: &str| {
+            args.iter()
+                .position(|arg| arg == flag)
+                .and_then(|index| args.get(index + 1))
+                .and_then(|value| value.parse().ok())
+        };
+        Options {
+            users: value("--users").unwrap_or(DEFAULT_USERS).max(1),
+            entries: value("--entries").unwrap_or(DEFAULT_ENTRIES).max(1),
+            requests: value("--requests")
+                .map(|requests: i32| requests as usize)
+                .unwrap_or(DEFAULT_REQUESTS)
+                .max(1),
+        }
+    }
+}
+
+// Routes measured, {} is the user's name. The default list comes from the snapshot, the sorted one
+// is built from the tables on every request.
+const ROUTES: &[(&str, &str)] = &[
+    ("list", "/users/{}"),
+    ("list sorted", "/users/{}?sort=score&order=desc"),
+    ("list v1", "/v1/users/{}"),
+    ("stats", "/users/{}/stats"),
+];
+
+pub fn run(server: Rocket, options: &Options, connection: &Connection) -> Result<(), BenchError> {
+    clean_up(connection)?;
+    seed(options, connection)?;
+    let result = measure(server, options);
+    clean_up(connection)?;
+
+    let report = result.map_err(BenchError::Server)?;
+    println!(
+        "{} users with {} entries each, {} requests per route",
+        options.users, options.entries, options.requests
+    );
+    println!(
+        "{:<12} {:>10} {:>10} {:>10}",
+        "route", "p50 ms", "p99 ms", "max ms"
+    );
+    for (route, mut latencies) in report {
+        latencies.sort();
+        println!(
+            "{:<12} {:>10.2} {:>10.2} {:>10.2}",
+            route,
+            millis(percentile(&latencies, 50)),
+            millis(percentile(&latencies, 99)),
+            millis(*latencies.last().unwrap()),
+        );
+    }
+    Ok(())
+}
+
+fn seed(options: &Options, connection: &Connection) -> Result<(), postgres::Error> {
+    let transaction = connection.transaction()?;
+    transaction.execute("INSERT INTO users (user_id, name, avatar_s3, avatar_anilist, provider, external_id) SELECT $1 + n, 'bench_user_' || n, '', '', 'anilist', $1 + n FROM generate_series(0, $2 - 1) AS n", &[&ID_BASE, &options.users])?;
+    transaction.execute("INSERT INTO anime (anime_id, description, cover_s3, cover_anilist, average, romaji, english, search_title, slug, genres, tags, episodes, season, season_year, format, studio) SELECT $1 + n, 'Synthetic anime number ' || n || ' for benchmarks.', '', '', (n % 100)::int2, 'Bench Anime ' || n, 'Bench Anime ' || n, 'bench anime ' || n, 'bench-anime-' || n, ARRAY['Action', 'Drama'], ARRAY['Benchmark'], 12, 'WINTER', 2000 + n % 25, 'TV', 'Bench Studio' FROM generate_series(0, $2 - 1) AS n", &[&ID_BASE, &options.entries])?;
+    transaction.execute("INSERT INTO lists (user_id, anime_id, user_title, start_day, end_day, score, status, progress, repeat) SELECT $1 + u, $1 + a, 'Bench Anime ' || a, date '2010-01-01' + (u * 7 + a) % 4000, date '2010-01-15' + (u * 7 + a) % 4000, ((u + a) % 100)::int2, (ARRAY['COMPLETED', 'CURRENT', 'PLANNING', 'DROPPED', 'PAUSED'])[1 + a % 5], 12, 0 FROM generate_series(0, $2 - 1) AS u, generate_series(0, $3 - 1) AS a", &[&ID_BASE, &options.users, &options.entries])?;
+    transaction.commit()?;
+
+    for user in 0..options.users {
+        stats::rebuild(ID_BASE + user, connection);
+    }
+    Ok(())
+}
+
+fn clean_up(connection: &Connection) -> Result<(), postgres::Error> {
+    let transaction = connection.transaction()?;
+    for table in USER_TABLES {
+        transaction.execute(
+            &format!("DELETE FROM {} WHERE user_id >= $1", table),
+            &[&ID_BASE],
+        )?;
+    }
+    transaction.execute("DELETE FROM users WHERE user_id >= $1", &[&ID_BASE])?;
+    transaction.execute("DELETE FROM anime WHERE anime_id >= $1", &[&ID_BASE])?;
+    transaction.commit()
+}
+
+// Latencies of every route, in ROUTES order. Each route is requested once per user first, so the
+// snapshots and statement caches are warm like on a running server.
+fn measure(
+    server: Rocket,
+    options: &Options,
+) -> Result<Vec<(&'static str, Vec<Duration>)>, String> {
+    let client = Client::new(server).map_err(|error| format!("server did not start: {}", error))?;
+    let name = |request: usize| format!("bench_user_{}", request as i32 % options.users);
+
+    let mut report = Vec::new();
+    for (route, path) in ROUTES {
+        for user in 0..options.users as usize {
+            client.get(path.replace("{}", &name(user))).dispatch();
+        }
+
+        let mut latencies = Vec::with_capacity(options.requests);
+        for request in 0..options.requests {
+            let started = Instant::now();
+            let mut response = client.get(path.replace("{}", &name(request))).dispatch();
+            // The body is part of the work, streamed responses are only built while read.
+            let _ = response.body_bytes();
+            latencies.push(started.elapsed());
+
+            if response.status().code >= 400 {
+                return Err(format!("{} answered {}", path, response.status()));
+            }
+        }
+        report.push((*route, latencies));
+    }
+    Ok(report)
+}
+
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    let index = (sorted.len() * percent + 99) / 100;
+    sorted[index.max(1) - 1]
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
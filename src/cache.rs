@@ -0,0 +1,265 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Precompressed snapshots of the list response, refreshed after every sync so the hot path can
+// skip the list join entirely. List responses also carry a weak ETag derived from what they are
+// built from, so polling clients that send it back get a 304 without the list being loaded. The
+// most requested lists are also kept serialized in memory, see memory_cache.
+
+use crate::{anilist_query, database, memory_cache, models, response};
+use log::error;
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::Outcome;
+use rocket_contrib::databases::postgres::Connection;
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
+
+const ZSTD_LEVEL: i32 = 19;
+
+// Caches may keep list responses but have to check back every time, which the ETag makes cheap.
+const REVALIDATE: &str = "public, no-cache";
+
+pub struct AcceptEncoding {
+    pub zstd: bool,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for AcceptEncoding {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let zstd = request
+            .headers()
+            .get("Accept-Encoding")
+            .flat_map(|value| value.split(','))
+            .any(|encoding| encoding.split(';').next().unwrap_or("").trim() == "zstd");
+        Outcome::Success(AcceptEncoding { zstd })
+    }
+}
+
+// ETags the client already has, from If-None-Match.
+pub struct IfNoneMatch(Vec<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for IfNoneMatch {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let tags = request
+            .headers()
+            .get("If-None-Match")
+            .flat_map(|value| value.split(','))
+            .map(|tag| tag.trim().to_owned())
+            .collect();
+        Outcome::Success(IfNoneMatch(tags))
+    }
+}
+
+impl IfNoneMatch {
+    // Weak comparison, as If-None-Match asks for.
+    pub fn matches(&self, etag: &str) -> bool {
+        self.0
+            .iter()
+            .any(|tag| tag == "*" || opaque(tag) == opaque(etag))
+    }
+}
+
+fn opaque(tag: &str) -> &str {
+    tag.trim_start_matches("W/")
+}
+
+// Serialized JSON, optionally still zstd compressed for clients that can decode it themselves.
+// Only the unversioned list route answers with it, so field names are the legacy ones.
+pub struct JsonBody {
+    body: Vec<u8>,
+    zstd: bool,
+    etag: Option<String>,
+}
+
+impl JsonBody {
+    pub fn plain<T: serde::Serialize>(value: &T) -> JsonBody {
+        JsonBody::serialized(response::legacy_json(value))
+    }
+
+    pub fn serialized(body: Vec<u8>) -> JsonBody {
+        JsonBody {
+            body,
+            zstd: false,
+            etag: None,
+        }
+    }
+
+    // The JSON itself, unless it is still compressed.
+    pub fn json(&self) -> Option<&[u8]> {
+        if self.zstd {
+            None
+        } else {
+            Some(&self.body)
+        }
+    }
+
+    pub fn with_etag(mut self, etag: Option<String>) -> JsonBody {
+        self.etag = etag;
+        self
+    }
+}
+
+impl<'r> Responder<'r> for JsonBody {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        let mut builder = Response::build();
+        builder
+            .header(ContentType::JSON)
+            .raw_header("Vary", "Accept-Encoding");
+        if self.zstd {
+            builder.raw_header("Content-Encoding", "zstd");
+        }
+        if let Some(etag) = self.etag {
+            builder
+                .raw_header("ETag", etag)
+                .raw_header("Cache-Control", REVALIDATE);
+        }
+        builder.sized_body(Cursor::new(self.body)).ok()
+    }
+}
+
+// 304 for a client whose copy of the list is still current.
+pub struct NotModified(pub String);
+
+impl<'r> Responder<'r> for NotModified {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Response::build()
+            .status(Status::NotModified)
+            .raw_header("ETag", self.0)
+            .raw_header("Cache-Control", REVALIDATE)
+            .raw_header("Vary", "Accept-Encoding")
+            .ok()
+    }
+}
+
+// A list's ETag and the user it belongs to.
+pub struct ListTag {
+    pub user_id: i32,
+    pub etag: String,
+}
+
+// Changes whenever the list response for the same query string would, without loading the list.
+// The version is part of it since a deploy can change how the same rows are written.
+pub fn list_etag(name: &str, query: Option<&str>, connection: &Connection) -> Option<ListTag> {
+    let (user_id, version) = database::get_list_version(name, connection)?;
+    let mut hasher = Sha256::new();
+    hasher.update(env!("CARGO_PKG_VERSION"));
+    hasher.update([0u8]);
+    hasher.update(version);
+    hasher.update([0u8]);
+    hasher.update(query.unwrap_or(""));
+    hasher.update([0u8]);
+    hasher.update(format!("{:?}", anilist_query::upstream_status()));
+    let digest: String = hasher.finalize()[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    Some(ListTag {
+        user_id,
+        etag: format!("W/\"{}\"", digest),
+    })
+}
+
+// The snapshot carries the upstream status from when it was built, so it is only used while
+// AniList is healthy and the live response has nothing new to say about freshness.
+pub fn cached_list(
+    name: &str,
+    encoding: &AcceptEncoding,
+    connection: &Connection,
+) -> Option<JsonBody> {
+    if anilist_query::upstream_status() != models::UpstreamStatus::Up {
+        return None;
+    }
+
+    let stmt = connection.prepare_cached("SELECT c.body FROM response_cache AS c INNER JOIN users AS u ON c.user_id = u.user_id WHERE u.name = $1").unwrap();
+
+    let compressed: Vec<u8> = match stmt.query(&[&name]) {
+        Ok(rows) => rows.iter().next()?.get(0),
+        Err(error) => {
+            error!(
+                "error reading cached response for user_name={}. Error: {}",
+                name, error
+            );
+            return None;
+        }
+    };
+
+    if encoding.zstd {
+        return Some(JsonBody {
+            body: compressed,
+            zstd: true,
+            etag: None,
+        });
+    }
+
+    match zstd::stream::decode_all(compressed.as_slice()) {
+        Ok(body) => Some(JsonBody::serialized(body)),
+        Err(error) => {
+            error!(
+                "error decompressing cached response for user_name={}. Error: {}",
+                name, error
+            );
+            None
+        }
+    }
+}
+
+pub fn refresh_snapshot(user_id: i32, connection: &Connection) {
+    memory_cache::invalidate(user_id);
+
+    // The snapshot stands in for the default request, which shows the list the way its owner
+    // prefers.
+    let list = match database::get_user_by_id(user_id, connection).and_then(|user| {
+        let query = models::ListQuery {
+            preferences: database::get_preferences(&user.name, connection),
+            ..models::ListQuery::default()
+        };
+        database::get_list(&user.name, &query, connection)
+    }) {
+        Some(list) => list,
+        None => return invalidate_snapshot(user_id, connection),
+    };
+
+    let json = response::legacy_json(&list);
+    let body = zstd::stream::encode_all(json.as_slice(), ZSTD_LEVEL);
+
+    match body {
+        Ok(body) => {
+            let stmt = connection.prepare_cached("INSERT INTO response_cache (user_id, body, created_at) VALUES ($1, $2, now()) ON CONFLICT (user_id) DO UPDATE SET body = excluded.body, created_at = excluded.created_at").unwrap();
+            if let Err(error) = stmt.execute(&[&user_id, &body]) {
+                error!(
+                    "error saving cached response for user_id={}. Error: {}",
+                    user_id, error
+                );
+            }
+        }
+        Err(error) => error!(
+            "error building cached response for user_id={}. Error: {}",
+            user_id, error
+        ),
+    }
+}
+
+pub fn invalidate_snapshot(user_id: i32, connection: &Connection) {
+    memory_cache::invalidate(user_id);
+
+    let stmt = connection
+        .prepare_cached("DELETE FROM response_cache WHERE user_id = $1")
+        .unwrap();
+
+    if let Err(error) = stmt.execute(&[&user_id]) {
+        error!(
+            "error removing cached response for user_id={}. Error: {}",
+            user_id, error
+        );
+    }
+}
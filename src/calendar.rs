@@ -0,0 +1,173 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// iCalendar export of a user's watch dates, one all day event for every start and completion, so
+// the history can be laid over a regular calendar.
+
+use crate::{database, sitemap};
+use chrono::{Duration, NaiveDate, Utc};
+use log::error;
+use rocket_contrib::databases::postgres::Connection;
+
+// RFC 5545 wants content lines folded after 75 octets.
+const MAX_LINE_OCTETS: usize = 75;
+
+// None when the user isn't tracked.
+pub fn watch_dates(name: &str, connection: &Connection) -> Option<String> {
+    let user = database::get_user(name, connection)?;
+    let stmt = connection.prepare_cached("SELECT a.anime_id, a.slug, COALESCE(l.user_title, a.romaji, a.english, a.native, ''), l.start_day, l.end_day FROM public_lists AS l INNER JOIN anime AS a ON l.anime_id = a.anime_id WHERE l.user_id = $1 AND (l.start_day IS NOT NULL OR l.end_day IS NOT NULL) ORDER BY COALESCE(l.start_day, l.end_day), a.anime_id").unwrap();
+
+    let rows = match stmt.query(&[&user.user_id]) {
+        Ok(rows) => rows,
+        Err(error) => {
+            error!(
+                "error getting watch dates for user_id={}. Error: {}",
+                user.user_id, error
+            );
+            return None;
+        }
+    };
+
+    let base = sitemap::base_url();
+    let host = base.splitn(2, "://").nth(1).unwrap_or(&base).to_owned();
+    let stamp = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+
+    let mut ics = String::new();
+    push_line(&mut ics, "BEGIN:VCALENDAR");
+    push_line(&mut ics, "VERSION:2.0");
+    push_line(&mut ics, "PRODID:-//anihistory//watch history//EN");
+    push_line(&mut ics, "CALSCALE:GREGORIAN");
+    push_line(
+        &mut ics,
+        &format!("X-WR-CALNAME:{}", escape(&format!("{}'s anime", user.name))),
+    );
+
+    for row in rows.iter() {
+        let anime_id: i32 = row.get(0);
+        let slug: Option<String> = row.get(1);
+        let title: String = row.get(2);
+        let start_day: Option<NaiveDate> = row.get(3);
+        let end_day: Option<NaiveDate> = row.get(4);
+        let url = format!(
+            "{}/anime/{}",
+            base,
+            slug.unwrap_or_else(|| anime_id.to_string())
+        );
+
+        let events = start_day
+            .map(|day| ("started", "Started", day))
+            .into_iter()
+            .chain(end_day.map(|day| ("completed", "Completed", day)));
+        for (kind, verb, day) in events {
+            push_line(&mut ics, "BEGIN:VEVENT");
+            push_line(
+                &mut ics,
+                &format!(
+                    "UID:{}-{}-{}-{}@{}",
+                    user.user_id, anime_id, kind, day, host
+                ),
+            );
+            push_line(&mut ics, &format!("DTSTAMP:{}", stamp));
+            push_line(
+                &mut ics,
+                &format!("DTSTART;VALUE=DATE:{}", day.format("%Y%m%d")),
+            );
+            push_line(
+                &mut ics,
+                &format!(
+                    "DTEND;VALUE=DATE:{}",
+                    (day + Duration::days(1)).format("%Y%m%d")
+                ),
+            );
+            push_line(
+                &mut ics,
+                &format!("SUMMARY:{}", escape(&format!("{} {}", verb, title))),
+            );
+            push_line(&mut ics, &format!("URL:{}", url));
+            push_line(&mut ics, "TRANSP:TRANSPARENT");
+            push_line(&mut ics, "END:VEVENT");
+        }
+    }
+
+    push_line(&mut ics, "END:VCALENDAR");
+    Some(ics)
+}
+
+// Appends a CRLF terminated content line, folding it without splitting a UTF-8 sequence.
+fn push_line(ics: &mut String, line: &str) {
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > MAX_LINE_OCTETS {
+            // The leading space of the continuation line counts towards its length.
+            ics.push_str("\r\n ");
+            octets = 1;
+        }
+        ics.push(c);
+        octets += c.len_utf8();
+    }
+    ics.push_str("\r\n");
+}
+
+// TEXT values escape backslashes, separators and newlines.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ';' => escaped.push_str("\\;"),
+            ',' => escaped.push_str("\\,"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_separators_and_newlines() {
+        assert_eq!(
+            escape("Re:Zero; Starting Life, Again\r\nPart 2 \\ Cut"),
+            r"Re:Zero\; Starting Life\, Again\nPart 2 \\ Cut"
+        );
+    }
+
+    #[test]
+    fn short_lines_are_not_folded() {
+        let mut ics = String::new();
+        push_line(&mut ics, "SUMMARY:Started K-On!");
+        assert_eq!(ics, "SUMMARY:Started K-On!\r\n");
+    }
+
+    #[test]
+    fn folds_at_75_octets() {
+        let line = "x".repeat(MAX_LINE_OCTETS + 10);
+        let mut ics = String::new();
+        push_line(&mut ics, &line);
+        assert_eq!(
+            ics,
+            format!("{}\r\n {}\r\n", "x".repeat(MAX_LINE_OCTETS), "x".repeat(10))
+        );
+    }
+
+    #[test]
+    fn folding_keeps_multibyte_characters_whole() {
+        // 74 octets, so the 3 octet character that follows doesn't fit on the first line.
+        let line = format!("SUMMARY:{}けいおん", "x".repeat(66));
+        let mut ics = String::new();
+        push_line(&mut ics, &line);
+        let lines: Vec<&str> = ics.trim_end_matches("\r\n").split("\r\n").collect();
+        assert_eq!(lines[0], format!("SUMMARY:{}", "x".repeat(66)));
+        assert_eq!(lines[1], " けいおん");
+        assert!(lines.iter().all(|line| line.len() <= MAX_LINE_OCTETS));
+    }
+}
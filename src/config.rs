@@ -0,0 +1,242 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Where the service finds its database, image store, AniList and MyAnimeList, and who may call it
+// from a browser.
+// Read once from the environment (and .env) at startup, so a bad value stops the process before it
+// does anything instead of surfacing in the middle of a sync. Tuning knobs of single subsystems
+// stay next to the code they tune.
+
+use crate::jobs;
+use dotenv::dotenv;
+use rusoto_core::Region;
+use std::env;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+const DEFAULT_S3_BUCKET: &str = "anihistory-images";
+
+const DEFAULT_IMAGE_DIR: &str = "static";
+
+const DEFAULT_WEBP_QUALITY: f32 = 80.0;
+
+const DEFAULT_ANILIST_URL: &str = "https://graphql.anilist.co";
+
+const DEFAULT_CORS_ORIGINS: &[&str] = &[
+    "http://localhost:4200",
+    "https://anihistory.moe",
+    "https://www.anihistory.moe",
+];
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageStoreKind {
+    S3,
+    // Files under image_dir, which Rocket serves at / when it is static.
+    Local,
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // DATABASE_URL, required.
+    pub database_url: String,
+    // IMAGE_STORE, s3 or local.
+    pub image_store: ImageStoreKind,
+    // S3_BUCKET, where covers and avatars are uploaded.
+    pub s3_bucket: String,
+    // S3_REGION, like us-east-1.
+    pub s3_region: Region,
+    // IMAGE_DIR, where the local store writes.
+    pub image_dir: String,
+    // IMAGE_BASE_URL, the public URL stored image URLs start with, like a CDN's. The bucket's URL
+    // on S3, and empty for the local store so URLs are relative to this server.
+    pub image_base_url: String,
+    // CLOUDFRONT_DISTRIBUTION_ID, the distribution in front of the bucket whose cached copies are
+    // invalidated when an image is replaced or deleted.
+    pub cloudfront_distribution_id: Option<String>,
+    // IMAGE_WEBP_QUALITY, 0 to 100, of the WebP copies of covers and avatars.
+    pub webp_quality: f32,
+    // ANILIST_URL, AniList's GraphQL endpoint.
+    pub anilist_url: String,
+    // MAL_CLIENT_ID, needed to sync lists from MyAnimeList.
+    pub mal_client_id: Option<String>,
+    // CORS_ORIGINS, comma separated.
+    pub cors_origins: Vec<String>,
+    // PORT, Rocket's own configuration decides when unset.
+    pub port: Option<u16>,
+    // TOKEN_ENCRYPTION_KEY, 64 hex digits. AniList access tokens are sealed with it, signing in
+    // with AniList is off without it.
+    pub token_key: Option<[u8; 32]>,
+    // TRUSTED_PROXIES, comma separated addresses of the proxies in front of the service. Only
+    // their X-Real-IP is believed, everyone else is known by the address they connect from.
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Missing(&'static str),
+    Invalid(&'static str, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Missing(name) => write!(f, "{} must be set", name),
+            ConfigError::Invalid(name, value) => write!(f, "{} is invalid: {}", name, value),
+        }
+    }
+}
+
+impl Settings {
+    pub fn from_env() -> Result<Settings, ConfigError> {
+        dotenv().ok();
+
+        let database_url = var("DATABASE_URL").ok_or(ConfigError::Missing("DATABASE_URL"))?;
+        let s3_region = match var("S3_REGION") {
+            Some(region) => {
+                Region::from_str(&region).map_err(|_| ConfigError::Invalid("S3_REGION", region))?
+            }
+            None => Region::UsEast1,
+        };
+        let image_store = match var("IMAGE_STORE").as_deref() {
+            None | Some("s3") => ImageStoreKind::S3,
+            Some("local") => ImageStoreKind::Local,
+            Some(other) => return Err(ConfigError::Invalid("IMAGE_STORE", other.to_owned())),
+        };
+        let s3_bucket = var("S3_BUCKET").unwrap_or_else(|| DEFAULT_S3_BUCKET.to_owned());
+        let image_base_url = match (var("IMAGE_BASE_URL"), image_store) {
+            (Some(url), _) => url.trim_end_matches('/').to_owned(),
+            (None, ImageStoreKind::S3) => format!("https://s3.amazonaws.com/{}", s3_bucket),
+            (None, ImageStoreKind::Local) => String::new(),
+        };
+        let webp_quality = match var("IMAGE_WEBP_QUALITY") {
+            Some(quality) => match quality.parse::<f32>() {
+                Ok(value) if (0.0..=100.0).contains(&value) => value,
+                _ => return Err(ConfigError::Invalid("IMAGE_WEBP_QUALITY", quality)),
+            },
+            None => DEFAULT_WEBP_QUALITY,
+        };
+        let port = match var("PORT") {
+            Some(port) => Some(
+                port.parse()
+                    .map_err(|_| ConfigError::Invalid("PORT", port))?,
+            ),
+            None => None,
+        };
+        let token_key = match var("TOKEN_ENCRYPTION_KEY") {
+            Some(key) => Some(parse_key(key.trim()).ok_or(ConfigError::Invalid(
+                "TOKEN_ENCRYPTION_KEY",
+                "expected 64 hex digits".to_owned(),
+            ))?),
+            None => None,
+        };
+        let trusted_proxies = match var("TRUSTED_PROXIES") {
+            Some(proxies) => proxies
+                .split(',')
+                .map(str::trim)
+                .filter(|proxy| !proxy.is_empty())
+                .map(|proxy| {
+                    proxy
+                        .parse()
+                        .map_err(|_| ConfigError::Invalid("TRUSTED_PROXIES", proxy.to_owned()))
+                })
+                .collect::<Result<_, _>>()?,
+            None => Vec::new(),
+        };
+        let cors_origins = match var("CORS_ORIGINS") {
+            Some(origins) => origins
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            None => DEFAULT_CORS_ORIGINS
+                .iter()
+                .map(|origin| (*origin).to_owned())
+                .collect(),
+        };
+
+        Ok(Settings {
+            database_url,
+            image_store,
+            s3_bucket,
+            s3_region,
+            image_dir: var("IMAGE_DIR").unwrap_or_else(|| DEFAULT_IMAGE_DIR.to_owned()),
+            image_base_url,
+            cloudfront_distribution_id: var("CLOUDFRONT_DISTRIBUTION_ID"),
+            webp_quality,
+            anilist_url: var("ANILIST_URL").unwrap_or_else(|| DEFAULT_ANILIST_URL.to_owned()),
+            mal_client_id: var("MAL_CLIENT_ID"),
+            cors_origins,
+            port,
+            token_key,
+            trusted_proxies,
+        })
+    }
+}
+
+// A sync still running when other instances take it for abandoned would run twice.
+fn check_job_timing() -> Result<(), ConfigError> {
+    let deadline = jobs::sync_deadline().as_secs();
+    let stale_secs = jobs::stale_secs();
+    if deadline >= stale_secs {
+        return Err(ConfigError::Invalid(
+            "SYNC_DEADLINE_SECS",
+            format!("{}s is not below JOB_STALE_SECS, {}s", deadline, stale_secs),
+        ));
+    }
+    Ok(())
+}
+
+fn parse_key(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0; 32];
+    for (index, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+// Empty values count as unset.
+fn var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.trim().is_empty())
+}
+
+// Tuning knobs are read through these where they are used, rather than once at startup. Values
+// that don't parse count as unset.
+pub fn env_parsed<T: FromStr>(name: &str) -> Option<T> {
+    var(name).and_then(|value| value.trim().parse().ok())
+}
+
+pub fn env_value<T: FromStr>(name: &str, default: T) -> T {
+    env_parsed(name).unwrap_or(default)
+}
+
+// Reads the settings for the rest of the process. Rocket reads its own configuration from ROCKET_*
+// variables when it ignites, so the port is handed to it through ROCKET_PORT.
+pub fn load() -> Result<&'static Settings, ConfigError> {
+    let settings = Settings::from_env()?;
+    check_job_timing()?;
+    if let Some(port) = settings.port {
+        env::set_var("ROCKET_PORT", port.to_string());
+    }
+    Ok(SETTINGS.get_or_init(|| settings))
+}
+
+// The settings load stored, for code too far from main to be handed them. main loads them before
+// anything else runs and stops when they are invalid, so they are always there by then.
+pub fn settings() -> &'static Settings {
+    SETTINGS
+        .get()
+        .expect("settings are read before config::load ran")
+}
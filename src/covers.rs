@@ -0,0 +1,221 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Zip archive of a user's completed covers, streamed while it is built. Covers are downloaded
+// one at a time, so memory stays at a few covers no matter how long the list is. zip64 isn't
+// written, an archive that would pass 65535 covers or 4 GiB fails the download instead.
+
+use crate::{database, streaming};
+use log::error;
+use rocket::http::ContentType;
+use rocket_contrib::databases::postgres::Connection;
+use std::convert::TryFrom;
+use std::thread;
+
+// Covers are already compressed images, so entries are stored as is.
+const METHOD_STORED: u16 = 0;
+
+const VERSION: u16 = 20;
+
+// File names are UTF-8.
+const FLAG_UTF8: u16 = 0x0800;
+
+// 1980-01-01, the earliest date a zip can hold.
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+pub fn archive(
+    name: &str,
+    connection: &Connection,
+) -> Result<streaming::Download, streaming::DownloadError> {
+    let user = database::get_user(name, connection).ok_or(streaming::DownloadError::NotFound)?;
+    let covers = database::get_completed_covers(user.user_id, connection);
+
+    let (sender, download) = streaming::download(
+        ContentType::new("application", "zip"),
+        format!("{}-covers.zip", user.name),
+    )?;
+    let user_name = user.name.clone();
+    thread::spawn(move || {
+        let mut zip = ZipStream::new(sender);
+        for (file_name, url) in covers {
+            let content = match database::download_image(&url) {
+                Ok((content, _)) => content,
+                Err(_) => continue,
+            };
+            match zip.add_file(&file_name, &content) {
+                Ok(()) => {}
+                Err(ZipError::Disconnected) => return,
+                // Dropping the sender fails the download, the client doesn't get a cut off archive.
+                Err(ZipError::TooLarge) => {
+                    error!(
+                        "covers archive for user_name={} is too large for a zip",
+                        user_name
+                    );
+                    return;
+                }
+            }
+        }
+        if zip.finish().is_err() {
+            error!(
+                "covers archive for user_name={} was not read to the end",
+                user_name
+            );
+        }
+    });
+
+    Ok(download)
+}
+
+#[derive(Debug, PartialEq)]
+enum ZipError {
+    // The client went away, nobody is reading the rest.
+    Disconnected,
+    // Another entry would take the entry count, a size or an offset past what the fields hold.
+    TooLarge,
+}
+
+struct ZipStream {
+    sender: streaming::Sender,
+    offset: u32,
+    central_directory: Vec<u8>,
+    entries: u16,
+}
+
+impl ZipStream {
+    fn new(sender: streaming::Sender) -> ZipStream {
+        ZipStream {
+            sender,
+            offset: 0,
+            central_directory: Vec::new(),
+            entries: 0,
+        }
+    }
+
+    fn add_file(&mut self, name: &str, content: &[u8]) -> Result<(), ZipError> {
+        let entries = self.entries.checked_add(1).ok_or(ZipError::TooLarge)?;
+        let size = u32::try_from(content.len()).map_err(|_| ZipError::TooLarge)?;
+        if u16::try_from(name.len()).is_err() {
+            return Err(ZipError::TooLarge);
+        }
+        let crc = crc32fast::hash(content);
+
+        let mut local = Vec::with_capacity(30 + name.len() + content.len());
+        put_u32(&mut local, 0x0403_4b50);
+        put_u16(&mut local, VERSION);
+        put_entry_fields(&mut local, name, crc, size);
+        put_u16(&mut local, 0);
+        local.extend_from_slice(name.as_bytes());
+        local.extend_from_slice(content);
+
+        // The central directory is written after the last entry, its offset and size have to fit
+        // as well.
+        let offset = u32::try_from(local.len())
+            .ok()
+            .and_then(|len| self.offset.checked_add(len))
+            .ok_or(ZipError::TooLarge)?;
+        let directory_size = self.central_directory.len() + 46 + name.len();
+        if u32::try_from(directory_size).is_err() {
+            return Err(ZipError::TooLarge);
+        }
+
+        let central = &mut self.central_directory;
+        put_u32(central, 0x0201_4b50);
+        put_u16(central, VERSION);
+        put_u16(central, VERSION);
+        put_entry_fields(central, name, crc, size);
+        // Extra field, comment, disk number, internal and external attributes.
+        put_u16(central, 0);
+        put_u16(central, 0);
+        put_u16(central, 0);
+        put_u16(central, 0);
+        put_u32(central, 0);
+        put_u32(central, self.offset);
+        central.extend_from_slice(name.as_bytes());
+
+        self.offset = offset;
+        self.entries = entries;
+        self.sender.send(local).map_err(|_| ZipError::Disconnected)
+    }
+
+    // Sizes and offsets were checked as entries were added.
+    fn finish(self) -> Result<(), ()> {
+        let mut end = self.central_directory;
+        let directory_size = end.len() as u32;
+        put_u32(&mut end, 0x0605_4b50);
+        put_u16(&mut end, 0);
+        put_u16(&mut end, 0);
+        put_u16(&mut end, self.entries);
+        put_u16(&mut end, self.entries);
+        put_u32(&mut end, directory_size);
+        put_u32(&mut end, self.offset);
+        put_u16(&mut end, 0);
+        self.sender.send(end)?;
+        self.sender.finish()
+    }
+}
+
+// Flags through file name length, shared by local headers and the central directory.
+fn put_entry_fields(buffer: &mut Vec<u8>, name: &str, crc: u32, size: u32) {
+    put_u16(buffer, FLAG_UTF8);
+    put_u16(buffer, METHOD_STORED);
+    put_u16(buffer, 0);
+    put_u16(buffer, DOS_DATE);
+    put_u32(buffer, crc);
+    put_u32(buffer, size);
+    put_u32(buffer, size);
+    put_u16(buffer, name.len() as u16);
+}
+
+fn put_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream() -> (ZipStream, streaming::Download) {
+        let (sender, download) = streaming::download(
+            ContentType::new("application", "zip"),
+            "test.zip".to_owned(),
+        )
+        .unwrap();
+        (ZipStream::new(sender), download)
+    }
+
+    #[test]
+    fn adds_entries() {
+        let (mut zip, _download) = stream();
+        zip.add_file("k-on.jpg", b"cover").unwrap();
+        assert_eq!(zip.entries, 1);
+        assert_eq!(zip.offset as usize, 30 + "k-on.jpg".len() + b"cover".len());
+        assert_eq!(zip.central_directory.len(), 46 + "k-on.jpg".len());
+    }
+
+    #[test]
+    fn refuses_entries_past_the_count_field() {
+        let (mut zip, _download) = stream();
+        zip.entries = u16::MAX;
+        assert_eq!(zip.add_file("k-on.jpg", b"cover"), Err(ZipError::TooLarge));
+        assert_eq!(zip.entries, u16::MAX);
+        assert!(zip.central_directory.is_empty());
+    }
+
+    #[test]
+    fn refuses_entries_past_4_gib() {
+        let (mut zip, _download) = stream();
+        zip.offset = u32::MAX - 40;
+        assert_eq!(zip.add_file("k-on.jpg", b"cover"), Err(ZipError::TooLarge));
+        assert_eq!(zip.offset, u32::MAX - 40);
+        assert!(zip.central_directory.is_empty());
+    }
+}
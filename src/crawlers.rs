@@ -0,0 +1,176 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// What crawlers and caches may do with each route: robots.txt, Cache-Control per route and bare
+// HTML pages carrying just the meta tags link previews and search engines read.
+
+use crate::{config, database, markup, models, sitemap, stats};
+use rocket::http::Method;
+use rocket::{Request, Response};
+use rocket_contrib::databases::postgres::Connection;
+
+// Routes that only make sense to their owner or change state.
+const DEFAULT_DISALLOW: &str = "/jobs/,/profile/,/subscriptions,/v1/subscriptions,/search/remote";
+
+const ALLOW: &[&str] = &["/users/", "/anime/", "/meta/", "/sitemaps/"];
+
+const DESCRIPTION_LENGTH: usize = 200;
+
+pub fn robots() -> String {
+    let mut robots = String::from("User-agent: *\n");
+    for path in ALLOW {
+        robots.push_str(&format!("Allow: {}\n", path));
+    }
+
+    let disallow = config::env_value("ROBOTS_DISALLOW", DEFAULT_DISALLOW.to_owned());
+    for path in disallow
+        .split(',')
+        .map(str::trim)
+        .filter(|path| !path.is_empty())
+    {
+        robots.push_str(&format!("Disallow: {}\n", path));
+    }
+
+    if let Some(delay) = config::env_parsed::<u32>("ROBOTS_CRAWL_DELAY") {
+        robots.push_str(&format!("Crawl-delay: {}\n", delay));
+    }
+
+    robots.push_str(&format!("Sitemap: {}\n", sitemap::sitemap_url()));
+    robots
+}
+
+// Cache-Control for a response, by the name of the route that produced it. Lists change with
+// every sync, anime rarely and crawler files almost never.
+pub fn cache_control(request: &Request, response: &mut Response) {
+    if response.headers().contains("Cache-Control") {
+        return;
+    }
+
+    let route = request.route().and_then(|route| route.name);
+    let policy = match (request.method(), route) {
+        (Method::Get, Some("robots")) | (Method::Get, Some("sitemap")) => "public, max-age=86400",
+        (Method::Get, Some("openapi_spec")) | (Method::Get, Some("docs")) => "public, max-age=3600",
+        (Method::Get, Some("sitemap_page")) => "public, max-age=86400",
+        (Method::Get, Some("anime")) | (Method::Get, Some("anime_meta")) => "public, max-age=3600",
+        (Method::Get, Some("search")) | (Method::Get, Some("search_remote")) => {
+            "public, max-age=3600"
+        }
+        (Method::Get, Some("users"))
+        | (Method::Get, Some("user"))
+        | (Method::Get, Some("user_v1"))
+        | (Method::Get, Some("user_range"))
+        | (Method::Get, Some("user_stats"))
+        | (Method::Get, Some("user_meta"))
+        | (Method::Get, Some("history"))
+        | (Method::Get, Some("global_stats"))
+        | (Method::Get, Some("similar_users"))
+        | (Method::Get, Some("recommendations"))
+        | (Method::Get, Some("compare"))
+        | (Method::Get, Some("covers"))
+        | (Method::Get, Some("export"))
+        | (Method::Get, Some("feed"))
+        | (Method::Get, Some("calendar")) => "public, max-age=300",
+        _ => "no-store",
+    };
+    response.set_raw_header("Cache-Control", policy);
+}
+
+pub fn user_meta(name: &str, connection: &Connection) -> Option<String> {
+    let (name, slug) = database::resolve_profile(name, connection)?;
+    if database::get_visibility(name.as_ref(), connection) != models::Visibility::Public {
+        return None;
+    }
+    let user = database::get_user(name.as_ref(), connection)?;
+
+    let description = match stats::get_stats(&user, connection) {
+        Some(stats) => format!(
+            "{}'s anime history: {} entries, {} completed.",
+            user.name, stats.entries, stats.completed
+        ),
+        None => format!("{}'s anime history.", user.name),
+    };
+    let url = format!(
+        "{}/users/{}",
+        sitemap::base_url(),
+        slug.unwrap_or_else(|| user.name.clone())
+    );
+
+    Some(page(
+        &format!("{} - anihistory", user.name),
+        &description,
+        &user.avatar_s3,
+        &url,
+    ))
+}
+
+pub fn anime_meta(slug: &str, connection: &Connection) -> Option<String> {
+    let anime = database::get_anime(slug, connection)?;
+
+    let title = anime
+        .english
+        .as_ref()
+        .or_else(|| anime.romaji.as_ref())
+        .or_else(|| anime.native.as_ref())
+        .cloned()
+        .unwrap_or_else(|| anime.id.to_string());
+    let url = format!(
+        "{}/anime/{}",
+        sitemap::base_url(),
+        anime.slug.unwrap_or_else(|| anime.id.to_string())
+    );
+
+    Some(page(
+        &format!("{} - anihistory", title),
+        &plain_text(&anime.description),
+        &anime.cover,
+        &url,
+    ))
+}
+
+fn page(title: &str, description: &str, image: &str, url: &str) -> String {
+    let (title, description, image, url) = (
+        markup::escape(title),
+        markup::escape(description),
+        markup::escape(image),
+        markup::escape(url),
+    );
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n\
+         <meta name=\"description\" content=\"{description}\">\n\
+         <meta property=\"og:title\" content=\"{title}\">\n\
+         <meta property=\"og:description\" content=\"{description}\">\n\
+         <meta property=\"og:image\" content=\"{image}\">\n\
+         <meta property=\"og:url\" content=\"{url}\">\n\
+         <link rel=\"canonical\" href=\"{url}\">\n</head>\n\
+         <body><a href=\"{url}\">{title}</a></body>\n</html>\n",
+        title = title,
+        description = description,
+        image = image,
+        url = url
+    )
+}
+
+// Descriptions come from AniList as HTML, meta tags need a short plain text version.
+fn plain_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => (),
+        }
+    }
+
+    let text = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+    match text.char_indices().nth(DESCRIPTION_LENGTH) {
+        Some((index, _)) => format!("{}...", &text[..index]),
+        None => text,
+    }
+}
@@ -6,111 +6,295 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use crate::{anilist_models, anilist_query, models};
-use chrono::NaiveDate;
-use dotenv::dotenv;
-use log::{error, info};
-use reqwest::blocking::get;
-use rocket_contrib::databases::postgres::{Connection, TlsMode};
-use rusoto_core::Region;
-use rusoto_s3::{PutObjectRequest, S3Client, S3};
+use crate::config::Settings;
+use crate::fields::Fieldset;
+use crate::{
+    anilist_models, anilist_query, config, dates, images, models, normalize, notifier, providers,
+    sealed, shutdown, stats, storage,
+};
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use log::{error, info, warn};
+use reqwest::header::CONTENT_TYPE;
+use rocket_contrib::databases::postgres::transaction::Transaction;
+use rocket_contrib::databases::postgres::types::ToSql;
+use rocket_contrib::databases::postgres::{Connection, GenericConnection, TlsMode};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::Read;
-use std::{env, thread, panic};
+use std::time::Instant;
+use std::{fmt, panic, thread};
 
-// Only used for upload_to_s3 because of spawned threads and I didn't want to make the connection
-// pool work with that.
-fn establish_connection() -> Connection {
-    dotenv().ok();
+// Share of a user's stored entries a single sync may delete without being forced.
+const DEFAULT_DELETE_THRESHOLD_PERCENT: usize = 50;
 
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let result = Connection::connect(database_url.as_ref(), TlsMode::None);
-    match result {
+// Deletions a sync may make regardless of the share, a short list losing one entry isn't suspect.
+const DEFAULT_DELETE_THRESHOLD_MIN: usize = 5;
+
+// Number of example entries listed per change type in a sync preview.
+const PREVIEW_SAMPLE_SIZE: usize = 10;
+
+// Entries on one page of a list at most.
+pub const MAX_PER_PAGE: i64 = 500;
+
+// Rows before the page, None for pages too far out for an offset to reach.
+pub fn page_offset(page: i64, per_page: i64) -> Option<i64> {
+    page.checked_sub(1)?.checked_mul(per_page)
+}
+
+// Maximum number of anime returned by a search.
+const SEARCH_LIMIT: i64 = 25;
+
+// For the command line tools, which run without Rocket and can't do anything without the
+// database. Threads that keep running use connect instead.
+pub fn establish_connection(settings: &Settings) -> Connection {
+    match connect(settings) {
         Ok(connection) => connection,
         Err(error) => {
-            error!("error connecting to {}. Error: {}", database_url, error);
+            error!(
+                "error connecting to {}. Error: {}",
+                settings.database_url, error
+            );
             panic!();
         }
     }
 }
 
-pub fn get_list(name: &str, connection: &postgres::Connection) -> Option<models::RestResponse> {
+// For threads that outlive a request and have to keep going when the database is away.
+pub fn connect(settings: &Settings) -> Result<Connection, postgres::Error> {
+    Connection::connect(settings.database_url.as_str(), TlsMode::None)
+}
+
+// Columns of an entry as (column, table, blank, fields), in the order entry_from_row reads them.
+// A column is read when any of the fields that show it are asked for, left out ones are selected
+// as their blank so the positions stay put.
+const ENTRY_COLUMNS: &[(&str, &str, &str, &[&str])] = &[
+    ("anime_id", "a", "NULL::int4", &["id"]),
+    (
+        "user_title",
+        "l",
+        "NULL::text",
+        &["user_title", "display_title"],
+    ),
+    (
+        "start_day",
+        "l",
+        "NULL::date",
+        &["start_day", "display_start_day"],
+    ),
+    (
+        "end_day",
+        "l",
+        "NULL::date",
+        &["end_day", "display_end_day"],
+    ),
+    (
+        "start_partial",
+        "l",
+        "NULL::text",
+        &["start_partial", "display_start_day"],
+    ),
+    (
+        "end_partial",
+        "l",
+        "NULL::text",
+        &["end_partial", "display_end_day"],
+    ),
+    ("score", "l", "NULL::int2", &["score"]),
+    ("status", "l", "NULL::text", &["status"]),
+    ("progress", "l", "NULL::int4", &["progress"]),
+    ("repeat", "l", "NULL::int4", &["repeat"]),
+    ("notes", "l", "NULL::text", &["notes"]),
+    ("custom_lists", "l", "'{}'::text[]", &["custom_lists"]),
+    ("average", "a", "NULL::int2", &["average"]),
+    ("native", "a", "NULL::text", &["native", "display_title"]),
+    ("romaji", "a", "NULL::text", &["romaji", "display_title"]),
+    ("english", "a", "NULL::text", &["english", "display_title"]),
+    ("description", "a", "''::text", &["description"]),
+    ("cover_s3", "a", "''::text", &["cover"]),
+    ("cover_xl_s3", "a", "NULL::text", &["cover_xl"]),
+    ("cover_thumb_s3", "a", "NULL::text", &["cover_thumb"]),
+    ("slug", "a", "NULL::text", &["slug"]),
+    ("genres", "a", "'{}'::text[]", &["genres"]),
+    ("tags", "a", "'{}'::text[]", &["tags"]),
+    ("episodes", "a", "NULL::int4", &["episodes"]),
+    ("season", "a", "NULL::text", &["season"]),
+    ("season_year", "a", "NULL::int4", &["season_year"]),
+    ("format", "a", "NULL::text", &["format"]),
+    ("studio", "a", "NULL::text", &["studio"]),
+];
+
+// Columns the list is sorted by, read whatever the fields are.
+const SORT_COLUMNS: &[&str] = &["user_title", "end_day", "score"];
+
+// The select list of entries from public_lists AS l and anime AS a, every column when there are no
+// fields.
+fn entry_columns(fields: Option<&Fieldset>, always: &[&str]) -> String {
+    ENTRY_COLUMNS
+        .iter()
+        .map(|(column, table, blank, shown_by)| {
+            let wanted = always.contains(column)
+                || fields.map_or(true, |fields| {
+                    shown_by.iter().any(|field| fields.contains(field))
+                });
+            if wanted {
+                format!("{}.{}", table, column)
+            } else {
+                format!("{} AS {}", blank, column)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// The entry selected by entry_columns from the given position on. Display fields are left to
+// apply_preferences.
+fn entry_from_row(row: &postgres::rows::Row, start: usize) -> models::ResponseItem {
+    models::ResponseItem {
+        id: row.get(start),
+        user_title: row.get(start + 1),
+        display_title: None,
+        start_day: row.get(start + 2),
+        end_day: row.get(start + 3),
+        start_partial: partial_date(row.get(start + 4)),
+        end_partial: partial_date(row.get(start + 5)),
+        display_start_day: None,
+        display_end_day: None,
+        score: row.get(start + 6),
+        status: row.get(start + 7),
+        progress: row.get(start + 8),
+        repeat: row.get(start + 9),
+        notes: row.get(start + 10),
+        custom_lists: row.get(start + 11),
+        average: row.get(start + 12),
+        native: row.get(start + 13),
+        romaji: row.get(start + 14),
+        english: row.get(start + 15),
+        description: row.get(start + 16),
+        cover: row.get(start + 17),
+        cover_xl: row.get(start + 18),
+        cover_thumb: row.get(start + 19),
+        slug: row.get(start + 20),
+        genres: row.get(start + 21),
+        tags: row.get(start + 22),
+        episodes: row.get(start + 23),
+        season: row.get(start + 24),
+        season_year: row.get(start + 25),
+        format: row.get(start + 26),
+        studio: row.get(start + 27),
+    }
+}
+
+// A page of the user's list. None when the user is unknown or, unless the list was filtered, has
+// nothing on their list yet.
+pub fn get_list(
+    name: &str,
+    query: &models::ListQuery,
+    connection: &postgres::Connection,
+) -> Option<models::RestResponse> {
+    // Normalized like search_title, which leaves nothing a LIKE pattern would interpret.
+    let title_pattern = query
+        .filter
+        .title
+        .as_ref()
+        .map(|title| format!("%{}%", normalize::normalize(title)));
+    let user_title_pattern = query
+        .filter
+        .title
+        .as_ref()
+        .map(|title| format!("%{}%", escape_like(title)));
+
+    // Pages past any offset are empty, the routes turn them away before they get here.
+    let offset = query.per_page.map_or(0, |per_page| {
+        page_offset(query.page, per_page).unwrap_or(i64::MAX)
+    });
+    let mut params: Vec<&dyn ToSql> = vec![&name, &query.per_page, &offset];
+    let mut conditions = Vec::new();
+    if let Some(min_score) = &query.filter.min_score {
+        params.push(min_score);
+        conditions.push(format!("l.score >= ${}", params.len()));
+    }
+    // Completion ranges are answered from the (user_id, end_day) index on lists.
+    if let Some(from) = &query.filter.completed_from {
+        params.push(from);
+        conditions.push(format!("l.end_day >= ${}", params.len()));
+    }
+    if let Some(to) = &query.filter.completed_to {
+        params.push(to);
+        conditions.push(format!("l.end_day <= ${}", params.len()));
+    }
+    if !query.preferences.show_adult {
+        conditions.push("NOT a.is_adult".to_owned());
+    }
+    if let (Some(title), Some(user_title)) = (&title_pattern, &user_title_pattern) {
+        params.push(title);
+        params.push(user_title);
+        conditions.push(format!(
+            "(a.search_title LIKE ${} OR l.user_title ILIKE ${})",
+            params.len() - 1,
+            params.len()
+        ));
+    }
+    let filters: String = conditions
+        .iter()
+        .map(|condition| format!(" AND {}", condition))
+        .collect();
+
+    // The lateral join keeps the user's row when the page holds no entries. Only the columns of
+    // the asked for fields are read.
     let stmt = connection
-	  .prepare_cached("SELECT u.user_id, u.name, u.avatar_s3, u.avatar_anilist, a.anime_id, a\
-	  .description, a.cover_s3, a.cover_anilist, a.average, a.native, a.romaji, a.english, l\
-	  .user_title, l.start_day, l.end_day, l.score FROM lists as l INNER JOIN users as u ON l\
-	  .user_id=u.user_id INNER JOIN anime as a ON l.anime_id=a.anime_id WHERE u.name = $1")
-	  .unwrap();
+        .prepare_cached(&format!(
+            "SELECT u.user_id, u.name, u.avatar_s3, u.sync_needs_confirmation, u.last_synced_at, \
+             u.last_sync_attempt_at, (SELECT count(*) FROM public_lists AS l \
+             INNER JOIN anime AS a ON l.anime_id = a.anime_id \
+             WHERE l.user_id = u.user_id{filters}), e.* FROM users AS u \
+             LEFT JOIN LATERAL (SELECT {columns} FROM public_lists AS l INNER JOIN anime AS a \
+             ON l.anime_id = a.anime_id WHERE l.user_id = u.user_id{filters} \
+             ORDER BY {inner_order} LIMIT $2 OFFSET $3) AS e ON true \
+             WHERE u.name = $1 ORDER BY {outer_order}",
+            filters = filters,
+            columns = entry_columns(query.fields.as_ref(), SORT_COLUMNS),
+            inner_order = order_clause(query, "l."),
+            outer_order = order_clause(query, "e."),
+        ))
+        .unwrap();
 
-    let results = stmt.query(&[&name]);
+    let results = stmt.query(&params);
 
     match results {
         Ok(result) => {
-            let mut database_list: Vec<models::ListItemMap> = Vec::with_capacity(result.len());
-            for row in result.iter() {
-                let user = models::User {
-                    user_id: row.get(0),
-                    name: row.get(1),
-                    avatar_s3: row.get(2),
-                    avatar_anilist: row.get(3),
-                };
-
-                let anime = models::Anime {
-                    anime_id: row.get(4),
-                    description: row.get(5),
-                    cover_s3: row.get(6),
-                    cover_anilist: row.get(7),
-                    average: row.get(8),
-                    native: row.get(9),
-                    romaji: row.get(10),
-                    english: row.get(11),
-                };
-
-                let list_item = models::ListItem {
-                    user_id: row.get(0),
-                    anime_id: row.get(4),
-                    user_title: row.get(12),
-                    start_day: row.get(13),
-                    end_day: row.get(14),
-                    score: row.get(15),
-                };
-
-                database_list.push(models::ListItemMap {
-                    user,
-                    anime,
-                    list_item,
-                });
+            // Every row repeats the user's columns, they are read from the first one only.
+            let first = result.iter().next()?;
+            let total = first.get(6);
+            if total == 0 && query.filter.is_empty() && query.preferences.show_adult {
+                return None;
             }
 
-            if database_list.len() > 0 {
-                let mut response_items: Vec<models::ResponseItem> =
-                    Vec::with_capacity(database_list.len());
-                for list_item in database_list.clone() {
-                    let item = models::ResponseItem {
-                        user_title: list_item.list_item.user_title,
-                        start_day: list_item.list_item.start_day,
-                        end_day: list_item.list_item.end_day,
-                        score: list_item.list_item.score,
-                        average: list_item.anime.average,
-                        native: list_item.anime.native,
-                        romaji: list_item.anime.romaji,
-                        english: list_item.anime.english,
-                        description: list_item.anime.description,
-                        cover: list_item.anime.cover_s3,
-                        id: list_item.anime.anime_id,
-                    };
-
-                    response_items.push(item);
+            // The only row of a user without entries in this page has no anime.
+            let mut list = Vec::with_capacity(result.len());
+            for row in result.iter() {
+                if row.get::<_, Option<i32>>(7).is_none() {
+                    continue;
                 }
-                Some(models::RestResponse {
-                    users: models::ResponseList {
-                        id: database_list[0].user.name.clone(),
-                        avatar: database_list[0].user.avatar_s3.clone(),
-                        list: response_items,
-                    },
-                })
-            } else {
-                None
+
+                let mut item = entry_from_row(&row, 7);
+                item.apply_preferences(&query.preferences);
+                list.push(item);
             }
+
+            Some(models::RestResponse {
+                users: models::ResponseList {
+                    id: first.get(1),
+                    avatar: first.get(2),
+                    needs_confirmation: first.get(3),
+                    list,
+                },
+                data_freshness: models::DataFreshness {
+                    last_synced_at: first.get(4),
+                    last_attempt_at: first.get(5),
+                    upstream_status: anilist_query::upstream_status(),
+                },
+                total,
+            })
         }
         Err(error) => {
             error!(
@@ -122,32 +306,208 @@ pub fn get_list(name: &str, connection: &postgres::Connection) -> Option<models:
     }
 }
 
-pub fn update_user_profile(user: anilist_models::User, connection: &Connection) {
-    let ext = get_ext(&user.avatar.large);
+// Entries changed and removed after the given point in time. Tombstones of entries that have been
+// added back are skipped, the entry itself shows up as changed instead.
+pub fn get_list_changes(
+    user: &models::User,
+    since: DateTime<Utc>,
+    preferences: &models::Preferences,
+    fields: Option<&Fieldset>,
+    connection: &Connection,
+) -> Option<models::ListDelta> {
+    let result = connection.transaction().and_then(|transaction| {
+        let as_of: DateTime<Utc> = transaction.query("SELECT now()", &[])?.get(0).get(0);
+
+        let changed = transaction.query(
+            &format!(
+                "SELECT {} FROM public_lists AS l INNER JOIN anime AS a \
+                 ON l.anime_id = a.anime_id WHERE l.user_id = $1 AND l.updated_at > $2 \
+                 AND (NOT a.is_adult OR $3) ORDER BY {}",
+                entry_columns(fields, &[]),
+                order_clause(&models::ListQuery::default(), "l.")
+            ),
+            &[&user.user_id, &since, &preferences.show_adult],
+        )?;
+
+        // Entries that turned private since are gone as far as anyone else can tell.
+        let removed = transaction.query(
+            "SELECT t.anime_id FROM list_tombstones AS t WHERE t.user_id = $1 \
+             AND t.deleted_at > $2 AND NOT EXISTS (SELECT 1 FROM public_lists AS l \
+             WHERE l.user_id = t.user_id AND l.anime_id = t.anime_id) UNION \
+             SELECT l.anime_id FROM lists AS l WHERE l.user_id = $1 AND l.private \
+             AND l.updated_at > $2 ORDER BY 1",
+            &[&user.user_id, &since],
+        )?;
+
+        Ok(models::ListDelta {
+            id: user.name.clone(),
+            since,
+            as_of,
+            changed: changed
+                .iter()
+                .map(|row| {
+                    let mut item = entry_from_row(&row, 0);
+                    item.apply_preferences(preferences);
+                    item
+                })
+                .collect(),
+            removed: removed.iter().map(|row| row.get(0)).collect(),
+        })
+    });
+
+    match result {
+        Ok(delta) => Some(delta),
+        Err(error) => {
+            error!(
+                "error getting list changes for user_id={} since={}. Error: {}",
+                user.user_id, since, error
+            );
+            None
+        }
+    }
+}
+
+// Anime on both users' lists. A score of 0 means unscored on AniList and doesn't count.
+pub fn compare_users(
+    name: &str,
+    other: &str,
+    connection: &Connection,
+) -> Option<models::Comparison> {
+    let stmt = connection.prepare_cached("SELECT a.anime_id, a.user_title, NULLIF(a.score, 0), NULLIF(b.score, 0) FROM public_lists AS a INNER JOIN public_lists AS b ON a.anime_id = b.anime_id WHERE a.user_id = (SELECT user_id FROM users WHERE name = $1) AND b.user_id = (SELECT user_id FROM users WHERE name = $2) ORDER BY a.anime_id").unwrap();
+
+    match stmt.query(&[&name, &other]) {
+        Ok(rows) => {
+            let shared: Vec<models::SharedAnime> = rows
+                .iter()
+                .map(|row| {
+                    let score: Option<i16> = row.get(2);
+                    let other_score: Option<i16> = row.get(3);
+                    models::SharedAnime {
+                        id: row.get(0),
+                        title: row.get(1),
+                        score,
+                        other_score,
+                        score_delta: score.and_then(|score| other_score.map(|other| score - other)),
+                    }
+                })
+                .collect();
+
+            let pairs: Vec<(f64, f64)> = shared
+                .iter()
+                .filter_map(|anime| match (anime.score, anime.other_score) {
+                    (Some(score), Some(other)) => Some((score as f64, other as f64)),
+                    _ => None,
+                })
+                .collect();
+
+            Some(models::Comparison {
+                user: name.to_owned(),
+                other: other.to_owned(),
+                shared,
+                affinity: pearson(&pairs),
+            })
+        }
+        Err(error) => {
+            error!(
+                "error comparing user_name={} with user_name={}. Error: {}",
+                name, other, error
+            );
+            None
+        }
+    }
+}
+
+fn pearson(pairs: &[(f64, f64)]) -> Option<f64> {
+    if pairs.len() < 2 {
+        return None;
+    }
+
+    let n = pairs.len() as f64;
+    let mean_x = pairs.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = pairs.iter().map(|(_, y)| y).sum::<f64>() / n;
+
+    let mut covariance = 0.0;
+    let mut variance_x = 0.0;
+    let mut variance_y = 0.0;
+    for (x, y) in pairs {
+        covariance += (x - mean_x) * (y - mean_y);
+        variance_x += (x - mean_x).powi(2);
+        variance_y += (y - mean_y).powi(2);
+    }
+
+    if variance_x == 0.0 || variance_y == 0.0 {
+        return None;
+    }
+    Some(covariance / (variance_x * variance_y).sqrt())
+}
+
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+// ORDER BY clause for a list query, with prefix being the alias of the lists table. Ties are
+// broken by anime_id so the order is stable between responses and clients can diff them position
+// by position. Entries still in progress have no end day and come first when sorting by end day
+// descending, missing scores and titles always come last.
+pub fn order_clause(query: &models::ListQuery, prefix: &str) -> String {
+    let (column, nulls) = match query.sort {
+        models::ListSort::EndDay if query.descending => ("end_day", "nulls first"),
+        models::ListSort::EndDay => ("end_day", "nulls last"),
+        models::ListSort::Score => ("score", "nulls last"),
+        models::ListSort::Title => ("user_title", "nulls last"),
+    };
+    let direction = if query.descending { "desc" } else { "asc" };
+
+    format!(
+        "{}{} {} {}, {}anime_id",
+        prefix, column, direction, nulls, prefix
+    )
+}
+
+// The user's id is the one they are stored under, see providers::Provider::user_id.
+pub fn update_user_profile(
+    user: anilist_models::User,
+    provider: providers::Provider,
+    store: &storage::Images,
+    connection: &Connection,
+) {
+    // Download their avatar to keep a copy, its format names the copy.
+    let avatar = download_image(&user.avatar.large).ok();
+    let ext = match &avatar {
+        Some((_, format)) => format.stored().ext(),
+        None => url_format(&user.avatar.large).ext(),
+    };
 
     let new_user = models::User {
         user_id: user.id.clone(),
         name: user.name.clone(),
-        avatar_s3: format!(
-            "https://s3.amazonaws.com/anihistory-images/assets/images/user_{}.{}",
-            user.id, ext
-        ),
+        avatar_s3: storage::url(&image_key(&ImageTypes::User, user.id, ext)),
         avatar_anilist: user.avatar.large.clone(),
     };
 
-    let stmt = connection.prepare_cached("INSERT INTO users (user_id, name, avatar_s3, avatar_anilist) VALUES ($1, $2, $3, $4) ON CONFLICT (user_id) DO UPDATE SET name = excluded.name, avatar_s3 = excluded.avatar_s3, avatar_anilist = excluded.avatar_anilist").unwrap();
+    let stmt = connection.prepare_cached("INSERT INTO users (user_id, name, avatar_s3, avatar_anilist, provider, external_id) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (user_id) DO UPDATE SET name = excluded.name, avatar_s3 = excluded.avatar_s3, avatar_anilist = excluded.avatar_anilist").unwrap();
 
     let result = stmt.execute(&[
         &new_user.user_id,
         &new_user.name,
         &new_user.avatar_s3,
         &new_user.avatar_anilist,
+        &provider.as_str(),
+        &provider.external_id(new_user.user_id),
     ]);
 
-    // Download their avatar and upload to S3.
-    let mut content = Vec::new();
-    download_image(&mut content, &user.avatar.large);
-    upload_to_s3(ImageTypes::User, user.id, ext.clone(), content);
+    if let Some((content, format)) = avatar {
+        upload_image(
+            store.as_ref(),
+            ImageTypes::User,
+            user.id,
+            content,
+            format,
+            &user.avatar.large,
+        );
+    }
 
     match result {
         Ok(_) => (),
@@ -158,229 +518,3310 @@ pub fn update_user_profile(user: anilist_models::User, connection: &Connection)
     }
 }
 
-pub fn delete_entries(lists: Vec<anilist_models::MediaList>, id: i32) {
-    let connection = establish_connection();
-    let mut used_lists = Vec::new();
+// Removes every row belonging to the user along with their avatar. Anime stay, they aren't
+// anyone's personal data, except for those nobody else has on their list. Those go too, together
+// with their cover.
+pub fn purge_user(
+    user_id: i32,
+    avatar_s3: &str,
+    store: &storage::Images,
+    connection: &Connection,
+) -> bool {
+    let result = connection.transaction().and_then(|transaction| {
+        let anime_ids: Vec<i32> = transaction
+            .query("SELECT anime_id FROM lists WHERE user_id = $1", &[&user_id])?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
 
-    for mut list in lists {
-        if list.name.to_lowercase().contains("completed")
-            || list.name.to_lowercase().contains("watching")
-        {
-            list.entries
-                .sort_unstable_by(|a, b| a.media.id.cmp(&b.media.id));
-            used_lists.push(list.clone());
+        for table in &[
+            "activities",
+            "sync_warnings",
+            "sync_errors",
+            "list_changes",
+            "list_history",
+            "list_tombstones",
+            "hidden_entries",
+            "lists",
+            "user_stats",
+            "user_embeddings",
+            "user_preferences",
+            "response_cache",
+            "profile_hits",
+            "sessions",
+            "anilist_tokens",
+            "webhooks",
+            "jobs",
+        ] {
+            transaction.execute(
+                &format!("DELETE FROM {} WHERE user_id = $1", table),
+                &[&user_id],
+            )?;
+        }
+        transaction.execute(
+            "DELETE FROM subscriptions WHERE subscriber_id = $1 OR target_id = $1",
+            &[&user_id],
+        )?;
+        transaction.execute("DELETE FROM users WHERE user_id = $1", &[&user_id])?;
+        transaction.execute(
+            "DELETE FROM pending_uploads WHERE image_type = 'user' AND id = $1",
+            &[&user_id],
+        )?;
+
+        let orphaned: Vec<(i32, String, Option<String>)> = transaction
+            .query(
+                "DELETE FROM anime AS a WHERE a.anime_id = ANY($1) \
+                 AND NOT EXISTS (SELECT 1 FROM lists AS l WHERE l.anime_id = a.anime_id) \
+                 AND NOT EXISTS (SELECT 1 FROM list_history AS h WHERE h.anime_id = a.anime_id) \
+                 AND NOT EXISTS (SELECT 1 FROM list_tombstones AS t WHERE t.anime_id = a.anime_id) \
+                 AND NOT EXISTS (SELECT 1 FROM hidden_entries AS e WHERE e.anime_id = a.anime_id) \
+                 AND NOT EXISTS (SELECT 1 FROM list_changes AS c WHERE c.anime_id = a.anime_id) \
+                 AND NOT EXISTS (SELECT 1 FROM activities AS v WHERE v.anime_id = a.anime_id) \
+                 AND NOT EXISTS (SELECT 1 FROM sync_warnings AS w WHERE w.anime_id = a.anime_id) \
+                 RETURNING a.anime_id, a.cover_s3, a.cover_xl_s3",
+                &[&anime_ids],
+            )?
+            .iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2)))
+            .collect();
+        transaction.commit()?;
+        Ok(orphaned)
+    });
+
+    match result {
+        Ok(orphaned) => {
+            if let Some(ext) = avatar_s3.rsplit('.').next() {
+                delete_image(store.as_ref(), ImageTypes::User, user_id, ext);
+            }
+            for (anime_id, cover_s3, cover_xl_s3) in orphaned {
+                delete_cover(store.as_ref(), anime_id, &cover_s3, &cover_xl_s3);
+            }
+            true
+        }
+        Err(error) => {
+            error!("error purging user_id={}. Error: {}", user_id, error);
+            false
         }
     }
+}
 
-    let stmt = connection.prepare_cached("SELECT user_id, anime_id, user_title, start_day, end_day, score FROM lists WHERE user_id = $1").unwrap();
+// Stored entries that are no longer on any of the user's tracked lists.
+fn stale_entries(
+    lists: &[anilist_models::MediaList],
+    existing: &HashMap<i32, models::ListItem>,
+) -> Vec<models::ListItem> {
+    let fetched: HashSet<i32> = lists
+        .iter()
+        .filter(|list| is_tracked_list(list))
+        .flat_map(|list| list.entries.iter().map(|entry| entry.media.id))
+        .collect();
 
-    let user_db_list_result = stmt.query(&[&id]);
+    let mut stale: Vec<models::ListItem> = existing
+        .values()
+        .filter(|list_item| !fetched.contains(&list_item.anime_id))
+        .cloned()
+        .collect();
+    stale.sort_unstable_by_key(|list_item| list_item.anime_id);
+    stale
+}
 
-    match user_db_list_result {
-        Ok(rows) => {
-            for row in rows.iter() {
-                let list_item = models::ListItem {
-                    user_id: row.get(0),
-                    anime_id: row.get(1),
-                    user_title: row.get(2),
-                    start_day: row.get(3),
-                    end_day: row.get(4),
-                    score: row.get(5),
-                };
-
-                let mut found = false;
-
-                for list in used_lists.clone() {
-                    let result = list
-                        .entries
-                        .binary_search_by(|e| e.media.id.cmp(&list_item.anime_id));
-                    match result {
-                        Ok(_) => found = true,
-                        Err(_) => {}
-                    }
-                }
+// Deletes the entries along with their warnings and leaves tombstones for incremental clients.
+fn delete_list_batch(
+    user_id: i32,
+    stale: &[models::ListItem],
+    connection: &dyn GenericConnection,
+) -> Result<u64, postgres::Error> {
+    if stale.is_empty() {
+        return Ok(0);
+    }
+    let anime_ids: Vec<i32> = stale.iter().map(|list_item| list_item.anime_id).collect();
+    info!("deleting anime_ids={:?} for user_id={}", anime_ids, user_id);
+
+    let deleted = connection
+        .prepare_cached("DELETE FROM lists WHERE user_id = $1 AND anime_id = ANY($2)")?
+        .execute(&[&user_id, &anime_ids])?;
+    connection
+        .prepare_cached("INSERT INTO list_tombstones (user_id, anime_id, deleted_at) SELECT $1, t.anime_id, now() FROM UNNEST($2::int4[]) AS t (anime_id) ON CONFLICT (user_id, anime_id) DO UPDATE SET deleted_at = excluded.deleted_at")?
+        .execute(&[&user_id, &anime_ids])?;
+    connection
+        .prepare_cached("DELETE FROM sync_warnings WHERE user_id = $1 AND anime_id = ANY($2)")?
+        .execute(&[&user_id, &anime_ids])?;
+    Ok(deleted)
+}
 
-                if !found {
-                    println!("deleting anime:{}", list_item.anime_id);
-                    let stmt = connection
-                        .prepare_cached("DELETE FROM lists WHERE user_id = $1 AND anime_id = $2")
-                        .unwrap();
+// Removes an entry from the user's list here without touching AniList, and keeps later syncs from
+// bringing it back. None on database errors, false when the entry was neither listed nor hidden.
+pub fn hide_entry(user_id: i32, anime_id: i32, connection: &Connection) -> Option<bool> {
+    let result = connection.transaction().and_then(|transaction| {
+        let hidden = transaction.execute("INSERT INTO hidden_entries (user_id, anime_id, hidden_at) VALUES ($1, $2, now()) ON CONFLICT (user_id, anime_id) DO NOTHING", &[&user_id, &anime_id])?;
+        let deleted = transaction.execute(
+            "DELETE FROM lists WHERE user_id = $1 AND anime_id = $2",
+            &[&user_id, &anime_id],
+        )?;
+        if hidden == 0 && deleted == 0 {
+            // Hiding an entry that is already hidden succeeds, anything else isn't on the list.
+            let already_hidden = transaction
+                .query(
+                    "SELECT 1 FROM hidden_entries WHERE user_id = $1 AND anime_id = $2",
+                    &[&user_id, &anime_id],
+                )?
+                .len()
+                > 0;
+            return Ok(already_hidden);
+        }
+        if deleted == 0 {
+            transaction.set_rollback();
+            return Ok(false);
+        }
 
-                    let delete_result = stmt.execute(&[&list_item.user_id, &list_item.anime_id]);
+        // Its history, activity and warnings would still give it away.
+        transaction.execute(
+            "DELETE FROM list_history WHERE user_id = $1 AND anime_id = $2",
+            &[&user_id, &anime_id],
+        )?;
+        transaction.execute(
+            "DELETE FROM list_changes WHERE user_id = $1 AND anime_id = $2",
+            &[&user_id, &anime_id],
+        )?;
+        transaction.execute(
+            "DELETE FROM activities WHERE user_id = $1 AND anime_id = $2",
+            &[&user_id, &anime_id],
+        )?;
+        transaction.execute(
+            "DELETE FROM sync_warnings WHERE user_id = $1 AND anime_id = $2",
+            &[&user_id, &anime_id],
+        )?;
+        transaction.execute("INSERT INTO list_tombstones (user_id, anime_id, deleted_at) VALUES ($1, $2, now()) ON CONFLICT (user_id, anime_id) DO UPDATE SET deleted_at = excluded.deleted_at", &[&user_id, &anime_id])?;
+        transaction.commit()?;
+        Ok(true)
+    });
 
-                    if delete_result.is_err() {
-                        error!(
-                            "error deleting list_entry={:?}. Error: {}",
-                            row,
-                            delete_result.expect_err("?")
-                        );
-                    }
-                }
-            }
+    match result {
+        Ok(true) => {
+            stats::rebuild(user_id, connection);
+            Some(true)
         }
-        Err(err) => {
-            error!("error retrieving list for user_id={:?}. Error: {}", id, err);
+        Ok(false) => Some(false),
+        Err(error) => {
+            error!(
+                "error hiding anime_id={} for user_id={}. Error: {}",
+                anime_id, user_id, error
+            );
+            None
         }
     }
 }
 
-pub fn update_entries(id: i32) {
-    let lists: Vec<anilist_models::MediaList> = anilist_query::get_lists(id);
+// Hidden entries are left out of syncs as if they weren't on AniList at all, so they are neither
+// stored again nor counted as deletions.
+fn drop_hidden(user_id: i32, lists: &mut Vec<anilist_models::MediaList>, connection: &Connection) {
+    let stmt = connection
+        .prepare_cached("SELECT anime_id FROM hidden_entries WHERE user_id = $1")
+        .unwrap();
 
-    delete_entries(lists.clone(), id);
-    let connection = establish_connection();
+    let hidden: HashSet<i32> = match stmt.query(&[&user_id]) {
+        Ok(rows) => rows.iter().map(|row| row.get(0)).collect(),
+        Err(error) => {
+            error!(
+                "error getting hidden entries for user_id={}. Error: {}",
+                user_id, error
+            );
+            HashSet::new()
+        }
+    };
 
-    for list in lists {
-        if list.name.to_lowercase().contains("completed")
-            || list.name.to_lowercase().contains("watching")
-        {
-            for entry in list.entries {
-                let ext = get_ext(&entry.media.cover_image.large);
-
-                let new_anime = models::Anime {
-                    anime_id: entry.media.id,
-                    description: entry.media.description,
-                    cover_s3: format!(
-                        "https://s3.amazonaws.com/anihistory-images/assets/images/anime_{}.{}",
-                        entry.media.id, ext
-                    ),
-                    cover_anilist: entry.media.cover_image.large.clone(),
-                    average: entry.media.average_score,
-                    native: entry.media.title.native,
-                    romaji: entry.media.title.romaji,
-                    english: entry.media.title.english,
-                };
-
-                let stmt = connection.prepare_cached("INSERT INTO anime (anime_id, description, cover_s3, cover_anilist, average, native, romaji, english) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) ON CONFLICT (anime_id) DO UPDATE SET description = excluded.description, cover_s3 = excluded.cover_s3, cover_anilist = excluded.cover_anilist, average = excluded.average, native = excluded.native, romaji = excluded.romaji, english = excluded.english").unwrap();
-
-                let anime_result = stmt.execute(&[
-                    &new_anime.anime_id,
-                    &new_anime.description,
-                    &new_anime.cover_s3,
-                    &new_anime.cover_anilist,
-                    &new_anime.average,
-                    &new_anime.native,
-                    &new_anime.romaji,
-                    &new_anime.english,
-                ]);
-
-                match anime_result {
-                    Ok(_) => {
-                        // Download cover images and upload to S3.
-                        let mut content = Vec::new();
-                        download_image(&mut content, &entry.media.cover_image.large);
-                        let closure_id = entry.media.id.clone();
-                        let closure_ext = ext.clone();
-                        thread::spawn(move || {
-                            upload_to_s3(ImageTypes::Anime, closure_id, closure_ext, content)
-                        });
-                    }
-                    Err(error) => {
-                        error!("error saving anime={:?}. Error: {}", new_anime, error);
-                    }
-                }
+    if !hidden.is_empty() {
+        for list in lists.iter_mut() {
+            list.entries
+                .retain(|entry| !hidden.contains(&entry.media.id));
+        }
+    }
+}
 
-                let start = construct_date(entry.started_at);
-                let end = construct_date(entry.completed_at);
-
-                let new_list = models::ListItem {
-                    user_id: id,
-                    anime_id: entry.media.id,
-                    user_title: entry.media.title.user_preferred,
-                    start_day: start,
-                    end_day: end,
-                    score: entry.score_raw,
-                };
-
-                let stmt = connection.prepare_cached("INSERT INTO lists (user_id, anime_id, user_title, start_day, end_day, score) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (user_id, anime_id) DO UPDATE SET user_title = excluded.user_title, start_day = excluded.start_day, end_day = excluded.end_day, score = excluded.score").unwrap();
-
-                let list_result = stmt.execute(&[
-                    &new_list.user_id,
-                    &new_list.anime_id,
-                    &new_list.user_title,
-                    &new_list.start_day,
-                    &new_list.end_day,
-                    &new_list.score,
-                ]);
-
-                if list_result.is_err() {
-                    error!(
-                        "error saving list_entry={:?}. Error: {}",
-                        new_list,
-                        list_result.expect_err("?")
-                    );
+// Defaults for users who never saved any.
+pub fn get_preferences(name: &str, connection: &Connection) -> models::Preferences {
+    let stmt = connection.prepare_cached("SELECT p.title_language, p.date_format, p.show_adult, u.sync_cadence FROM users AS u LEFT JOIN user_preferences AS p ON p.user_id = u.user_id WHERE u.name = $1").unwrap();
+
+    match stmt.query(&[&name]) {
+        Ok(rows) => match rows.iter().next() {
+            Some(row) => {
+                let defaults = models::Preferences::default();
+                let title_language: Option<String> = row.get(0);
+                let date_format: Option<String> = row.get(1);
+                let show_adult: Option<bool> = row.get(2);
+                let sync_cadence: String = row.get(3);
+                models::Preferences {
+                    title_language: title_language
+                        .and_then(|value| models::TitleLanguage::parse(&value))
+                        .unwrap_or(defaults.title_language),
+                    date_format: date_format
+                        .and_then(|value| models::DateFormat::parse(&value))
+                        .unwrap_or(defaults.date_format),
+                    show_adult: show_adult.unwrap_or(defaults.show_adult),
+                    sync_cadence: models::SyncCadence::parse(&sync_cadence)
+                        .unwrap_or(defaults.sync_cadence),
                 }
             }
+            None => models::Preferences::default(),
+        },
+        Err(error) => {
+            error!(
+                "error getting preferences for user_name={}. Error: {}",
+                name, error
+            );
+            models::Preferences::default()
         }
     }
-    info!("Database updated for user_id={}", id);
 }
 
-fn upload_to_s3(prefix: ImageTypes, id: i32, ext: String, content: Vec<u8>) {
-    let image_prefix: String;
-    match prefix {
-        ImageTypes::Anime => image_prefix = "anime".to_owned(),
-        ImageTypes::User => image_prefix = "user".to_owned(),
-    };
+pub fn set_preferences(
+    user_id: i32,
+    preferences: &models::Preferences,
+    connection: &Connection,
+) -> bool {
+    // The cadence lives on the user row, where the scheduler looks for it.
+    let result = connection.transaction().and_then(|transaction| {
+        transaction.execute("INSERT INTO user_preferences (user_id, title_language, date_format, show_adult, updated_at) VALUES ($1, $2, $3, $4, now()) ON CONFLICT (user_id) DO UPDATE SET title_language = excluded.title_language, date_format = excluded.date_format, show_adult = excluded.show_adult, updated_at = excluded.updated_at", &[
+            &user_id,
+            &preferences.title_language.as_str(),
+            &preferences.date_format.as_str(),
+            &preferences.show_adult,
+        ])?;
+        transaction.execute(
+            "UPDATE users SET sync_cadence = $2 WHERE user_id = $1",
+            &[&user_id, &preferences.sync_cadence.as_str()],
+        )?;
+        transaction.commit()
+    });
 
-    let client = S3Client::new(Region::UsEast1);
-    let bucket_name = "anihistory-images";
-    let mime = naive_mime(&ext);
-    let key = format!("assets/images/{}_{}.{}", image_prefix, id, ext);
-
-    let put_request = PutObjectRequest {
-        bucket: bucket_name.to_owned(),
-        key: key.clone(),
-        body: Some(content.into()),
-        content_type: Some(mime),
-        acl: Some("public-read".to_owned()),
-        ..PutObjectRequest::default()
-    };
+    match result {
+        Ok(_) => true,
+        Err(error) => {
+            error!(
+                "error saving preferences for user_id={}. Error: {}",
+                user_id, error
+            );
+            false
+        }
+    }
+}
 
-    match client.put_object(put_request).sync() {
-        Ok(_) => (),
+// Whether a sync deletes too much of a list to go ahead unforced, it has to remove more than
+// DELETE_THRESHOLD_MIN entries and more than DELETE_THRESHOLD_PERCENT of them.
+fn exceeds_delete_threshold(deletions: usize, total: usize) -> bool {
+    deletes_too_many(
+        deletions,
+        total,
+        config::env_value("DELETE_THRESHOLD_MIN", DEFAULT_DELETE_THRESHOLD_MIN),
+        config::env_value("DELETE_THRESHOLD_PERCENT", DEFAULT_DELETE_THRESHOLD_PERCENT),
+    )
+}
+
+fn deletes_too_many(deletions: usize, total: usize, min: usize, percent: usize) -> bool {
+    if deletions <= min || total == 0 {
+        return false;
+    }
+    deletions * 100 > total * percent
+}
+
+#[derive(Debug)]
+pub enum SyncError {
+    Upstream(anilist_query::AnilistError),
+    // The sync ran past its deadline and stopped, entries gathered until then are saved.
+    TimedOut,
+    // Nothing of the sync was written.
+    Database(postgres::Error),
+    // The process is shutting down, the sync stopped before writing anything.
+    Interrupted,
+    // The sync panicked, whatever it had written by then stays.
+    Crashed,
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncError::Upstream(error) => write!(f, "{}", error),
+            SyncError::TimedOut => write!(f, "sync did not finish before its deadline"),
+            SyncError::Database(error) => write!(f, "sync could not be saved: {}", error),
+            SyncError::Interrupted => write!(f, "sync was interrupted by a shutdown"),
+            SyncError::Crashed => write!(f, "sync stopped on an internal error"),
+        }
+    }
+}
+
+// Where the user's list comes from, the id the provider knows them by and their name. Users that
+// aren't stored yet are taken for AniList users.
+fn get_provider(user_id: i32, connection: &Connection) -> (providers::Provider, i32, String) {
+    let stmt = connection
+        .prepare_cached("SELECT provider, external_id, name FROM users WHERE user_id = $1")
+        .unwrap();
+
+    let stored = match stmt.query(&[&user_id]) {
+        Ok(rows) => rows.iter().next().map(|row| {
+            let provider: String = row.get(0);
+            (provider, row.get(1), row.get(2))
+        }),
         Err(error) => {
             error!(
-                "error uploading assets/images/{}_{}.{} to S3. Error: {}",
-                image_prefix, id, ext, error
+                "error getting the provider of user_id={}. Error: {}",
+                user_id, error
             );
+            None
         }
+    };
+    match stored {
+        Some((provider, external_id, name)) => (
+            providers::Provider::parse(&provider).unwrap_or(providers::Provider::AniList),
+            external_id,
+            name,
+        ),
+        None => (providers::Provider::AniList, user_id, String::new()),
     }
 }
 
-fn construct_date(date: anilist_models::Date) -> Option<NaiveDate> {
-    match date.year {
-        Some(year) => match date.month {
-            Some(month) => match date.day {
-                Some(day) => Some(NaiveDate::from_ymd(year, month as u32, day as u32)),
-                None => None,
-            },
-            None => None,
+pub fn update_entries(
+    id: i32,
+    force: bool,
+    job_id: i32,
+    deadline: Instant,
+    store: &storage::Images,
+    settings: &Settings,
+) -> Result<(), SyncError> {
+    let connection = connect(settings).map_err(SyncError::Database)?;
+    record_sync_attempt(id, &connection);
+
+    // No single statement may outlive the sync, so a stuck one can't hold its locks forever.
+    let timeout_ms = deadline
+        .saturating_duration_since(Instant::now())
+        .as_millis()
+        .max(1)
+        .to_string();
+    if let Err(error) = connection.execute(
+        "SELECT set_config('statement_timeout', $1, false)",
+        &[&timeout_ms],
+    ) {
+        error!(
+            "error setting statement_timeout for user_id={}. Error: {}",
+            id, error
+        );
+    }
+
+    // Users who signed in with AniList get their private entries and scores synced too.
+    let (provider, external_id, name) = get_provider(id, &connection);
+    let source = provider.source();
+    let fetched = match get_anilist_token(id, &connection) {
+        Some(token) => match source.get_lists(external_id, &name, Some(deadline), Some(&token)) {
+            // A revoked token is dropped, whatever is public can still be synced.
+            Err(anilist_query::AnilistError::InvalidToken) => {
+                info!("user_id={} revoked their AniList token", id);
+                remove_anilist_token(id, &connection);
+                source.get_lists(external_id, &name, Some(deadline), None)
+            }
+            fetched => fetched,
         },
-        None => None,
+        None => source.get_lists(external_id, &name, Some(deadline), None),
+    };
+
+    // Leave the stored list alone when AniList can't be reached so it can still be served.
+    let mut lists = match fetched {
+        Ok(lists) => lists,
+        Err(anilist_query::AnilistError::DeadlineExceeded) => return Err(SyncError::TimedOut),
+        // The stored list stays, but is withheld until the user makes it public again.
+        Err(anilist_query::AnilistError::PrivateList) => {
+            info!("user_id={} made their list private", id);
+            set_restricted(id, true, &connection);
+            return Err(SyncError::Upstream(
+                anilist_query::AnilistError::PrivateList,
+            ));
+        }
+        Err(error) => {
+            error!("error fetching lists for user_id={}. Error: {}", id, error);
+            return Err(SyncError::Upstream(error));
+        }
+    };
+    drop_hidden(id, &mut lists, &connection);
+
+    // A user's first sync would report every entry as new, so only diff against existing rows.
+    let existing = get_list_items(id, &connection);
+    let initial_import = existing.is_empty();
+    let synced_versions = get_anilist_updated_at(id, &connection);
+    let anime_ids: Vec<i32> = lists
+        .iter()
+        .flat_map(|list| list.entries.iter().map(|entry| entry.media.id))
+        .collect();
+    let stored_covers = get_stored_covers(&anime_ids, &connection);
+
+    let stale = stale_entries(&lists, &existing);
+    // Hidden or renamed lists on AniList look exactly like a user dropping everything, so big
+    // deletions wait until the user confirms them with a forced update.
+    let hold_deletions = !force && exceeds_delete_threshold(stale.len(), existing.len());
+    if hold_deletions {
+        warn!(
+            "sync for user_id={} would delete {} of {} entries, waiting for confirmation",
+            id,
+            stale.len(),
+            existing.len()
+        );
+    }
+
+    // Covers are copied while the entries are gathered, the rows are written together afterwards.
+    let mut anime_rows = Vec::new();
+    let mut list_rows = Vec::new();
+    let mut warnings = Vec::new();
+    let mut sources = Vec::new();
+    let mut quarantined = Vec::new();
+    let mut refreshed = Vec::new();
+    let mut gathered = HashSet::new();
+    let mut timed_out = false;
+
+    'lists: for list in lists {
+        if is_tracked_list(&list) {
+            for entry in list.entries {
+                if shutdown::requested() {
+                    info!("sync for user_id={} stopped for a shutdown", id);
+                    return Err(SyncError::Interrupted);
+                }
+                if Instant::now() >= deadline {
+                    timed_out = true;
+                    break 'lists;
+                }
+
+                // An entry on several lists is written once, a batch can't upsert a row twice.
+                if gathered.contains(&entry.media.id) {
+                    continue;
+                }
+
+                // The S3 key only depends on the anime id, so a new cover on AniList has to be
+                // noticed here or the old image is served forever.
+                let (cover_changed, cover_version) = cover_state(&entry.media, &stored_covers);
+
+                // Entries the user hasn't touched since the last sync are already stored, so skip
+                // their upserts and cover uploads. A forced update still rewrites everything. The
+                // anime itself still changes on AniList as episodes air and scores move, so its
+                // details are refreshed all the same.
+                if !force
+                    && !cover_changed
+                    && entry.updated_at.is_some()
+                    && synced_versions.get(&entry.media.id) == Some(&entry.updated_at)
+                {
+                    gathered.insert(entry.media.id);
+                    if validate_entry(&entry).is_ok() {
+                        refreshed.push(prepare_anime(entry.media, false, cover_version, store).0);
+                    }
+                    continue;
+                }
+
+                gathered.insert(entry.media.id);
+                // One broken entry is set aside instead of failing the whole sync.
+                if let Err(error) = validate_entry(&entry) {
+                    quarantined.push(quarantine(&entry, error));
+                    continue;
+                }
+                warnings.extend(entry_warnings(&entry));
+                let new_list = list_item_from_entry(id, &entry);
+                let (anime_row, cover_warnings) = prepare_anime(
+                    entry.media.clone(),
+                    cover_changed || force,
+                    cover_version,
+                    store,
+                );
+                warnings.extend(cover_warnings);
+                anime_rows.push(anime_row);
+                list_rows.push((new_list, entry.updated_at));
+                sources.push(entry);
+            }
+        }
+    }
+
+    // The whole sync is written at once or not at all, so a crash can't leave half a list behind.
+    // Entries the database rejects are quarantined with the rest, the others are still written.
+    let result = connection.transaction().and_then(|transaction| {
+        transaction
+            .prepare_cached("UPDATE users SET sync_needs_confirmation = $2 WHERE user_id = $1")?
+            .execute(&[&id, &hold_deletions])?;
+        if !hold_deletions {
+            delete_list_batch(id, &stale, &transaction)?;
+        }
+        refresh_anime(&refreshed, &transaction)?;
+        let rejected = write_entries(&anime_rows, &list_rows, &transaction)?;
+        let failed: HashSet<i32> = rejected
+            .iter()
+            .map(|(index, _)| list_rows[*index].0.anime_id)
+            .collect();
+        quarantined.extend(
+            rejected
+                .into_iter()
+                .map(|(index, error)| quarantine(&sources[index], error)),
+        );
+        warnings.retain(|warning| !failed.contains(&warning.anime_id));
+
+        let written: Vec<i32> = list_rows
+            .iter()
+            .map(|(list_item, _)| list_item.anime_id)
+            .filter(|anime_id| !failed.contains(anime_id))
+            .collect();
+        let mut changes = Vec::new();
+        if !hold_deletions {
+            changes.extend(stale.iter().map(|old| (Some(old), None)));
+        }
+        for (new_list, _) in list_rows.iter() {
+            if failed.contains(&new_list.anime_id) {
+                continue;
+            }
+            let old = existing.get(&new_list.anime_id);
+            if let Some(old) = old {
+                record_history(old, new_list, &transaction)?;
+            }
+            changes.push((old, Some(new_list)));
+        }
+        record_changes(id, job_id, &changes, &transaction)?;
+        replace_warnings(id, job_id, &written, &warnings, &transaction)?;
+        replace_quarantine(id, job_id, &written, &anime_ids, &quarantined, &transaction)?;
+        transaction.commit()?;
+        Ok(failed)
+    });
+    let failed = match result {
+        Ok(failed) => failed,
+        Err(error) => {
+            error!("error saving sync for user_id={}. Error: {}", id, error);
+            reset_statement_timeout(&connection);
+            return Err(SyncError::Database(error));
+        }
+    };
+    if !quarantined.is_empty() {
+        warn!(
+            "sync for user_id={} quarantined {} entries: anime_ids={:?}",
+            id,
+            quarantined.len(),
+            quarantined
+                .iter()
+                .map(|entry| entry.anime_id)
+                .collect::<Vec<i32>>()
+        );
+    }
+
+    let mut stats_delta = stats::StatsDelta::default();
+    let mut events = Vec::new();
+    if !hold_deletions {
+        for list_item in stale {
+            stats_delta.record(Some(&list_item), None);
+            if list_item.private {
+                continue;
+            }
+            events.push(models::ChangeEvent {
+                user_id: list_item.user_id,
+                anime_id: list_item.anime_id,
+                title: list_item.user_title,
+                kind: models::ChangeKind::Removed,
+            });
+        }
+    }
+    for (new_list, _) in list_rows
+        .iter()
+        .filter(|(list_item, _)| !failed.contains(&list_item.anime_id))
+    {
+        let old = existing.get(&new_list.anime_id);
+        stats_delta.record(old, Some(new_list));
+        if !initial_import {
+            if let Some(event) = change_event(old, new_list) {
+                events.push(event);
+            }
+        }
+    }
+    let warning_count = warnings.len();
+
+    // Subscribers only hear about complete syncs, and the deltas of a partial one can't be trusted.
+    if timed_out {
+        reset_statement_timeout(&connection);
+        stats::rebuild(id, &connection);
+        warn!(
+            "sync for user_id={} passed its deadline, stopped with {} warnings and {} quarantined entries",
+            id, warning_count, quarantined.len()
+        );
+        return Err(SyncError::TimedOut);
+    }
+
+    if force {
+        stats::rebuild(id, &connection);
+    } else {
+        stats::apply(id, &stats_delta, &connection);
+    }
+    // Profiles that aren't shown don't announce anything either.
+    if is_public(id, &connection) {
+        notifier::fan_out(&events, &connection, &notifier::LogNotifier);
+    }
+    if provider == providers::Provider::AniList {
+        sync_activities(id, deadline, &connection);
     }
+    record_sync_success(id, &connection);
+    info!(
+        "Database updated for user_id={} with {} warnings and {} quarantined entries",
+        id,
+        warning_count,
+        quarantined.len()
+    );
+    Ok(())
 }
 
-fn download_image(content: &mut Vec<u8>, url: &String) {
-    let mut resp = get(url).unwrap();
-    resp.read_to_end(content).unwrap();
+// An anime the way it is upserted, with the columns only writes need.
+struct AnimeRow {
+    anime: models::Anime,
+    search_title: String,
+    slug: String,
+    cover_version: i32,
+    mal_id: Option<i32>,
+    is_adult: bool,
 }
 
-fn get_ext(url: &String) -> String {
-    let link_parts: Vec<&str> = url.split('/').collect();
-    let splitted: Vec<&str> = link_parts[link_parts.len() - 1].split(".").collect();
-    splitted[1].to_owned()
+// Upserts the anime and, when upload_cover is set, copies its cover to the image store. Returns the
+// problems that left it incomplete.
+fn save_anime(
+    media: anilist_models::Media,
+    upload_cover: bool,
+    cover_version: i32,
+    store: &storage::Images,
+    connection: &Connection,
+) -> Vec<models::SyncWarning> {
+    let (row, mut warnings) = prepare_anime(media, upload_cover, cover_version, store);
+    let rows = [row];
+    if let Err(error) = insert_anime_batch(&rows, connection) {
+        error!("error saving anime={:?}. Error: {}", rows[0].anime, error);
+        warnings.push(models::SyncWarning {
+            anime_id: rows[0].anime.anime_id,
+            kind: models::WarningKind::SaveFailed,
+            detail: "anime could not be saved".to_owned(),
+        });
+    }
+    warnings
 }
 
-fn naive_mime(ext: &String) -> String {
-    if ext.contains("jp") {
-        "image/jpeg".to_owned()
+// Builds the anime's row and, when upload_cover is set, copies its cover to the image store.
+// Returns the problems that left it incomplete.
+fn prepare_anime(
+    media: anilist_models::Media,
+    upload_cover: bool,
+    cover_version: i32,
+    store: &storage::Images,
+) -> (AnimeRow, Vec<models::SyncWarning>) {
+    let mut warnings = Vec::new();
+    let mal_id = media.id_mal;
+    let is_adult = media.is_adult.unwrap_or(false);
+    let ext = if has_cover(&media) {
+        Some(cover_ext(
+            ImageTypes::Anime,
+            media.id,
+            &media.cover_image.large,
+            upload_cover,
+            store,
+            &mut warnings,
+        ))
     } else {
-        format!("image/{}", ext)
+        None
+    };
+    let cover_xl_anilist = extra_large_cover(&media).cloned();
+    let xl_ext = cover_xl_anilist.as_ref().map(|url| {
+        cover_ext(
+            ImageTypes::AnimeXl,
+            media.id,
+            url,
+            upload_cover && ext.is_some(),
+            store,
+            &mut warnings,
+        )
+    });
+
+    let new_anime = models::Anime {
+        anime_id: media.id,
+        description: media.description,
+        cover_s3: ext.map_or_else(String::new, |ext| {
+            cover_url(ImageTypes::Anime, media.id, ext, cover_version)
+        }),
+        cover_anilist: media.cover_image.large.clone(),
+        cover_xl_s3: xl_ext.map(|ext| cover_url(ImageTypes::AnimeXl, media.id, ext, cover_version)),
+        cover_thumb_s3: ext
+            .map(|_| cover_url(ImageTypes::AnimeThumb, media.id, "webp", cover_version)),
+        average: media.average_score,
+        native: media.title.native,
+        romaji: media.title.romaji,
+        english: media.title.english,
+        genres: media.genres.unwrap_or_default(),
+        // Spoiler tags would give away plot points in a genre filter.
+        tags: media
+            .tags
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|tag| !tag.is_media_spoiler.unwrap_or(false))
+            .map(|tag| tag.name)
+            .collect(),
+        episodes: media.episodes,
+        season: media.season,
+        season_year: media.season_year,
+        format: media.format,
+        studio: media
+            .studios
+            .and_then(|studios| studios.nodes.into_iter().next())
+            .map(|studio| studio.name),
+    };
+
+    let search_title =
+        normalize::search_title(&[&new_anime.native, &new_anime.romaji, &new_anime.english]);
+
+    let slug = normalize::slug(&new_anime.romaji, new_anime.anime_id);
+
+    let row = AnimeRow {
+        anime: new_anime,
+        search_title,
+        slug,
+        cover_version,
+        mal_id,
+        is_adult,
+    };
+    (row, warnings)
+}
+
+// Upserts the anime with a single statement, each column is sent as an array and unnested into
+// rows. Anime ids have to be unique within the batch.
+fn insert_anime_batch(
+    rows: &[AnimeRow],
+    connection: &dyn GenericConnection,
+) -> Result<u64, postgres::Error> {
+    if rows.is_empty() {
+        return Ok(0);
     }
+
+    let anime_ids: Vec<i32> = rows.iter().map(|row| row.anime.anime_id).collect();
+    let descriptions: Vec<String> = rows
+        .iter()
+        .map(|row| row.anime.description.clone())
+        .collect();
+    let covers_s3: Vec<String> = rows.iter().map(|row| row.anime.cover_s3.clone()).collect();
+    let covers_anilist: Vec<String> = rows
+        .iter()
+        .map(|row| row.anime.cover_anilist.clone())
+        .collect();
+    let averages: Vec<Option<i16>> = rows.iter().map(|row| row.anime.average).collect();
+    let natives: Vec<Option<String>> = rows.iter().map(|row| row.anime.native.clone()).collect();
+    let romajis: Vec<Option<String>> = rows.iter().map(|row| row.anime.romaji.clone()).collect();
+    let englishes: Vec<Option<String>> = rows.iter().map(|row| row.anime.english.clone()).collect();
+    let search_titles: Vec<String> = rows.iter().map(|row| row.search_title.clone()).collect();
+    let slugs: Vec<String> = rows.iter().map(|row| row.slug.clone()).collect();
+    // Arrays of arrays would have to be rectangular, so genres and tags travel as JSON.
+    let genres: Vec<String> = rows
+        .iter()
+        .map(|row| serde_json::to_string(&row.anime.genres).unwrap())
+        .collect();
+    let tags: Vec<String> = rows
+        .iter()
+        .map(|row| serde_json::to_string(&row.anime.tags).unwrap())
+        .collect();
+    let episodes: Vec<Option<i32>> = rows.iter().map(|row| row.anime.episodes).collect();
+    let seasons: Vec<Option<String>> = rows.iter().map(|row| row.anime.season.clone()).collect();
+    let season_years: Vec<Option<i32>> = rows.iter().map(|row| row.anime.season_year).collect();
+    let formats: Vec<Option<String>> = rows.iter().map(|row| row.anime.format.clone()).collect();
+    let studios: Vec<Option<String>> = rows.iter().map(|row| row.anime.studio.clone()).collect();
+    let cover_versions: Vec<i32> = rows.iter().map(|row| row.cover_version).collect();
+    let mal_ids: Vec<Option<i32>> = rows.iter().map(|row| row.mal_id).collect();
+    let adult: Vec<bool> = rows.iter().map(|row| row.is_adult).collect();
+    let covers_xl_s3: Vec<Option<String>> = rows
+        .iter()
+        .map(|row| row.anime.cover_xl_s3.clone())
+        .collect();
+    let covers_thumb_s3: Vec<Option<String>> = rows
+        .iter()
+        .map(|row| row.anime.cover_thumb_s3.clone())
+        .collect();
+
+    let stmt = connection.prepare_cached("INSERT INTO anime (anime_id, description, cover_s3, cover_anilist, average, native, romaji, english, search_title, slug, genres, tags, episodes, season, season_year, format, studio, cover_version, mal_id, is_adult, cover_xl_s3, cover_thumb_s3, search_document) SELECT v.anime_id, v.description, v.cover_s3, v.cover_anilist, v.average, v.native, v.romaji, v.english, v.search_title, v.slug, ARRAY(SELECT jsonb_array_elements_text(v.genres::jsonb)), ARRAY(SELECT jsonb_array_elements_text(v.tags::jsonb)), v.episodes, v.season, v.season_year, v.format, v.studio, v.cover_version, v.mal_id, v.is_adult, v.cover_xl_s3, v.cover_thumb_s3, setweight(to_tsvector('simple', v.search_title), 'A') || setweight(to_tsvector('english', v.description), 'B') FROM UNNEST($1::int4[], $2::text[], $3::text[], $4::text[], $5::int2[], $6::text[], $7::text[], $8::text[], $9::text[], $10::text[], $11::text[], $12::text[], $13::int4[], $14::text[], $15::int4[], $16::text[], $17::text[], $18::int4[], $19::int4[], $20::bool[], $21::text[], $22::text[]) AS v (anime_id, description, cover_s3, cover_anilist, average, native, romaji, english, search_title, slug, genres, tags, episodes, season, season_year, format, studio, cover_version, mal_id, is_adult, cover_xl_s3, cover_thumb_s3) ON CONFLICT (anime_id) DO UPDATE SET description = excluded.description, cover_s3 = excluded.cover_s3, cover_anilist = excluded.cover_anilist, average = excluded.average, native = excluded.native, romaji = excluded.romaji, english = excluded.english, search_title = excluded.search_title, slug = excluded.slug, genres = excluded.genres, tags = excluded.tags, episodes = excluded.episodes, season = excluded.season, season_year = excluded.season_year, format = excluded.format, studio = excluded.studio, cover_version = excluded.cover_version, mal_id = excluded.mal_id, is_adult = excluded.is_adult, cover_xl_s3 = excluded.cover_xl_s3, cover_thumb_s3 = excluded.cover_thumb_s3, search_document = excluded.search_document, updated_at = CASE WHEN (anime.description, anime.cover_s3, anime.cover_anilist, anime.average, anime.native, anime.romaji, anime.english, anime.slug, anime.genres, anime.tags, anime.episodes, anime.season, anime.season_year, anime.format, anime.studio, anime.cover_version, anime.mal_id, anime.is_adult, anime.cover_xl_s3, anime.cover_thumb_s3) IS DISTINCT FROM (excluded.description, excluded.cover_s3, excluded.cover_anilist, excluded.average, excluded.native, excluded.romaji, excluded.english, excluded.slug, excluded.genres, excluded.tags, excluded.episodes, excluded.season, excluded.season_year, excluded.format, excluded.studio, excluded.cover_version, excluded.mal_id, excluded.is_adult, excluded.cover_xl_s3, excluded.cover_thumb_s3) THEN now() ELSE anime.updated_at END")?;
+
+    stmt.execute(&[
+        &anime_ids,
+        &descriptions,
+        &covers_s3,
+        &covers_anilist,
+        &averages,
+        &natives,
+        &romajis,
+        &englishes,
+        &search_titles,
+        &slugs,
+        &genres,
+        &tags,
+        &episodes,
+        &seasons,
+        &season_years,
+        &formats,
+        &studios,
+        &cover_versions,
+        &mal_ids,
+        &adult,
+        &covers_xl_s3,
+        &covers_thumb_s3,
+    ])
 }
 
-enum ImageTypes {
-    Anime,
-    User,
+// Upserts a sync's entries with a single statement, together with AniList's updatedAt of each.
+// updated_at only moves when the entry actually changed, so incremental clients aren't sent the
+// whole list after every sync.
+fn insert_list_batch(
+    rows: &[(models::ListItem, Option<i64>)],
+    connection: &dyn GenericConnection,
+) -> Result<u64, postgres::Error> {
+    if rows.is_empty() {
+        return Ok(0);
+    }
+
+    let user_ids: Vec<i32> = rows.iter().map(|(item, _)| item.user_id).collect();
+    let anime_ids: Vec<i32> = rows.iter().map(|(item, _)| item.anime_id).collect();
+    let user_titles: Vec<Option<String>> = rows
+        .iter()
+        .map(|(item, _)| item.user_title.clone())
+        .collect();
+    let start_days: Vec<Option<NaiveDate>> = rows.iter().map(|(item, _)| item.start_day).collect();
+    let end_days: Vec<Option<NaiveDate>> = rows.iter().map(|(item, _)| item.end_day).collect();
+    let start_partials: Vec<Option<String>> = rows
+        .iter()
+        .map(|(item, _)| item.start_partial.map(|date| date.to_string()))
+        .collect();
+    let end_partials: Vec<Option<String>> = rows
+        .iter()
+        .map(|(item, _)| item.end_partial.map(|date| date.to_string()))
+        .collect();
+    let scores: Vec<Option<i16>> = rows.iter().map(|(item, _)| item.score).collect();
+    let statuses: Vec<Option<String>> = rows.iter().map(|(item, _)| item.status.clone()).collect();
+    let anilist_updated_at: Vec<Option<i64>> = rows.iter().map(|(_, updated)| *updated).collect();
+    let progress: Vec<Option<i32>> = rows.iter().map(|(item, _)| item.progress).collect();
+    let repeats: Vec<Option<i32>> = rows.iter().map(|(item, _)| item.repeat).collect();
+    let notes: Vec<Option<String>> = rows.iter().map(|(item, _)| item.notes.clone()).collect();
+    // Like genres and tags, the entries' custom lists would make a ragged array.
+    let custom_lists: Vec<String> = rows
+        .iter()
+        .map(|(item, _)| serde_json::to_string(&item.custom_lists).unwrap())
+        .collect();
+    let private: Vec<bool> = rows.iter().map(|(item, _)| item.private).collect();
+
+    let stmt = connection.prepare_cached("INSERT INTO lists (user_id, anime_id, user_title, start_day, end_day, score, status, anilist_updated_at, progress, repeat, notes, custom_lists, private, start_partial, end_partial, updated_at) SELECT v.user_id, v.anime_id, v.user_title, v.start_day, v.end_day, v.score, v.status, v.anilist_updated_at, v.progress, v.repeat, v.notes, ARRAY(SELECT jsonb_array_elements_text(v.custom_lists::jsonb)), v.private, v.start_partial, v.end_partial, now() FROM UNNEST($1::int4[], $2::int4[], $3::text[], $4::date[], $5::date[], $6::int2[], $7::text[], $8::int8[], $9::int4[], $10::int4[], $11::text[], $12::text[], $13::bool[], $14::text[], $15::text[]) AS v (user_id, anime_id, user_title, start_day, end_day, score, status, anilist_updated_at, progress, repeat, notes, custom_lists, private, start_partial, end_partial) ON CONFLICT (user_id, anime_id) DO UPDATE SET user_title = excluded.user_title, start_day = excluded.start_day, end_day = excluded.end_day, score = excluded.score, status = excluded.status, anilist_updated_at = excluded.anilist_updated_at, progress = excluded.progress, repeat = excluded.repeat, notes = excluded.notes, custom_lists = excluded.custom_lists, private = excluded.private, start_partial = excluded.start_partial, end_partial = excluded.end_partial, updated_at = CASE WHEN (lists.user_title, lists.start_day, lists.end_day, lists.score, lists.status, lists.progress, lists.repeat, lists.notes, lists.custom_lists, lists.private, lists.start_partial, lists.end_partial) IS DISTINCT FROM (excluded.user_title, excluded.start_day, excluded.end_day, excluded.score, excluded.status, excluded.progress, excluded.repeat, excluded.notes, excluded.custom_lists, excluded.private, excluded.start_partial, excluded.end_partial) THEN excluded.updated_at ELSE lists.updated_at END")?;
+
+    stmt.execute(&[
+        &user_ids,
+        &anime_ids,
+        &user_titles,
+        &start_days,
+        &end_days,
+        &scores,
+        &statuses,
+        &anilist_updated_at,
+        &progress,
+        &repeats,
+        &notes,
+        &custom_lists,
+        &private,
+        &start_partials,
+        &end_partials,
+    ])
+}
+
+// Writes the anime and entries as one batch. When the database rejects the batch's data, every
+// entry is retried on its own and the ones that still fail are returned with their error, by index,
+// instead of failing the sync.
+fn write_entries(
+    anime_rows: &[AnimeRow],
+    list_rows: &[(models::ListItem, Option<i64>)],
+    transaction: &Transaction,
+) -> Result<Vec<(usize, String)>, postgres::Error> {
+    let batch = transaction.transaction()?;
+    match insert_anime_batch(anime_rows, &batch).and_then(|_| insert_list_batch(list_rows, &batch))
+    {
+        Ok(_) => {
+            batch.commit()?;
+            return Ok(Vec::new());
+        }
+        Err(error) if is_data_error(&error) => {
+            warn!(
+                "batch of {} entries was rejected, writing them one by one. Error: {}",
+                list_rows.len(),
+                error
+            );
+        }
+        Err(error) => return Err(error),
+    }
+    // Dropping the savepoint rolls it back.
+    drop(batch);
+
+    let mut rejected = Vec::new();
+    for index in 0..list_rows.len() {
+        let single = transaction.transaction()?;
+        match insert_anime_batch(&anime_rows[index..=index], &single)
+            .and_then(|_| insert_list_batch(&list_rows[index..=index], &single))
+        {
+            Ok(_) => single.commit()?,
+            Err(error) if is_data_error(&error) => rejected.push((index, error.to_string())),
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(rejected)
+}
+
+// Details of anime whose entries were skipped, in a savepoint of their own. Rejected details are
+// left as they were stored rather than failing the sync.
+fn refresh_anime(rows: &[AnimeRow], transaction: &Transaction) -> Result<(), postgres::Error> {
+    let batch = transaction.transaction()?;
+    match refresh_anime_batch(rows, &batch) {
+        Ok(_) => batch.commit(),
+        Err(error) if is_data_error(&error) => {
+            warn!(
+                "refreshing {} unchanged anime was rejected, keeping their stored details. Error: {}",
+                rows.len(),
+                error
+            );
+            Ok(())
+        }
+        Err(error) => Err(error),
+    }
+}
+
+// Updates everything but the covers, which were copied by an earlier sync and keep their URLs.
+// Anime whose details are unchanged aren't touched, so their updated_at stays.
+fn refresh_anime_batch(
+    rows: &[AnimeRow],
+    connection: &dyn GenericConnection,
+) -> Result<u64, postgres::Error> {
+    if rows.is_empty() {
+        return Ok(0);
+    }
+
+    let anime_ids: Vec<i32> = rows.iter().map(|row| row.anime.anime_id).collect();
+    let descriptions: Vec<String> = rows
+        .iter()
+        .map(|row| row.anime.description.clone())
+        .collect();
+    let averages: Vec<Option<i16>> = rows.iter().map(|row| row.anime.average).collect();
+    let natives: Vec<Option<String>> = rows.iter().map(|row| row.anime.native.clone()).collect();
+    let romajis: Vec<Option<String>> = rows.iter().map(|row| row.anime.romaji.clone()).collect();
+    let englishes: Vec<Option<String>> = rows.iter().map(|row| row.anime.english.clone()).collect();
+    let search_titles: Vec<String> = rows.iter().map(|row| row.search_title.clone()).collect();
+    let slugs: Vec<String> = rows.iter().map(|row| row.slug.clone()).collect();
+    let genres: Vec<String> = rows
+        .iter()
+        .map(|row| serde_json::to_string(&row.anime.genres).unwrap())
+        .collect();
+    let tags: Vec<String> = rows
+        .iter()
+        .map(|row| serde_json::to_string(&row.anime.tags).unwrap())
+        .collect();
+    let episodes: Vec<Option<i32>> = rows.iter().map(|row| row.anime.episodes).collect();
+    let seasons: Vec<Option<String>> = rows.iter().map(|row| row.anime.season.clone()).collect();
+    let season_years: Vec<Option<i32>> = rows.iter().map(|row| row.anime.season_year).collect();
+    let formats: Vec<Option<String>> = rows.iter().map(|row| row.anime.format.clone()).collect();
+    let studios: Vec<Option<String>> = rows.iter().map(|row| row.anime.studio.clone()).collect();
+    let mal_ids: Vec<Option<i32>> = rows.iter().map(|row| row.mal_id).collect();
+    let adult: Vec<bool> = rows.iter().map(|row| row.is_adult).collect();
+
+    let stmt = connection.prepare_cached("UPDATE anime SET description = v.description, average = v.average, native = v.native, romaji = v.romaji, english = v.english, search_title = v.search_title, slug = v.slug, genres = ARRAY(SELECT jsonb_array_elements_text(v.genres::jsonb)), tags = ARRAY(SELECT jsonb_array_elements_text(v.tags::jsonb)), episodes = v.episodes, season = v.season, season_year = v.season_year, format = v.format, studio = v.studio, mal_id = v.mal_id, is_adult = v.is_adult, search_document = setweight(to_tsvector('simple', v.search_title), 'A') || setweight(to_tsvector('english', v.description), 'B'), updated_at = now() FROM UNNEST($1::int4[], $2::text[], $3::int2[], $4::text[], $5::text[], $6::text[], $7::text[], $8::text[], $9::text[], $10::text[], $11::int4[], $12::text[], $13::int4[], $14::text[], $15::text[], $16::int4[], $17::bool[]) AS v (anime_id, description, average, native, romaji, english, search_title, slug, genres, tags, episodes, season, season_year, format, studio, mal_id, is_adult) WHERE anime.anime_id = v.anime_id AND (anime.description, anime.average, anime.native, anime.romaji, anime.english, anime.slug, anime.genres, anime.tags, anime.episodes, anime.season, anime.season_year, anime.format, anime.studio, anime.mal_id, anime.is_adult) IS DISTINCT FROM (v.description, v.average, v.native, v.romaji, v.english, v.slug, ARRAY(SELECT jsonb_array_elements_text(v.genres::jsonb)), ARRAY(SELECT jsonb_array_elements_text(v.tags::jsonb)), v.episodes, v.season, v.season_year, v.format, v.studio, v.mal_id, v.is_adult)")?;
+
+    stmt.execute(&[
+        &anime_ids,
+        &descriptions,
+        &averages,
+        &natives,
+        &romajis,
+        &englishes,
+        &search_titles,
+        &slugs,
+        &genres,
+        &tags,
+        &episodes,
+        &seasons,
+        &season_years,
+        &formats,
+        &studios,
+        &mal_ids,
+        &adult,
+    ])
+}
+
+// Errors caused by the values written (SQLSTATE classes 22 and 23), rather than by the database.
+fn is_data_error(error: &postgres::Error) -> bool {
+    error.code().map_or(false, |state| {
+        state.code().starts_with("22") || state.code().starts_with("23")
+    })
+}
+
+// The version busts browser and CDN caches holding the previous cover.
+fn cover_url(image_type: ImageTypes, anime_id: i32, ext: &str, cover_version: i32) -> String {
+    format!(
+        "{}?v={}",
+        storage::url(&image_key(&image_type, anime_id, ext)),
+        cover_version
+    )
+}
+
+// Extension of the cover's key. A copied cover is named after the image that was downloaded,
+// otherwise the URL has to do.
+fn cover_ext(
+    image_type: ImageTypes,
+    anime_id: i32,
+    url: &str,
+    upload: bool,
+    store: &storage::Images,
+    warnings: &mut Vec<models::SyncWarning>,
+) -> &'static str {
+    if upload {
+        match copy_cover(image_type, anime_id, url, store) {
+            Ok(format) => return format.stored().ext(),
+            Err(warning) => warnings.push(warning),
+        }
+    }
+    url_format(url).ext()
+}
+
+// Uploads happen in the background, only a failed download is reported. Regular covers get a
+// thumbnail next to them.
+fn copy_cover(
+    image_type: ImageTypes,
+    anime_id: i32,
+    url: &str,
+    store: &storage::Images,
+) -> Result<images::Format, models::SyncWarning> {
+    let (content, format) = download_image(url).map_err(|error| models::SyncWarning {
+        anime_id,
+        kind: models::WarningKind::CoverDownloadFailed,
+        detail: format!("cover {}", error),
+    })?;
+    let url = url.to_owned();
+    let store = store.clone();
+    let task = shutdown::Task::start();
+    thread::spawn(move || {
+        if let ImageTypes::Anime = image_type {
+            match images::thumbnail(&content) {
+                Ok(thumb) => upload_image(
+                    store.as_ref(),
+                    ImageTypes::AnimeThumb,
+                    anime_id,
+                    thumb,
+                    images::Format::WebP,
+                    &url,
+                ),
+                Err(error) => error!(
+                    "error making a thumbnail for anime_id={}. Error: {}",
+                    anime_id, error
+                ),
+            }
+        }
+        upload_image(store.as_ref(), image_type, anime_id, content, format, &url);
+        drop(task);
+    });
+    Ok(format)
+}
+
+// Stores a single anime straight from AniList, for anime that aren't on any tracked list. None
+// when AniList doesn't know the id.
+pub fn ingest_anime(
+    anime_id: i32,
+    store: &storage::Images,
+    connection: &Connection,
+) -> Result<Option<models::AnimeDetail>, anilist_query::AnilistError> {
+    let media = match anilist_query::get_media(anime_id)? {
+        Some(media) => media,
+        None => return Ok(None),
+    };
+
+    let stored_covers = get_stored_covers(&[anime_id], connection);
+    let (cover_changed, cover_version) = cover_state(&media, &stored_covers);
+    for warning in save_anime(media, cover_changed, cover_version, store, connection) {
+        warn!(
+            "ingested anime_id={} with warning {}: {}",
+            anime_id,
+            warning.kind.as_str(),
+            warning.detail
+        );
+    }
+
+    Ok(get_anime(anime_id.to_string().as_ref(), connection))
+}
+
+// Downloads the cover from AniList and uploads it again, e.g. after the stored copy went missing.
+// The version moves on, so caches holding a broken copy let go of it. None when the anime isn't
+// stored or AniList no longer knows it.
+pub fn reupload_cover(
+    anime_id: i32,
+    store: &storage::Images,
+    connection: &Connection,
+) -> Result<Option<models::AnimeDetail>, anilist_query::AnilistError> {
+    let cover_version = match get_stored_covers(&[anime_id], connection).get(&anime_id) {
+        Some((_, version, _, _)) => version + 1,
+        None => return Ok(None),
+    };
+    let media = match anilist_query::get_media(anime_id)? {
+        Some(media) => media,
+        None => return Ok(None),
+    };
+
+    for warning in save_anime(media, true, cover_version, store, connection) {
+        warn!(
+            "re-uploaded cover of anime_id={} with warning {}: {}",
+            anime_id,
+            warning.kind.as_str(),
+            warning.detail
+        );
+    }
+
+    Ok(get_anime(anime_id.to_string().as_ref(), connection))
+}
+
+// Removes an anime from every list along with its history and cover, for records that are broken
+// beyond what a sync fixes. Syncs store it again from scratch if it's still on AniList. Returns the
+// users who had it, None when the anime isn't stored.
+pub fn purge_anime(
+    anime_id: i32,
+    store: &storage::Images,
+    connection: &Connection,
+) -> Option<Vec<i32>> {
+    let result = connection.transaction().and_then(|transaction| {
+        let user_ids: Vec<i32> = transaction
+            .query(
+                "DELETE FROM lists WHERE anime_id = $1 RETURNING user_id",
+                &[&anime_id],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        // Incremental clients have to hear that it's gone.
+        for user_id in user_ids.iter() {
+            transaction.execute("INSERT INTO list_tombstones (user_id, anime_id, deleted_at) VALUES ($1, $2, now()) ON CONFLICT (user_id, anime_id) DO UPDATE SET deleted_at = excluded.deleted_at", &[user_id, &anime_id])?;
+        }
+        transaction.execute(
+            "DELETE FROM list_history WHERE anime_id = $1",
+            &[&anime_id],
+        )?;
+        transaction.execute(
+            "DELETE FROM list_changes WHERE anime_id = $1",
+            &[&anime_id],
+        )?;
+        transaction.execute(
+            "DELETE FROM sync_warnings WHERE anime_id = $1",
+            &[&anime_id],
+        )?;
+        transaction.execute(
+            "DELETE FROM sync_errors WHERE anime_id = $1",
+            &[&anime_id],
+        )?;
+        transaction.execute("DELETE FROM activities WHERE anime_id = $1", &[&anime_id])?;
+
+        let deleted = transaction.query(
+            "DELETE FROM anime WHERE anime_id = $1 RETURNING cover_s3, cover_xl_s3",
+            &[&anime_id],
+        )?;
+        let covers: Option<(String, Option<String>)> =
+            deleted.iter().next().map(|row| (row.get(0), row.get(1)));
+        if covers.is_none() {
+            transaction.set_rollback();
+        } else {
+            transaction.commit()?;
+        }
+        Ok(covers.map(|covers| (covers, user_ids)))
+    });
+
+    match result {
+        Ok(Some(((cover_s3, cover_xl_s3), user_ids))) => {
+            delete_cover(store.as_ref(), anime_id, &cover_s3, &cover_xl_s3);
+            for user_id in user_ids.iter() {
+                stats::rebuild(*user_id, connection);
+            }
+            info!("purged anime_id={} from {} lists", anime_id, user_ids.len());
+            Some(user_ids)
+        }
+        Ok(None) => None,
+        Err(error) => {
+            error!("error purging anime_id={}. Error: {}", anime_id, error);
+            None
+        }
+    }
+}
+
+// Whether the cover differs from the stored one, and the version its S3 URL should carry.
+fn cover_state(
+    media: &anilist_models::Media,
+    stored_covers: &HashMap<i32, (String, i32, bool, bool)>,
+) -> (bool, i32) {
+    match stored_covers.get(&media.id) {
+        // Anime stored before extra large covers, thumbnails or WebP copies still need theirs.
+        // The large cover is the same, so its URL keeps the version.
+        Some((url, version, has_xl, current)) if *url == media.cover_image.large => (
+            !current || (!has_xl && extra_large_cover(media).is_some()),
+            *version,
+        ),
+        Some((_, version, _, _)) => (true, version + 1),
+        None => (true, 0),
+    }
+}
+
+// Problems with an entry's data on AniList that leave it incomplete in responses.
+fn entry_warnings(entry: &anilist_models::Entry) -> Vec<models::SyncWarning> {
+    let mut warnings = Vec::new();
+    let mut warn = |kind, detail: &str| {
+        warnings.push(models::SyncWarning {
+            anime_id: entry.media.id,
+            kind,
+            detail: detail.to_owned(),
+        })
+    };
+
+    if !has_cover(&entry.media) {
+        warn(
+            models::WarningKind::MissingCover,
+            "AniList has no cover image",
+        );
+    }
+    if entry.media.title.user_preferred.is_none() {
+        warn(models::WarningKind::MissingTitle, "AniList has no title");
+    }
+    // Dates without a year are simply unset, anything else has to be a real date or a real year or
+    // month of one.
+    let is_date = |date: &anilist_models::Date| {
+        date.year.is_none()
+            || construct_date(date).is_some()
+            || construct_partial_date(date).is_some()
+    };
+    if !is_date(&entry.started_at) {
+        warn(
+            models::WarningKind::InvalidDate,
+            "start date is not a real date",
+        );
+    }
+    if !is_date(&entry.completed_at) {
+        warn(
+            models::WarningKind::InvalidDate,
+            "completion date is not a real date",
+        );
+    }
+
+    warnings
+}
+
+// Problems that keep an entry from being written at all. They quarantine the entry, problems that
+// only leave it incomplete are warnings.
+fn validate_entry(entry: &anilist_models::Entry) -> Result<(), String> {
+    let media = &entry.media;
+    if media.id <= 0 {
+        return Err(format!("{} is not an AniList id", media.id));
+    }
+
+    let mut texts = vec![
+        media.description.as_str(),
+        media.cover_image.large.as_str(),
+        media.site_url.as_str(),
+    ];
+    texts.extend(
+        [
+            &media.cover_image.extra_large,
+            &media.title.user_preferred,
+            &media.title.english,
+            &media.title.romaji,
+            &media.title.native,
+            &media.season,
+            &media.format,
+            &entry.status,
+        ]
+        .iter()
+        .copied()
+        .filter_map(Option::as_deref),
+    );
+    texts.extend(media.genres.iter().flatten().map(String::as_str));
+    texts.extend(media.tags.iter().flatten().map(|tag| tag.name.as_str()));
+    texts.extend(
+        media
+            .studios
+            .iter()
+            .flat_map(|studios| studios.nodes.iter())
+            .map(|studio| studio.name.as_str()),
+    );
+    // Postgres text can't hold them.
+    if texts.iter().any(|text| text.contains('\0')) {
+        return Err("text contains a NUL character".to_owned());
+    }
+
+    Ok(())
+}
+
+// An entry that was left out of a sync, with AniList's data as it came for reprocessing.
+struct Quarantined {
+    anime_id: i32,
+    payload: String,
+    error: String,
+}
+
+fn quarantine(entry: &anilist_models::Entry, error: String) -> Quarantined {
+    Quarantined {
+        anime_id: entry.media.id,
+        payload: serde_json::to_string(entry).unwrap(),
+        error,
+    }
+}
+
+fn has_cover(media: &anilist_models::Media) -> bool {
+    is_image_file(&media.cover_image.large)
+}
+
+fn extra_large_cover(media: &anilist_models::Media) -> Option<&String> {
+    media
+        .cover_image
+        .extra_large
+        .as_ref()
+        .filter(|url| is_image_file(url))
+}
+
+fn is_image_file(url: &str) -> bool {
+    url.rsplit('/')
+        .next()
+        .map_or(false, |file| file.contains('.'))
+}
+
+// Warnings are kept per entry, so entries skipped by later syncs keep theirs until they change.
+// Every written entry loses its old warnings, warnings holds the new ones.
+fn replace_warnings(
+    user_id: i32,
+    job_id: i32,
+    anime_ids: &[i32],
+    warnings: &[models::SyncWarning],
+    connection: &dyn GenericConnection,
+) -> Result<(), postgres::Error> {
+    connection
+        .prepare_cached("DELETE FROM sync_warnings WHERE user_id = $1 AND anime_id = ANY($2)")?
+        .execute(&[&user_id, &anime_ids])?;
+
+    let stmt = connection.prepare_cached("INSERT INTO sync_warnings (user_id, anime_id, kind, detail, job_id, created_at) VALUES ($1, $2, $3, $4, $5, now()) ON CONFLICT (user_id, anime_id, kind) DO UPDATE SET detail = excluded.detail")?;
+    for warning in warnings {
+        stmt.execute(&[
+            &user_id,
+            &warning.anime_id,
+            &warning.kind.as_str(),
+            &warning.detail,
+            &job_id,
+        ])?;
+    }
+    Ok(())
+}
+
+// Like warnings, quarantined entries are kept until a later sync writes them. Entries that left the
+// user's lists are dropped, listed holds what is still on them.
+fn replace_quarantine(
+    user_id: i32,
+    job_id: i32,
+    written: &[i32],
+    listed: &[i32],
+    quarantined: &[Quarantined],
+    connection: &dyn GenericConnection,
+) -> Result<(), postgres::Error> {
+    connection
+        .prepare_cached("DELETE FROM sync_errors WHERE user_id = $1 AND (anime_id = ANY($2) OR NOT (anime_id = ANY($3)))")?
+        .execute(&[&user_id, &written, &listed])?;
+
+    let stmt = connection.prepare_cached("INSERT INTO sync_errors (user_id, anime_id, job_id, payload, error, created_at) VALUES ($1, $2, $3, $4, $5, now()) ON CONFLICT (user_id, anime_id) DO UPDATE SET job_id = excluded.job_id, payload = excluded.payload, error = excluded.error, created_at = excluded.created_at")?;
+    for entry in quarantined {
+        stmt.execute(&[
+            &user_id,
+            &entry.anime_id,
+            &job_id,
+            &entry.payload,
+            &entry.error,
+        ])?;
+    }
+    Ok(())
+}
+
+pub fn get_job_quarantined(job_id: i32, connection: &Connection) -> i64 {
+    let stmt = connection
+        .prepare_cached("SELECT count(*) FROM sync_errors WHERE job_id = $1")
+        .unwrap();
+
+    match stmt.query(&[&job_id]) {
+        Ok(rows) => rows.get(0).get(0),
+        Err(error) => {
+            error!(
+                "error counting quarantined entries for job_id={}. Error: {}",
+                job_id, error
+            );
+            0
+        }
+    }
+}
+
+// Most recent first, with the totals over every user.
+pub fn get_quarantine(limit: i64, connection: &Connection) -> Option<models::Quarantine> {
+    let counts = connection
+        .prepare_cached("SELECT count(*), count(DISTINCT user_id) FROM sync_errors")
+        .unwrap();
+    let stmt = connection.prepare_cached("SELECT e.sync_error_id, e.user_id, u.name, e.anime_id, e.job_id, e.error, e.payload, e.created_at FROM sync_errors AS e LEFT JOIN users AS u ON e.user_id = u.user_id ORDER BY e.created_at DESC, e.user_id, e.anime_id LIMIT $1").unwrap();
+
+    let result = counts.query(&[]).and_then(|totals| {
+        let entries = stmt
+            .query(&[&limit])?
+            .iter()
+            .map(|row| quarantined_from_row(&row))
+            .collect();
+        let totals = totals.get(0);
+        Ok(models::Quarantine {
+            entries: totals.get(0),
+            users: totals.get(1),
+            recent: entries,
+        })
+    });
+
+    match result {
+        Ok(quarantine) => Some(quarantine),
+        Err(error) => {
+            error!("error getting quarantined entries. Error: {}", error);
+            None
+        }
+    }
+}
+
+pub fn get_quarantined_entries(
+    sync_error_ids: &[i32],
+    connection: &Connection,
+) -> Vec<models::QuarantinedEntry> {
+    let stmt = connection.prepare_cached("SELECT e.sync_error_id, e.user_id, u.name, e.anime_id, e.job_id, e.error, e.payload, e.created_at FROM sync_errors AS e LEFT JOIN users AS u ON e.user_id = u.user_id WHERE e.sync_error_id = ANY($1) ORDER BY e.sync_error_id").unwrap();
+
+    match stmt.query(&[&sync_error_ids]) {
+        Ok(rows) => rows.iter().map(|row| quarantined_from_row(&row)).collect(),
+        Err(error) => {
+            error!("error getting quarantined entries. Error: {}", error);
+            Vec::new()
+        }
+    }
+}
+
+fn quarantined_from_row(row: &postgres::rows::Row) -> models::QuarantinedEntry {
+    let payload: String = row.get(6);
+    models::QuarantinedEntry {
+        id: row.get(0),
+        user_id: row.get(1),
+        name: row.get(2),
+        anime_id: row.get(3),
+        job_id: row.get(4),
+        error: row.get(5),
+        payload: serde_json::from_str(&payload).unwrap_or(serde_json::Value::String(payload)),
+        created_at: row.get(7),
+    }
+}
+
+// A quarantined entry the way it is replayed.
+pub struct QuarantinedPayload {
+    pub sync_error_id: i32,
+    pub user_id: i32,
+    pub job_id: i32,
+    pub payload: String,
+}
+
+// Every quarantined entry, or only the one with sync_error_id. None on database errors.
+pub fn get_quarantined_payloads(
+    sync_error_id: Option<i32>,
+    connection: &Connection,
+) -> Option<Vec<QuarantinedPayload>> {
+    let stmt = connection.prepare_cached("SELECT sync_error_id, user_id, job_id, payload FROM sync_errors WHERE $1::int4 IS NULL OR sync_error_id = $1 ORDER BY sync_error_id").unwrap();
+
+    match stmt.query(&[&sync_error_id]) {
+        Ok(rows) => Some(
+            rows.iter()
+                .map(|row| QuarantinedPayload {
+                    sync_error_id: row.get(0),
+                    user_id: row.get(1),
+                    job_id: row.get(2),
+                    payload: row.get(3),
+                })
+                .collect(),
+        ),
+        Err(error) => {
+            error!("error getting quarantined payloads. Error: {}", error);
+            None
+        }
+    }
+}
+
+// Runs a quarantined entry through the validation, cover copy and writes of a sync again, from the
+// payload AniList sent back then. The quarantine is lifted when it is written, otherwise the error
+// says why it still can't be. Entries the user hid since are simply dropped.
+pub fn replay_entry(
+    quarantined: &QuarantinedPayload,
+    store: &storage::Images,
+    connection: &Connection,
+) -> Result<(), String> {
+    let user_id = quarantined.user_id;
+    let entry: anilist_models::Entry = serde_json::from_str(&quarantined.payload)
+        .map_err(|error| format!("payload can't be read: {}", error))?;
+    validate_entry(&entry)?;
+
+    let anime_id = entry.media.id;
+    let (cover_changed, cover_version) =
+        cover_state(&entry.media, &get_stored_covers(&[anime_id], connection));
+    let mut warnings = entry_warnings(&entry);
+    let list_rows = [(list_item_from_entry(user_id, &entry), entry.updated_at)];
+    let (anime_row, cover_warnings) =
+        prepare_anime(entry.media, cover_changed, cover_version, store);
+    warnings.extend(cover_warnings);
+
+    let result = connection.transaction().and_then(|transaction| {
+        let hidden = transaction
+            .query(
+                "SELECT 1 FROM hidden_entries WHERE user_id = $1 AND anime_id = $2",
+                &[&user_id, &anime_id],
+            )?
+            .len()
+            > 0;
+        if !hidden {
+            let old = transaction
+                .query("SELECT user_id, anime_id, user_title, start_day, end_day, score, status, progress, repeat, notes, custom_lists, private, start_partial, end_partial FROM lists WHERE user_id = $1 AND anime_id = $2", &[&user_id, &anime_id])?
+                .iter()
+                .next()
+                .map(|row| list_item_from_row(&row));
+            insert_anime_batch(&[anime_row], &transaction)?;
+            insert_list_batch(&list_rows, &transaction)?;
+            if let Some(old) = &old {
+                record_history(old, &list_rows[0].0, &transaction)?;
+            }
+            record_changes(
+                user_id,
+                quarantined.job_id,
+                &[(old.as_ref(), Some(&list_rows[0].0))],
+                &transaction,
+            )?;
+            replace_warnings(
+                user_id,
+                quarantined.job_id,
+                &[anime_id],
+                &warnings,
+                &transaction,
+            )?;
+        }
+        transaction.execute(
+            "DELETE FROM sync_errors WHERE sync_error_id = $1",
+            &[&quarantined.sync_error_id],
+        )?;
+        transaction.commit()
+    });
+
+    result.map_err(|error| {
+        if !is_data_error(&error) {
+            error!(
+                "error replaying sync_error_id={}. Error: {}",
+                quarantined.sync_error_id, error
+            );
+        }
+        error.to_string()
+    })
+}
+
+pub fn set_quarantine_error(sync_error_id: i32, message: &str, connection: &Connection) {
+    let stmt = connection
+        .prepare_cached("UPDATE sync_errors SET error = $2 WHERE sync_error_id = $1")
+        .unwrap();
+
+    if let Err(error) = stmt.execute(&[&sync_error_id, &message]) {
+        error!(
+            "error updating sync_error_id={}. Error: {}",
+            sync_error_id, error
+        );
+    }
+}
+
+pub fn get_quarantined_user_ids(connection: &Connection) -> Vec<i32> {
+    let stmt = connection
+        .prepare_cached("SELECT DISTINCT user_id FROM sync_errors ORDER BY user_id")
+        .unwrap();
+
+    match stmt.query(&[]) {
+        Ok(rows) => rows.iter().map(|row| row.get(0)).collect(),
+        Err(error) => {
+            error!(
+                "error getting users with quarantined entries. Error: {}",
+                error
+            );
+            Vec::new()
+        }
+    }
+}
+
+pub fn get_job_warnings(job_id: i32, connection: &Connection) -> Vec<models::SyncWarning> {
+    let stmt = connection
+        .prepare_cached(
+            "SELECT anime_id, kind, detail FROM sync_warnings WHERE job_id = $1 \
+             ORDER BY anime_id, kind",
+        )
+        .unwrap();
+
+    match stmt.query(&[&job_id]) {
+        Ok(rows) => rows
+            .iter()
+            .filter_map(|row| {
+                let kind: String = row.get(1);
+                models::WarningKind::parse(&kind).map(|kind| models::SyncWarning {
+                    anime_id: row.get(0),
+                    kind,
+                    detail: row.get(2),
+                })
+            })
+            .collect(),
+        Err(error) => {
+            error!(
+                "error getting warnings for job_id={}. Error: {}",
+                job_id, error
+            );
+            Vec::new()
+        }
+    }
+}
+
+// Number of entries on the user's list with each kind of warning.
+pub fn get_warning_counts(name: &str, connection: &Connection) -> BTreeMap<String, i64> {
+    let stmt = connection.prepare_cached("SELECT w.kind, count(*) FROM sync_warnings AS w INNER JOIN users AS u ON w.user_id = u.user_id WHERE u.name = $1 GROUP BY w.kind").unwrap();
+
+    match stmt.query(&[&name]) {
+        Ok(rows) => rows.iter().map(|row| (row.get(0), row.get(1))).collect(),
+        Err(error) => {
+            error!(
+                "error counting warnings for user_name={}. Error: {}",
+                name, error
+            );
+            BTreeMap::new()
+        }
+    }
+}
+
+fn record_sync_attempt(user_id: i32, connection: &Connection) {
+    let stmt = connection
+        .prepare_cached("UPDATE users SET last_sync_attempt_at = now() WHERE user_id = $1")
+        .unwrap();
+
+    if let Err(error) = stmt.execute(&[&user_id]) {
+        error!(
+            "error recording sync attempt for user_id={}. Error: {}",
+            user_id, error
+        );
+    }
+}
+
+fn set_restricted(user_id: i32, restricted: bool, connection: &Connection) {
+    let stmt = connection
+        .prepare_cached("UPDATE users SET restricted = $2 WHERE user_id = $1")
+        .unwrap();
+
+    if let Err(error) = stmt.execute(&[&user_id, &restricted]) {
+        error!(
+            "error setting restricted={} for user_id={}. Error: {}",
+            restricted, user_id, error
+        );
+    }
+}
+
+// The user's id and everything a list response is built from that changes, as one string: sync
+// times, the user's columns, preferences, the newest change to their entries and to the anime on
+// their list, covers included. None for unknown users.
+pub fn get_list_version(name: &str, connection: &Connection) -> Option<(i32, String)> {
+    let stmt = connection.prepare_cached("SELECT u.user_id, concat_ws('|', u.last_synced_at, u.last_sync_attempt_at, u.sync_needs_confirmation, u.avatar_s3, (SELECT max(l.updated_at) FROM lists AS l WHERE l.user_id = u.user_id), (SELECT count(*) FROM lists AS l WHERE l.user_id = u.user_id), (SELECT max(t.deleted_at) FROM list_tombstones AS t WHERE t.user_id = u.user_id), (SELECT p.updated_at FROM user_preferences AS p WHERE p.user_id = u.user_id), (SELECT concat_ws(',', max(a.updated_at), sum(a.cover_version)) FROM lists AS l JOIN anime AS a ON a.anime_id = l.anime_id WHERE l.user_id = u.user_id)) FROM users AS u WHERE u.name = $1").unwrap();
+
+    match stmt.query(&[&name]) {
+        Ok(rows) => rows.iter().next().map(|row| (row.get(0), row.get(1))),
+        Err(error) => {
+            error!(
+                "error getting list version for user_name={}. Error: {}",
+                name, error
+            );
+            None
+        }
+    }
+}
+
+// Unknown users count as public, the caller reports them as not found.
+pub fn get_visibility(name: &str, connection: &Connection) -> models::Visibility {
+    let stmt = connection
+        .prepare_cached(
+            "SELECT restricted, takedown_requested_at IS NOT NULL FROM users WHERE name = $1",
+        )
+        .unwrap();
+
+    let (restricted, taken_down) = match stmt.query(&[&name]) {
+        Ok(rows) => rows
+            .iter()
+            .next()
+            .map_or((false, false), |row| (row.get(0), row.get(1))),
+        Err(error) => {
+            error!(
+                "error checking visibility for user_name={}. Error: {}",
+                name, error
+            );
+            (false, false)
+        }
+    };
+
+    if taken_down {
+        models::Visibility::TakenDown
+    } else if restricted {
+        models::Visibility::Private
+    } else {
+        models::Visibility::Public
+    }
+}
+
+// Whether the user's profile is shown, like get_visibility but by id. Unknown users and lookups
+// that fail count as hidden.
+fn is_public(user_id: i32, connection: &Connection) -> bool {
+    let stmt = connection
+        .prepare_cached(
+            "SELECT NOT restricted AND takedown_requested_at IS NULL FROM users WHERE user_id = $1",
+        )
+        .unwrap();
+
+    match stmt.query(&[&user_id]) {
+        Ok(rows) => rows.iter().next().map_or(false, |row| row.get(0)),
+        Err(error) => {
+            error!(
+                "error checking visibility for user_id={}. Error: {}",
+                user_id, error
+            );
+            false
+        }
+    }
+}
+
+// Adds the list activity since the latest stored one. Activity is extra, the sync succeeds without
+// it and the next one picks up where this one couldn't.
+fn sync_activities(user_id: i32, deadline: Instant, connection: &Connection) {
+    let latest: Option<DateTime<Utc>> = match connection
+        .query(
+            "SELECT max(created_at) FROM activities WHERE user_id = $1",
+            &[&user_id],
+        )
+        .map(|rows| rows.get(0).get(0))
+    {
+        Ok(latest) => latest,
+        Err(error) => {
+            error!(
+                "error getting the latest activity of user_id={}. Error: {}",
+                user_id, error
+            );
+            return;
+        }
+    };
+
+    let since = latest.map(|latest| latest.timestamp());
+    let activities = match anilist_query::get_activities(user_id, since, Some(deadline)) {
+        Ok(activities) => activities,
+        Err(error) => {
+            warn!(
+                "error fetching activity for user_id={}. Error: {}",
+                user_id, error
+            );
+            return;
+        }
+    };
+
+    // Hidden entries stay hidden in the activity AniList still shows.
+    let stmt = connection.prepare_cached("INSERT INTO activities (activity_id, user_id, anime_id, status, episode_from, episode_to, created_at) SELECT $1, $2, $3, $4, $5, $6, $7 WHERE NOT EXISTS (SELECT 1 FROM hidden_entries WHERE user_id = $2 AND anime_id = $3) ON CONFLICT (activity_id) DO NOTHING").unwrap();
+    for activity in activities {
+        // Activity on media AniList has since removed has nothing to point at.
+        let (anime_id, status) = match (&activity.media, &activity.status) {
+            (Some(media), Some(status)) => (media.id, status),
+            _ => continue,
+        };
+        let (episode_from, episode_to) = episodes(activity.progress.as_deref());
+        let created_at = match Utc.timestamp_opt(activity.created_at, 0).single() {
+            Some(created_at) => created_at,
+            None => continue,
+        };
+        if let Err(error) = stmt.execute(&[
+            &activity.id,
+            &user_id,
+            &anime_id,
+            status,
+            &episode_from,
+            &episode_to,
+            &created_at,
+        ]) {
+            error!(
+                "error saving activity_id={} for user_id={}. Error: {}",
+                activity.id, user_id, error
+            );
+        }
+    }
+}
+
+// AniList's progress is a single episode or a range like "4 - 6".
+fn episodes(progress: Option<&str>) -> (Option<i32>, Option<i32>) {
+    let progress = match progress {
+        Some(progress) => progress,
+        None => return (None, None),
+    };
+    let mut parts = progress
+        .split('-')
+        .map(|part| part.trim().parse::<i32>().ok());
+    let from = parts.next().flatten();
+    let to = parts.next().flatten().or(from);
+    (from, to)
+}
+
+// The user's activity after since, oldest first, at most limit of it. Adult anime are left out
+// unless the user shows them. None when the user isn't tracked.
+pub fn get_activities(
+    name: &str,
+    since: Option<DateTime<Utc>>,
+    limit: i64,
+    connection: &Connection,
+) -> Option<Vec<models::Activity>> {
+    let user = get_user(name, connection)?;
+    let show_adult = get_preferences(name, connection).show_adult;
+    let stmt = connection.prepare_cached("SELECT v.activity_id, v.anime_id, a.romaji, a.english, a.native, v.status, v.episode_from, v.episode_to, v.created_at FROM activities AS v LEFT JOIN anime AS a ON v.anime_id = a.anime_id WHERE v.user_id = $1 AND ($2::timestamptz IS NULL OR v.created_at > $2) AND (a.is_adult IS NOT TRUE OR $4) AND NOT EXISTS (SELECT 1 FROM hidden_entries AS h WHERE h.user_id = v.user_id AND h.anime_id = v.anime_id) ORDER BY v.created_at, v.activity_id LIMIT $3").unwrap();
+
+    match stmt.query(&[&user.user_id, &since, &limit, &show_adult]) {
+        Ok(rows) => Some(
+            rows.iter()
+                .map(|row| models::Activity {
+                    activity_id: row.get(0),
+                    anime_id: row.get(1),
+                    romaji: row.get(2),
+                    english: row.get(3),
+                    native: row.get(4),
+                    status: row.get(5),
+                    episode_from: row.get(6),
+                    episode_to: row.get(7),
+                    created_at: row.get(8),
+                })
+                .collect(),
+        ),
+        Err(error) => {
+            error!(
+                "error getting activity for user_id={}. Error: {}",
+                user.user_id, error
+            );
+            None
+        }
+    }
+}
+
+fn record_sync_success(user_id: i32, connection: &Connection) {
+    let stmt = connection
+        .prepare_cached(
+            "UPDATE users SET last_synced_at = now(), restricted = false WHERE user_id = $1",
+        )
+        .unwrap();
+
+    if let Err(error) = stmt.execute(&[&user_id]) {
+        error!(
+            "error recording sync success for user_id={}. Error: {}",
+            user_id, error
+        );
+    }
+}
+
+pub fn preview_entries(
+    id: i32,
+    connection: &Connection,
+) -> Result<models::SyncPreview, anilist_query::AnilistError> {
+    // Anyone can ask for a preview, so it only sees what the provider shows everyone.
+    let (provider, external_id, name) = get_provider(id, connection);
+    let mut lists = provider
+        .source()
+        .get_lists(external_id, &name, None, None)?;
+    drop_hidden(id, &mut lists, connection);
+    let existing = get_list_items(id, connection);
+
+    let mut fetched = HashSet::new();
+    let mut adds = Vec::new();
+    let mut updates = Vec::new();
+
+    for list in lists.iter().filter(|list| is_tracked_list(list)) {
+        for entry in list.entries.iter() {
+            let item = list_item_from_entry(id, entry);
+            if !fetched.insert(item.anime_id) {
+                continue;
+            }
+
+            match existing.get(&item.anime_id) {
+                Some(old) if old == &item => {}
+                Some(_) => updates.push(item),
+                None => adds.push(item),
+            }
+        }
+    }
+
+    let mut deletes: Vec<models::ListItem> = existing
+        .values()
+        .filter(|item| !fetched.contains(&item.anime_id))
+        .cloned()
+        .collect();
+    deletes.sort_unstable_by_key(|item| item.anime_id);
+
+    Ok(models::SyncPreview {
+        needs_confirmation: exceeds_delete_threshold(deletes.len(), existing.len()),
+        adds: preview_changes(&adds),
+        updates: preview_changes(&updates),
+        deletes: preview_changes(&deletes),
+    })
+}
+
+fn preview_changes(items: &[models::ListItem]) -> models::PreviewChanges {
+    models::PreviewChanges {
+        count: items.len(),
+        samples: items
+            .iter()
+            .take(PREVIEW_SAMPLE_SIZE)
+            .map(|item| models::PreviewItem {
+                anime_id: item.anime_id,
+                user_title: item.user_title.clone(),
+            })
+            .collect(),
+    }
+}
+
+// Custom lists only repeat entries that are already part of one of the status lists.
+fn is_tracked_list(list: &anilist_models::MediaList) -> bool {
+    !list.is_custom_list
+}
+
+fn list_item_from_row(row: &postgres::rows::Row) -> models::ListItem {
+    models::ListItem {
+        user_id: row.get(0),
+        anime_id: row.get(1),
+        user_title: row.get(2),
+        start_day: row.get(3),
+        end_day: row.get(4),
+        score: row.get(5),
+        status: row.get(6),
+        progress: row.get(7),
+        repeat: row.get(8),
+        notes: row.get(9),
+        custom_lists: row.get(10),
+        private: row.get(11),
+        start_partial: partial_date(row.get(12)),
+        end_partial: partial_date(row.get(13)),
+    }
+}
+
+fn list_item_from_entry(user_id: i32, entry: &anilist_models::Entry) -> models::ListItem {
+    models::ListItem {
+        user_id,
+        anime_id: entry.media.id,
+        user_title: entry.media.title.user_preferred.clone(),
+        start_day: construct_date(&entry.started_at),
+        end_day: construct_date(&entry.completed_at),
+        start_partial: construct_partial_date(&entry.started_at),
+        end_partial: construct_partial_date(&entry.completed_at),
+        score: entry.score_raw,
+        status: entry.status.clone(),
+        progress: entry.progress,
+        repeat: entry.repeat,
+        notes: entry.notes.clone(),
+        custom_lists: entry
+            .custom_lists
+            .iter()
+            .flatten()
+            .filter(|list| list.enabled)
+            .map(|list| list.name.clone())
+            .collect(),
+        private: entry.private.unwrap_or(false) || entry.hidden_from_status_lists.unwrap_or(false),
+    }
+}
+
+// Keeps the values an entry had before a sync changed its score, status or dates.
+fn record_history(
+    old: &models::ListItem,
+    new: &models::ListItem,
+    connection: &dyn GenericConnection,
+) -> Result<(), postgres::Error> {
+    // Private values aren't kept past the sync that replaces them.
+    if old.private {
+        return Ok(());
+    }
+    if (old.score, &old.status, old.start_day, old.end_day)
+        == (new.score, &new.status, new.start_day, new.end_day)
+    {
+        return Ok(());
+    }
+
+    let stmt = connection.prepare_cached("INSERT INTO list_history (user_id, anime_id, score, status, start_day, end_day, replaced_at) VALUES ($1, $2, $3, $4, $5, $6, now())")?;
+
+    stmt.execute(&[
+        &old.user_id,
+        &old.anime_id,
+        &old.score,
+        &old.status,
+        &old.start_day,
+        &old.end_day,
+    ])?;
+    Ok(())
+}
+
+// Logs what a sync did to each entry, given as (before, after) pairs. Entries that come out the
+// same are left out. The log is public, so private entries count as not being on the list.
+fn record_changes(
+    user_id: i32,
+    job_id: i32,
+    changes: &[(Option<&models::ListItem>, Option<&models::ListItem>)],
+    connection: &dyn GenericConnection,
+) -> Result<(), postgres::Error> {
+    let mut anime_ids: Vec<i32> = Vec::new();
+    let mut operations: Vec<&str> = Vec::new();
+    let mut befores: Vec<Option<String>> = Vec::new();
+    let mut afters: Vec<Option<String>> = Vec::new();
+    for (before, after) in changes.iter() {
+        let before = before.filter(|item| !item.private);
+        let after = after.filter(|item| !item.private);
+        let (anime_id, operation) = match (before, after) {
+            (Some(before), Some(after)) if before == after => continue,
+            (Some(_), Some(after)) => (after.anime_id, models::ListChangeOperation::Updated),
+            (None, Some(after)) => (after.anime_id, models::ListChangeOperation::Added),
+            (Some(before), None) => (before.anime_id, models::ListChangeOperation::Removed),
+            (None, None) => continue,
+        };
+        anime_ids.push(anime_id);
+        operations.push(operation.as_str());
+        befores.push(before.map(list_item_json));
+        afters.push(after.map(list_item_json));
+    }
+    if anime_ids.is_empty() {
+        return Ok(());
+    }
+
+    connection
+        .prepare_cached("INSERT INTO list_changes (user_id, anime_id, job_id, operation, before, after, changed_at) SELECT $1, v.anime_id, $2, v.operation, v.before, v.after, now() FROM UNNEST($3::int4[], $4::text[], $5::text[], $6::text[]) AS v (anime_id, operation, before, after)")?
+        .execute(&[&user_id, &job_id, &anime_ids, &operations, &befores, &afters])?;
+    Ok(())
+}
+
+fn list_item_json(item: &models::ListItem) -> String {
+    let day = |day: Option<NaiveDate>| day.map(|day| day.format("%Y-%m-%d").to_string());
+    serde_json::json!({
+        "userTitle": item.user_title,
+        "startDay": day(item.start_day),
+        "endDay": day(item.end_day),
+        "startPartial": item.start_partial,
+        "endPartial": item.end_partial,
+        "score": item.score,
+        "status": item.status,
+        "progress": item.progress,
+        "repeat": item.repeat,
+        "notes": item.notes,
+        "customLists": item.custom_lists,
+    })
+    .to_string()
+}
+
+// The changes syncs made after since, oldest first, at most limit of them. None when the user
+// isn't tracked.
+pub fn get_changes(
+    name: &str,
+    since: Option<DateTime<Utc>>,
+    limit: i64,
+    connection: &Connection,
+) -> Option<Vec<models::ListChange>> {
+    let user = get_user(name, connection)?;
+    let stmt = connection.prepare_cached("SELECT c.change_id, c.anime_id, a.romaji, a.english, a.native, c.job_id, c.operation, c.before, c.after, c.changed_at FROM list_changes AS c LEFT JOIN anime AS a ON c.anime_id = a.anime_id WHERE c.user_id = $1 AND ($2::timestamptz IS NULL OR c.changed_at > $2) ORDER BY c.changed_at, c.change_id LIMIT $3").unwrap();
+
+    let json = |value: Option<String>| value.and_then(|value| serde_json::from_str(&value).ok());
+    match stmt.query(&[&user.user_id, &since, &limit]) {
+        Ok(rows) => Some(
+            rows.iter()
+                .filter_map(|row| {
+                    let operation: String = row.get(6);
+                    Some(models::ListChange {
+                        id: row.get(0),
+                        anime_id: row.get(1),
+                        romaji: row.get(2),
+                        english: row.get(3),
+                        native: row.get(4),
+                        job_id: row.get(5),
+                        operation: models::ListChangeOperation::parse(&operation)?,
+                        before: json(row.get(7)),
+                        after: json(row.get(8)),
+                        changed_at: row.get(9),
+                    })
+                })
+                .collect(),
+        ),
+        Err(error) => {
+            error!(
+                "error getting list changes for user_id={}. Error: {}",
+                user.user_id, error
+            );
+            None
+        }
+    }
+}
+
+pub fn get_history(
+    name: &str,
+    anime_id: Option<i32>,
+    connection: &Connection,
+) -> Option<Vec<models::HistoryEntry>> {
+    let user = get_user(name, connection)?;
+    let stmt = connection.prepare_cached("SELECT h.anime_id, a.romaji, a.english, a.native, h.score, h.status, h.start_day, h.end_day, h.replaced_at FROM list_history AS h INNER JOIN anime AS a ON h.anime_id = a.anime_id WHERE h.user_id = $1 AND ($2::int4 IS NULL OR h.anime_id = $2) AND NOT EXISTS (SELECT 1 FROM lists AS l WHERE l.user_id = h.user_id AND l.anime_id = h.anime_id AND l.private) ORDER BY h.replaced_at DESC, h.anime_id").unwrap();
+
+    match stmt.query(&[&user.user_id, &anime_id]) {
+        Ok(rows) => Some(
+            rows.iter()
+                .map(|row| models::HistoryEntry {
+                    anime_id: row.get(0),
+                    romaji: row.get(1),
+                    english: row.get(2),
+                    native: row.get(3),
+                    score: row.get(4),
+                    status: row.get(5),
+                    start_day: row.get(6),
+                    end_day: row.get(7),
+                    replaced_at: row.get(8),
+                })
+                .collect(),
+        ),
+        Err(error) => {
+            error!(
+                "error getting history for user_id={}. Error: {}",
+                user.user_id, error
+            );
+            None
+        }
+    }
+}
+
+// period is month or year, None when the user isn't tracked.
+pub fn get_timeline(name: &str, period: &str, connection: &Connection) -> Option<models::Timeline> {
+    let user = get_user(name, connection)?;
+    let show_adult = get_preferences(name, connection).show_adult;
+
+    // Entries are bucketed first, a window over date_trunc($2, ...) itself wouldn't match the
+    // GROUP BY, since every use of a parameter counts as a different expression.
+    let result = connection
+        .prepare_cached(
+            "SELECT b.period, count(*), sum(count(*)) OVER (ORDER BY b.period)::int8 \
+             FROM (SELECT date_trunc($2, l.end_day)::date AS period FROM public_lists AS l \
+             INNER JOIN anime AS a ON l.anime_id = a.anime_id \
+             WHERE l.user_id = $1 AND l.end_day IS NOT NULL AND (NOT a.is_adult OR $3) \
+             AND NOT EXISTS (SELECT 1 FROM hidden_entries AS h \
+             WHERE h.user_id = l.user_id AND h.anime_id = l.anime_id)) AS b \
+             GROUP BY b.period ORDER BY b.period",
+        )
+        .and_then(|stmt| stmt.query(&[&user.user_id, &period, &show_adult]))
+        .and_then(|rows| {
+            let periods: Vec<models::TimelinePeriod> = rows
+                .iter()
+                .map(|row| models::TimelinePeriod {
+                    start: row.get(0),
+                    count: row.get(1),
+                    cumulative: row.get(2),
+                })
+                .collect();
+            let undated: i64 = connection
+                .prepare_cached(
+                    "SELECT count(*) FROM public_lists AS l \
+                     INNER JOIN anime AS a ON l.anime_id = a.anime_id \
+                     WHERE l.user_id = $1 AND l.end_day IS NULL AND l.status = 'COMPLETED' \
+                     AND (NOT a.is_adult OR $2) AND NOT EXISTS (SELECT 1 FROM hidden_entries AS h \
+                     WHERE h.user_id = l.user_id AND h.anime_id = l.anime_id)",
+                )?
+                .query(&[&user.user_id, &show_adult])?
+                .get(0)
+                .get(0);
+            Ok(models::Timeline {
+                period: period.to_owned(),
+                total: periods.last().map_or(0, |last| last.cumulative),
+                undated,
+                periods,
+            })
+        });
+
+    match result {
+        Ok(timeline) => Some(timeline),
+        Err(error) => {
+            error!(
+                "error getting timeline for user_id={}. Error: {}",
+                user.user_id, error
+            );
+            None
+        }
+    }
+}
+
+fn change_event(
+    old: Option<&models::ListItem>,
+    new: &models::ListItem,
+) -> Option<models::ChangeEvent> {
+    // Subscribers see what the public list shows, private entries count as not being on it.
+    if new.private {
+        return None;
+    }
+    let old = old.filter(|item| !item.private);
+    let kind = match old {
+        Some(old) if old == new => return None,
+        Some(old) if old.end_day.is_none() && new.end_day.is_some() => {
+            models::ChangeKind::Completed
+        }
+        Some(_) => models::ChangeKind::Updated,
+        None if new.end_day.is_some() => models::ChangeKind::Completed,
+        None => models::ChangeKind::Added,
+    };
+
+    Some(models::ChangeEvent {
+        user_id: new.user_id,
+        anime_id: new.anime_id,
+        title: new.user_title.clone(),
+        kind,
+    })
+}
+
+fn get_list_items(user_id: i32, connection: &Connection) -> HashMap<i32, models::ListItem> {
+    let stmt = connection.prepare_cached("SELECT user_id, anime_id, user_title, start_day, end_day, score, status, progress, repeat, notes, custom_lists, private, start_partial, end_partial FROM lists WHERE user_id = $1").unwrap();
+
+    let mut items = HashMap::new();
+    match stmt.query(&[&user_id]) {
+        Ok(rows) => {
+            for row in rows.iter() {
+                let list_item = list_item_from_row(&row);
+                items.insert(list_item.anime_id, list_item);
+            }
+        }
+        Err(error) => {
+            error!(
+                "error retrieving list for user_id={}. Error: {}",
+                user_id, error
+            );
+        }
+    }
+
+    items
+}
+
+// Stored AniList cover URL, cover version and whether an extra large cover was copied, of every
+// anime in the fetched lists.
+// AniList's cover URL, version, whether an extra large copy is stored and whether the stored copies
+// are what this version stores (a thumbnail, and WebP in place of JPEG and PNG), keyed by anime_id.
+fn get_stored_covers(
+    anime_ids: &[i32],
+    connection: &Connection,
+) -> HashMap<i32, (String, i32, bool, bool)> {
+    let stmt = connection
+        .prepare_cached(
+            "SELECT anime_id, cover_anilist, cover_version, cover_xl_s3 IS NOT NULL, cover_s3 = '' OR (cover_thumb_s3 IS NOT NULL AND cover_s3 !~* '[.](jpe?g|png)([?]|$)') FROM anime WHERE anime_id = ANY($1)",
+        )
+        .unwrap();
+
+    match stmt.query(&[&anime_ids]) {
+        Ok(rows) => rows
+            .iter()
+            .map(|row| (row.get(0), (row.get(1), row.get(2), row.get(3), row.get(4))))
+            .collect(),
+        Err(error) => {
+            error!("error retrieving stored covers. Error: {}", error);
+            HashMap::new()
+        }
+    }
+}
+
+// AniList's updatedAt of every stored entry as of the sync that wrote it, keyed by anime_id.
+fn get_anilist_updated_at(user_id: i32, connection: &Connection) -> HashMap<i32, Option<i64>> {
+    let stmt = connection
+        .prepare_cached("SELECT anime_id, anilist_updated_at FROM lists WHERE user_id = $1")
+        .unwrap();
+
+    match stmt.query(&[&user_id]) {
+        Ok(rows) => rows.iter().map(|row| (row.get(0), row.get(1))).collect(),
+        Err(error) => {
+            error!(
+                "error retrieving entry versions for user_id={}. Error: {}",
+                user_id, error
+            );
+            HashMap::new()
+        }
+    }
+}
+
+pub fn search_anime(query: &str, connection: &Connection) -> Vec<models::SearchResult> {
+    let normalized = normalize::normalize(query);
+    if normalized.is_empty() {
+        return Vec::new();
+    }
+
+    let pattern = format!("%{}%", normalized);
+    let stmt = connection.prepare_cached("SELECT anime_id, romaji, english, native, cover_s3, 1.0::real FROM anime WHERE search_title LIKE $1 ORDER BY length(search_title), anime_id LIMIT $2").unwrap();
+    let results = query_search_results(&stmt, &[&pattern, &SEARCH_LIMIT], query);
+    if !results.is_empty() {
+        return results;
+    }
+
+    // Full text search over titles and descriptions finds anime by words in any order, e.g. a
+    // character or place only mentioned in the description.
+    let stmt = connection.prepare_cached("SELECT anime_id, romaji, english, native, cover_s3, ts_rank(search_document, query, 32) AS quality FROM anime, (SELECT plainto_tsquery('simple', $1) || plainto_tsquery('english', $3) AS query) AS q WHERE search_document @@ query ORDER BY quality DESC, anime_id LIMIT $2").unwrap();
+    // Titles are matched in their normalized form, descriptions as English text.
+    let results = query_search_results(&stmt, &[&normalized, &SEARCH_LIMIT, &query], query);
+    if !results.is_empty() {
+        return results;
+    }
+
+    // Nothing matches the words either, so rank by trigram similarity (pg_trgm) to still find
+    // titles with typos in them.
+    let stmt = connection.prepare_cached("SELECT anime_id, romaji, english, native, cover_s3, word_similarity($1, search_title) AS quality FROM anime WHERE $1 <% search_title ORDER BY quality DESC, anime_id LIMIT $2").unwrap();
+    query_search_results(&stmt, &[&normalized, &SEARCH_LIMIT], query)
+}
+
+fn query_search_results(
+    stmt: &postgres::stmt::Statement,
+    params: &[&dyn ToSql],
+    query: &str,
+) -> Vec<models::SearchResult> {
+    match stmt.query(params) {
+        Ok(rows) => rows
+            .iter()
+            .map(|row| models::SearchResult {
+                id: row.get(0),
+                romaji: row.get(1),
+                english: row.get(2),
+                native: row.get(3),
+                cover: row.get(4),
+                match_quality: row.get(5),
+            })
+            .collect(),
+        Err(error) => {
+            error!(
+                "error searching anime for query={}. Error: {}",
+                query, error
+            );
+            Vec::new()
+        }
+    }
+}
+
+// Accepts the current slug, a plain anime id or an outdated slug that still ends in the id.
+pub fn get_anime(slug: &str, connection: &Connection) -> Option<models::AnimeDetail> {
+    let stmt = connection.prepare_cached("SELECT anime_id, slug, romaji, english, native, description, cover_s3, average, genres, tags, episodes, season, season_year, format, studio, cover_xl_s3 FROM anime WHERE slug = $1 OR anime_id = $2 ORDER BY slug = $1 DESC LIMIT 1").unwrap();
+
+    let id = normalize::slug_id(slug).unwrap_or(0);
+    let mut anime = match stmt.query(&[&slug, &id]) {
+        Ok(rows) => rows.iter().next().map(|row| models::AnimeDetail {
+            id: row.get(0),
+            slug: row.get(1),
+            romaji: row.get(2),
+            english: row.get(3),
+            native: row.get(4),
+            description: row.get(5),
+            cover: row.get(6),
+            average: row.get(7),
+            genres: row.get(8),
+            tags: row.get(9),
+            episodes: row.get(10),
+            season: row.get(11),
+            season_year: row.get(12),
+            format: row.get(13),
+            studio: row.get(14),
+            cover_xl: row.get(15),
+            watchers: Vec::new(),
+        })?,
+        Err(error) => {
+            error!("error getting anime for slug={}. Error: {}", slug, error);
+            return None;
+        }
+    };
+
+    anime.watchers = get_watchers(anime.id, connection);
+    Some(anime)
+}
+
+fn get_watchers(anime_id: i32, connection: &Connection) -> Vec<models::Watcher> {
+    let stmt = connection.prepare_cached("SELECT u.name, u.avatar_s3, l.score, l.status, l.start_day, l.end_day FROM public_lists AS l INNER JOIN visible_users AS u ON l.user_id = u.user_id WHERE l.anime_id = $1 ORDER BY l.end_day DESC NULLS LAST, u.name").unwrap();
+
+    match stmt.query(&[&anime_id]) {
+        Ok(rows) => rows
+            .iter()
+            .map(|row| models::Watcher {
+                id: row.get(0),
+                avatar: row.get(1),
+                score: row.get(2),
+                status: row.get(3),
+                start_day: row.get(4),
+                end_day: row.get(5),
+            })
+            .collect(),
+        Err(error) => {
+            error!(
+                "error getting watchers for anime_id={}. Error: {}",
+                anime_id, error
+            );
+            Vec::new()
+        }
+    }
+}
+
+pub fn get_user(name: &str, connection: &Connection) -> Option<models::User> {
+    let stmt = connection
+        .prepare_cached(
+            "SELECT user_id, name, avatar_s3, avatar_anilist FROM users WHERE name = $1",
+        )
+        .unwrap();
+
+    match stmt.query(&[&name]) {
+        Ok(rows) => rows.iter().next().map(|row| models::User {
+            user_id: row.get(0),
+            name: row.get(1),
+            avatar_s3: row.get(2),
+            avatar_anilist: row.get(3),
+        }),
+        Err(error) => {
+            error!("error getting user_name={}. Error: {}", name, error);
+            None
+        }
+    }
+}
+
+// Archive file names and S3 URLs of the covers of a user's completed anime.
+pub fn get_completed_covers(user_id: i32, connection: &Connection) -> Vec<(String, String)> {
+    let stmt = connection.prepare_cached("SELECT a.anime_id, a.slug, a.cover_s3 FROM public_lists AS l INNER JOIN anime AS a ON l.anime_id = a.anime_id WHERE l.user_id = $1 AND l.status = 'COMPLETED' AND a.cover_s3 <> '' ORDER BY l.end_day NULLS LAST, a.anime_id").unwrap();
+
+    match stmt.query(&[&user_id]) {
+        Ok(rows) => rows
+            .iter()
+            .map(|row| {
+                let anime_id: i32 = row.get(0);
+                let slug: Option<String> = row.get(1);
+                let url: String = row.get(2);
+                let ext = url
+                    .split('?')
+                    .next()
+                    .and_then(|path| path.rsplit('.').next())
+                    .unwrap_or("jpg")
+                    .to_owned();
+                let name = slug.unwrap_or_else(|| format!("anime_{}", anime_id));
+                (format!("{}.{}", name, ext), url)
+            })
+            .collect(),
+        Err(error) => {
+            error!(
+                "error getting completed covers for user_id={}. Error: {}",
+                user_id, error
+            );
+            Vec::new()
+        }
+    }
+}
+
+// One page of tracked users, most recently synced first, and the number of users overall. Users
+// that are restricted or taken down aren't listed or counted.
+pub fn get_tracked_users(
+    page: i64,
+    per_page: i64,
+    connection: &Connection,
+) -> Option<(Vec<models::TrackedUser>, i64)> {
+    let stmt = connection.prepare_cached("SELECT u.name, u.slug, u.avatar_s3, (SELECT count(*) FROM public_lists AS l WHERE l.user_id = u.user_id), u.last_synced_at, count(*) OVER () FROM visible_users AS u ORDER BY u.last_synced_at DESC NULLS LAST, u.name LIMIT $1 OFFSET $2").unwrap();
+
+    let offset = page_offset(page, per_page).unwrap_or(i64::MAX);
+    match stmt.query(&[&per_page, &offset]) {
+        Ok(rows) => {
+            let total = rows.iter().next().map(|row| row.get(5));
+            let users = rows
+                .iter()
+                .map(|row| models::TrackedUser {
+                    id: row.get(0),
+                    slug: row.get(1),
+                    avatar: row.get(2),
+                    entries: row.get(3),
+                    last_synced_at: row.get(4),
+                })
+                .collect();
+            // Past the last page there are no rows to carry the total.
+            match total {
+                Some(total) => Some((users, total)),
+                None => Some((users, count_users(connection)?)),
+            }
+        }
+        Err(error) => {
+            error!("error getting tracked users. Error: {}", error);
+            None
+        }
+    }
+}
+
+fn count_users(connection: &Connection) -> Option<i64> {
+    let stmt = connection
+        .prepare_cached("SELECT count(*) FROM visible_users")
+        .unwrap();
+
+    match stmt.query(&[]) {
+        Ok(rows) => Some(rows.get(0).get(0)),
+        Err(error) => {
+            error!("error counting users. Error: {}", error);
+            None
+        }
+    }
+}
+
+// The AniList name a path segment refers to and that user's slug. Names win over slugs, so a new
+// user can't be shadowed by someone else's slug.
+pub fn resolve_profile(
+    name_or_slug: &str,
+    connection: &Connection,
+) -> Option<(String, Option<String>)> {
+    let stmt = connection.prepare_cached("SELECT name, slug FROM users WHERE name = $1 OR slug = $1 ORDER BY name = $1 DESC LIMIT 1").unwrap();
+
+    match stmt.query(&[&name_or_slug]) {
+        Ok(rows) => rows.iter().next().map(|row| (row.get(0), row.get(1))),
+        Err(error) => {
+            error!(
+                "error resolving profile for name_or_slug={}. Error: {}",
+                name_or_slug, error
+            );
+            None
+        }
+    }
+}
+
+pub fn slug_taken(slug: &str, user_id: i32, connection: &Connection) -> Option<bool> {
+    let stmt = connection.prepare_cached("SELECT EXISTS (SELECT 1 FROM users WHERE (slug = $1 OR lower(name) = $1) AND user_id <> $2)").unwrap();
+
+    match stmt.query(&[&slug, &user_id]) {
+        Ok(rows) => Some(rows.get(0).get(0)),
+        Err(error) => {
+            error!(
+                "error checking slug={} for user_id={}. Error: {}",
+                slug, user_id, error
+            );
+            None
+        }
+    }
+}
+
+pub fn set_user_slug(user_id: i32, slug: Option<&str>, connection: &Connection) -> bool {
+    let stmt = connection
+        .prepare_cached("UPDATE users SET slug = $2 WHERE user_id = $1")
+        .unwrap();
+
+    match stmt.execute(&[&user_id, &slug]) {
+        Ok(updated) => updated > 0,
+        Err(error) => {
+            error!(
+                "error setting slug={:?} for user_id={}. Error: {}",
+                slug, user_id, error
+            );
+            false
+        }
+    }
+}
+
+pub fn get_user_ids(connection: &Connection) -> Vec<i32> {
+    let stmt = connection
+        .prepare_cached(
+            "SELECT user_id FROM users WHERE takedown_requested_at IS NULL ORDER BY user_id",
+        )
+        .unwrap();
+
+    match stmt.query(&[]) {
+        Ok(rows) => rows.iter().map(|row| row.get(0)).collect(),
+        Err(error) => {
+            error!("error getting user ids. Error: {}", error);
+            Vec::new()
+        }
+    }
+}
+
+// Users whose cadence says they are due for a sync within the next slack_secs, judged by their
+// last attempt so lists that keep failing aren't retried on every run. The interval between syncs
+// is max_interval_secs divided by one more than the profile's views in the last window_hours,
+// bounded by min_interval_secs, and never below a week for weekly users. Most viewed come first.
+pub fn get_due_user_ids(
+    slack_secs: i64,
+    min_interval_secs: i64,
+    max_interval_secs: i64,
+    window_hours: i32,
+    connection: &Connection,
+) -> Vec<i32> {
+    let stmt = connection
+        .prepare_cached(
+            "SELECT u.user_id FROM users AS u LEFT JOIN (SELECT user_id, sum(hits) AS hits FROM profile_hits WHERE hour > now() - make_interval(hours => $4) GROUP BY user_id) AS h ON h.user_id = u.user_id, LATERAL (SELECT LEAST(GREATEST($3::float8 / (1 + COALESCE(h.hits, 0)), $2::float8), $3::float8) AS secs) AS adaptive WHERE u.takedown_requested_at IS NULL AND u.sync_cadence <> 'manual' AND (u.last_sync_attempt_at IS NULL OR u.last_sync_attempt_at + make_interval(secs => CASE u.sync_cadence WHEN 'weekly' THEN GREATEST(adaptive.secs, 604800) ELSE adaptive.secs END) <= now() + make_interval(secs => $1::float8)) ORDER BY COALESCE(h.hits, 0) DESC, u.user_id",
+        )
+        .unwrap();
+
+    match stmt.query(&[
+        &(slack_secs as f64),
+        &(min_interval_secs as f64),
+        &(max_interval_secs as f64),
+        &window_hours,
+    ]) {
+        Ok(rows) => rows.iter().map(|row| row.get(0)).collect(),
+        Err(error) => {
+            error!("error getting users due for a sync. Error: {}", error);
+            Vec::new()
+        }
+    }
+}
+
+pub fn get_user_by_id(user_id: i32, connection: &Connection) -> Option<models::User> {
+    let stmt = connection
+        .prepare_cached(
+            "SELECT user_id, name, avatar_s3, avatar_anilist FROM users WHERE user_id = $1",
+        )
+        .unwrap();
+
+    query_users(&stmt, user_id).into_iter().next()
+}
+
+// Sessions are stored by the SHA-256 of their token, the token itself only ever goes to the client.
+pub fn session_hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub fn get_session_user(token_hash: &str, connection: &Connection) -> Option<i32> {
+    let stmt = connection
+        .prepare_cached("SELECT user_id FROM sessions WHERE token_hash = $1 AND expires_at > now()")
+        .unwrap();
+
+    match stmt.query(&[&token_hash]) {
+        Ok(rows) => rows.iter().next().map(|row| row.get(0)),
+        Err(error) => {
+            error!("error looking up session. Error: {}", error);
+            None
+        }
+    }
+}
+
+// The user's expired sessions are cleared out on the way.
+pub fn create_session(user_id: i32, token: &str, ttl_days: i32, connection: &Connection) -> bool {
+    let result = connection.transaction().and_then(|transaction| {
+        transaction.execute(
+            "DELETE FROM sessions WHERE user_id = $1 AND expires_at <= now()",
+            &[&user_id],
+        )?;
+        transaction.execute(
+            "INSERT INTO sessions (token_hash, user_id, created_at, expires_at) VALUES ($1, $2, now(), now() + make_interval(days => $3))",
+            &[&session_hash(token), &user_id, &ttl_days],
+        )?;
+        transaction.commit()
+    });
+
+    match result {
+        Ok(_) => true,
+        Err(error) => {
+            error!(
+                "error creating session for user_id={}. Error: {}",
+                user_id, error
+            );
+            false
+        }
+    }
+}
+
+// Whether the session existed, it can't be used from now on.
+pub fn delete_session(token_hash: &str, connection: &Connection) -> Result<bool, postgres::Error> {
+    let deleted = connection
+        .prepare_cached("DELETE FROM sessions WHERE token_hash = $1")?
+        .execute(&[&token_hash])?;
+    Ok(deleted > 0)
+}
+
+// Signs the user out everywhere, e.g. after a token leaked. Returns how many sessions ended.
+pub fn delete_sessions(user_id: i32, connection: &Connection) -> Result<u64, postgres::Error> {
+    connection
+        .prepare_cached("DELETE FROM sessions WHERE user_id = $1")?
+        .execute(&[&user_id])
+}
+
+// Expired tokens are left in place until the next sign in replaces them, but never handed out.
+// Neither are tokens that don't open with the configured key.
+pub fn get_anilist_token(user_id: i32, connection: &Connection) -> Option<String> {
+    let key = config::settings().token_key?;
+    let stmt = connection
+        .prepare_cached(
+            "SELECT sealed_token FROM anilist_tokens WHERE user_id = $1 AND expires_at > now()",
+        )
+        .unwrap();
+
+    match stmt.query(&[&user_id]) {
+        Ok(rows) => rows.iter().next().and_then(|row| {
+            let sealed: Vec<u8> = row.get(0);
+            let token = sealed::open(&key, &sealed);
+            if token.is_none() {
+                warn!(
+                    "AniList token of user_id={} doesn't open with TOKEN_ENCRYPTION_KEY",
+                    user_id
+                );
+            }
+            token
+        }),
+        Err(error) => {
+            error!(
+                "error getting AniList token for user_id={}. Error: {}",
+                user_id, error
+            );
+            None
+        }
+    }
+}
+
+pub fn save_anilist_token(
+    user_id: i32,
+    access_token: &str,
+    expires_at: DateTime<Utc>,
+    connection: &Connection,
+) -> bool {
+    let sealed = match config::settings()
+        .token_key
+        .and_then(|key| sealed::seal(&key, access_token))
+    {
+        Some(sealed) => sealed,
+        None => {
+            error!(
+                "error sealing AniList token for user_id={}, TOKEN_ENCRYPTION_KEY is unset",
+                user_id
+            );
+            return false;
+        }
+    };
+    let stmt = connection.prepare_cached("INSERT INTO anilist_tokens (user_id, sealed_token, expires_at, updated_at) VALUES ($1, $2, $3, now()) ON CONFLICT (user_id) DO UPDATE SET sealed_token = excluded.sealed_token, expires_at = excluded.expires_at, updated_at = excluded.updated_at").unwrap();
+
+    match stmt.execute(&[&user_id, &sealed, &expires_at]) {
+        Ok(_) => true,
+        Err(error) => {
+            error!(
+                "error saving AniList token for user_id={}. Error: {}",
+                user_id, error
+            );
+            false
+        }
+    }
+}
+
+pub fn remove_anilist_token(user_id: i32, connection: &Connection) {
+    let stmt = connection
+        .prepare_cached("DELETE FROM anilist_tokens WHERE user_id = $1")
+        .unwrap();
+
+    if let Err(error) = stmt.execute(&[&user_id]) {
+        error!(
+            "error removing AniList token for user_id={}. Error: {}",
+            user_id, error
+        );
+    }
+}
+
+pub fn add_subscription(
+    subscriber_id: i32,
+    target_id: i32,
+    connection: &Connection,
+) -> Result<(), postgres::Error> {
+    connection
+        .prepare_cached("INSERT INTO subscriptions (subscriber_id, target_id) VALUES ($1, $2) ON CONFLICT (subscriber_id, target_id) DO NOTHING")?
+        .execute(&[&subscriber_id, &target_id])?;
+    Ok(())
+}
+
+// Whether there was a subscription to remove.
+pub fn remove_subscription(
+    subscriber_id: i32,
+    target_id: i32,
+    connection: &Connection,
+) -> Result<bool, postgres::Error> {
+    let deleted = connection
+        .prepare_cached("DELETE FROM subscriptions WHERE subscriber_id = $1 AND target_id = $2")?
+        .execute(&[&subscriber_id, &target_id])?;
+    Ok(deleted > 0)
+}
+
+pub fn get_subscriptions(subscriber_id: i32, connection: &Connection) -> Vec<models::User> {
+    let stmt = connection.prepare_cached("SELECT u.user_id, u.name, u.avatar_s3, u.avatar_anilist FROM subscriptions AS s INNER JOIN users AS u ON s.target_id = u.user_id WHERE s.subscriber_id = $1").unwrap();
+
+    query_users(&stmt, subscriber_id)
+}
+
+pub fn get_subscribers(target_id: i32, connection: &Connection) -> Vec<models::User> {
+    let stmt = connection.prepare_cached("SELECT u.user_id, u.name, u.avatar_s3, u.avatar_anilist FROM subscriptions AS s INNER JOIN users AS u ON s.subscriber_id = u.user_id WHERE s.target_id = $1").unwrap();
+
+    query_users(&stmt, target_id)
+}
+
+fn query_users(stmt: &postgres::stmt::Statement, id: i32) -> Vec<models::User> {
+    match stmt.query(&[&id]) {
+        Ok(rows) => rows
+            .iter()
+            .map(|row| models::User {
+                user_id: row.get(0),
+                name: row.get(1),
+                avatar_s3: row.get(2),
+                avatar_anilist: row.get(3),
+            })
+            .collect(),
+        Err(error) => {
+            error!("error getting users for user_id={}. Error: {}", id, error);
+            Vec::new()
+        }
+    }
+}
+
+// Failed uploads are queued for uploads::start_retrier, which downloads the image from url again.
+fn upload_image(
+    store: &dyn storage::ImageStore,
+    image_type: ImageTypes,
+    id: i32,
+    content: Vec<u8>,
+    format: images::Format,
+    url: &str,
+) {
+    if let Err(error) = store_image(store, &image_type, id, content, format) {
+        error!(
+            "error uploading {}. Error: {}",
+            image_key(&image_type, id, format.stored().ext()),
+            error
+        );
+        queue_upload_retry(&image_type, id, url, &error);
+    }
+}
+
+// Stores the image under the key of the format it is kept in, converting it to WebP when needed.
+// When that fails the original is stored under the key with its own type, browsers go by the type.
+fn store_image(
+    store: &dyn storage::ImageStore,
+    image_type: &ImageTypes,
+    id: i32,
+    content: Vec<u8>,
+    format: images::Format,
+) -> Result<(), String> {
+    let stored = format.stored();
+    let key = image_key(image_type, id, stored.ext());
+    let (content, mime) = if stored == format {
+        (content, format.mime())
+    } else {
+        match images::to_webp(&content) {
+            Ok(webp) => (webp, stored.mime()),
+            Err(error) => {
+                error!("error converting {} to WebP. Error: {}", key, error);
+                (content, format.mime())
+            }
+        }
+    };
+    store.put(&key, content, mime)
+}
+
+// Uploads run on their own threads, which only need a connection when one fails.
+fn queue_upload_retry(image_type: &ImageTypes, id: i32, url: &str, error: &str) {
+    let connection = match connect(config::settings()) {
+        Ok(connection) => connection,
+        Err(connect_error) => {
+            error!(
+                "error connecting to queue a retry of the {} image for id={}, it is fetched again once it is needed. Error: {}",
+                image_type.name(),
+                id,
+                connect_error
+            );
+            return;
+        }
+    };
+    let stmt = connection.prepare_cached("INSERT INTO pending_uploads (image_type, id, source_url, last_error) VALUES ($1, $2, $3, $4) ON CONFLICT (image_type, id) DO UPDATE SET source_url = excluded.source_url, last_error = excluded.last_error").unwrap();
+
+    if let Err(error) = stmt.execute(&[&image_type.name(), &id, &url, &error]) {
+        error!(
+            "error queueing a retry of the {} image for id={}. Error: {}",
+            image_type.name(),
+            id,
+            error
+        );
+    }
+}
+
+// Claims up to limit uploads that are due, for lease_secs so other instances leave them alone.
+pub fn claim_pending_uploads(
+    limit: i64,
+    lease_secs: i64,
+    connection: &Connection,
+) -> Vec<models::PendingUpload> {
+    let stmt = connection.prepare_cached("UPDATE pending_uploads AS p SET next_attempt_at = now() + make_interval(secs => $2) FROM (SELECT image_type, id FROM pending_uploads WHERE next_attempt_at <= now() ORDER BY next_attempt_at LIMIT $1 FOR UPDATE SKIP LOCKED) AS due WHERE p.image_type = due.image_type AND p.id = due.id RETURNING p.image_type, p.id, p.source_url, p.attempts").unwrap();
+
+    match stmt.query(&[&limit, &(lease_secs as f64)]) {
+        Ok(rows) => rows
+            .iter()
+            .map(|row| models::PendingUpload {
+                image_type: row.get(0),
+                id: row.get(1),
+                source_url: row.get(2),
+                attempts: row.get(3),
+            })
+            .collect(),
+        Err(error) => {
+            error!("error claiming pending uploads. Error: {}", error);
+            Vec::new()
+        }
+    }
+}
+
+// Downloads the image from AniList again and stores it. Images of anime and users that are gone
+// count as stored, and so do images AniList replaced since, the sync that saw the new image
+// uploaded it or queued its own retry.
+pub fn retry_upload(
+    upload: &models::PendingUpload,
+    store: &storage::Images,
+    connection: &Connection,
+) -> Result<(), String> {
+    let image_type = ImageTypes::from_name(&upload.image_type)
+        .ok_or_else(|| format!("unknown image type {}", upload.image_type))?;
+    let query = match image_type {
+        ImageTypes::User => "SELECT avatar_anilist FROM users WHERE user_id = $1",
+        _ => "SELECT cover_anilist FROM anime WHERE anime_id = $1",
+    };
+    let current: Option<String> = connection
+        .prepare_cached(query)
+        .and_then(|stmt| stmt.query(&[&upload.id]))
+        .map_err(|error| error.to_string())?
+        .iter()
+        .next()
+        .map(|row| row.get(0));
+    // Only the regular cover's URL is stored, extra large covers go by whether the anime is left.
+    let outdated = match (&image_type, &current) {
+        (_, None) => true,
+        (ImageTypes::AnimeXl, Some(_)) => false,
+        (_, Some(current)) => *current != upload.source_url,
+    };
+    if outdated {
+        return Ok(());
+    }
+
+    let id = upload.id;
+    let (content, format) =
+        download_image(&upload.source_url).map_err(|error| error.to_string())?;
+    match image_type {
+        ImageTypes::AnimeThumb => {
+            let thumb = images::thumbnail(&content)?;
+            store_image(store.as_ref(), &image_type, id, thumb, images::Format::WebP)
+        }
+        _ => store_image(store.as_ref(), &image_type, id, content, format),
+    }
+}
+
+pub fn finish_pending_upload(image_type: &str, id: i32, connection: &Connection) {
+    let stmt = connection
+        .prepare_cached("DELETE FROM pending_uploads WHERE image_type = $1 AND id = $2")
+        .unwrap();
+
+    if let Err(error) = stmt.execute(&[&image_type, &id]) {
+        error!(
+            "error removing the pending {} upload for id={}. Error: {}",
+            image_type, id, error
+        );
+    }
+}
+
+pub fn reschedule_pending_upload(
+    image_type: &str,
+    id: i32,
+    error: &str,
+    delay_secs: i64,
+    connection: &Connection,
+) {
+    let stmt = connection.prepare_cached("UPDATE pending_uploads SET attempts = attempts + 1, last_error = $3, next_attempt_at = now() + make_interval(secs => $4) WHERE image_type = $1 AND id = $2").unwrap();
+
+    if let Err(db_error) = stmt.execute(&[&image_type, &id, &error, &(delay_secs as f64)]) {
+        error!(
+            "error rescheduling the pending {} upload for id={}. Error: {}",
+            image_type, id, db_error
+        );
+    }
+}
+
+fn delete_image(store: &dyn storage::ImageStore, image_type: ImageTypes, id: i32, ext: &str) {
+    let key = image_key(&image_type, id, ext);
+    if let Err(error) = store.delete(&key) {
+        error!("error deleting {}. Error: {}", key, error);
+    }
+}
+
+fn image_key(image_type: &ImageTypes, id: i32, ext: &str) -> String {
+    match image_type {
+        ImageTypes::Anime => format!("assets/images/anime_{}.{}", id, ext),
+        ImageTypes::AnimeXl => format!("assets/images/anime_xl_{}.{}", id, ext),
+        ImageTypes::AnimeThumb => format!("assets/images/anime_{}_thumb.{}", id, ext),
+        ImageTypes::User => format!("assets/images/user_{}.{}", id, ext),
+    }
+}
+
+// Anime without a cover of their own have nothing stored.
+fn delete_cover(
+    store: &dyn storage::ImageStore,
+    anime_id: i32,
+    cover_s3: &str,
+    cover_xl_s3: &Option<String>,
+) {
+    let variants = std::iter::once((ImageTypes::Anime, cover_s3)).chain(
+        cover_xl_s3
+            .iter()
+            .map(|url| (ImageTypes::AnimeXl, url.as_str())),
+    );
+    for (image_type, cover) in variants {
+        let url = cover.split('?').next().unwrap_or("");
+        if let Some(ext) = url.rsplit('.').next().filter(|_| !url.is_empty()) {
+            delete_image(store, image_type, anime_id, ext);
+        }
+    }
+    if !cover_s3.is_empty() {
+        delete_image(store, ImageTypes::AnimeThumb, anime_id, "webp");
+    }
+}
+
+// The deadline has passed, but the cleanup after it still has to run.
+fn reset_statement_timeout(connection: &Connection) {
+    if let Err(error) = connection.execute("SET statement_timeout = DEFAULT", &[]) {
+        error!("error resetting statement_timeout. Error: {}", error);
+    }
+}
+
+fn construct_date(date: &anilist_models::Date) -> Option<NaiveDate> {
+    match date.year {
+        Some(year) => match date.month {
+            Some(month) => match date.day {
+                Some(day) => NaiveDate::from_ymd_opt(year, month as u32, day as u32),
+                None => None,
+            },
+            None => None,
+        },
+        None => None,
+    }
+}
+
+// The year or month of a date AniList has no day for.
+fn construct_partial_date(date: &anilist_models::Date) -> Option<dates::PartialDate> {
+    dates::PartialDate::from_parts(date.year, date.month, date.day)
+}
+
+// Partial dates are stored as they are written.
+fn partial_date(value: Option<String>) -> Option<dates::PartialDate> {
+    value.and_then(|value| dates::PartialDate::parse(&value))
+}
+
+// The image and its format. The magic bytes decide, the response's Content-Type is only trusted
+// when they aren't recognized.
+pub fn download_image(url: &str) -> Result<(Vec<u8>, images::Format), images::ImageError> {
+    let result = fetch_image(url);
+    if let Err(error) = &result {
+        error!("error downloading image={}. Error: {}", url, error);
+    }
+    result
+}
+
+fn fetch_image(url: &str) -> Result<(Vec<u8>, images::Format), images::ImageError> {
+    let download_error = |error: String| images::ImageError::Download(url.to_owned(), error);
+    let mut response = anilist_query::client()
+        .get(url)
+        .send()
+        .and_then(|resp| resp.error_for_status())
+        .map_err(|error| download_error(error.to_string()))?;
+    let declared = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(images::Format::from_mime);
+
+    let mut content = Vec::new();
+    response
+        .read_to_end(&mut content)
+        .map_err(|error| download_error(error.to_string()))?;
+
+    match images::Format::detect(&content).or(declared) {
+        Some(format) => Ok((content, format)),
+        None => Err(images::ImageError::Unsupported(url.to_owned())),
+    }
+}
+
+// The format an image is stored in going by its URL, for when it wasn't downloaded. URLs that
+// don't name one are taken for the JPEG and PNG images AniList serves.
+fn url_format(url: &str) -> images::Format {
+    match images::Format::from_url(url) {
+        Ok(format) => format.stored(),
+        Err(error) => {
+            warn!("{}, assuming it is stored as WebP", error);
+            images::Format::WebP
+        }
+    }
+}
+
+enum ImageTypes {
+    Anime,
+    AnimeXl,
+    AnimeThumb,
+    User,
+}
+
+impl ImageTypes {
+    // As stored in pending_uploads.
+    fn name(&self) -> &'static str {
+        match self {
+            ImageTypes::Anime => "anime",
+            ImageTypes::AnimeXl => "anime_xl",
+            ImageTypes::AnimeThumb => "anime_thumb",
+            ImageTypes::User => "user",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<ImageTypes> {
+        match name {
+            "anime" => Some(ImageTypes::Anime),
+            "anime_xl" => Some(ImageTypes::AnimeXl),
+            "anime_thumb" => Some(ImageTypes::AnimeThumb),
+            "user" => Some(ImageTypes::User),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{ImageStore, MemoryStore};
+
+    #[test]
+    fn stores_images_kept_as_they_are_under_their_own_key() {
+        let store = MemoryStore::default();
+        store_image(
+            &store,
+            &ImageTypes::Anime,
+            1,
+            b"webp".to_vec(),
+            images::Format::WebP,
+        )
+        .unwrap();
+
+        let stored = store.images.lock().unwrap();
+        assert_eq!(
+            stored.get("assets/images/anime_1.webp"),
+            Some(&(b"webp".to_vec(), "image/webp".to_owned()))
+        );
+    }
+
+    #[test]
+    fn stores_the_original_when_it_cant_be_converted() {
+        let store = MemoryStore::default();
+        store_image(
+            &store,
+            &ImageTypes::User,
+            2,
+            b"not a png".to_vec(),
+            images::Format::Png,
+        )
+        .unwrap();
+
+        let stored = store.images.lock().unwrap();
+        assert_eq!(
+            stored.get("assets/images/user_2.webp"),
+            Some(&(b"not a png".to_vec(), "image/png".to_owned()))
+        );
+    }
+
+    #[test]
+    fn deletes_every_copy_of_a_cover() {
+        let store = MemoryStore::default();
+        for key in &[
+            "assets/images/anime_3.webp",
+            "assets/images/anime_xl_3.gif",
+            "assets/images/anime_3_thumb.webp",
+            "assets/images/anime_4.webp",
+        ] {
+            store.put(key, Vec::new(), "image/webp").unwrap();
+        }
+
+        delete_cover(
+            &store,
+            3,
+            "https://images.example/assets/images/anime_3.webp?v=2",
+            &Some("https://images.example/assets/images/anime_xl_3.gif?v=2".to_owned()),
+        );
+
+        let stored = store.images.lock().unwrap();
+        assert_eq!(
+            stored.keys().collect::<Vec<_>>(),
+            vec!["assets/images/anime_4.webp"]
+        );
+    }
+
+    #[test]
+    fn covers_that_were_never_stored_delete_nothing() {
+        let store = MemoryStore::default();
+        store
+            .put("assets/images/anime_5_thumb.webp", Vec::new(), "image/webp")
+            .unwrap();
+
+        delete_cover(&store, 5, "", &None);
+
+        assert_eq!(store.images.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn short_lists_may_lose_a_few_entries() {
+        assert!(!deletes_too_many(1, 2, 5, 50));
+        assert!(!deletes_too_many(5, 5, 5, 50));
+        assert!(deletes_too_many(6, 10, 5, 50));
+        assert!(!deletes_too_many(6, 20, 5, 50));
+        assert!(!deletes_too_many(0, 0, 0, 50));
+    }
+
+    fn list_item(end_day: Option<NaiveDate>, private: bool) -> models::ListItem {
+        models::ListItem {
+            user_id: 1,
+            anime_id: 2,
+            user_title: Some("Hibike! Euphonium".to_owned()),
+            start_day: None,
+            end_day,
+            start_partial: None,
+            end_partial: None,
+            score: None,
+            status: Some("COMPLETED".to_owned()),
+            progress: None,
+            repeat: None,
+            notes: None,
+            custom_lists: Vec::new(),
+            private,
+        }
+    }
+
+    #[test]
+    fn private_entries_raise_no_change_events() {
+        let finished = NaiveDate::from_ymd_opt(2024, 1, 31);
+        let watching = list_item(None, true);
+        assert!(change_event(Some(&watching), &list_item(finished, true)).is_none());
+        assert!(change_event(None, &list_item(finished, true)).is_none());
+        assert!(change_event(Some(&list_item(None, false)), &list_item(finished, true)).is_none());
+    }
+
+    #[test]
+    fn entries_made_public_are_new_to_subscribers() {
+        let finished = NaiveDate::from_ymd_opt(2024, 1, 31);
+        let event = change_event(
+            Some(&list_item(finished, true)),
+            &list_item(finished, false),
+        );
+        assert_eq!(
+            event.map(|event| event.kind),
+            Some(models::ChangeKind::Completed)
+        );
+    }
+
+    #[test]
+    fn sessions_are_stored_by_their_hash() {
+        assert_eq!(
+            session_hash("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn sparse_fields_read_only_their_columns() {
+        let fields = Fieldset::parse("display_title,cover").unwrap();
+        let columns = entry_columns(Some(&fields), SORT_COLUMNS);
+        for read in &[
+            "a.anime_id",
+            "l.user_title",
+            "a.romaji",
+            "a.cover_s3",
+            "l.score",
+        ] {
+            assert!(columns.contains(read), "{} isn't read", read);
+        }
+        assert!(columns.contains("''::text AS description"));
+        assert!(columns.contains("'{}'::text[] AS genres"));
+        assert_eq!(
+            columns.split(", ").count(),
+            entry_columns(None, &[]).split(", ").count()
+        );
+        assert!(!entry_columns(None, &[]).contains(" AS "));
+    }
 }
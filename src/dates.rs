@@ -0,0 +1,232 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// How days are written in responses. Models go through iso so the wire format doesn't hang on
+// chrono's serde defaults, list routes can ask for milliseconds since the Unix epoch instead. Dates
+// AniList only knows the year or month of are PartialDates, written as ISO-8601 with reduced
+// precision in either representation.
+
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::fmt;
+
+const ISO_FORMAT: &str = "%Y-%m-%d";
+
+// Response fields holding a day, in both namings.
+const DAY_FIELDS: &[&str] = &["startDay", "endDay", "start_day", "end_day"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DateRepr {
+    // 2023-01-31
+    Iso,
+    // 1675123200000, midnight UTC of the day.
+    EpochMillis,
+}
+
+impl DateRepr {
+    pub fn parse(value: &str) -> Option<DateRepr> {
+        match value {
+            "iso" => Some(DateRepr::Iso),
+            "epoch_ms" => Some(DateRepr::EpochMillis),
+            _ => None,
+        }
+    }
+}
+
+impl Default for DateRepr {
+    fn default() -> DateRepr {
+        DateRepr::Iso
+    }
+}
+
+// For Option<NaiveDate> fields, #[serde(with = "dates::iso")].
+pub mod iso {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        day: &Option<NaiveDate>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        day.map(|day| day.format(ISO_FORMAT).to_string())
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<NaiveDate>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(day) => NaiveDate::parse_from_str(&day, ISO_FORMAT)
+                .map(Some)
+                .map_err(serde::de::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
+
+// 2023 or 2023-05. Full dates are NaiveDates, a PartialDate always lacks the day.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PartialDate {
+    pub year: i32,
+    pub month: Option<u32>,
+}
+
+async_graphql::scalar!(PartialDate);
+
+impl PartialDate {
+    // The date AniList gave when it has a year but no day. None for full dates, unset ones and
+    // parts no real date has.
+    pub fn from_parts(
+        year: Option<i32>,
+        month: Option<i32>,
+        day: Option<i32>,
+    ) -> Option<PartialDate> {
+        if day.is_some() {
+            return None;
+        }
+        let year = year?;
+        let month = match month {
+            Some(month) if month < 1 || month > 12 => return None,
+            Some(month) => Some(month as u32),
+            None => None,
+        };
+        NaiveDate::from_ymd_opt(year, month.unwrap_or(1), 1)?;
+        Some(PartialDate { year, month })
+    }
+
+    pub fn parse(value: &str) -> Option<PartialDate> {
+        let full = match value.len() {
+            4 => format!("{}-01-01", value),
+            7 => format!("{}-01", value),
+            _ => return None,
+        };
+        let day = NaiveDate::parse_from_str(&full, ISO_FORMAT).ok()?;
+        Some(PartialDate {
+            year: day.year(),
+            month: if value.len() == 7 {
+                Some(day.month())
+            } else {
+                None
+            },
+        })
+    }
+}
+
+impl fmt::Display for PartialDate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.month {
+            Some(month) => write!(f, "{:04}-{:02}", self.year, month),
+            None => write!(f, "{:04}", self.year),
+        }
+    }
+}
+
+impl Serialize for PartialDate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PartialDate {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<PartialDate, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        PartialDate::parse(&value)
+            .ok_or_else(|| serde::de::Error::custom(format!("not a year or month: {}", value)))
+    }
+}
+
+// The response with its days in the asked for representation. ISO days are what the models
+// serialize to already, so only epoch_ms goes through an intermediate JSON value.
+pub struct Represented<T> {
+    value: T,
+    repr: DateRepr,
+}
+
+pub fn represent<T: Serialize>(value: T, repr: DateRepr) -> Represented<T> {
+    Represented { value, repr }
+}
+
+impl<T: Serialize> Serialize for Represented<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.repr {
+            DateRepr::Iso => self.value.serialize(serializer),
+            DateRepr::EpochMillis => serde_json::to_value(&self.value)
+                .map_err(serde::ser::Error::custom)
+                .map(epoch_millis)?
+                .serialize(serializer),
+        }
+    }
+}
+
+fn epoch_millis(value: Value) -> Value {
+    match value {
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| match value {
+                    Value::String(day) if DAY_FIELDS.contains(&key.as_str()) => {
+                        (key, day_millis(day))
+                    }
+                    value => (key, epoch_millis(value)),
+                })
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(epoch_millis).collect()),
+        value => value,
+    }
+}
+
+fn day_millis(day: String) -> Value {
+    match NaiveDate::parse_from_str(&day, ISO_FORMAT) {
+        Ok(day) => {
+            Value::from(DateTime::<Utc>::from_utc(day.and_hms(0, 0, 0), Utc).timestamp_millis())
+        }
+        Err(_) => Value::String(day),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_dates_keep_their_precision() {
+        let year = PartialDate::from_parts(Some(2023), None, None).unwrap();
+        let month = PartialDate::from_parts(Some(2023), Some(5), None).unwrap();
+        assert_eq!(year.to_string(), "2023");
+        assert_eq!(month.to_string(), "2023-05");
+        assert_eq!(PartialDate::parse("2023"), Some(year));
+        assert_eq!(PartialDate::parse("2023-05"), Some(month));
+    }
+
+    #[test]
+    fn full_and_impossible_dates_are_not_partial() {
+        assert_eq!(PartialDate::from_parts(Some(2023), Some(5), Some(14)), None);
+        assert_eq!(PartialDate::from_parts(None, Some(5), None), None);
+        assert_eq!(PartialDate::from_parts(Some(2023), Some(13), None), None);
+        assert_eq!(PartialDate::from_parts(Some(2023), None, Some(14)), None);
+        assert_eq!(PartialDate::parse("2023-13"), None);
+        assert_eq!(PartialDate::parse("2023-05-14"), None);
+    }
+
+    #[test]
+    fn epoch_millis_leaves_partial_dates_as_strings() {
+        let value = serde_json::json!({
+            "list": [{
+                "startDay": "2023-01-31",
+                "startPartial": PartialDate::parse("2023-05"),
+            }],
+        });
+        assert_eq!(
+            epoch_millis(value),
+            serde_json::json!({
+                "list": [{"startDay": 1675123200000i64, "startPartial": "2023-05"}],
+            })
+        );
+    }
+}
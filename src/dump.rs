@@ -0,0 +1,223 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Database export and import for moving an instance between Postgres servers. Images are not
+// included, they are re-uploaded by the next sync of each user.
+
+use crate::config::Settings;
+use crate::database;
+use chrono::{DateTime, Utc};
+use log::info;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+// Bump whenever the archive layout changes so old dumps are rejected instead of misread.
+const FORMAT_VERSION: u32 = 1;
+
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug)]
+pub enum DumpError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Database(postgres::Error),
+    MissingFile(String),
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for DumpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DumpError::Io(error) => write!(f, "io error: {}", error),
+            DumpError::Json(error) => write!(f, "invalid dump contents: {}", error),
+            DumpError::Database(error) => write!(f, "database error: {}", error),
+            DumpError::MissingFile(name) => write!(f, "dump is missing {}", name),
+            DumpError::UnsupportedVersion(version) => write!(
+                f,
+                "dump format version {} is not supported, expected {}",
+                version, FORMAT_VERSION
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for DumpError {
+    fn from(error: io::Error) -> Self {
+        DumpError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for DumpError {
+    fn from(error: serde_json::Error) -> Self {
+        DumpError::Json(error)
+    }
+}
+
+impl From<postgres::Error> for DumpError {
+    fn from(error: postgres::Error) -> Self {
+        DumpError::Database(error)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    format_version: u32,
+    exported_at: DateTime<Utc>,
+    tables: Vec<TableSummary>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TableSummary {
+    name: String,
+    rows: i64,
+}
+
+// Exported tables in an order that satisfies their foreign keys on import. Sessions are left out
+// on purpose, they are credentials and not data worth migrating.
+const TABLES: &[&str] = &[
+    "users",
+    "anime",
+    "lists",
+    "list_history",
+    "activities",
+    "list_tombstones",
+    "hidden_entries",
+    "subscriptions",
+    "user_preferences",
+];
+
+pub fn export(path: &str, settings: &Settings) -> Result<(), DumpError> {
+    let connection = database::establish_connection(settings);
+
+    let encoder = zstd::stream::Encoder::new(File::create(path)?, ZSTD_LEVEL)?.auto_finish();
+    let mut archive = tar::Builder::new(encoder);
+
+    let mut tables = Vec::with_capacity(TABLES.len());
+    for table in TABLES {
+        // Every row becomes a JSON object keyed by column name, so new columns are picked up
+        // without touching this module.
+        let rows = connection.query(
+            &format!(
+                "SELECT COUNT(*), COALESCE(json_agg(t), '[]')::text FROM {} AS t",
+                table
+            ),
+            &[],
+        )?;
+        let row = rows.get(0);
+        let count: i64 = row.get(0);
+        let content: String = row.get(1);
+
+        append_file(&mut archive, &format!("{}.json", table), content.as_bytes())?;
+        tables.push(TableSummary {
+            name: table.to_string(),
+            rows: count,
+        });
+    }
+
+    let manifest = Manifest {
+        format_version: FORMAT_VERSION,
+        exported_at: Utc::now(),
+        tables,
+    };
+    append_file(
+        &mut archive,
+        "manifest.json",
+        &serde_json::to_vec(&manifest)?,
+    )?;
+    archive.into_inner()?.flush()?;
+
+    for table in manifest.tables.iter() {
+        info!(
+            "exported {} rows from {} to {}",
+            table.rows, table.name, path
+        );
+    }
+    Ok(())
+}
+
+pub fn import(path: &str, settings: &Settings) -> Result<(), DumpError> {
+    let mut files = read_archive(path)?;
+
+    let manifest_content = files
+        .remove("manifest.json")
+        .ok_or(DumpError::MissingFile("manifest.json".to_owned()))?;
+    let manifest: Manifest = serde_json::from_slice(&manifest_content)?;
+    if manifest.format_version != FORMAT_VERSION {
+        return Err(DumpError::UnsupportedVersion(manifest.format_version));
+    }
+
+    let connection = database::establish_connection(settings);
+    let transaction = connection.transaction()?;
+    for table in TABLES {
+        // Dumps from before a table was exported simply leave it empty.
+        if !manifest.tables.iter().any(|summary| summary.name == *table) {
+            info!("{} has no {} table, skipping it", path, table);
+            continue;
+        }
+
+        let name = format!("{}.json", table);
+        let content = files.remove(&name).ok_or(DumpError::MissingFile(name))?;
+        let content = String::from_utf8_lossy(&content).into_owned();
+
+        let imported = transaction.execute(
+            &format!(
+                "INSERT INTO {0} SELECT * FROM json_populate_recordset(NULL::{0}, $1::text::json) \
+                 ON CONFLICT DO NOTHING",
+                table
+            ),
+            &[&content],
+        )?;
+        info!("imported {} rows into {} from {}", imported, table, path);
+    }
+    // Imported history keeps its ids, so new rows have to be numbered after them.
+    transaction.execute(
+        "SELECT setval(pg_get_serial_sequence('list_history', 'history_id'), COALESCE(max(history_id), 0) + 1, false) FROM list_history",
+        &[],
+    )?;
+    transaction.commit()?;
+
+    info!(
+        "import of dump exported at {} finished",
+        manifest.exported_at
+    );
+    Ok(())
+}
+
+fn append_file<W: Write>(
+    archive: &mut tar::Builder<W>,
+    name: &str,
+    content: &[u8],
+) -> Result<(), DumpError> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    header.set_cksum();
+
+    archive.append_data(&mut header, name, content)?;
+    Ok(())
+}
+
+fn read_archive(path: &str) -> Result<HashMap<String, Vec<u8>>, DumpError> {
+    let decoder = zstd::stream::Decoder::new(File::open(path)?)?;
+    let mut archive = tar::Archive::new(decoder);
+
+    let mut files = HashMap::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+        files.insert(name, content);
+    }
+
+    Ok(files)
+}
@@ -0,0 +1,208 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Errors routes answer with, rendered as RFC 7807 problem details. The catchers give requests that
+// never reach a handler, like unknown paths or failed guards, the same shape.
+
+use crate::anilist_query::AnilistError;
+use crate::{request_id, response};
+use log::error;
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{Responder, Response};
+use rocket::{catch, catchers, Catcher};
+use serde_derive::Serialize;
+use std::io::Cursor;
+use utoipa::ToSchema;
+
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    Unprocessable(String),
+    // With the seconds to wait for Retry-After, when known.
+    RateLimited(String, Option<u64>),
+    // AniList failed, refused or couldn't be reached.
+    Upstream(AnilistError),
+    Database(postgres::Error),
+    Internal(String),
+}
+
+// Body of every error response. Like every response model its fields are camelCase, unversioned
+// routes get them in snake_case.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Problem {
+    // Always about:blank, code tells the errors apart.
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    // Reason phrase of the status.
+    pub title: String,
+    pub status: u16,
+    // Stable identifier to match on, like not_found or upstream_unavailable.
+    pub code: String,
+    // What went wrong with this request, for people.
+    pub detail: String,
+    // X-Request-Id of the request, quoted in the logs of everything it caused.
+    pub request_id: Option<String>,
+}
+
+impl AppError {
+    fn status(&self) -> Status {
+        match self {
+            AppError::BadRequest(_) => Status::BadRequest,
+            AppError::Unauthorized(_) => Status::Unauthorized,
+            AppError::Forbidden(_) => Status::Forbidden,
+            AppError::NotFound(_) => Status::NotFound,
+            AppError::Conflict(_) => Status::Conflict,
+            AppError::Unprocessable(_) => Status::UnprocessableEntity,
+            AppError::RateLimited(..) => Status::TooManyRequests,
+            AppError::Upstream(AnilistError::PrivateList) => Status::Forbidden,
+            AppError::Upstream(_) => Status::ServiceUnavailable,
+            AppError::Database(_) | AppError::Internal(_) => Status::InternalServerError,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::NotFound(_) => "not_found",
+            AppError::Conflict(_) => "conflict",
+            AppError::Unprocessable(_) => "unprocessable",
+            AppError::RateLimited(..) => "rate_limited",
+            AppError::Upstream(AnilistError::PrivateList) => "private_list",
+            AppError::Upstream(_) => "upstream_unavailable",
+            AppError::Database(_) => "database_error",
+            AppError::Internal(_) => "internal_error",
+        }
+    }
+
+    fn detail(&self) -> String {
+        match self {
+            AppError::BadRequest(detail)
+            | AppError::Unauthorized(detail)
+            | AppError::Forbidden(detail)
+            | AppError::NotFound(detail)
+            | AppError::Conflict(detail)
+            | AppError::Unprocessable(detail)
+            | AppError::RateLimited(detail, _)
+            | AppError::Internal(detail) => detail.clone(),
+            AppError::Upstream(error) => error.to_string(),
+            // The query and its values stay in the logs.
+            AppError::Database(_) => "The database could not complete the request".to_owned(),
+        }
+    }
+}
+
+impl From<AnilistError> for AppError {
+    fn from(error: AnilistError) -> AppError {
+        AppError::Upstream(error)
+    }
+}
+
+impl From<reqwest::Error> for AppError {
+    fn from(error: reqwest::Error) -> AppError {
+        AppError::Upstream(AnilistError::Unreachable(error))
+    }
+}
+
+impl From<postgres::Error> for AppError {
+    fn from(error: postgres::Error) -> AppError {
+        AppError::Database(error)
+    }
+}
+
+impl<'r> Responder<'r> for AppError {
+    fn respond_to(self, request: &Request) -> rocket::response::Result<'r> {
+        if let AppError::Database(error) = &self {
+            error!(
+                "database error answering {}. Error: {}",
+                request.uri(),
+                error
+            );
+        }
+
+        let status = self.status();
+        let problem = Problem {
+            problem_type: "about:blank".to_owned(),
+            title: status.reason.to_owned(),
+            status: status.code,
+            code: self.code().to_owned(),
+            detail: self.detail(),
+            request_id: request
+                .headers()
+                .get_one(request_id::HEADER)
+                .map(str::to_owned),
+        };
+        // Same naming policy as every other body.
+        let body = if request.uri().path().starts_with("/v1/") {
+            serde_json::to_vec(&problem).unwrap()
+        } else {
+            response::legacy_json(&problem)
+        };
+
+        let mut builder = Response::build();
+        builder
+            .status(status)
+            .header(ContentType::new("application", "problem+json"));
+        match &self {
+            AppError::RateLimited(_, Some(retry_after))
+            | AppError::Upstream(AnilistError::CircuitOpen(retry_after)) => {
+                builder.raw_header("Retry-After", retry_after.to_string());
+            }
+            _ => (),
+        }
+        builder.sized_body(Cursor::new(body)).ok()
+    }
+}
+
+#[catch(400)]
+fn bad_request(_: &Request) -> AppError {
+    AppError::BadRequest("The request could not be understood".to_owned())
+}
+
+#[catch(401)]
+fn unauthorized(_: &Request) -> AppError {
+    AppError::Unauthorized("Missing or unknown token".to_owned())
+}
+
+#[catch(403)]
+fn forbidden(_: &Request) -> AppError {
+    AppError::Forbidden("The token does not allow this".to_owned())
+}
+
+#[catch(404)]
+fn not_found(_: &Request) -> AppError {
+    AppError::NotFound("Nothing here".to_owned())
+}
+
+#[catch(422)]
+fn unprocessable(_: &Request) -> AppError {
+    AppError::Unprocessable("The request body is invalid".to_owned())
+}
+
+#[catch(500)]
+fn internal_error(_: &Request) -> AppError {
+    AppError::Internal("Something went wrong".to_owned())
+}
+
+pub fn catchers() -> Vec<Catcher> {
+    catchers![
+        bad_request,
+        unauthorized,
+        forbidden,
+        not_found,
+        unprocessable,
+        internal_error
+    ]
+}
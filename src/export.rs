@@ -0,0 +1,282 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// List exports for importing into other trackers. Rows are read through a cursor on a connection
+// of the export's own and written out as they arrive, so the list is never held in memory. When
+// the database can't be reached the download fails instead of ending early.
+
+use crate::config::Settings;
+use crate::{database, dates, streaming};
+use chrono::NaiveDate;
+use log::error;
+use rocket::http::ContentType;
+use rocket_contrib::databases::postgres::Connection;
+use std::thread;
+
+// Rows fetched from the cursor at a time.
+const BATCH_SIZE: i32 = 500;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Csv,
+    // MyAnimeList's XML export, which MAL and most other trackers import.
+    Mal,
+}
+
+impl Format {
+    pub fn parse(value: &str) -> Option<Format> {
+        match value {
+            "csv" => Some(Format::Csv),
+            "mal" => Some(Format::Mal),
+            _ => None,
+        }
+    }
+}
+
+struct ExportRow {
+    anime_id: i32,
+    mal_id: Option<i32>,
+    title: Option<String>,
+    format: Option<String>,
+    episodes: Option<i32>,
+    status: Option<String>,
+    score: Option<i16>,
+    progress: Option<i32>,
+    repeat: Option<i32>,
+    start_day: Option<NaiveDate>,
+    end_day: Option<NaiveDate>,
+    start_partial: Option<dates::PartialDate>,
+    end_partial: Option<dates::PartialDate>,
+}
+
+pub fn export(
+    name: &str,
+    format: Format,
+    settings: &Settings,
+    connection: &Connection,
+) -> Result<streaming::Download, streaming::DownloadError> {
+    let user = database::get_user(name, connection).ok_or(streaming::DownloadError::NotFound)?;
+
+    let (content_type, file_name) = match format {
+        Format::Csv => (ContentType::CSV, format!("{}.csv", user.name)),
+        Format::Mal => (ContentType::XML, format!("{}.xml", user.name)),
+    };
+    let (sender, download) = streaming::download(content_type, file_name)?;
+
+    let settings = settings.clone();
+    thread::spawn(move || {
+        let connection = match database::connect(&settings) {
+            Ok(connection) => connection,
+            Err(error) => {
+                error!(
+                    "error connecting to export the list of user_id={}. Error: {}",
+                    user.user_id, error
+                );
+                return;
+            }
+        };
+        match write_rows(user.user_id, format, &sender, &connection) {
+            Ok(()) => {
+                let _ = sender.finish();
+            }
+            Err(error) => error!(
+                "error exporting list for user_id={}. Error: {}",
+                user.user_id, error
+            ),
+        }
+    });
+
+    Ok(download)
+}
+
+fn write_rows(
+    user_id: i32,
+    format: Format,
+    sender: &streaming::Sender,
+    connection: &Connection,
+) -> Result<(), String> {
+    let send = |chunk: String| {
+        sender
+            .send(chunk.into_bytes())
+            .map_err(|_| "client went away".to_owned())
+    };
+
+    send(header(format))?;
+
+    let transaction = connection
+        .transaction()
+        .map_err(|error| error.to_string())?;
+    transaction
+        .execute(
+            "DECLARE export_rows NO SCROLL CURSOR FOR SELECT a.anime_id, a.mal_id, \
+             COALESCE(l.user_title, a.romaji, a.english, a.native), a.format, a.episodes, \
+             l.status, l.score, l.progress, l.repeat, l.start_day, l.end_day, \
+             l.start_partial, l.end_partial \
+             FROM public_lists AS l INNER JOIN anime AS a ON l.anime_id = a.anime_id \
+             WHERE l.user_id = $1 ORDER BY a.anime_id",
+            &[&user_id],
+        )
+        .map_err(|error| error.to_string())?;
+
+    loop {
+        let rows = transaction
+            .query(&format!("FETCH {} FROM export_rows", BATCH_SIZE), &[])
+            .map_err(|error| error.to_string())?;
+        if rows.is_empty() {
+            break;
+        }
+
+        let mut chunk = String::new();
+        for row in rows.iter() {
+            let row = ExportRow {
+                anime_id: row.get(0),
+                mal_id: row.get(1),
+                title: row.get(2),
+                format: row.get(3),
+                episodes: row.get(4),
+                status: row.get(5),
+                score: row.get(6),
+                progress: row.get(7),
+                repeat: row.get(8),
+                start_day: row.get(9),
+                end_day: row.get(10),
+                start_partial: row
+                    .get::<_, Option<String>>(11)
+                    .and_then(|date| dates::PartialDate::parse(&date)),
+                end_partial: row
+                    .get::<_, Option<String>>(12)
+                    .and_then(|date| dates::PartialDate::parse(&date)),
+            };
+            chunk.push_str(&match format {
+                Format::Csv => csv_row(&row),
+                Format::Mal => mal_entry(&row),
+            });
+        }
+        send(chunk)?;
+    }
+
+    if format == Format::Mal {
+        send("</myanimelist>\n".to_owned())?;
+    }
+    Ok(())
+}
+
+fn header(format: Format) -> String {
+    match format {
+        Format::Csv => {
+            "anilist_id,mal_id,title,format,episodes,status,score,progress,rewatches,started,completed\n"
+                .to_owned()
+        }
+        Format::Mal => "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<myanimelist>\n\
+                        <myinfo><user_export_type>1</user_export_type></myinfo>\n"
+            .to_owned(),
+    }
+}
+
+fn csv_row(row: &ExportRow) -> String {
+    let fields = [
+        row.anime_id.to_string(),
+        optional(&row.mal_id),
+        csv_field(row.title.as_ref().map_or("", String::as_str)),
+        csv_field(row.format.as_ref().map_or("", String::as_str)),
+        optional(&row.episodes),
+        csv_field(row.status.as_ref().map_or("", String::as_str)),
+        optional(&row.score),
+        optional(&row.progress),
+        optional(&row.repeat),
+        csv_date(&row.start_day, &row.start_partial),
+        csv_date(&row.end_day, &row.end_partial),
+    ];
+    format!("{}\n", fields.join(","))
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+// Days as 2023-05-14, dates AniList has no day for as 2023-05 or 2023.
+fn csv_date(day: &Option<NaiveDate>, partial: &Option<dates::PartialDate>) -> String {
+    match (day, partial) {
+        (Some(day), _) => day.to_string(),
+        (None, partial) => optional(partial),
+    }
+}
+
+fn optional<T: ToString>(value: &Option<T>) -> String {
+    value.as_ref().map_or_else(String::new, ToString::to_string)
+}
+
+// MAL scores run from 1 to 10, AniList's raw scores from 1 to 100.
+fn mal_entry(row: &ExportRow) -> String {
+    let (status, rewatching) = match row.status.as_ref().map(String::as_str) {
+        Some("CURRENT") => ("Watching", 0),
+        Some("REPEATING") => ("Watching", 1),
+        Some("COMPLETED") => ("Completed", 0),
+        Some("PAUSED") => ("On-Hold", 0),
+        Some("DROPPED") => ("Dropped", 0),
+        _ => ("Plan to Watch", 0),
+    };
+    let score = row
+        .score
+        .map_or(0, |score| ((i32::from(score) + 5) / 10).min(10));
+    // MAL writes unknown parts of a date as zeros.
+    let mal_date =
+        |day: &Option<NaiveDate>, partial: &Option<dates::PartialDate>| match (day, partial) {
+            (Some(day), _) => day.format("%Y-%m-%d").to_string(),
+            (None, Some(partial)) => {
+                format!("{:04}-{:02}-00", partial.year, partial.month.unwrap_or(0))
+            }
+            (None, None) => "0000-00-00".to_owned(),
+        };
+
+    format!(
+        "<anime>\n\
+         <series_animedb_id>{}</series_animedb_id>\n\
+         <series_title><![CDATA[{}]]></series_title>\n\
+         <series_type>{}</series_type>\n\
+         <series_episodes>{}</series_episodes>\n\
+         <my_watched_episodes>{}</my_watched_episodes>\n\
+         <my_start_date>{}</my_start_date>\n\
+         <my_finish_date>{}</my_finish_date>\n\
+         <my_score>{}</my_score>\n\
+         <my_status>{}</my_status>\n\
+         <my_times_watched>{}</my_times_watched>\n\
+         <my_rewatching>{}</my_rewatching>\n\
+         <update_on_import>1</update_on_import>\n\
+         </anime>\n",
+        row.mal_id.unwrap_or(0),
+        row.title
+            .as_ref()
+            .map_or_else(String::new, |title| title.replace("]]>", "]]]]><![CDATA[>")),
+        mal_type(row.format.as_ref().map(String::as_str)),
+        row.episodes.unwrap_or(0),
+        row.progress.unwrap_or(0),
+        mal_date(&row.start_day, &row.start_partial),
+        mal_date(&row.end_day, &row.end_partial),
+        score,
+        status,
+        row.repeat.unwrap_or(0),
+        rewatching
+    )
+}
+
+fn mal_type(format: Option<&str>) -> &'static str {
+    match format {
+        Some("TV") | Some("TV_SHORT") => "TV",
+        Some("MOVIE") => "Movie",
+        Some("SPECIAL") => "Special",
+        Some("OVA") => "OVA",
+        Some("ONA") => "ONA",
+        Some("MUSIC") => "Music",
+        _ => "Unknown",
+    }
+}
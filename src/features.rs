@@ -0,0 +1,47 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Optional subsystems an instance can run without. Each is on unless its FEATURE_* variable is
+// false, 0 or off. Routes of a disabled feature aren't mounted and its background work doesn't
+// start, GET /status tells the frontend which ones it can use.
+
+use serde_derive::Serialize;
+use std::env;
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FeatureFlags {
+    // Signing in with AniList, FEATURE_OAUTH. Sessions from before it was turned off stay valid.
+    pub oauth: bool,
+    // Registering webhooks and delivering sync outcomes to them, FEATURE_WEBHOOKS.
+    pub webhooks: bool,
+    // Syncing due users without anyone pressing update, FEATURE_SCHEDULER. Still needs
+    // REFRESH_INTERVAL_SECS.
+    pub scheduler: bool,
+    // /graphql and /graphiql, FEATURE_GRAPHQL.
+    pub graphql: bool,
+}
+
+impl FeatureFlags {
+    pub fn from_env() -> FeatureFlags {
+        FeatureFlags {
+            oauth: enabled("FEATURE_OAUTH"),
+            webhooks: enabled("FEATURE_WEBHOOKS"),
+            scheduler: enabled("FEATURE_SCHEDULER"),
+            graphql: enabled("FEATURE_GRAPHQL"),
+        }
+    }
+}
+
+fn enabled(name: &str) -> bool {
+    match env::var(name) {
+        Ok(value) => !matches!(value.trim().to_lowercase().as_str(), "false" | "0" | "off"),
+        Err(_) => true,
+    }
+}
@@ -0,0 +1,123 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Atom feed of a user's most recent completions, for following someone in a feed reader.
+
+use crate::{database, markup, sitemap};
+use chrono::{DateTime, NaiveDate, Utc};
+use log::error;
+use rocket_contrib::databases::postgres::Connection;
+
+const FEED_ENTRIES: i64 = 50;
+
+struct FeedEntry {
+    anime_id: i32,
+    slug: Option<String>,
+    title: String,
+    description: String,
+    cover: String,
+    score: Option<i16>,
+    end_day: NaiveDate,
+}
+
+// None when the user isn't tracked.
+pub fn completions(name: &str, connection: &Connection) -> Option<String> {
+    let user = database::get_user(name, connection)?;
+    let stmt = connection.prepare_cached("SELECT a.anime_id, a.slug, COALESCE(l.user_title, a.romaji, a.english, a.native, ''), a.description, a.cover_s3, NULLIF(l.score, 0), l.end_day FROM public_lists AS l INNER JOIN anime AS a ON l.anime_id = a.anime_id WHERE l.user_id = $1 AND l.end_day IS NOT NULL ORDER BY l.end_day DESC, a.anime_id DESC LIMIT $2").unwrap();
+
+    let entries: Vec<FeedEntry> = match stmt.query(&[&user.user_id, &FEED_ENTRIES]) {
+        Ok(rows) => rows
+            .iter()
+            .map(|row| FeedEntry {
+                anime_id: row.get(0),
+                slug: row.get(1),
+                title: row.get(2),
+                description: row.get(3),
+                cover: row.get(4),
+                score: row.get(5),
+                end_day: row.get(6),
+            })
+            .collect(),
+        Err(error) => {
+            error!(
+                "error getting feed entries for user_id={}. Error: {}",
+                user.user_id, error
+            );
+            return None;
+        }
+    };
+
+    let base = sitemap::base_url();
+    let profile_url = format!("{}/users/{}", base, user.name);
+    // An empty feed still needs an updated time, the start of the epoch says nothing happened.
+    let updated = entries.first().map_or_else(
+        || day_time(NaiveDate::from_ymd(1970, 1, 1)),
+        |entry| day_time(entry.end_day),
+    );
+
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <feed xmlns=\"http://www.w3.org/2005/Atom\">\n\
+         <id>{url}</id>\n\
+         <title>{name}'s completed anime</title>\n\
+         <link href=\"{url}\"/>\n\
+         <updated>{updated}</updated>\n\
+         <author><name>{name}</name></author>\n\
+         <icon>{avatar}</icon>\n",
+        url = markup::escape(&profile_url),
+        name = markup::escape(&user.name),
+        updated = updated.to_rfc3339(),
+        avatar = markup::escape(&user.avatar_s3),
+    );
+
+    for entry in entries {
+        let anime_url = format!(
+            "{}/anime/{}",
+            base,
+            entry.slug.unwrap_or_else(|| entry.anime_id.to_string())
+        );
+        let summary = match entry.score {
+            Some(score) => format!("Completed {} with a score of {}/100", entry.title, score),
+            None => format!("Completed {}", entry.title),
+        };
+        // The description is already HTML, only the cover is added in front of it.
+        let content = format!(
+            "<img src=\"{}\" alt=\"\"/><p>{}</p>{}",
+            markup::escape(&entry.cover),
+            markup::escape(&summary),
+            entry.description
+        );
+
+        xml.push_str(&format!(
+            "<entry>\n\
+             <id>{profile}#anime-{anime_id}-{day}</id>\n\
+             <title>{title}</title>\n\
+             <link href=\"{link}\"/>\n\
+             <updated>{updated}</updated>\n\
+             <summary>{summary}</summary>\n\
+             <content type=\"html\">{content}</content>\n\
+             </entry>\n",
+            profile = markup::escape(&profile_url),
+            anime_id = entry.anime_id,
+            day = entry.end_day,
+            title = markup::escape(&entry.title),
+            link = markup::escape(&anime_url),
+            updated = day_time(entry.end_day).to_rfc3339(),
+            summary = markup::escape(&summary),
+            content = markup::escape(&content),
+        ));
+    }
+
+    xml.push_str("</feed>\n");
+    Some(xml)
+}
+
+// AniList only keeps the day an anime was completed.
+fn day_time(day: NaiveDate) -> DateTime<Utc> {
+    DateTime::from_utc(day.and_hms(0, 0, 0), Utc)
+}
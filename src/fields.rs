@@ -0,0 +1,566 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Sparse fieldsets of list responses. ?fields=user_title,cover,score keeps only those fields of
+// every entry, the grid view has no use for hundreds of descriptions. The entry's id is always
+// kept, the rest of the response is left as it is. The list query only reads the columns the
+// fields need, and entries are cut down while they are serialized.
+
+use serde::ser::{
+    Serialize, SerializeMap, SerializeSeq, SerializeStruct, SerializeStructVariant, SerializeTuple,
+    SerializeTupleStruct, SerializeTupleVariant, Serializer,
+};
+
+// Entries are the models::ResponseItems of a response, wherever they are nested.
+const ENTRY: &str = "ResponseItem";
+
+// Fields of models::ResponseItem as they are asked for.
+const LIST_FIELDS: &[&str] = &[
+    "id",
+    "user_title",
+    "display_title",
+    "start_day",
+    "end_day",
+    "start_partial",
+    "end_partial",
+    "display_start_day",
+    "display_end_day",
+    "score",
+    "status",
+    "progress",
+    "repeat",
+    "notes",
+    "custom_lists",
+    "average",
+    "native",
+    "romaji",
+    "english",
+    "description",
+    "cover",
+    "cover_xl",
+    "cover_thumb",
+    "slug",
+    "genres",
+    "tags",
+    "episodes",
+    "season",
+    "season_year",
+    "format",
+    "studio",
+];
+
+// The entry fields a request asked for, by their camelCase names.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fieldset {
+    fields: Vec<String>,
+}
+
+impl Fieldset {
+    // Comma separated snake_case names, unknown names are an error naming the first of them.
+    pub fn parse(value: &str) -> Result<Fieldset, String> {
+        let mut fields = vec!["id".to_owned()];
+        for field in value
+            .split(',')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+        {
+            if !LIST_FIELDS.contains(&field) {
+                return Err(field.to_owned());
+            }
+            let field = camel_case(field);
+            if !fields.contains(&field) {
+                fields.push(field);
+            }
+        }
+        Ok(Fieldset { fields })
+    }
+
+    pub fn contains(&self, field: &str) -> bool {
+        self.fields.iter().any(|name| *name == camel_case(field))
+    }
+}
+
+fn camel_case(field: &str) -> String {
+    let mut parts = field.split('_');
+    let mut name = parts.next().unwrap_or("").to_owned();
+    for part in parts {
+        let mut chars = part.chars();
+        if let Some(first) = chars.next() {
+            name.extend(first.to_uppercase());
+            name.push_str(chars.as_str());
+        }
+    }
+    name
+}
+
+// The response with its entries cut down to the fieldset. Responses without one serialize as they
+// are.
+pub struct Sparse<T> {
+    value: T,
+    fields: Option<Fieldset>,
+}
+
+pub fn sparse<T: Serialize>(value: T, fields: Option<Fieldset>) -> Sparse<T> {
+    Sparse { value, fields }
+}
+
+impl<T: Serialize> Serialize for Sparse<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.fields {
+            None => self.value.serialize(serializer),
+            Some(fields) => self.value.serialize(Pruning { serializer, fields }),
+        }
+    }
+}
+
+// Passes everything on to the serializer underneath, except for the fields of entries the fieldset
+// leaves out.
+struct Pruning<'a, S> {
+    serializer: S,
+    fields: &'a Fieldset,
+}
+
+// A value nested in the response, serialized through Pruning as well.
+struct Pruned<'a, T: ?Sized> {
+    value: &'a T,
+    fields: &'a Fieldset,
+}
+
+impl<T: Serialize + ?Sized> Serialize for Pruned<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.value.serialize(Pruning {
+            serializer,
+            fields: self.fields,
+        })
+    }
+}
+
+// Sequences, maps and structs of the response. Only entries skip fields.
+struct Compound<'a, C> {
+    compound: C,
+    fields: &'a Fieldset,
+    entry: bool,
+}
+
+fn compound<C>(compound: C, fields: &Fieldset, entry: bool) -> Compound<'_, C> {
+    Compound {
+        compound,
+        fields,
+        entry,
+    }
+}
+
+impl<'a, S: Serializer> Serializer for Pruning<'a, S> {
+    type Ok = S::Ok;
+    type Error = S::Error;
+    type SerializeSeq = Compound<'a, S::SerializeSeq>;
+    type SerializeTuple = Compound<'a, S::SerializeTuple>;
+    type SerializeTupleStruct = Compound<'a, S::SerializeTupleStruct>;
+    type SerializeTupleVariant = Compound<'a, S::SerializeTupleVariant>;
+    type SerializeMap = Compound<'a, S::SerializeMap>;
+    type SerializeStruct = Compound<'a, S::SerializeStruct>;
+    type SerializeStructVariant = Compound<'a, S::SerializeStructVariant>;
+
+    fn serialize_bool(self, value: bool) -> Result<S::Ok, S::Error> {
+        self.serializer.serialize_bool(value)
+    }
+
+    fn serialize_i8(self, value: i8) -> Result<S::Ok, S::Error> {
+        self.serializer.serialize_i8(value)
+    }
+
+    fn serialize_i16(self, value: i16) -> Result<S::Ok, S::Error> {
+        self.serializer.serialize_i16(value)
+    }
+
+    fn serialize_i32(self, value: i32) -> Result<S::Ok, S::Error> {
+        self.serializer.serialize_i32(value)
+    }
+
+    fn serialize_i64(self, value: i64) -> Result<S::Ok, S::Error> {
+        self.serializer.serialize_i64(value)
+    }
+
+    fn serialize_i128(self, value: i128) -> Result<S::Ok, S::Error> {
+        self.serializer.serialize_i128(value)
+    }
+
+    fn serialize_u8(self, value: u8) -> Result<S::Ok, S::Error> {
+        self.serializer.serialize_u8(value)
+    }
+
+    fn serialize_u16(self, value: u16) -> Result<S::Ok, S::Error> {
+        self.serializer.serialize_u16(value)
+    }
+
+    fn serialize_u32(self, value: u32) -> Result<S::Ok, S::Error> {
+        self.serializer.serialize_u32(value)
+    }
+
+    fn serialize_u64(self, value: u64) -> Result<S::Ok, S::Error> {
+        self.serializer.serialize_u64(value)
+    }
+
+    fn serialize_u128(self, value: u128) -> Result<S::Ok, S::Error> {
+        self.serializer.serialize_u128(value)
+    }
+
+    fn serialize_f32(self, value: f32) -> Result<S::Ok, S::Error> {
+        self.serializer.serialize_f32(value)
+    }
+
+    fn serialize_f64(self, value: f64) -> Result<S::Ok, S::Error> {
+        self.serializer.serialize_f64(value)
+    }
+
+    fn serialize_char(self, value: char) -> Result<S::Ok, S::Error> {
+        self.serializer.serialize_char(value)
+    }
+
+    fn serialize_str(self, value: &str) -> Result<S::Ok, S::Error> {
+        self.serializer.serialize_str(value)
+    }
+
+    fn serialize_bytes(self, value: &[u8]) -> Result<S::Ok, S::Error> {
+        self.serializer.serialize_bytes(value)
+    }
+
+    fn serialize_none(self) -> Result<S::Ok, S::Error> {
+        self.serializer.serialize_none()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<S::Ok, S::Error> {
+        let fields = self.fields;
+        self.serializer.serialize_some(&Pruned { value, fields })
+    }
+
+    fn serialize_unit(self) -> Result<S::Ok, S::Error> {
+        self.serializer.serialize_unit()
+    }
+
+    fn serialize_unit_struct(self, name: &'static str) -> Result<S::Ok, S::Error> {
+        self.serializer.serialize_unit_struct(name)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+    ) -> Result<S::Ok, S::Error> {
+        self.serializer.serialize_unit_variant(name, index, variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<S::Ok, S::Error> {
+        let fields = self.fields;
+        self.serializer
+            .serialize_newtype_struct(name, &Pruned { value, fields })
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<S::Ok, S::Error> {
+        let fields = self.fields;
+        self.serializer
+            .serialize_newtype_variant(name, index, variant, &Pruned { value, fields })
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, S::Error> {
+        let fields = self.fields;
+        Ok(compound(self.serializer.serialize_seq(len)?, fields, false))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, S::Error> {
+        let fields = self.fields;
+        Ok(compound(
+            self.serializer.serialize_tuple(len)?,
+            fields,
+            false,
+        ))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, S::Error> {
+        let fields = self.fields;
+        Ok(compound(
+            self.serializer.serialize_tuple_struct(name, len)?,
+            fields,
+            false,
+        ))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant, S::Error> {
+        let fields = self.fields;
+        Ok(compound(
+            self.serializer
+                .serialize_tuple_variant(name, index, variant, len)?,
+            fields,
+            false,
+        ))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, S::Error> {
+        let fields = self.fields;
+        Ok(compound(self.serializer.serialize_map(len)?, fields, false))
+    }
+
+    fn serialize_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, S::Error> {
+        let fields = self.fields;
+        Ok(compound(
+            self.serializer.serialize_struct(name, len)?,
+            fields,
+            name == ENTRY,
+        ))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStructVariant, S::Error> {
+        let fields = self.fields;
+        Ok(compound(
+            self.serializer
+                .serialize_struct_variant(name, index, variant, len)?,
+            fields,
+            false,
+        ))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        self.serializer.is_human_readable()
+    }
+}
+
+impl<C: SerializeSeq> SerializeSeq for Compound<'_, C> {
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), C::Error> {
+        let fields = self.fields;
+        self.compound.serialize_element(&Pruned { value, fields })
+    }
+
+    fn end(self) -> Result<C::Ok, C::Error> {
+        self.compound.end()
+    }
+}
+
+impl<C: SerializeTuple> SerializeTuple for Compound<'_, C> {
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), C::Error> {
+        let fields = self.fields;
+        self.compound.serialize_element(&Pruned { value, fields })
+    }
+
+    fn end(self) -> Result<C::Ok, C::Error> {
+        self.compound.end()
+    }
+}
+
+impl<C: SerializeTupleStruct> SerializeTupleStruct for Compound<'_, C> {
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), C::Error> {
+        let fields = self.fields;
+        self.compound.serialize_field(&Pruned { value, fields })
+    }
+
+    fn end(self) -> Result<C::Ok, C::Error> {
+        self.compound.end()
+    }
+}
+
+impl<C: SerializeTupleVariant> SerializeTupleVariant for Compound<'_, C> {
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), C::Error> {
+        let fields = self.fields;
+        self.compound.serialize_field(&Pruned { value, fields })
+    }
+
+    fn end(self) -> Result<C::Ok, C::Error> {
+        self.compound.end()
+    }
+}
+
+impl<C: SerializeMap> SerializeMap for Compound<'_, C> {
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), C::Error> {
+        self.compound.serialize_key(key)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), C::Error> {
+        let fields = self.fields;
+        self.compound.serialize_value(&Pruned { value, fields })
+    }
+
+    fn end(self) -> Result<C::Ok, C::Error> {
+        self.compound.end()
+    }
+}
+
+impl<C: SerializeStruct> SerializeStruct for Compound<'_, C> {
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), C::Error> {
+        if self.entry && !self.fields.fields.iter().any(|field| field == key) {
+            return self.compound.skip_field(key);
+        }
+        let fields = self.fields;
+        self.compound
+            .serialize_field(key, &Pruned { value, fields })
+    }
+
+    fn end(self) -> Result<C::Ok, C::Error> {
+        self.compound.end()
+    }
+}
+
+impl<C: SerializeStructVariant> SerializeStructVariant for Compound<'_, C> {
+    type Ok = C::Ok;
+    type Error = C::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), C::Error> {
+        let fields = self.fields;
+        self.compound
+            .serialize_field(key, &Pruned { value, fields })
+    }
+
+    fn end(self) -> Result<C::Ok, C::Error> {
+        self.compound.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_derive::Serialize;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn unknown_fields_are_named() {
+        assert_eq!(Fieldset::parse("score,scroe,covr"), Err("scroe".to_owned()));
+    }
+
+    #[test]
+    fn fields_are_camel_cased_once_and_keep_the_id() {
+        let fields = Fieldset::parse(" user_title, cover_xl,,user_title ").unwrap();
+        assert_eq!(fields.fields, vec!["id", "userTitle", "coverXl"]);
+        assert!(fields.contains("id"));
+        assert!(fields.contains("cover_xl"));
+        assert!(!fields.contains("description"));
+        assert_eq!(Fieldset::parse("").unwrap().fields, vec!["id"]);
+    }
+
+    #[derive(Serialize, Clone)]
+    #[serde(rename = "ResponseItem", rename_all = "camelCase")]
+    struct Entry {
+        id: i32,
+        score: Option<i16>,
+        description: String,
+    }
+
+    #[derive(Serialize)]
+    struct User {
+        id: &'static str,
+        list: Vec<Entry>,
+    }
+
+    #[derive(Serialize)]
+    struct Delta {
+        id: &'static str,
+        changed: Vec<Entry>,
+        removed: Vec<i32>,
+    }
+
+    fn entry(id: i32) -> Entry {
+        Entry {
+            id,
+            score: Some(80),
+            description: "Band".to_owned(),
+        }
+    }
+
+    #[test]
+    fn prunes_entries_of_lists_and_deltas() {
+        let fields = Fieldset::parse("score").unwrap();
+        let mut list = BTreeMap::new();
+        list.insert(
+            "users",
+            User {
+                id: "kumiko",
+                list: vec![entry(1)],
+            },
+        );
+        assert_eq!(
+            serde_json::to_string(&sparse(list, Some(fields.clone()))).unwrap(),
+            r#"{"users":{"id":"kumiko","list":[{"id":1,"score":80}]}}"#
+        );
+
+        let delta = Delta {
+            id: "kumiko",
+            changed: vec![entry(2)],
+            removed: vec![3],
+        };
+        assert_eq!(
+            serde_json::to_string(&sparse(delta, Some(fields))).unwrap(),
+            r#"{"id":"kumiko","changed":[{"id":2,"score":80}],"removed":[3]}"#
+        );
+    }
+
+    #[test]
+    fn responses_without_a_fieldset_are_left_alone() {
+        let list = User {
+            id: "kumiko",
+            list: vec![entry(1)],
+        };
+        assert_eq!(
+            serde_json::to_string(&sparse(list, None)).unwrap(),
+            r#"{"id":"kumiko","list":[{"id":1,"score":80,"description":"Band"}]}"#
+        );
+    }
+}
@@ -0,0 +1,150 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// GraphQL view of lists, anime and stats, so clients can ask for just the fields they render. It
+// reads through the same database functions and visibility rules as the REST routes. Rocket runs
+// handlers on blocking threads, so queries are executed to completion on the handler's thread.
+// Every root field costs a database round trip or more, so each is given a cost and a query that
+// adds up to more than MAX_COMPLEXITY, aliased copies of the same field included, is refused.
+
+use crate::{database, models, stats, PgDbConn};
+use async_graphql::{Context, EmptyMutation, EmptySubscription, Error, Object, Result, Schema};
+use std::sync::Mutex;
+
+pub type ApiSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+// Deep queries only repeat the same few objects, nothing legitimate comes close.
+const MAX_DEPTH: usize = 8;
+
+// Enough for a list with its stats and a few anime, not for a list fetched over and over.
+const MAX_COMPLEXITY: usize = 300;
+
+// A whole list with every entry's fields.
+const LIST_COST: usize = 100;
+
+// Stats aggregate over every entry of a list, or of every list.
+const STATS_COST: usize = 100;
+
+const ANIME_COST: usize = 10;
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    // A user's list, by AniList name or profile slug. Without perPage the whole list is returned.
+    #[graphql(complexity = "LIST_COST + child_complexity")]
+    async fn list(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        page: Option<i64>,
+        per_page: Option<i64>,
+    ) -> Result<Option<models::RestResponse>> {
+        let connection = ctx.data_unchecked::<Mutex<PgDbConn>>().lock().unwrap();
+        let name = match visible_name(name, &connection)? {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+
+        let mut query = models::ListQuery {
+            preferences: database::get_preferences(name.as_ref(), &connection),
+            ..models::ListQuery::default()
+        };
+        if let Some(per_page) = per_page {
+            if per_page < 1 || per_page > database::MAX_PER_PAGE {
+                return Err(Error::new(format!(
+                    "perPage must be between 1 and {}",
+                    database::MAX_PER_PAGE
+                )));
+            }
+            query.per_page = Some(per_page);
+        }
+        match page {
+            Some(page) if page < 1 => return Err(Error::new("page must be at least 1")),
+            Some(page) => {
+                query.page = page;
+                query.per_page = query.per_page.or(Some(database::MAX_PER_PAGE));
+            }
+            None => (),
+        }
+        if let Some(per_page) = query.per_page {
+            if database::page_offset(query.page, per_page).is_none() {
+                return Err(Error::new("page is out of range"));
+            }
+        }
+
+        Ok(database::get_list(name.as_ref(), &query, &connection))
+    }
+
+    // By slug or AniList id.
+    #[graphql(complexity = "ANIME_COST + child_complexity")]
+    async fn anime(&self, ctx: &Context<'_>, slug: String) -> Option<models::AnimeDetail> {
+        let connection = ctx.data_unchecked::<Mutex<PgDbConn>>().lock().unwrap();
+        database::get_anime(slug.as_ref(), &connection)
+    }
+
+    #[graphql(complexity = "STATS_COST + child_complexity")]
+    async fn user_stats(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+    ) -> Result<Option<models::UserStats>> {
+        let connection = ctx.data_unchecked::<Mutex<PgDbConn>>().lock().unwrap();
+        let user = match visible_name(name, &connection)? {
+            Some(name) => database::get_user(name.as_ref(), &connection),
+            None => None,
+        };
+
+        Ok(user.and_then(|user| stats::get_stats(&user, &connection)))
+    }
+
+    #[graphql(complexity = "STATS_COST + child_complexity")]
+    async fn global_stats(&self, ctx: &Context<'_>) -> Option<models::GlobalStats> {
+        let connection = ctx.data_unchecked::<Mutex<PgDbConn>>().lock().unwrap();
+        stats::get_global_stats(&connection)
+    }
+}
+
+pub fn schema() -> ApiSchema {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+}
+
+// Resolvers share the request's pooled connection, behind a lock because the context has to be
+// shareable between threads.
+pub fn execute(
+    schema: &ApiSchema,
+    request: async_graphql::Request,
+    database_conn: PgDbConn,
+) -> async_graphql::Response {
+    futures::executor::block_on(schema.execute(request.data(Mutex::new(database_conn))))
+}
+
+pub fn graphiql() -> String {
+    async_graphql::http::GraphiQLSource::build()
+        .endpoint("/graphql")
+        .finish()
+}
+
+// Same rules as the REST routes: taken down profiles don't exist, private lists are an error.
+fn visible_name(name: String, connection: &PgDbConn) -> Result<Option<String>> {
+    let name = database::resolve_profile(name.as_ref(), connection)
+        .map(|(name, _)| name)
+        .unwrap_or(name);
+
+    match database::get_visibility(name.as_ref(), connection) {
+        models::Visibility::Public => Ok(Some(name)),
+        models::Visibility::Private => Err(Error::new(format!(
+            "{} has made their list private on AniList, so it is no longer shown here",
+            name
+        ))),
+        models::Visibility::TakenDown => Ok(None),
+    }
+}
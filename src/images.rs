@@ -0,0 +1,153 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Variants of downloaded covers and avatars. JPEG and PNG images are stored as WebP at
+// IMAGE_WEBP_QUALITY, and the list grid gets a thumbnail a fraction of a cover's weight.
+
+use crate::config;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+use std::fmt;
+
+// Width of thumbnails, twice the width of a cover in the list grid. The height follows the cover.
+const THUMB_WIDTH: u32 = 160;
+
+// The image formats covers and avatars are kept in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Jpeg,
+    Png,
+    Gif,
+    WebP,
+}
+
+impl Format {
+    // Going by the magic bytes at the start of the image.
+    pub fn detect(content: &[u8]) -> Option<Format> {
+        match image::guess_format(content).ok()? {
+            ImageFormat::Jpeg => Some(Format::Jpeg),
+            ImageFormat::Png => Some(Format::Png),
+            ImageFormat::Gif => Some(Format::Gif),
+            ImageFormat::WebP => Some(Format::WebP),
+            _ => None,
+        }
+    }
+
+    // Parameters like charset are ignored.
+    pub fn from_mime(mime: &str) -> Option<Format> {
+        let essence = mime.split(';').next().unwrap_or("").trim().to_lowercase();
+        match essence.as_str() {
+            "image/jpeg" | "image/jpg" | "image/pjpeg" => Some(Format::Jpeg),
+            "image/png" => Some(Format::Png),
+            "image/gif" => Some(Format::Gif),
+            "image/webp" => Some(Format::WebP),
+            _ => None,
+        }
+    }
+
+    pub fn from_ext(ext: &str) -> Option<Format> {
+        match ext.to_lowercase().as_str() {
+            "jpg" | "jpeg" => Some(Format::Jpeg),
+            "png" => Some(Format::Png),
+            "gif" => Some(Format::Gif),
+            "webp" => Some(Format::WebP),
+            _ => None,
+        }
+    }
+
+    // The extension of the file name in the URL's path, the query string doesn't count.
+    pub fn from_url(url: &str) -> Result<Format, ImageError> {
+        let path = url.split(|c| c == '?' || c == '#').next().unwrap_or("");
+        let file = path.rsplit('/').next().unwrap_or("");
+        match file.rsplit_once('.') {
+            Some((_, ext)) => {
+                Format::from_ext(ext).ok_or_else(|| ImageError::Unsupported(url.to_owned()))
+            }
+            None => Err(ImageError::NoExtension(url.to_owned())),
+        }
+    }
+
+    pub fn ext(self) -> &'static str {
+        match self {
+            Format::Jpeg => "jpg",
+            Format::Png => "png",
+            Format::Gif => "gif",
+            Format::WebP => "webp",
+        }
+    }
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            Format::Jpeg => "image/jpeg",
+            Format::Png => "image/png",
+            Format::Gif => "image/gif",
+            Format::WebP => "image/webp",
+        }
+    }
+
+    // JPEG and PNG images are stored as WebP, other formats (GIFs may be animated) as they are.
+    pub fn stored(self) -> Format {
+        match self {
+            Format::Jpeg | Format::Png => Format::WebP,
+            format => format,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ImageError {
+    // The request failed or AniList answered with an error status.
+    Download(String, String),
+    // Neither the image's bytes nor its Content-Type name a format covers are kept in.
+    Unsupported(String),
+    // The URL's file name has no extension to go by.
+    NoExtension(String),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Download(url, error) => {
+                write!(f, "{} could not be downloaded: {}", url, error)
+            }
+            ImageError::Unsupported(url) => {
+                write!(f, "{} is not a JPEG, PNG, GIF or WebP image", url)
+            }
+            ImageError::NoExtension(url) => write!(f, "{} has no file extension", url),
+        }
+    }
+}
+
+pub fn to_webp(content: &[u8]) -> Result<Vec<u8>, String> {
+    let image = image::load_from_memory(content).map_err(|error| error.to_string())?;
+    encode(&image)
+}
+
+// The cover scaled down to THUMB_WIDTH as WebP. Covers already that narrow keep their size.
+pub fn thumbnail(content: &[u8]) -> Result<Vec<u8>, String> {
+    let cover = image::load_from_memory(content).map_err(|error| error.to_string())?;
+    let thumb = if cover.width() > THUMB_WIDTH {
+        let height = (u64::from(cover.height()) * u64::from(THUMB_WIDTH) / u64::from(cover.width()))
+            .max(1) as u32;
+        cover.resize_exact(THUMB_WIDTH, height, FilterType::Lanczos3)
+    } else {
+        cover
+    };
+
+    encode(&thumb)
+}
+
+fn encode(image: &DynamicImage) -> Result<Vec<u8>, String> {
+    // The encoder only takes 8 bit RGB and RGBA.
+    let image = match image {
+        DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_) => image.clone(),
+        image => DynamicImage::ImageRgba8(image.to_rgba8()),
+    };
+    let encoder = webp::Encoder::from_image(&image).map_err(|error| error.to_owned())?;
+    Ok(encoder.encode(config::settings().webp_quality).to_vec())
+}
@@ -0,0 +1,654 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::config::Settings;
+use crate::{
+    anilist_query, cache, config, database, logging, models, providers, shutdown, stats, storage,
+    taste, webhooks,
+};
+use chrono::{Duration as ChronoDuration, Utc};
+use log::{error, info, warn};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use rocket_contrib::databases::postgres::Connection;
+use std::collections::HashSet;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long an idle worker waits before looking for queued jobs again.
+const WORKER_POLL_SECS: u64 = 1;
+
+// Running time after which a job claimed by another instance is assumed to be abandoned.
+const DEFAULT_JOB_STALE_SECS: u64 = 60 * 60;
+
+// Longest a sync may run before it stops and fails. It has to stay below JOB_STALE_SECS, or a sync
+// that is still running gets queued a second time, config::load refuses to start otherwise.
+const DEFAULT_SYNC_DEADLINE_SECS: u64 = 30 * 60;
+
+// Finished jobs over this window make up the throughput a batch's ETA is based on.
+const THROUGHPUT_WINDOW_SECS: f64 = 10.0 * 60.0;
+
+// Failures listed in a batch's progress, the counts still include all of them.
+const MAX_BATCH_FAILURES: i64 = 100;
+
+// Namespace for the advisory locks taken per user while queueing, so they can't clash with locks
+// taken for other purposes.
+const QUEUE_LOCK_NAMESPACE: i32 = 1;
+
+// Random characters ending a generated worker id.
+const WORKER_ID_SUFFIX_LEN: usize = 8;
+
+static WORKER_ID: OnceLock<String> = OnceLock::new();
+
+// Queues a sync for the user unless one is already queued or running, in which case that job is
+// returned instead. The boolean is true when a new job was created. Either job joins the batch.
+// New jobs keep the ID of the request being handled, so the sync's logs and failure can be traced
+// back to it.
+pub fn queue_sync(
+    user_id: i32,
+    force: bool,
+    batch_id: Option<i32>,
+    connection: &Connection,
+) -> Option<(models::Job, bool)> {
+    let request_id = logging::request_id();
+    let result = connection.transaction().and_then(|transaction| {
+        // Serializes concurrent requests for the same user across all instances.
+        transaction.execute(
+            "SELECT pg_advisory_xact_lock($1, $2)",
+            &[&QUEUE_LOCK_NAMESPACE, &user_id],
+        )?;
+
+        let active = transaction.query("SELECT job_id, user_id, kind, state, error, created_at, started_at, finished_at, request_id, username, sync_job_id FROM jobs WHERE user_id = $1 AND kind = 'sync' AND state IN ('queued', 'running') ORDER BY job_id DESC LIMIT 1", &[&user_id])?;
+        let (job, created) = match active.iter().next() {
+            Some(row) => (job_from_row(&row), false),
+            None => {
+                let created = transaction.query("INSERT INTO jobs (user_id, kind, state, force, request_id) VALUES ($1, 'sync', 'queued', $2, $3) RETURNING job_id, user_id, kind, state, error, created_at, started_at, finished_at, request_id, username, sync_job_id", &[&user_id, &force, &request_id])?;
+                (job_from_row(&created.get(0)), true)
+            }
+        };
+
+        // A user whose sync is already queued is part of the batch with that job.
+        if let Some(batch_id) = batch_id {
+            transaction.execute(
+                "INSERT INTO batch_jobs (batch_id, job_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                &[&batch_id, &job.job_id],
+            )?;
+        }
+        transaction.commit()?;
+        Ok((job, created))
+    });
+
+    match result {
+        Ok(queued) => Some(queued),
+        Err(error) => {
+            error!(
+                "error queueing sync job for user_id={}. Error: {}",
+                user_id, error
+            );
+            None
+        }
+    }
+}
+
+pub fn get_job(job_id: i32, connection: &Connection) -> Option<models::Job> {
+    let stmt = connection.prepare_cached("SELECT job_id, user_id, kind, state, error, created_at, started_at, finished_at, request_id, username, sync_job_id FROM jobs WHERE job_id = $1").unwrap();
+
+    match stmt.query(&[&job_id]) {
+        Ok(rows) => rows.iter().next().map(|row| {
+            let mut job = job_from_row(&row);
+            job.warnings = database::get_job_warnings(job.job_id, connection);
+            job.quarantined = database::get_job_quarantined(job.job_id, connection);
+            job
+        }),
+        Err(error) => {
+            error!("error getting job_id={}. Error: {}", job_id, error);
+            None
+        }
+    }
+}
+
+// Queues a lookup of a batch update's name, which queues the user's sync once they are found on
+// AniList. Names are rate limited before they get here, so lookups aren't deduplicated.
+pub fn queue_lookup(username: &str, connection: &Connection) -> Option<models::Job> {
+    let stmt = connection.prepare_cached("INSERT INTO jobs (kind, state, username, request_id) VALUES ('lookup', 'queued', $1, $2) RETURNING job_id, user_id, kind, state, error, created_at, started_at, finished_at, request_id, username, sync_job_id").unwrap();
+
+    match stmt.query(&[&username, &logging::request_id()]) {
+        Ok(rows) => rows.iter().next().map(|row| job_from_row(&row)),
+        Err(error) => {
+            error!(
+                "error queueing lookup job for username={}. Error: {}",
+                username, error
+            );
+            None
+        }
+    }
+}
+
+// Groups the jobs of a maintenance operation that touches many users, so its progress can be
+// followed as a whole.
+pub fn create_batch(kind: &str, connection: &Connection) -> Option<i32> {
+    let stmt = connection
+        .prepare_cached(
+            "INSERT INTO job_batches (kind, created_at) VALUES ($1, now()) RETURNING batch_id",
+        )
+        .unwrap();
+
+    match stmt.query(&[&kind]) {
+        Ok(rows) => rows.iter().next().map(|row| row.get(0)),
+        Err(error) => {
+            error!("error creating {} batch. Error: {}", kind, error);
+            None
+        }
+    }
+}
+
+// The ETA assumes the batch keeps finishing jobs at the rate of the last THROUGHPUT_WINDOW_SECS, or
+// since its first job started if that was more recent.
+pub fn get_batch(batch_id: i32, connection: &Connection) -> Option<models::BatchProgress> {
+    let stmt = connection.prepare_cached("SELECT b.kind, b.created_at, count(j.job_id) FILTER (WHERE j.state = 'queued'), count(j.job_id) FILTER (WHERE j.state = 'running'), count(j.job_id) FILTER (WHERE j.state = 'succeeded'), count(j.job_id) FILTER (WHERE j.state = 'failed'), count(j.job_id) FILTER (WHERE j.finished_at > now() - make_interval(secs => $2)), EXTRACT(EPOCH FROM now() - GREATEST(min(j.started_at), now() - make_interval(secs => $2)))::float8 FROM job_batches AS b LEFT JOIN batch_jobs AS m ON m.batch_id = b.batch_id LEFT JOIN jobs AS j ON m.job_id = j.job_id WHERE b.batch_id = $1 GROUP BY b.batch_id").unwrap();
+
+    let rows = match stmt.query(&[&batch_id, &THROUGHPUT_WINDOW_SECS]) {
+        Ok(rows) => rows,
+        Err(error) => {
+            error!("error getting batch_id={}. Error: {}", batch_id, error);
+            return None;
+        }
+    };
+    let row = rows.iter().next()?;
+
+    let queued: i64 = row.get(2);
+    let running: i64 = row.get(3);
+    let succeeded: i64 = row.get(4);
+    let failed: i64 = row.get(5);
+    let recently_finished: i64 = row.get(6);
+    let window_secs: Option<f64> = row.get(7);
+
+    let jobs_per_minute = match window_secs {
+        Some(secs) if secs > 0.0 => Some(recently_finished as f64 * 60.0 / secs),
+        _ => None,
+    };
+    let pending = queued + running;
+    let eta = match jobs_per_minute {
+        _ if pending == 0 => None,
+        Some(rate) if rate > 0.0 => {
+            let secs = (pending as f64 / rate * 60.0).ceil() as i64;
+            Some(Utc::now() + ChronoDuration::seconds(secs))
+        }
+        _ => None,
+    };
+
+    Some(models::BatchProgress {
+        batch_id,
+        kind: row.get(0),
+        created_at: row.get(1),
+        total: queued + running + succeeded + failed,
+        queued,
+        running,
+        succeeded,
+        failed,
+        jobs_per_minute,
+        eta,
+        failures: get_batch_failures(batch_id, connection),
+    })
+}
+
+fn get_batch_failures(batch_id: i32, connection: &Connection) -> Vec<models::BatchFailure> {
+    let stmt = connection.prepare_cached("SELECT j.job_id, j.user_id, u.name, COALESCE(j.error, ''), j.request_id FROM batch_jobs AS m INNER JOIN jobs AS j ON m.job_id = j.job_id LEFT JOIN users AS u ON j.user_id = u.user_id WHERE m.batch_id = $1 AND j.state = 'failed' ORDER BY j.job_id LIMIT $2").unwrap();
+
+    match stmt.query(&[&batch_id, &MAX_BATCH_FAILURES]) {
+        Ok(rows) => rows
+            .iter()
+            .map(|row| models::BatchFailure {
+                job_id: row.get(0),
+                user_id: row.get(1),
+                name: row.get(2),
+                error: row.get(3),
+                request_id: row.get(4),
+            })
+            .collect(),
+        Err(error) => {
+            error!(
+                "error getting failures of batch_id={}. Error: {}",
+                batch_id, error
+            );
+            Vec::new()
+        }
+    }
+}
+
+// Most recent failures first.
+pub fn recent_failures(limit: i64, connection: &Connection) -> Vec<models::SyncFailure> {
+    let stmt = connection.prepare_cached("SELECT j.job_id, j.user_id, u.name, COALESCE(j.error, ''), j.finished_at, j.request_id FROM jobs AS j LEFT JOIN users AS u ON j.user_id = u.user_id WHERE j.state = 'failed' AND j.kind = 'sync' ORDER BY j.finished_at DESC NULLS LAST, j.job_id DESC LIMIT $1").unwrap();
+
+    match stmt.query(&[&limit]) {
+        Ok(rows) => rows
+            .iter()
+            .map(|row| models::SyncFailure {
+                job_id: row.get(0),
+                user_id: row.get(1),
+                name: row.get(2),
+                error: row.get(3),
+                finished_at: row.get(4),
+                request_id: row.get(5),
+            })
+            .collect(),
+        Err(error) => {
+            error!("error getting recent sync failures. Error: {}", error);
+            Vec::new()
+        }
+    }
+}
+
+// Replays quarantined entries without a sync, every one of them or only the one with
+// sync_error_id, so a fix for whatever broke them heals old failures. None on database errors.
+pub fn retry_quarantined(
+    sync_error_id: Option<i32>,
+    images: &storage::Images,
+    connection: &Connection,
+) -> Option<models::QuarantineRetry> {
+    let quarantined = database::get_quarantined_payloads(sync_error_id, connection)?;
+
+    let mut healed_users = HashSet::new();
+    let mut remaining = Vec::new();
+    for entry in quarantined.iter() {
+        match database::replay_entry(entry, images, connection) {
+            Ok(_) => {
+                healed_users.insert(entry.user_id);
+            }
+            Err(message) => {
+                database::set_quarantine_error(entry.sync_error_id, &message, connection);
+                remaining.push(entry.sync_error_id);
+            }
+        }
+    }
+
+    // Healed entries change the lists the way a sync would.
+    for user_id in healed_users {
+        stats::rebuild(user_id, connection);
+        cache::refresh_snapshot(user_id, connection);
+    }
+
+    info!(
+        "replayed {} quarantined entries, {} still fail",
+        quarantined.len(),
+        remaining.len()
+    );
+    Some(models::QuarantineRetry {
+        retried: quarantined.len() as i64,
+        healed: (quarantined.len() - remaining.len()) as i64,
+        remaining: database::get_quarantined_entries(&remaining, connection),
+    })
+}
+
+// Starts the threads that consume the job queue. Each worker claims one queued job at a time, so
+// any number of worker processes can share the same table. Workers stop claiming on shutdown.
+pub fn start_workers(concurrency: usize, images: storage::Images, settings: &'static Settings) {
+    match database::connect(settings) {
+        Ok(connection) => recover_interrupted(&connection),
+        // Interrupted jobs are still recovered by other instances once they have gone stale.
+        Err(error) => error!(
+            "error connecting to recover interrupted jobs. Error: {}",
+            error
+        ),
+    }
+
+    info!("starting {} job workers", concurrency);
+    for _ in 0..concurrency {
+        let images = images.clone();
+        thread::spawn(move || {
+            // Dropped when claiming fails, so a worker reconnects after the database restarted.
+            let mut connection = None;
+            while !shutdown::requested() {
+                if connection.is_none() {
+                    match database::connect(settings) {
+                        Ok(connected) => connection = Some(connected),
+                        Err(error) => {
+                            error!("error connecting a job worker. Error: {}", error);
+                            thread::sleep(Duration::from_secs(WORKER_POLL_SECS));
+                            continue;
+                        }
+                    }
+                }
+                let current = connection.as_ref().unwrap();
+
+                // Taken before claiming, so a shutdown can't miss a job between claim and run.
+                let task = shutdown::Task::start();
+                match claim_next(current) {
+                    Ok(Some(job)) => match (job.kind.as_str(), job.user_id) {
+                        ("lookup", _) => run_lookup(job, &images, current),
+                        (_, Some(user_id)) => run_sync(job, user_id, &images, settings, current),
+                        (_, None) => set_state(
+                            job.job_id,
+                            models::JobState::Failed,
+                            Some("Sync without a user"),
+                            current,
+                        ),
+                    },
+                    Ok(None) => {
+                        drop(task);
+                        thread::sleep(Duration::from_secs(WORKER_POLL_SECS));
+                    }
+                    Err(error) => {
+                        error!("error claiming next job. Error: {}", error);
+                        drop(task);
+                        connection = None;
+                        thread::sleep(Duration::from_secs(WORKER_POLL_SECS));
+                    }
+                }
+            }
+        });
+    }
+}
+
+struct ClaimedJob {
+    job_id: i32,
+    user_id: Option<i32>,
+    kind: String,
+    username: Option<String>,
+    force: bool,
+    request_id: Option<String>,
+}
+
+// Puts jobs a crashed instance left in "running" back into the queue. Only an instance started
+// under the crashed one's WORKER_ID knows its jobs can't still be running, other jobs count as
+// interrupted once they have been running for longer than any sync should take.
+fn recover_interrupted(connection: &Connection) {
+    match requeue(Some(stale_secs()), connection) {
+        Ok(job_ids) => {
+            if job_ids.is_empty() {
+                info!("no interrupted jobs to recover");
+            } else {
+                warn!(
+                    "requeued {} interrupted jobs: job_ids={:?}",
+                    job_ids.len(),
+                    job_ids
+                );
+            }
+        }
+        Err(error) => error!("error recovering interrupted jobs. Error: {}", error),
+    }
+}
+
+// Puts the jobs this instance is still running back in the queue, for a shutdown that can't wait
+// for them any longer.
+pub fn requeue_running(connection: &Connection) {
+    match requeue(None, connection) {
+        Ok(job_ids) => {
+            if !job_ids.is_empty() {
+                warn!(
+                    "requeued {} running jobs: job_ids={:?}",
+                    job_ids.len(),
+                    job_ids
+                );
+            }
+        }
+        Err(error) => error!("error requeueing running jobs. Error: {}", error),
+    }
+}
+
+// Requeues the running jobs this instance claimed and, with stale_secs, those of any instance
+// running for longer than that.
+fn requeue(stale_secs: Option<u64>, connection: &Connection) -> Result<Vec<i32>, postgres::Error> {
+    let transaction = connection.transaction()?;
+    let rows = transaction.query("SELECT job_id, claimed_by, EXTRACT(EPOCH FROM now() - started_at)::float8 FROM jobs WHERE state = 'running' FOR UPDATE", &[])?;
+    let job_ids: Vec<i32> = rows
+        .iter()
+        .filter(|row| {
+            let claimed_by: Option<String> = row.get(1);
+            is_interrupted(claimed_by.as_deref(), row.get(2), worker_id(), stale_secs)
+        })
+        .map(|row| row.get(0))
+        .collect();
+    if !job_ids.is_empty() {
+        transaction.execute("UPDATE jobs SET state = 'queued', started_at = NULL, claimed_by = NULL WHERE job_id = ANY($1)", &[&job_ids])?;
+    }
+    transaction.commit()?;
+    Ok(job_ids)
+}
+
+fn is_interrupted(
+    claimed_by: Option<&str>,
+    running_secs: Option<f64>,
+    worker_id: &str,
+    stale_secs: Option<u64>,
+) -> bool {
+    let stale = match (running_secs, stale_secs) {
+        (Some(running_secs), Some(stale_secs)) => running_secs > stale_secs as f64,
+        _ => false,
+    };
+    claimed_by == Some(worker_id) || stale
+}
+
+// The name this instance claims jobs under. Without WORKER_ID every process gets a name of its
+// own, so instances never requeue each other's jobs, but a restarted one leaves the jobs it was
+// running to go stale.
+fn worker_id() -> &'static str {
+    WORKER_ID.get_or_init(|| config::env_parsed("WORKER_ID").unwrap_or_else(generated_worker_id))
+}
+
+// Host and process, with a random suffix for process ids that are reused, e.g. inside containers.
+fn generated_worker_id() -> String {
+    let host = config::env_value("HOSTNAME", "localhost".to_owned());
+    let suffix: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(WORKER_ID_SUFFIX_LEN)
+        .map(char::from)
+        .collect();
+    format!("{}:{}:{}", host, std::process::id(), suffix)
+}
+
+fn claim_next(connection: &Connection) -> Result<Option<ClaimedJob>, postgres::Error> {
+    let rows = connection
+        .prepare_cached("UPDATE jobs SET state = 'running', started_at = now(), claimed_by = $1 WHERE job_id = (SELECT job_id FROM jobs WHERE state = 'queued' ORDER BY job_id FOR UPDATE SKIP LOCKED LIMIT 1) RETURNING job_id, user_id, kind, username, force, request_id")?
+        .query(&[&worker_id()])?;
+    Ok(rows.iter().next().map(|row| ClaimedJob {
+        job_id: row.get(0),
+        user_id: row.get(1),
+        kind: row.get(2),
+        username: row.get(3),
+        force: row.get(4),
+        request_id: row.get(5),
+    }))
+}
+
+// Runs a claimed sync job to completion on the calling thread, recording the outcome.
+fn run_sync(
+    job: ClaimedJob,
+    user_id: i32,
+    images: &storage::Images,
+    settings: &Settings,
+    connection: &Connection,
+) {
+    logging::set_request_id(job.request_id.clone());
+    info!("job_id={} is now running", job.job_id);
+    let deadline = Instant::now() + sync_deadline();
+
+    // A panicking sync fails its job rather than leaving it running until it goes stale.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        database::update_entries(user_id, job.force, job.job_id, deadline, images, settings)
+    }))
+    .unwrap_or(Err(database::SyncError::Crashed));
+    match result {
+        Ok(_) => {
+            cache::refresh_snapshot(user_id, connection);
+            taste::refresh(user_id, connection);
+            set_state(job.job_id, models::JobState::Succeeded, None, connection);
+            webhooks::sync_finished(
+                job.job_id,
+                user_id,
+                models::JobState::Succeeded,
+                None,
+                connection,
+            );
+        }
+        // Nothing was written, another worker or the next start picks the job up again.
+        Err(database::SyncError::Interrupted) => {
+            let stmt = connection
+                .prepare_cached("UPDATE jobs SET state = 'queued', started_at = NULL, claimed_by = NULL WHERE job_id = $1")
+                .unwrap();
+            match stmt.execute(&[&job.job_id]) {
+                Ok(_) => info!("job_id={} is queued again", job.job_id),
+                Err(error) => error!("error requeueing job_id={}. Error: {}", job.job_id, error),
+            }
+        }
+        Err(error) => {
+            cache::invalidate_snapshot(user_id, connection);
+            let message = error.to_string();
+            set_state(
+                job.job_id,
+                models::JobState::Failed,
+                Some(&message),
+                connection,
+            );
+            webhooks::sync_finished(
+                job.job_id,
+                user_id,
+                models::JobState::Failed,
+                Some(&message),
+                connection,
+            );
+        }
+    }
+    logging::set_request_id(None);
+}
+
+// Finds a batch update's name on AniList and queues the user's sync, which the lookup then points
+// at. Taken down users are as unknown here as they are to a single update.
+fn run_lookup(job: ClaimedJob, images: &storage::Images, connection: &Connection) {
+    logging::set_request_id(job.request_id.clone());
+    info!("job_id={} is now running", job.job_id);
+    let username = job.username.unwrap_or_default();
+
+    let found = match anilist_query::get_id(&username) {
+        Ok(Some(user)) => {
+            if database::get_visibility(user.name.as_ref(), connection)
+                == models::Visibility::TakenDown
+            {
+                Err("User not found".to_owned())
+            } else {
+                let user_id = user.id;
+                database::update_user_profile(
+                    user,
+                    providers::Provider::AniList,
+                    images,
+                    connection,
+                );
+                queue_sync(user_id, false, None, connection)
+                    .map(|(sync, _)| (user_id, sync.job_id))
+                    .ok_or_else(|| "Could not queue the update".to_owned())
+            }
+        }
+        Ok(None) => Err("User not found".to_owned()),
+        Err(error) => Err(error.to_string()),
+    };
+
+    match found {
+        Ok((user_id, sync_job_id)) => {
+            let stmt = connection
+                .prepare_cached("UPDATE jobs SET user_id = $2, sync_job_id = $3 WHERE job_id = $1")
+                .unwrap();
+            if let Err(error) = stmt.execute(&[&job.job_id, &user_id, &sync_job_id]) {
+                error!(
+                    "error linking job_id={} to its sync. Error: {}",
+                    job.job_id, error
+                );
+            }
+            set_state(job.job_id, models::JobState::Succeeded, None, connection);
+        }
+        Err(message) => set_state(
+            job.job_id,
+            models::JobState::Failed,
+            Some(&message),
+            connection,
+        ),
+    }
+    logging::set_request_id(None);
+}
+
+pub fn sync_deadline() -> Duration {
+    Duration::from_secs(config::env_value(
+        "SYNC_DEADLINE_SECS",
+        DEFAULT_SYNC_DEADLINE_SECS,
+    ))
+}
+
+// Running time after which a job claimed by another instance is assumed to be abandoned.
+pub fn stale_secs() -> u64 {
+    config::env_value("JOB_STALE_SECS", DEFAULT_JOB_STALE_SECS)
+}
+
+fn set_state(job_id: i32, state: models::JobState, message: Option<&str>, connection: &Connection) {
+    let stmt = match state {
+        models::JobState::Running => connection.prepare_cached(
+            "UPDATE jobs SET state = $2, error = $3, started_at = now() WHERE job_id = $1",
+        ),
+        models::JobState::Queued => {
+            connection.prepare_cached("UPDATE jobs SET state = $2, error = $3 WHERE job_id = $1")
+        }
+        models::JobState::Succeeded | models::JobState::Failed => connection.prepare_cached(
+            "UPDATE jobs SET state = $2, error = $3, finished_at = now() WHERE job_id = $1",
+        ),
+    }
+    .unwrap();
+
+    match stmt.execute(&[&job_id, &state.as_str(), &message]) {
+        Ok(_) => info!("job_id={} is now {}", job_id, state.as_str()),
+        Err(error) => error!(
+            "error setting job_id={} to state={}. Error: {}",
+            job_id,
+            state.as_str(),
+            error
+        ),
+    }
+}
+
+fn job_from_row(row: &postgres::rows::Row) -> models::Job {
+    let state: String = row.get(3);
+    models::Job {
+        job_id: row.get(0),
+        user_id: row.get(1),
+        kind: row.get(2),
+        state: models::JobState::parse(&state).unwrap_or(models::JobState::Failed),
+        error: row.get(4),
+        created_at: row.get(5),
+        started_at: row.get(6),
+        finished_at: row.get(7),
+        request_id: row.get(8),
+        username: row.get(9),
+        sync_job_id: row.get(10),
+        quarantined: 0,
+        warnings: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jobs_claimed_by_another_worker_are_not_requeued() {
+        assert!(!is_interrupted(Some("b"), Some(10.0), "a", None));
+        assert!(!is_interrupted(Some("b"), Some(10.0), "a", Some(60)));
+        assert!(!is_interrupted(None, None, "a", Some(60)));
+    }
+
+    #[test]
+    fn own_and_stale_jobs_are_requeued() {
+        assert!(is_interrupted(Some("a"), Some(1.0), "a", None));
+        assert!(is_interrupted(Some("b"), Some(61.0), "a", Some(60)));
+    }
+
+    #[test]
+    fn generated_worker_ids_are_unique() {
+        let id = generated_worker_id();
+        assert!(id.contains(&format!(":{}:", std::process::id())));
+        assert_ne!(id, generated_worker_id());
+    }
+}
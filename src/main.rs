@@ -8,66 +8,2337 @@
 
 #![feature(proc_macro_hygiene, decl_macro)]
 
+use crate::error::AppError;
+use chrono::{DateTime, NaiveDate, Utc};
+use rocket::delete;
+use rocket::fairing::AdHoc;
 use rocket::get;
-use rocket::http::Method;
+use rocket::http::uri::{Origin, Uri};
+use rocket::http::{ContentType, Cookie, Cookies, Method, SameSite};
 use rocket::post;
+use rocket::put;
+use rocket::request::LenientForm;
+use rocket::response::content::Content;
 use rocket::response::status::Accepted;
-use rocket::response::status::NotFound;
+use rocket::response::status::Created;
+use rocket::response::status::NoContent;
+use rocket::response::Redirect;
 use rocket::routes;
-use rocket_contrib::database;
+use rocket::State;
+use rocket::{FromForm, Responder};
 use rocket_contrib::databases::postgres;
 use rocket_contrib::json::Json;
 use rocket_contrib::serve::StaticFiles;
 use rocket_cors::Error;
 use rocket_cors::{AllowedHeaders, AllowedOrigins};
-use std::thread;
+use std::collections::HashSet;
+use std::time::Duration;
+use std::{env, thread};
+use utoipa::IntoParams;
 
+mod anilist_graphql;
 mod anilist_models;
 mod anilist_query;
+mod auth;
+#[cfg(feature = "bench")]
+mod bench;
+mod cache;
+mod calendar;
+mod config;
+mod covers;
+mod crawlers;
 mod database;
+mod dates;
+mod dump;
+mod error;
+mod export;
+mod features;
+mod feed;
+mod fields;
+mod graphql;
+mod images;
+mod jobs;
+mod logging;
+mod mal_models;
+mod mal_query;
+mod markup;
+mod memory_cache;
+mod migrations;
 mod models;
+mod normalize;
+mod notifier;
+mod oauth;
+mod openapi;
+mod pool;
+mod profile;
+mod providers;
+mod rate_limit;
+mod remote_search;
+mod request_id;
+mod response;
+mod scheduler;
+mod sealed;
+mod shutdown;
+mod sitemap;
+mod stats;
+mod storage;
+mod streaming;
+mod takedown;
+mod taste;
+mod uploads;
+mod warmup;
+mod webhooks;
 
-#[database("postgres_connection")]
-pub struct PgDbConn(postgres::Connection);
+const DEFAULT_DUMP_PATH: &str = "dump.tar.zst";
 
-#[get("/users/<username>")]
+const DEFAULT_WORKER_CONCURRENCY: usize = 2;
+
+const DEFAULT_USERS_PER_PAGE: i64 = 50;
+
+// Activity returned by a single request, later activity is fetched with since.
+const ACTIVITY_LIMIT: i64 = 1000;
+
+// Changes returned by a single request, later ones are fetched with since.
+const CHANGES_LIMIT: i64 = 1000;
+
+// Usernames a single batch update may name.
+const MAX_BATCH_USERS: usize = 50;
+
+const DEFAULT_FAILURES: i64 = 50;
+
+const MAX_FAILURES: i64 = 500;
+
+// Which workloads this process runs, chosen with --mode (or its older name --role): api nodes only
+// serve HTTP and queue jobs, workers only consume the job queue, with WORKER_CONCURRENCY syncs at a
+// time, and run the scheduler.
+#[derive(Clone, Copy, PartialEq)]
+enum Role {
+    Api,
+    Worker,
+    All,
+}
+
+impl Role {
+    fn parse(value: &str) -> Option<Role> {
+        match value {
+            "api" => Some(Role::Api),
+            "worker" => Some(Role::Worker),
+            "all" => Some(Role::All),
+            _ => None,
+        }
+    }
+}
+
+pub use pool::PgDbConn;
+
+#[derive(Responder)]
+enum ProfileResponse {
+    List(cache::JsonBody),
+    NotModified(cache::NotModified),
+    Moved(Redirect),
+}
+
+// Query parameters selecting part of a list, see models::ListQuery.
+#[derive(FromForm, Default, PartialEq, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ListParams {
+    page: Option<i64>,
+    per_page: Option<i64>,
+    sort: Option<String>,
+    order: Option<String>,
+    min_score: Option<i16>,
+    completed_after: Option<String>,
+    completed_before: Option<String>,
+    title: Option<String>,
+    // Override the owner's models::Preferences.
+    title_language: Option<String>,
+    date_format: Option<String>,
+    adult: Option<bool>,
+    // iso or epoch_ms for start_day and end_day.
+    date_repr: Option<String>,
+    // Comma separated entry fields to return, like user_title,cover,score.
+    fields: Option<String>,
+}
+
+// Users the service already tracks, most recently synced first.
+#[utoipa::path(
+    get,
+    path = "/users",
+    tag = "users",
+    params(
+        ("page" = Option<i64>, Query, description = "Page to return, from 1"),
+        ("per_page" = Option<i64>, Query, description = "Users per page"),
+    ),
+    responses(
+        (status = 200, description = "Tracked users, most recently synced first", body = response::TrackedUserPage),
+        (status = 400, description = "Invalid page or per_page", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/users?<page>&<per_page>")]
+fn users(
+    page: Option<i64>,
+    per_page: Option<i64>,
+    database_conn: PgDbConn,
+) -> Result<response::Legacy<response::Envelope<Vec<models::TrackedUser>>>, AppError> {
+    let page = page.unwrap_or(1);
+    let per_page = per_page.unwrap_or(DEFAULT_USERS_PER_PAGE);
+    if per_page < 1 || per_page > database::MAX_PER_PAGE {
+        return Err(AppError::BadRequest(format!(
+            "per_page must be between 1 and {}",
+            database::MAX_PER_PAGE
+        )));
+    }
+    if page < 1 {
+        return Err(AppError::BadRequest("page must be at least 1".to_owned()));
+    }
+    if database::page_offset(page, per_page).is_none() {
+        return Err(AppError::BadRequest("page is out of range".to_owned()));
+    }
+
+    match database::get_tracked_users(page, per_page, &database_conn) {
+        Some((users, total)) => Ok(response::Legacy(
+            response::Envelope::new(users, "/users".to_owned()).paginated(page, per_page, total),
+        )),
+        None => Err(AppError::Internal("Could not list users".to_owned())),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{username}",
+    tag = "users",
+    params(
+        ("username" = String, Path, description = "AniList name or profile slug"),
+        ("since" = Option<String>, Query, description = "Sync job id or RFC 3339 timestamp, returns the changes since then instead"),
+        ListParams,
+    ),
+    responses(
+        (status = 200, description = "The list, or a ListDelta when since is set", body = models::RestResponse),
+        (status = 301, description = "Moved to the user's profile slug"),
+        (status = 304, description = "The list hasn't changed since the ETag sent in If-None-Match"),
+        (status = 400, description = "Invalid query parameters", body = error::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The list is private on AniList", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/users/<username>?<since>&<params..>")]
 fn user(
     username: String,
+    since: Option<String>,
+    params: LenientForm<ListParams>,
+    origin: &Origin,
+    encoding: cache::AcceptEncoding,
+    if_none_match: cache::IfNoneMatch,
+    hits: State<warmup::ProfileHits>,
+    database_conn: PgDbConn,
+) -> Result<ProfileResponse, AppError> {
+    // Users with a vanity URL are sent there when looked up by their AniList name.
+    let name = match database::resolve_profile(username.as_ref(), &database_conn) {
+        Some((name, Some(slug))) if name == username && slug != username => {
+            ensure_public(name.as_ref(), &database_conn)?;
+            let query = origin
+                .query()
+                .map(|query| format!("?{}", query))
+                .unwrap_or_default();
+            return Ok(ProfileResponse::Moved(Redirect::moved(format!(
+                "/users/{}{}",
+                slug, query
+            ))));
+        }
+        Some((name, _)) => name,
+        None => username,
+    };
+    ensure_public(name.as_ref(), &database_conn)?;
+    hits.record(name.as_ref());
+
+    let preferences = database::get_preferences(name.as_ref(), &database_conn);
+    let query = list_query(&params, preferences)?;
+    if let Some(since) = since {
+        return list_changes(
+            name.as_ref(),
+            since.as_ref(),
+            &query.preferences,
+            query.fields.as_ref(),
+            &database_conn,
+        )
+        .map(|delta| {
+            ProfileResponse::List(cache::JsonBody::plain(&dates::represent(
+                fields::sparse(delta, query.fields.clone()),
+                query.date_repr,
+            )))
+        });
+    }
+
+    let tag = cache::list_etag(name.as_ref(), origin.query(), &database_conn);
+    let query_string = origin.query().unwrap_or("");
+    if let Some(tag) = &tag {
+        if if_none_match.matches(&tag.etag) {
+            return Ok(ProfileResponse::NotModified(cache::NotModified(
+                tag.etag.clone(),
+            )));
+        }
+        if let Some(body) = memory_cache::get(tag.user_id, query_string, &tag.etag) {
+            return Ok(ProfileResponse::List(
+                cache::JsonBody::serialized(body).with_etag(Some(tag.etag.clone())),
+            ));
+        }
+    }
+
+    // The snapshot only holds the whole list in the default order.
+    let mut body = None;
+    if *params == ListParams::default() {
+        body = cache::cached_list(name.as_ref(), &encoding, &database_conn);
+    }
+    let body = match body {
+        Some(body) => body,
+        None => match database::get_list(name.as_ref(), &query, &database_conn) {
+            Some(list) => cache::JsonBody::plain(&dates::represent(
+                fields::sparse(list, query.fields.clone()),
+                query.date_repr,
+            )),
+            None => return Err(AppError::NotFound("User or list not found".to_owned())),
+        },
+    };
+
+    match tag {
+        Some(tag) => {
+            if let Some(json) = body.json() {
+                memory_cache::put(tag.user_id, query_string, &tag.etag, json);
+            }
+            Ok(ProfileResponse::List(body.with_etag(Some(tag.etag))))
+        }
+        None => Ok(ProfileResponse::List(body)),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{username}/range",
+    tag = "users",
+    params(
+        ("username" = String, Path, description = "AniList name or profile slug"),
+        ("from" = String, Query, description = "First completion day, like 2023-01-31"),
+        ("to" = String, Query, description = "Last completion day, like 2023-12-31"),
+        ListParams,
+    ),
+    responses(
+        (status = 200, description = "Entries completed within the range", body = models::RestResponse),
+        (status = 400, description = "Invalid dates or query parameters", body = error::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The list is private on AniList", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/users/<username>/range?<from>&<to>&<params..>")]
+fn user_range(
+    username: String,
+    from: String,
+    to: String,
+    params: LenientForm<ListParams>,
+    database_conn: PgDbConn,
+) -> Result<response::Legacy<dates::Represented<fields::Sparse<models::RestResponse>>>, AppError> {
+    let parse = |day: &str| {
+        NaiveDate::parse_from_str(day, "%Y-%m-%d").map_err(|_| {
+            AppError::BadRequest("from and to must be dates like 2023-01-31".to_owned())
+        })
+    };
+    let (from, to) = (parse(from.as_ref())?, parse(to.as_ref())?);
+    if from > to {
+        return Err(AppError::BadRequest("from must not be after to".to_owned()));
+    }
+
+    let name = profile_name(username, &database_conn)?;
+
+    // The range replaces completed_after and completed_before.
+    let preferences = database::get_preferences(name.as_ref(), &database_conn);
+    let mut query = list_query(&params, preferences)?;
+    query.filter.completed_from = Some(from);
+    query.filter.completed_to = Some(to);
+
+    match database::get_list(name.as_ref(), &query, &database_conn) {
+        Some(list) => Ok(response::Legacy(dates::represent(
+            fields::sparse(list, query.fields.clone()),
+            query.date_repr,
+        ))),
+        None => Err(AppError::NotFound("User not found".to_owned())),
+    }
+}
+
+fn list_query(
+    params: &ListParams,
+    preferences: models::Preferences,
+) -> Result<models::ListQuery, AppError> {
+    let mut query = models::ListQuery {
+        preferences,
+        ..models::ListQuery::default()
+    };
+
+    if let Some(sort) = &params.sort {
+        query.sort = models::ListSort::parse(sort.as_ref()).ok_or_else(|| {
+            AppError::BadRequest("sort must be one of score, end_day or title".to_owned())
+        })?;
+    }
+
+    query.descending = match params.order.as_ref().map(String::as_str) {
+        None | Some("desc") => true,
+        Some("asc") => false,
+        Some(_) => return Err(AppError::BadRequest("order must be asc or desc".to_owned())),
+    };
+
+    match params.per_page {
+        Some(per_page) if per_page < 1 || per_page > database::MAX_PER_PAGE => {
+            return Err(AppError::BadRequest(format!(
+                "per_page must be between 1 and {}",
+                database::MAX_PER_PAGE
+            )))
+        }
+        Some(per_page) => query.per_page = Some(per_page),
+        // Asking for a page without a size uses the largest one.
+        None if params.page.is_some() => query.per_page = Some(database::MAX_PER_PAGE),
+        None => (),
+    }
+
+    match params.page {
+        Some(page) if page < 1 => {
+            return Err(AppError::BadRequest("page must be at least 1".to_owned()))
+        }
+        Some(page) => query.page = page,
+        None => (),
+    }
+    if let Some(per_page) = query.per_page {
+        if database::page_offset(query.page, per_page).is_none() {
+            return Err(AppError::BadRequest("page is out of range".to_owned()));
+        }
+    }
+
+    // Exclusive bounds, stored as the nearest included days.
+    let parse_day = |day: &Option<String>, name: &str| match day {
+        Some(day) => NaiveDate::parse_from_str(day, "%Y-%m-%d")
+            .map(Some)
+            .map_err(|_| AppError::BadRequest(format!("{} must be a date like 2023-01-31", name))),
+        None => Ok(None),
+    };
+    query.filter.completed_from =
+        parse_day(&params.completed_after, "completed_after")?.and_then(|day| day.succ_opt());
+    query.filter.completed_to =
+        parse_day(&params.completed_before, "completed_before")?.and_then(|day| day.pred_opt());
+
+    query.filter.min_score = params.min_score;
+    query.filter.title = params
+        .title
+        .as_ref()
+        .map(|title| title.trim().to_owned())
+        .filter(|title| !title.is_empty());
+
+    if let Some(title_language) = &params.title_language {
+        query.preferences.title_language = models::TitleLanguage::parse(title_language)
+            .ok_or_else(|| {
+                AppError::BadRequest(
+                    "title_language must be one of user_preferred, romaji, english or native"
+                        .to_owned(),
+                )
+            })?;
+    }
+    if let Some(date_format) = &params.date_format {
+        query.preferences.date_format =
+            models::DateFormat::parse(date_format).ok_or_else(|| {
+                AppError::BadRequest("date_format must be one of iso, us, eu or long".to_owned())
+            })?;
+    }
+    if let Some(adult) = params.adult {
+        query.preferences.show_adult = adult;
+    }
+    if let Some(date_repr) = &params.date_repr {
+        query.date_repr = dates::DateRepr::parse(date_repr)
+            .ok_or_else(|| AppError::BadRequest("date_repr must be iso or epoch_ms".to_owned()))?;
+    }
+    if let Some(fields) = &params.fields {
+        query.fields = Some(fields::Fieldset::parse(fields).map_err(|field| {
+            AppError::BadRequest(format!("fields names an unknown field: {}", field))
+        })?);
+    }
+
+    Ok(query)
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{username}/history",
+    tag = "users",
+    params(
+        ("username" = String, Path, description = "AniList name or profile slug"),
+        ("anime_id" = Option<i32>, Query, description = "Only the history of this anime"),
+    ),
+    responses(
+        (status = 200, description = "Values entries had before a sync replaced them", body = [models::HistoryEntry]),
+        (status = 403, description = "The list is private on AniList", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/users/<username>/history?<anime_id>")]
+fn history(
+    username: String,
+    anime_id: Option<i32>,
+    database_conn: PgDbConn,
+) -> Result<response::Legacy<Vec<models::HistoryEntry>>, AppError> {
+    let name = profile_name(username, &database_conn)?;
+
+    match database::get_history(name.as_ref(), anime_id, &database_conn) {
+        Some(history) => Ok(response::Legacy(history)),
+        None => Err(AppError::NotFound("User not found".to_owned())),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{username}/activity",
+    tag = "users",
+    params(
+        ("username" = String, Path, description = "AniList name or profile slug"),
+        ("since" = Option<String>, Query, description = "RFC 3339 timestamp or YYYY-MM-DD date, only later activity is returned"),
+    ),
+    responses(
+        (status = 200, description = "List activity from AniList, oldest first and at most 1000 of it", body = [models::Activity]),
+        (status = 400, description = "Invalid since", body = error::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The list is private on AniList", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/users/<username>/activity?<since>")]
+fn activity(
+    username: String,
+    since: Option<String>,
+    database_conn: PgDbConn,
+) -> Result<response::Legacy<Vec<models::Activity>>, AppError> {
+    let since = since.as_deref().map(parse_since).transpose()?;
+    let name = profile_name(username, &database_conn)?;
+
+    match database::get_activities(name.as_ref(), since, ACTIVITY_LIMIT, &database_conn) {
+        Some(activities) => Ok(response::Legacy(activities)),
+        None => Err(AppError::NotFound("User not found".to_owned())),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{username}/changes",
+    tag = "users",
+    params(
+        ("username" = String, Path, description = "AniList name or profile slug"),
+        ("since" = Option<String>, Query, description = "RFC 3339 timestamp or YYYY-MM-DD date, only later changes are returned"),
+    ),
+    responses(
+        (status = 200, description = "Entries syncs added, updated and removed, oldest first and at most 1000 of them", body = [models::ListChange]),
+        (status = 400, description = "Invalid since", body = error::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The list is private on AniList", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/users/<username>/changes?<since>")]
+fn changes(
+    username: String,
+    since: Option<String>,
+    database_conn: PgDbConn,
+) -> Result<response::Legacy<Vec<models::ListChange>>, AppError> {
+    let since = since.as_deref().map(parse_since).transpose()?;
+    let name = profile_name(username, &database_conn)?;
+
+    match database::get_changes(name.as_ref(), since, CHANGES_LIMIT, &database_conn) {
+        Some(changes) => Ok(response::Legacy(changes)),
+        None => Err(AppError::NotFound("User not found".to_owned())),
+    }
+}
+
+// An RFC 3339 timestamp, or a plain date for the start of that day in UTC.
+fn parse_since(since: &str) -> Result<DateTime<Utc>, AppError> {
+    match DateTime::parse_from_rfc3339(since) {
+        Ok(since) => Ok(since.with_timezone(&Utc)),
+        Err(_) => match NaiveDate::parse_from_str(since, "%Y-%m-%d") {
+            Ok(day) => Ok(DateTime::from_utc(day.and_hms(0, 0, 0), Utc)),
+            Err(_) => Err(AppError::BadRequest(
+                "since must be an RFC 3339 timestamp or a date".to_owned(),
+            )),
+        },
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{username}/timeline",
+    tag = "users",
+    params(
+        ("username" = String, Path, description = "AniList name or profile slug"),
+        ("period" = Option<String>, Query, description = "month (default) or year"),
+    ),
+    responses(
+        (status = 200, description = "Entries counted by the month or year they were completed in", body = models::Timeline),
+        (status = 400, description = "Unknown period", body = error::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The list is private on AniList", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/users/<username>/timeline?<period>")]
+fn timeline(
+    username: String,
+    period: Option<String>,
+    database_conn: PgDbConn,
+) -> Result<response::Legacy<models::Timeline>, AppError> {
+    let period = match period.as_deref() {
+        None | Some("month") => "month",
+        Some("year") => "year",
+        Some(_) => {
+            return Err(AppError::BadRequest(
+                "period must be month or year".to_owned(),
+            ))
+        }
+    };
+    let name = profile_name(username, &database_conn)?;
+
+    match database::get_timeline(name.as_ref(), period, &database_conn) {
+        Some(timeline) => Ok(response::Legacy(timeline)),
+        None => Err(AppError::NotFound("User not found".to_owned())),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{username}/stats",
+    tag = "users",
+    params(
+        ("username" = String, Path, description = "AniList name or profile slug"),
+    ),
+    responses(
+        (status = 200, body = models::UserStats),
+        (status = 403, description = "The list is private on AniList", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/users/<username>/stats")]
+fn user_stats(
+    username: String,
+    database_conn: PgDbConn,
+) -> Result<response::Legacy<models::UserStats>, AppError> {
+    let user = profile_user(username.as_ref(), &database_conn)?;
+    match stats::get_stats(&user, &database_conn) {
+        Some(stats) => Ok(response::Legacy(stats)),
+        None => Err(AppError::Internal("Could not load stats".to_owned())),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{username}/covers.zip",
+    tag = "exports",
+    params(
+        ("username" = String, Path, description = "AniList name or profile slug"),
+    ),
+    responses(
+        (status = 200, description = "Covers of completed anime", body = Vec<u8>, content_type = "application/zip"),
+        (status = 403, description = "The list is private on AniList", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = error::Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many downloads are being prepared, retry after Retry-After seconds", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/users/<username>/covers.zip")]
+fn covers(username: String, database_conn: PgDbConn) -> Result<streaming::Download, AppError> {
+    let name = profile_name(username, &database_conn)?;
+
+    covers::archive(name.as_ref(), &database_conn).map_err(download_error)
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{username}/feed.atom",
+    tag = "exports",
+    params(
+        ("username" = String, Path, description = "AniList name or profile slug"),
+    ),
+    responses(
+        (status = 200, description = "Atom feed of recent completions", body = String, content_type = "application/atom+xml"),
+        (status = 403, description = "The list is private on AniList", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/users/<username>/feed.atom")]
+fn feed(username: String, database_conn: PgDbConn) -> Result<Content<String>, AppError> {
+    let name = profile_name(username, &database_conn)?;
+
+    feed::completions(name.as_ref(), &database_conn)
+        .map(|xml| Content(ContentType::new("application", "atom+xml"), xml))
+        .ok_or_else(|| AppError::NotFound("User not found".to_owned()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{username}/calendar.ics",
+    tag = "exports",
+    params(
+        ("username" = String, Path, description = "AniList name or profile slug"),
+    ),
+    responses(
+        (status = 200, description = "Start and completion days as calendar events", body = String, content_type = "text/calendar"),
+        (status = 403, description = "The list is private on AniList", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/users/<username>/calendar.ics")]
+fn calendar(username: String, database_conn: PgDbConn) -> Result<Content<String>, AppError> {
+    let name = profile_name(username, &database_conn)?;
+
+    calendar::watch_dates(name.as_ref(), &database_conn)
+        .map(|ics| Content(ContentType::Calendar, ics))
+        .ok_or_else(|| AppError::NotFound("User not found".to_owned()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{username}/export",
+    tag = "exports",
+    params(
+        ("username" = String, Path, description = "AniList name or profile slug"),
+        ("format" = Option<String>, Query, description = "csv (the default) or mal"),
+    ),
+    responses(
+        (status = 200, description = "The whole list as CSV or MyAnimeList XML", body = String, content_type = "text/csv"),
+        (status = 400, description = "Unknown format", body = error::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The list is private on AniList", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = error::Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many downloads are being prepared, retry after Retry-After seconds", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/users/<username>/export?<format>")]
+fn export(
+    username: String,
+    format: Option<String>,
+    settings: State<config::Settings>,
+    database_conn: PgDbConn,
+) -> Result<streaming::Download, AppError> {
+    let format = match format.as_ref().map(String::as_str) {
+        None => export::Format::Csv,
+        Some(format) => export::Format::parse(format)
+            .ok_or_else(|| AppError::BadRequest("format must be csv or mal".to_owned()))?,
+    };
+    let name = profile_name(username, &database_conn)?;
+
+    export::export(name.as_ref(), format, &settings, &database_conn).map_err(download_error)
+}
+
+fn download_error(error: streaming::DownloadError) -> AppError {
+    let detail = error.to_string();
+    match error {
+        streaming::DownloadError::NotFound => AppError::NotFound(detail),
+        streaming::DownloadError::Busy => {
+            AppError::RateLimited(detail, Some(streaming::RETRY_AFTER_SECS))
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{username}/similar-users",
+    tag = "users",
+    params(
+        ("username" = String, Path, description = "AniList name or profile slug"),
+    ),
+    responses(
+        (status = 200, body = [models::SimilarUser]),
+        (status = 403, description = "The list is private on AniList", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/users/<username>/similar-users")]
+fn similar_users(
+    username: String,
+    database_conn: PgDbConn,
+) -> Result<response::Legacy<Vec<models::SimilarUser>>, AppError> {
+    let user = profile_user(username.as_ref(), &database_conn)?;
+    Ok(response::Legacy(taste::similar_users(
+        user.user_id,
+        &database_conn,
+    )))
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{username}/recommendations",
+    tag = "users",
+    params(
+        ("username" = String, Path, description = "AniList name or profile slug"),
+    ),
+    responses(
+        (status = 200, body = [models::Recommendation]),
+        (status = 403, description = "The list is private on AniList", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/users/<username>/recommendations")]
+fn recommendations(
+    username: String,
+    database_conn: PgDbConn,
+) -> Result<response::Legacy<Vec<models::Recommendation>>, AppError> {
+    let user = profile_user(username.as_ref(), &database_conn)?;
+    Ok(response::Legacy(taste::recommendations(
+        user.user_id,
+        &database_conn,
+    )))
+}
+
+// AniList name behind a name or profile slug, as long as the list may be shown.
+fn profile_name(username: String, connection: &postgres::Connection) -> Result<String, AppError> {
+    let name = database::resolve_profile(username.as_ref(), connection)
+        .map(|(name, _)| name)
+        .unwrap_or(username);
+    ensure_public(name.as_ref(), connection)?;
+    Ok(name)
+}
+
+fn profile_user(
+    username: &str,
+    connection: &postgres::Connection,
+) -> Result<models::User, AppError> {
+    let name = profile_name(username.to_owned(), connection)?;
+    database::get_user(name.as_ref(), connection)
+        .ok_or_else(|| AppError::NotFound("User not found".to_owned()))
+}
+
+// Every route serving a profile goes through here. Lists made private on AniList are withheld
+// instead of serving what was stored before, profiles with a takedown act as if they never existed.
+fn ensure_public(name: &str, connection: &postgres::Connection) -> Result<(), AppError> {
+    match database::get_visibility(name, connection) {
+        models::Visibility::Public => Ok(()),
+        models::Visibility::Private => Err(AppError::Forbidden(format!(
+            "{} has made their list private on AniList, so it is no longer shown here",
+            name
+        ))),
+        models::Visibility::TakenDown => Err(AppError::NotFound("User not found".to_owned())),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/stats/global",
+    tag = "stats",
+    responses(
+        (status = 200, body = models::GlobalStats),
+        (status = 500, description = "Stats could not be aggregated", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/stats/global")]
+fn global_stats(
+    database_conn: PgDbConn,
+) -> Result<response::Legacy<models::GlobalStats>, AppError> {
+    match stats::get_global_stats(&database_conn) {
+        Some(stats) => Ok(response::Legacy(stats)),
+        None => Err(AppError::Internal("Could not aggregate stats".to_owned())),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{username}/compare/{other}",
+    tag = "users",
+    params(
+        ("username" = String, Path, description = "AniList name or profile slug"),
+        ("other" = String, Path, description = "AniList name or profile slug to compare with"),
+    ),
+    responses(
+        (status = 200, body = models::Comparison),
+        (status = 403, description = "The list is private on AniList", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/users/<username>/compare/<other>")]
+fn compare(
+    username: String,
+    other: String,
+    database_conn: PgDbConn,
+) -> Result<response::Legacy<models::Comparison>, AppError> {
+    let mut names = Vec::with_capacity(2);
+    for name in &[username, other] {
+        match database::resolve_profile(name.as_ref(), &database_conn) {
+            Some((name, _)) => names.push(name),
+            None => return Err(AppError::NotFound(format!("User {} not found", name))),
+        }
+    }
+    for name in &names {
+        ensure_public(name.as_ref(), &database_conn)?;
+    }
+
+    match database::compare_users(&names[0], &names[1], &database_conn) {
+        Some(comparison) => Ok(response::Legacy(comparison)),
+        None => Err(AppError::Internal("Could not compare the lists".to_owned())),
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/profile/slug",
+    tag = "profile",
+    request_body = models::SlugRequest,
+    responses(
+        (status = 204, description = "Slug set or removed"),
+        (status = 401, description = "Missing or unknown session token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 409, description = "Slug is taken", body = error::Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Slug is invalid or reserved", body = error::Problem, content_type = "application/problem+json"),
+    ),
+    security(("session_token" = []))
+)]
+#[put("/profile/slug", data = "<request>")]
+fn profile_slug(
+    request: Json<models::SlugRequest>,
+    user: auth::AuthenticatedUser,
+    database_conn: PgDbConn,
+) -> Result<NoContent, AppError> {
+    match profile::set_slug(
+        user.user_id,
+        request.slug.as_ref().map(String::as_str),
+        &database_conn,
+    ) {
+        Ok(_) => Ok(NoContent),
+        Err(error) => {
+            let detail = error.to_string();
+            Err(match error {
+                profile::SlugError::Invalid | profile::SlugError::Reserved => {
+                    AppError::Unprocessable(detail)
+                }
+                profile::SlugError::Taken => AppError::Conflict(detail),
+                profile::SlugError::Database => AppError::Internal(detail),
+            })
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/profile/preferences",
+    tag = "profile",
+    responses(
+        (status = 200, body = models::Preferences),
+        (status = 401, description = "Missing or unknown session token", body = error::Problem, content_type = "application/problem+json")
+    ),
+    security(("session_token" = []))
+)]
+#[get("/profile/preferences")]
+fn preferences(
+    user: auth::AuthenticatedUser,
+    database_conn: PgDbConn,
+) -> Result<response::Legacy<models::Preferences>, AppError> {
+    let user = database::get_user_by_id(user.user_id, &database_conn)
+        .ok_or_else(|| AppError::NotFound("User not found".to_owned()))?;
+    Ok(response::Legacy(database::get_preferences(
+        user.name.as_ref(),
+        &database_conn,
+    )))
+}
+
+#[utoipa::path(
+    put,
+    path = "/profile/preferences",
+    tag = "profile",
+    request_body = models::Preferences,
+    responses(
+        (status = 200, description = "Saved, fields left out were reset to their defaults", body = models::Preferences),
+        (status = 401, description = "Missing or unknown session token", body = error::Problem, content_type = "application/problem+json")
+    ),
+    security(("session_token" = []))
+)]
+#[put("/profile/preferences", data = "<request>")]
+fn set_preferences(
+    request: Json<models::Preferences>,
+    user: auth::AuthenticatedUser,
+    database_conn: PgDbConn,
+) -> Result<response::Legacy<models::Preferences>, AppError> {
+    if !database::set_preferences(user.user_id, &request, &database_conn) {
+        return Err(AppError::Internal(
+            "Could not save the preferences".to_owned(),
+        ));
+    }
+
+    // The snapshot is built with the owner's preferences.
+    cache::refresh_snapshot(user.user_id, &database_conn);
+    Ok(response::Legacy(request.into_inner()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/webhooks",
+    tag = "webhooks",
+    request_body = models::WebhookRequest,
+    responses(
+        (status = 201, description = "Registered, syncs of the caller's list are delivered to it", body = models::Webhook),
+        (status = 401, description = "Missing or unknown session token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 409, description = "Webhook limit reached", body = error::Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Invalid url or secret, or a url that resolves to a private address", body = error::Problem, content_type = "application/problem+json")
+    ),
+    security(("session_token" = []))
+)]
+#[post("/webhooks", format = "json", data = "<request>")]
+fn register_webhook(
+    request: Json<models::WebhookRequest>,
+    user: auth::AuthenticatedUser,
+    database_conn: PgDbConn,
+) -> Result<Created<response::Legacy<models::Webhook>>, AppError> {
+    match webhooks::register(user.user_id, &request, &database_conn) {
+        Ok(webhook) => Ok(Created(
+            format!("/webhooks/{}", webhook.id),
+            Some(response::Legacy(webhook)),
+        )),
+        Err(error) => {
+            let detail = error.to_string();
+            Err(match error {
+                webhooks::WebhookError::InvalidUrl
+                | webhooks::WebhookError::PrivateAddress
+                | webhooks::WebhookError::SecretTooShort => AppError::Unprocessable(detail),
+                webhooks::WebhookError::TooMany => AppError::Conflict(detail),
+                webhooks::WebhookError::Database => AppError::Internal(detail),
+            })
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/webhooks/{id}",
+    tag = "webhooks",
+    params(("id" = i32, Path)),
+    responses(
+        (status = 204, description = "Webhook removed"),
+        (status = 401, description = "Missing or unknown session token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Webhook not found", body = error::Problem, content_type = "application/problem+json")
+    ),
+    security(("session_token" = []))
+)]
+#[delete("/webhooks/<id>")]
+fn remove_webhook(
+    id: i32,
+    user: auth::AuthenticatedUser,
+    database_conn: PgDbConn,
+) -> Result<NoContent, AppError> {
+    if webhooks::remove(user.user_id, id, &database_conn) {
+        Ok(NoContent)
+    } else {
+        Err(AppError::NotFound("Webhook not found".to_owned()))
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/users/{username}/entries/{anime_id}",
+    tag = "profile",
+    params(
+        ("username" = String, Path, description = "AniList name or profile slug"),
+        ("anime_id" = i32, Path, description = "AniList id of the entry's anime"),
+    ),
+    responses(
+        (status = 204, description = "Entry hidden, syncs leave it out from now on"),
+        (status = 401, description = "Missing or unknown session token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not the caller's list", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Entry not found", body = error::Problem, content_type = "application/problem+json")
+    ),
+    security(("session_token" = []))
+)]
+#[delete("/users/<username>/entries/<anime_id>")]
+fn hide_entry(
+    username: String,
+    anime_id: i32,
+    user: auth::AuthenticatedUser,
+    database_conn: PgDbConn,
+) -> Result<NoContent, AppError> {
+    let owner = database::resolve_profile(username.as_ref(), &database_conn)
+        .and_then(|(name, _)| database::get_user(name.as_ref(), &database_conn))
+        .ok_or_else(|| AppError::NotFound("User not found".to_owned()))?;
+    if owner.user_id != user.user_id {
+        return Err(AppError::Forbidden(
+            "Entries can only be hidden from your own list".to_owned(),
+        ));
+    }
+
+    match database::hide_entry(user.user_id, anime_id, &database_conn) {
+        Some(true) => {
+            cache::refresh_snapshot(user.user_id, &database_conn);
+            taste::refresh(user.user_id, &database_conn);
+            Ok(NoContent)
+        }
+        Some(false) => Err(AppError::NotFound("Entry not found".to_owned())),
+        None => Err(AppError::Internal("Could not hide the entry".to_owned())),
+    }
+}
+
+// since is either the job_id of one of the user's finished syncs or an RFC 3339 timestamp, usually
+// the as_of of the previous delta.
+fn list_changes(
+    username: &str,
+    since: &str,
+    preferences: &models::Preferences,
+    fields: Option<&fields::Fieldset>,
+    connection: &postgres::Connection,
+) -> Result<models::ListDelta, AppError> {
+    let user = match database::get_user(username, connection) {
+        Some(user) => user,
+        None => return Err(AppError::NotFound("User not found".to_owned())),
+    };
+
+    let since = match since.parse::<i32>() {
+        Ok(job_id) => match jobs::get_job(job_id, connection) {
+            Some(ref job) if job.user_id == Some(user.user_id) && job.kind == "sync" => {
+                match job.finished_at {
+                    Some(finished_at) => finished_at,
+                    None => return Err(AppError::Conflict("Sync has not finished yet".to_owned())),
+                }
+            }
+            _ => return Err(AppError::NotFound("Sync not found".to_owned())),
+        },
+        Err(_) => match DateTime::parse_from_rfc3339(since) {
+            Ok(since) => since.with_timezone(&Utc),
+            Err(_) => {
+                return Err(AppError::BadRequest(
+                    "since must be a sync job id or an RFC 3339 timestamp".to_owned(),
+                ))
+            }
+        },
+    };
+
+    match database::get_list_changes(&user, since, preferences, fields, connection) {
+        Some(delta) => Ok(delta),
+        None => Err(AppError::Internal("Could not load list changes".to_owned())),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/users/{username}",
+    tag = "users",
+    params(
+        ("username" = String, Path, description = "AniList name or profile slug"),
+        ListParams,
+    ),
+    responses(
+        (status = 200, body = response::ListPage),
+        (status = 400, description = "Invalid query parameters", body = error::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "The list is private on AniList", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/users/<username>?<params..>")]
+fn user_v1(
+    username: String,
+    params: LenientForm<ListParams>,
+    database_conn: PgDbConn,
+) -> Result<
+    Json<dates::Represented<fields::Sparse<response::Envelope<models::ResponseList>>>>,
+    AppError,
+> {
+    let name = profile_name(username.clone(), &database_conn)?;
+
+    // Page links carry the sort and filters along, page and per_page are added by the envelope.
+    let mut self_link = format!("/v1/users/{}", username);
+    let min_score = params.min_score.map(|score| score.to_string());
+    let adult = params.adult.map(|adult| adult.to_string());
+    let link_params: Vec<String> = vec![
+        ("sort", &params.sort),
+        ("order", &params.order),
+        ("min_score", &min_score),
+        ("completed_after", &params.completed_after),
+        ("completed_before", &params.completed_before),
+        ("title", &params.title),
+        ("title_language", &params.title_language),
+        ("date_format", &params.date_format),
+        ("adult", &adult),
+        ("date_repr", &params.date_repr),
+        ("fields", &params.fields),
+    ]
+    .into_iter()
+    .filter_map(|(key, value)| {
+        value
+            .as_ref()
+            .map(|value| format!("{}={}", key, Uri::percent_encode(value)))
+    })
+    .collect();
+    if !link_params.is_empty() {
+        self_link = format!("{}?{}", self_link, link_params.join("&"));
+    }
+
+    let preferences = database::get_preferences(name.as_ref(), &database_conn);
+    let query = list_query(&params, preferences)?;
+    match database::get_list(name.as_ref(), &query, &database_conn) {
+        Some(list) => {
+            let per_page = query.per_page.unwrap_or(list.total);
+            let envelope = response::Envelope::new(list.users, self_link)
+                .paginated(query.page, per_page, list.total)
+                .freshness(list.data_freshness)
+                .sorted(database::order_clause(&query, ""))
+                .warnings(database::get_warning_counts(name.as_ref(), &database_conn));
+            Ok(Json(dates::represent(
+                fields::sparse(envelope, query.fields.clone()),
+                query.date_repr,
+            )))
+        }
+        None => Err(AppError::NotFound("User or list not found".to_owned())),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/users/{username}",
+    tag = "sync",
+    params(
+        ("username" = String, Path, description = "AniList name"),
+        ("force" = Option<bool>, Query, description = "Confirm a large deletion held back by the last sync"),
+        ("provider" = Option<String>, Query, description = "anilist (default) or mal, the site the list is synced from. MyAnimeList users are read back as mal:{username}"),
+    ),
+    responses(
+        (status = 202, description = "Sync queued", body = models::Job),
+        (status = 400, description = "Unknown provider", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = error::Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many updates from this address or for this user, see Retry-After", body = error::Problem, content_type = "application/problem+json"),
+        (status = 503, description = "AniList is unavailable", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/users/<username>?<force>&<provider>")]
+fn update(
+    username: String,
+    force: Option<bool>,
+    provider: Option<String>,
+    client: rate_limit::ClientIp,
+    limiter: State<rate_limit::UpdateLimiter>,
+    images: State<storage::Images>,
+    database_conn: PgDbConn,
+) -> Result<Accepted<response::Legacy<models::Job>>, AppError> {
+    let provider = match provider {
+        Some(provider) => providers::Provider::parse(&provider)
+            .ok_or_else(|| AppError::BadRequest("provider must be anilist or mal".to_owned()))?,
+        None => providers::Provider::AniList,
+    };
+    limiter.check(&client, username.as_ref())?;
+    match provider.source().get_user(username.as_ref()) {
+        Ok(Some(user)) => {
+            // A sync would bring back data that is waiting to be purged.
+            if database::get_visibility(user.name.as_ref(), &database_conn)
+                == models::Visibility::TakenDown
+            {
+                return Err(AppError::NotFound("User not found".to_owned()));
+            }
+            database::update_user_profile(user.clone(), provider, &images, &database_conn);
+            let force = force.unwrap_or(false);
+            match jobs::queue_sync(user.id, force, None, &database_conn) {
+                Some((job, _)) => Ok(Accepted(Some(response::Legacy(job)))),
+                None => Err(AppError::Internal("Could not queue the update".to_owned())),
+            }
+        }
+        Ok(None) => Err(AppError::NotFound("User not found".to_owned())),
+        Err(error) => Err(AppError::Upstream(error)),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/users/batch",
+    tag = "sync",
+    request_body(content = Vec<String>, description = "AniList names, at most 50"),
+    responses(
+        (status = 202, description = "Outcome of each username, in the order they were sent. Queued names are looked up on AniList by a job, which queues the sync", body = [models::BatchUpdateResult]),
+        (status = 400, description = "No usernames or too many", body = error::Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many updates from this address, see Retry-After", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/users/batch", format = "json", data = "<usernames>")]
+fn update_batch(
+    usernames: Json<Vec<String>>,
+    client: rate_limit::ClientIp,
+    limiter: State<rate_limit::UpdateLimiter>,
+    database_conn: PgDbConn,
+) -> Result<Accepted<response::Legacy<Vec<models::BatchUpdateResult>>>, AppError> {
+    // Names that differ only in case are the same AniList user.
+    let mut seen = HashSet::new();
+    let usernames: Vec<String> = usernames
+        .into_inner()
+        .into_iter()
+        .map(|username| username.trim().to_owned())
+        .filter(|username| !username.is_empty() && seen.insert(username.to_lowercase()))
+        .collect();
+    if usernames.is_empty() || usernames.len() > MAX_BATCH_USERS {
+        return Err(AppError::BadRequest(format!(
+            "Send between 1 and {} usernames",
+            MAX_BATCH_USERS
+        )));
+    }
+
+    let allowed = limiter.check_batch(&client, &usernames)?;
+    let results = usernames
+        .into_iter()
+        .zip(allowed)
+        .map(|(username, allowed)| {
+            let (status, job_id, detail) = match allowed {
+                Ok(()) => match jobs::queue_lookup(&username, &database_conn) {
+                    Some(job) => (models::BatchUpdateStatus::Queued, Some(job.job_id), None),
+                    None => (
+                        models::BatchUpdateStatus::Failed,
+                        None,
+                        Some("Could not queue the update".to_owned()),
+                    ),
+                },
+                Err(detail) => (models::BatchUpdateStatus::RateLimited, None, Some(detail)),
+            };
+            models::BatchUpdateResult {
+                username,
+                status,
+                job_id,
+                detail,
+            }
+        })
+        .collect();
+    Ok(Accepted(Some(response::Legacy(results))))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/users/{username}",
+    tag = "admin",
+    params(
+        ("username" = String, Path, description = "AniList name"),
+        ("confirm" = Option<i32>, Query, description = "AniList id the user has to have, guards against deleting whoever took over a name"),
+    ),
+    responses(
+        (status = 204, description = "User, list and avatar deleted"),
+        (status = 401, description = "Missing admin token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Wrong admin token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = error::Problem, content_type = "application/problem+json"),
+        (status = 409, description = "User has a different id than confirm", body = error::Problem, content_type = "application/problem+json"),
+    ),
+    security(("admin_token" = []))
+)]
+#[delete("/users/<username>?<confirm>")]
+fn delete_user(
+    username: String,
+    confirm: Option<i32>,
+    _admin: auth::Admin,
+    images: State<storage::Images>,
+    database_conn: PgDbConn,
+) -> Result<NoContent, AppError> {
+    // Unlike a takedown this is immediate, there is no grace period to undo it in.
+    let user = match database::get_user(username.as_ref(), &database_conn) {
+        Some(user) => user,
+        None => return Err(AppError::NotFound("User not found".to_owned())),
+    };
+    if confirm.map_or(false, |user_id| user_id != user.user_id) {
+        return Err(AppError::Conflict(format!(
+            "{} does not have the id to confirm",
+            user.name
+        )));
+    }
+
+    if database::purge_user(user.user_id, &user.avatar_s3, &images, &database_conn) {
+        Ok(NoContent)
+    } else {
+        Err(AppError::Internal("Could not delete the user".to_owned()))
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/auth/anilist",
+    tag = "auth",
+    responses(
+        (status = 303, description = "Redirect to AniList to authorize anihistory"),
+        (status = 404, description = "Sign in with AniList is not configured", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/auth/anilist")]
+fn anilist_login(
+    mut cookies: Cookies,
+    settings: State<config::Settings>,
+) -> Result<Redirect, AppError> {
+    let config = match oauth::config(&settings) {
+        Some(config) => config,
+        None => {
+            return Err(AppError::NotFound(
+                "AniList sign in is not configured".to_owned(),
+            ))
+        }
+    };
+
+    // The callback has to come back with the same state, otherwise anyone could link their own
+    // AniList account to someone else's browser.
+    let state = oauth::new_token();
+    cookies.add(
+        Cookie::build(oauth::STATE_COOKIE, state.clone())
+            .path("/auth/anilist")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .secure(config.redirect_uri.starts_with("https://"))
+            .finish(),
+    );
+    Ok(Redirect::to(oauth::authorize_url(&config, &state)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/auth/anilist/callback",
+    tag = "auth",
+    params(
+        ("code" = Option<String>, Query, description = "Authorization code from AniList"),
+        ("state" = Option<String>, Query, description = "State sent with the redirect to AniList"),
+        ("error" = Option<String>, Query, description = "Set by AniList when authorization was denied"),
+    ),
+    responses(
+        (status = 200, description = "Signed in, the AniList account is linked", body = models::OAuthSession),
+        (status = 400, description = "Missing code or mismatched state", body = error::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Authorization denied", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Sign in with AniList is not configured", body = error::Problem, content_type = "application/problem+json"),
+        (status = 503, description = "AniList is unavailable", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/auth/anilist/callback?<code>&<state>&<error>")]
+fn anilist_callback(
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    mut cookies: Cookies,
+    settings: State<config::Settings>,
+    images: State<storage::Images>,
+    database_conn: PgDbConn,
+) -> Result<response::Legacy<models::OAuthSession>, AppError> {
+    let config = match oauth::config(&settings) {
+        Some(config) => config,
+        None => {
+            return Err(AppError::NotFound(
+                "AniList sign in is not configured".to_owned(),
+            ))
+        }
+    };
+
+    let expected = cookies
+        .get(oauth::STATE_COOKIE)
+        .map(|cookie| cookie.value().to_owned());
+    cookies.remove(
+        Cookie::build(oauth::STATE_COOKIE, "")
+            .path("/auth/anilist")
+            .finish(),
+    );
+    match (state, expected) {
+        (Some(state), Some(expected))
+            if auth::constant_time_eq(state.as_bytes(), expected.as_bytes()) => {}
+        _ => {
+            return Err(AppError::BadRequest(
+                "State does not match, start signing in again".to_owned(),
+            ))
+        }
+    }
+
+    if error.is_some() {
+        return Err(AppError::Forbidden(
+            "Authorization was denied on AniList".to_owned(),
+        ));
+    }
+    let code = match code {
+        Some(code) => code,
+        None => {
+            return Err(AppError::BadRequest(
+                "Missing authorization code".to_owned(),
+            ))
+        }
+    };
+
+    match oauth::sign_in(&config, &code, &images, &database_conn) {
+        Ok(session) => Ok(response::Legacy(session)),
+        Err(oauth::SignInError::Upstream(error)) => Err(AppError::Upstream(error)),
+        Err(oauth::SignInError::TakenDown) => Err(AppError::NotFound("User not found".to_owned())),
+        Err(error) => Err(AppError::Internal(error.to_string())),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/auth/session",
+    tag = "auth",
+    responses(
+        (status = 204, description = "Signed out, the token no longer works"),
+        (status = 401, description = "Missing or unknown session token", body = error::Problem, content_type = "application/problem+json"),
+    ),
+    security(("session_token" = []))
+)]
+#[delete("/auth/session")]
+fn sign_out(user: auth::AuthenticatedUser, database_conn: PgDbConn) -> Result<NoContent, AppError> {
+    database::delete_session(&user.session, &database_conn)?;
+    Ok(NoContent)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/auth/sessions",
+    tag = "auth",
+    responses(
+        (status = 204, description = "Every session of the user is revoked, including this one"),
+        (status = 401, description = "Missing or unknown session token", body = error::Problem, content_type = "application/problem+json"),
+    ),
+    security(("session_token" = []))
+)]
+#[delete("/auth/sessions")]
+fn sign_out_everywhere(
+    user: auth::AuthenticatedUser,
+    database_conn: PgDbConn,
+) -> Result<NoContent, AppError> {
+    database::delete_sessions(user.user_id, &database_conn)?;
+    Ok(NoContent)
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/users/{username}/takedown",
+    tag = "admin",
+    params(
+        ("username" = String, Path, description = "AniList name"),
+    ),
+    responses(
+        (status = 200, body = models::Takedown),
+        (status = 401, description = "Missing admin token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Wrong admin token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = error::Problem, content_type = "application/problem+json"),
+    ),
+    security(("admin_token" = []))
+)]
+#[put("/admin/users/<username>/takedown")]
+fn request_takedown(
+    username: String,
+    _admin: auth::Admin,
+    database_conn: PgDbConn,
+) -> Result<response::Legacy<models::Takedown>, AppError> {
+    match takedown::request(username.as_ref(), &database_conn) {
+        Some(takedown) => Ok(response::Legacy(takedown)),
+        None => Err(AppError::NotFound("User not found".to_owned())),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/users/{username}/takedown",
+    tag = "admin",
+    params(
+        ("username" = String, Path, description = "AniList name"),
+    ),
+    responses(
+        (status = 204, description = "Takedown lifted"),
+        (status = 401, description = "Missing admin token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Wrong admin token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No pending takedown", body = error::Problem, content_type = "application/problem+json"),
+    ),
+    security(("admin_token" = []))
+)]
+#[delete("/admin/users/<username>/takedown")]
+fn lift_takedown(
+    username: String,
+    _admin: auth::Admin,
     database_conn: PgDbConn,
-) -> Result<Json<models::RestResponse>, NotFound<String>> {
-    match database::get_list(username.as_ref(), &database_conn) {
-        Some(list) => Ok(Json(list)),
-        None => Err(NotFound("User or list not found".to_owned())),
+) -> Result<NoContent, AppError> {
+    if takedown::lift(username.as_ref(), &database_conn) {
+        Ok(NoContent)
+    } else {
+        Err(AppError::NotFound(
+            "No pending takedown for user".to_owned(),
+        ))
     }
 }
 
-#[post("/users/<username>")]
-fn update(username: String, database_conn: PgDbConn) -> Result<Accepted<String>, NotFound<String>> {
+#[utoipa::path(
+    post,
+    path = "/admin/users/{username}/sync",
+    tag = "admin",
+    params(
+        ("username" = String, Path, description = "AniList name"),
+    ),
+    responses(
+        (status = 202, description = "Forced sync queued, or the one already queued", body = models::Job),
+        (status = 401, description = "Missing admin token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Wrong admin token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = error::Problem, content_type = "application/problem+json"),
+    ),
+    security(("admin_token" = []))
+)]
+#[post("/admin/users/<username>/sync")]
+fn force_sync(
+    username: String,
+    _admin: auth::Admin,
+    database_conn: PgDbConn,
+) -> Result<Accepted<response::Legacy<models::Job>>, AppError> {
+    // Works from the stored user, so it doesn't depend on AniList finding the name.
+    let user = match database::get_user(username.as_ref(), &database_conn) {
+        Some(user) => user,
+        None => return Err(AppError::NotFound("User not found".to_owned())),
+    };
+    if database::get_visibility(user.name.as_ref(), &database_conn) == models::Visibility::TakenDown
+    {
+        return Err(AppError::NotFound("User not found".to_owned()));
+    }
+
+    match jobs::queue_sync(user.user_id, true, None, &database_conn) {
+        Some((job, _)) => Ok(Accepted(Some(response::Legacy(job)))),
+        None => Err(AppError::Internal("Could not queue the update".to_owned())),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/sync-failures",
+    tag = "admin",
+    params(
+        ("limit" = Option<i64>, Query, description = "Failures to return, 50 by default and 500 at most"),
+    ),
+    responses(
+        (status = 200, body = [models::SyncFailure]),
+        (status = 401, description = "Missing admin token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Wrong admin token", body = error::Problem, content_type = "application/problem+json"),
+    ),
+    security(("admin_token" = []))
+)]
+#[get("/admin/sync-failures?<limit>")]
+fn sync_failures(
+    limit: Option<i64>,
+    _admin: auth::Admin,
+    database_conn: PgDbConn,
+) -> response::Legacy<Vec<models::SyncFailure>> {
+    let limit = limit.unwrap_or(DEFAULT_FAILURES).max(1).min(MAX_FAILURES);
+    response::Legacy(jobs::recent_failures(limit, &database_conn))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/sync-errors",
+    tag = "admin",
+    params(
+        ("limit" = Option<i64>, Query, description = "Entries to return, 50 by default and 500 at most"),
+    ),
+    responses(
+        (status = 200, body = models::Quarantine),
+        (status = 401, description = "Missing admin token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Wrong admin token", body = error::Problem, content_type = "application/problem+json"),
+    ),
+    security(("admin_token" = []))
+)]
+// Plain JSON rather than Legacy, which would rename the keys of the payloads AniList sent too.
+#[get("/admin/sync-errors?<limit>")]
+fn sync_errors(
+    limit: Option<i64>,
+    _admin: auth::Admin,
+    database_conn: PgDbConn,
+) -> Result<Json<models::Quarantine>, AppError> {
+    let limit = limit.unwrap_or(DEFAULT_FAILURES).max(1).min(MAX_FAILURES);
+    database::get_quarantine(limit, &database_conn)
+        .map(Json)
+        .ok_or_else(|| AppError::Internal("Could not list quarantined entries".to_owned()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/sync-errors/reprocess",
+    tag = "admin",
+    responses(
+        (status = 201, description = "Syncs of every user with quarantined entries queued as one batch", body = models::BatchProgress),
+        (status = 401, description = "Missing admin token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Wrong admin token", body = error::Problem, content_type = "application/problem+json"),
+    ),
+    security(("admin_token" = []))
+)]
+#[post("/admin/sync-errors/reprocess")]
+fn reprocess_sync_errors(
+    _admin: auth::Admin,
+    database_conn: PgDbConn,
+) -> Result<Created<response::Legacy<models::BatchProgress>>, AppError> {
+    match scheduler::reprocess_quarantined(&database_conn)
+        .and_then(|batch_id| jobs::get_batch(batch_id, &database_conn))
+    {
+        Some(batch) => Ok(Created(
+            format!("/admin/jobs/{}", batch.batch_id),
+            Some(response::Legacy(batch)),
+        )),
+        None => Err(AppError::Internal(
+            "Could not queue the reprocessing".to_owned(),
+        )),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/sync-errors/{id}/retry",
+    tag = "admin",
+    params(
+        ("id" = i32, Path, description = "Id of the quarantined entry"),
+    ),
+    responses(
+        (status = 200, description = "Entry replayed from its stored payload, remaining holds it when it still fails", body = models::QuarantineRetry),
+        (status = 401, description = "Missing admin token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Wrong admin token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No quarantined entry with this id", body = error::Problem, content_type = "application/problem+json"),
+    ),
+    security(("admin_token" = []))
+)]
+// Plain JSON for the payloads, like sync_errors.
+#[post("/admin/sync-errors/<id>/retry")]
+fn retry_sync_error(
+    id: i32,
+    _admin: auth::Admin,
+    images: State<storage::Images>,
+    database_conn: PgDbConn,
+) -> Result<Json<models::QuarantineRetry>, AppError> {
+    match jobs::retry_quarantined(Some(id), &images, &database_conn) {
+        Some(retry) if retry.retried == 0 => {
+            Err(AppError::NotFound("Quarantined entry not found".to_owned()))
+        }
+        Some(retry) => Ok(Json(retry)),
+        None => Err(AppError::Internal(
+            "Could not retry the quarantined entry".to_owned(),
+        )),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/sync-errors/retry",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Every quarantined entry replayed from its stored payload", body = models::QuarantineRetry),
+        (status = 401, description = "Missing admin token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Wrong admin token", body = error::Problem, content_type = "application/problem+json"),
+    ),
+    security(("admin_token" = []))
+)]
+// Plain JSON for the payloads, like sync_errors.
+#[post("/admin/sync-errors/retry")]
+fn retry_sync_errors(
+    _admin: auth::Admin,
+    images: State<storage::Images>,
+    database_conn: PgDbConn,
+) -> Result<Json<models::QuarantineRetry>, AppError> {
+    jobs::retry_quarantined(None, &images, &database_conn)
+        .map(Json)
+        .ok_or_else(|| AppError::Internal("Could not retry the quarantined entries".to_owned()))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/anime/{id}",
+    tag = "admin",
+    params(
+        ("id" = i32, Path, description = "AniList id"),
+    ),
+    responses(
+        (status = 204, description = "Anime removed from every list, syncs store it again"),
+        (status = 401, description = "Missing admin token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Wrong admin token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Anime not found", body = error::Problem, content_type = "application/problem+json"),
+    ),
+    security(("admin_token" = []))
+)]
+#[delete("/admin/anime/<id>")]
+fn purge_anime(
+    id: i32,
+    _admin: auth::Admin,
+    images: State<storage::Images>,
+    database_conn: PgDbConn,
+) -> Result<NoContent, AppError> {
+    match database::purge_anime(id, &images, &database_conn) {
+        Some(user_ids) => {
+            for user_id in user_ids {
+                cache::refresh_snapshot(user_id, &database_conn);
+            }
+            Ok(NoContent)
+        }
+        None => Err(AppError::NotFound("Anime not found".to_owned())),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/anime/{id}/cover",
+    tag = "admin",
+    params(
+        ("id" = i32, Path, description = "AniList id"),
+    ),
+    responses(
+        (status = 200, description = "Cover uploaded again", body = models::AnimeDetail),
+        (status = 401, description = "Missing admin token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Wrong admin token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Anime not found", body = error::Problem, content_type = "application/problem+json"),
+        (status = 503, description = "AniList is unavailable", body = error::Problem, content_type = "application/problem+json"),
+    ),
+    security(("admin_token" = []))
+)]
+#[post("/admin/anime/<id>/cover")]
+fn reupload_cover(
+    id: i32,
+    _admin: auth::Admin,
+    images: State<storage::Images>,
+    database_conn: PgDbConn,
+) -> Result<response::Legacy<models::AnimeDetail>, AppError> {
+    match database::reupload_cover(id, &images, &database_conn) {
+        Ok(Some(anime)) => Ok(response::Legacy(anime)),
+        Ok(None) => Err(AppError::NotFound("Anime not found".to_owned())),
+        Err(error) => Err(AppError::Upstream(error)),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/jobs",
+    tag = "admin",
+    responses(
+        (status = 201, description = "Syncs of every user queued as one batch", body = models::BatchProgress),
+        (status = 401, description = "Missing admin token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Wrong admin token", body = error::Problem, content_type = "application/problem+json"),
+    ),
+    security(("admin_token" = []))
+)]
+#[post("/admin/jobs")]
+fn refresh_all(
+    _admin: auth::Admin,
+    database_conn: PgDbConn,
+) -> Result<Created<response::Legacy<models::BatchProgress>>, AppError> {
+    match scheduler::refresh_all(&database_conn)
+        .and_then(|batch_id| jobs::get_batch(batch_id, &database_conn))
+    {
+        Some(batch) => Ok(Created(
+            format!("/admin/jobs/{}", batch.batch_id),
+            Some(response::Legacy(batch)),
+        )),
+        None => Err(AppError::Internal("Could not queue the refresh".to_owned())),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/jobs/{batch_id}",
+    tag = "admin",
+    params(
+        ("batch_id" = i32, Path),
+    ),
+    responses(
+        (status = 200, body = models::BatchProgress),
+        (status = 401, description = "Missing admin token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Wrong admin token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Batch not found", body = error::Problem, content_type = "application/problem+json"),
+    ),
+    security(("admin_token" = []))
+)]
+#[get("/admin/jobs/<batch_id>")]
+fn batch(
+    batch_id: i32,
+    _admin: auth::Admin,
+    database_conn: PgDbConn,
+) -> Result<response::Legacy<models::BatchProgress>, AppError> {
+    match jobs::get_batch(batch_id, &database_conn) {
+        Some(batch) => Ok(response::Legacy(batch)),
+        None => Err(AppError::NotFound("Batch not found".to_owned())),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/jobs/{job_id}",
+    tag = "sync",
+    params(
+        ("job_id" = i32, Path),
+    ),
+    responses(
+        (status = 200, body = models::Job),
+        (status = 404, description = "Job not found", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/jobs/<job_id>")]
+fn job(job_id: i32, database_conn: PgDbConn) -> Result<response::Legacy<models::Job>, AppError> {
+    match jobs::get_job(job_id, &database_conn) {
+        Some(job) => Ok(response::Legacy(job)),
+        None => Err(AppError::NotFound("Job not found".to_owned())),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{username}/sync-preview",
+    tag = "sync",
+    params(
+        ("username" = String, Path, description = "AniList name"),
+    ),
+    responses(
+        (status = 200, description = "What a sync would change", body = models::SyncPreview),
+        (status = 404, description = "User not found", body = error::Problem, content_type = "application/problem+json"),
+        (status = 503, description = "AniList is unavailable", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/users/<username>/sync-preview")]
+fn sync_preview(
+    username: String,
+    database_conn: PgDbConn,
+) -> Result<response::Legacy<models::SyncPreview>, AppError> {
     match anilist_query::get_id(username.as_ref()) {
-        Some(user) => {
-            database::update_user_profile(user.clone(), &database_conn);
-            thread::spawn(move || database::update_entries(user.id));
-            Ok(Accepted(Some("Added to the queue".to_owned())))
+        Ok(Some(user)) => database::preview_entries(user.id, &database_conn)
+            .map(response::Legacy)
+            .map_err(AppError::Upstream),
+        Ok(None) => Err(AppError::NotFound("User not found".to_owned())),
+        Err(error) => Err(AppError::Upstream(error)),
+    }
+}
+
+#[post("/graphql", format = "json", data = "<request>")]
+fn graphql(
+    request: Json<async_graphql::Request>,
+    schema: State<graphql::ApiSchema>,
+    database_conn: PgDbConn,
+) -> Json<async_graphql::Response> {
+    Json(graphql::execute(
+        &schema,
+        request.into_inner(),
+        database_conn,
+    ))
+}
+
+#[get("/graphql")]
+fn graphiql() -> Content<String> {
+    Content(ContentType::HTML, graphql::graphiql())
+}
+
+#[get("/openapi.json")]
+fn openapi_spec() -> Content<String> {
+    Content(ContentType::JSON, openapi::spec())
+}
+
+#[get("/docs")]
+fn docs() -> Content<String> {
+    Content(ContentType::HTML, openapi::swagger_ui())
+}
+
+#[utoipa::path(
+    get,
+    path = "/status",
+    tag = "meta",
+    responses(
+        (status = 200, description = "Version, AniList health and the features this instance runs", body = models::ServiceStatus),
+    )
+)]
+#[get("/status")]
+fn status(features: State<features::FeatureFlags>) -> response::Legacy<models::ServiceStatus> {
+    response::Legacy(models::ServiceStatus {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        upstream_status: anilist_query::upstream_status(),
+        features: *features,
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "meta",
+    responses(
+        (status = 200, description = "Gauges of the connection pool in Prometheus' text format", body = String, content_type = "text/plain"),
+    )
+)]
+#[get("/metrics")]
+fn metrics(pool: State<pool::Pool>) -> Content<String> {
+    let state = pool.state();
+    let gauges = [
+        (
+            "anihistory_db_pool_max",
+            "Connections the pool may open",
+            state.max,
+        ),
+        (
+            "anihistory_db_pool_connections",
+            "Connections open",
+            state.connections,
+        ),
+        (
+            "anihistory_db_pool_idle",
+            "Open connections waiting for a request",
+            state.idle,
+        ),
+        (
+            "anihistory_db_pool_active",
+            "Connections held by requests",
+            state.active(),
+        ),
+    ];
+    let body: String = gauges
+        .iter()
+        .map(|(name, help, value)| {
+            format!(
+                "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n",
+                name = name,
+                help = help,
+                value = value
+            )
+        })
+        .collect();
+    Content(ContentType::Plain, body)
+}
+
+#[get("/robots.txt")]
+fn robots() -> Content<String> {
+    Content(ContentType::Plain, crawlers::robots())
+}
+
+#[get("/meta/users/<username>")]
+fn user_meta(username: String, database_conn: PgDbConn) -> Result<Content<String>, AppError> {
+    crawlers::user_meta(username.as_ref(), &database_conn)
+        .map(|html| Content(ContentType::HTML, html))
+        .ok_or_else(|| AppError::NotFound("User not found".to_owned()))
+}
+
+#[get("/meta/anime/<slug>")]
+fn anime_meta(slug: String, database_conn: PgDbConn) -> Result<Content<String>, AppError> {
+    crawlers::anime_meta(slug.as_ref(), &database_conn)
+        .map(|html| Content(ContentType::HTML, html))
+        .ok_or_else(|| AppError::NotFound("Anime not found".to_owned()))
+}
+
+#[get("/sitemap.xml")]
+fn sitemap(database_conn: PgDbConn) -> Result<Content<String>, AppError> {
+    match sitemap::root(&database_conn) {
+        Some(xml) => Ok(Content(ContentType::XML, xml)),
+        None => Err(AppError::Internal("Could not build sitemap".to_owned())),
+    }
+}
+
+#[get("/sitemaps/<section>/<file>")]
+fn sitemap_page(
+    section: String,
+    file: String,
+    database_conn: PgDbConn,
+) -> Result<Content<String>, AppError> {
+    let page = file
+        .trim_end_matches(".xml")
+        .parse::<i64>()
+        .ok()
+        .filter(|page| *page >= 1);
+
+    match (sitemap::Section::parse(section.as_ref()), page) {
+        (Some(section), Some(page)) => sitemap::page(section, page, &database_conn)
+            .map(|xml| Content(ContentType::XML, xml))
+            .ok_or_else(|| AppError::NotFound("Sitemap not found".to_owned())),
+        _ => Err(AppError::NotFound("Sitemap not found".to_owned())),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/anime/search",
+    tag = "anime",
+    params(
+        ("q" = String, Query),
+    ),
+    responses(
+        (status = 200, body = [models::SearchResult]),
+    )
+)]
+#[get("/anime/search?<q>")]
+fn search(q: String, database_conn: PgDbConn) -> response::Legacy<Vec<models::SearchResult>> {
+    response::Legacy(database::search_anime(q.as_ref(), &database_conn))
+}
+
+#[utoipa::path(
+    get,
+    path = "/search/remote",
+    tag = "anime",
+    params(
+        ("q" = String, Query),
+    ),
+    responses(
+        (status = 200, description = "AniList search results", body = [models::RemoteSearchResult]),
+        (status = 429, description = "Too many uncached searches", body = error::Problem, content_type = "application/problem+json"),
+        (status = 503, description = "AniList is unavailable", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/search/remote?<q>")]
+fn search_remote(
+    q: String,
+    database_conn: PgDbConn,
+) -> Result<response::Legacy<Vec<models::RemoteSearchResult>>, AppError> {
+    match remote_search::search(q.as_ref(), &database_conn) {
+        Ok(results) => Ok(response::Legacy(results)),
+        Err(remote_search::RemoteSearchError::RateLimited) => Err(AppError::RateLimited(
+            remote_search::RemoteSearchError::RateLimited.to_string(),
+            None,
+        )),
+        Err(remote_search::RemoteSearchError::Upstream(error)) => Err(AppError::Upstream(error)),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/anime/{slug}",
+    tag = "anime",
+    params(
+        ("slug" = String, Path, description = "Slug or AniList id"),
+    ),
+    responses(
+        (status = 200, body = models::AnimeDetail),
+        (status = 404, description = "Anime not found", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/anime/<slug>")]
+fn anime(
+    slug: String,
+    database_conn: PgDbConn,
+) -> Result<response::Legacy<models::AnimeDetail>, AppError> {
+    match database::get_anime(slug.as_ref(), &database_conn) {
+        Some(anime) => Ok(response::Legacy(anime)),
+        None => Err(AppError::NotFound("Anime not found".to_owned())),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/anime/{id}/ingest",
+    tag = "anime",
+    params(
+        ("id" = i32, Path, description = "AniList id"),
+    ),
+    responses(
+        (status = 200, body = models::AnimeDetail),
+        (status = 404, description = "Anime not found on AniList", body = error::Problem, content_type = "application/problem+json"),
+        (status = 503, description = "AniList is unavailable", body = error::Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/anime/<id>/ingest")]
+fn ingest_anime(
+    id: i32,
+    images: State<storage::Images>,
+    database_conn: PgDbConn,
+) -> Result<response::Legacy<models::AnimeDetail>, AppError> {
+    match database::ingest_anime(id, &images, &database_conn) {
+        Ok(Some(anime)) => Ok(response::Legacy(anime)),
+        Ok(None) => Err(AppError::NotFound("Anime not found on AniList".to_owned())),
+        Err(error) => Err(AppError::Upstream(error)),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/users/{username}/subscription",
+    tag = "subscriptions",
+    params(
+        ("username" = String, Path, description = "AniList name"),
+    ),
+    responses(
+        (status = 201, description = "Subscribed"),
+        (status = 401, description = "Missing or unknown session token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "User's list is private", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "User not found", body = error::Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Subscription could not be saved", body = error::Problem, content_type = "application/problem+json"),
+    ),
+    security(("session_token" = []))
+)]
+#[post("/users/<username>/subscription")]
+fn subscribe(
+    username: String,
+    subscriber: auth::AuthenticatedUser,
+    database_conn: PgDbConn,
+) -> Result<Created<String>, AppError> {
+    // Subscribing reveals no more than the profile itself would.
+    let target = profile_user(&username, &database_conn)?;
+    database::add_subscription(subscriber.user_id, target.user_id, &database_conn)?;
+    Ok(Created(
+        format!("/users/{}/subscription", target.name),
+        Some("Subscribed".to_owned()),
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/users/{username}/subscription",
+    tag = "subscriptions",
+    params(
+        ("username" = String, Path, description = "AniList name"),
+    ),
+    responses(
+        (status = 204, description = "Unsubscribed"),
+        (status = 401, description = "Missing or unknown session token", body = error::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "User or subscription not found", body = error::Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Subscription could not be removed", body = error::Problem, content_type = "application/problem+json"),
+    ),
+    security(("session_token" = []))
+)]
+#[delete("/users/<username>/subscription")]
+fn unsubscribe(
+    username: String,
+    subscriber: auth::AuthenticatedUser,
+    database_conn: PgDbConn,
+) -> Result<NoContent, AppError> {
+    match database::get_user(username.as_ref(), &database_conn) {
+        Some(target) => {
+            if database::remove_subscription(subscriber.user_id, target.user_id, &database_conn)? {
+                Ok(NoContent)
+            } else {
+                Err(AppError::NotFound("Subscription not found".to_owned()))
+            }
         }
-        None => Err(NotFound("User not found".to_owned())),
+        None => Err(AppError::NotFound("User not found".to_owned())),
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/subscriptions",
+    tag = "subscriptions",
+    responses(
+        (status = 200, description = "Users the caller is subscribed to", body = [models::User]),
+        (status = 401, description = "Missing or unknown session token", body = error::Problem, content_type = "application/problem+json")
+    ),
+    security(("session_token" = []))
+)]
+#[get("/subscriptions")]
+fn subscriptions(
+    subscriber: auth::AuthenticatedUser,
+    database_conn: PgDbConn,
+) -> response::Legacy<Vec<models::User>> {
+    response::Legacy(database::get_subscriptions(
+        subscriber.user_id,
+        &database_conn,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/subscriptions",
+    tag = "subscriptions",
+    responses(
+        (status = 200, description = "Users the caller is subscribed to", body = response::UserPage),
+        (status = 401, description = "Missing or unknown session token", body = error::Problem, content_type = "application/problem+json")
+    ),
+    security(("session_token" = []))
+)]
+#[get("/subscriptions")]
+fn subscriptions_v1(
+    subscriber: auth::AuthenticatedUser,
+    database_conn: PgDbConn,
+) -> Json<response::Envelope<Vec<models::User>>> {
+    let users = database::get_subscriptions(subscriber.user_id, &database_conn);
+    let total = users.len() as i64;
+    Json(response::Envelope::new(users, "/v1/subscriptions".to_owned()).paginated(1, total, total))
+}
+
 fn main() -> Result<(), Error> {
-    if setup_logger().is_err() {
+    if logging::setup().is_err() {
         std::process::abort()
     }
+    let args: Vec<String> = env::args().skip(1).collect();
+    #[cfg(feature = "bench")]
+    {
+        if args.first().map(String::as_str) == Some("bench") {
+            if let Err(error) = bench::use_scratch_database() {
+                log::error!("refusing to benchmark: {}", error);
+                std::process::exit(1);
+            }
+        }
+    }
+    let settings = match config::load() {
+        Ok(settings) => settings,
+        Err(error) => {
+            log::error!("refusing to start: {}", error);
+            std::process::exit(1);
+        }
+    };
 
-    let allowed_origins = AllowedOrigins::some_exact(&[
-        "http://localhost:4200",
-        "https://anihistory.moe",
-        "https://www.anihistory.moe",
-    ]);
+    match args.first().map(String::as_str) {
+        Some("export") => exit_with(dump::export(
+            flag_value(&args, "--out").unwrap_or(DEFAULT_DUMP_PATH),
+            settings,
+        )),
+        Some("import") => exit_with(dump::import(
+            flag_value(&args, "--in").unwrap_or(DEFAULT_DUMP_PATH),
+            settings,
+        )),
+        _ => {}
+    }
+
+    // --migrate-only lets a deploy migrate in its own step before any instance is replaced.
+    let connection = database::establish_connection(settings);
+    if let Err(error) = migrations::migrate(&connection) {
+        log::error!(
+            "refusing to start: migrating the database failed: {}",
+            error
+        );
+        std::process::exit(1);
+    }
+    if args.iter().any(|arg| arg == "--migrate-only") {
+        std::process::exit(0);
+    }
+
+    let allow_newer_db = args.iter().any(|arg| arg == "--allow-newer-db");
+    let schema_check = migrations::check_schema_version(&connection, allow_newer_db);
+    if let Err(error) = schema_check {
+        log::error!("refusing to start: {}", error);
+        std::process::exit(1);
+    }
+
+    #[cfg(feature = "bench")]
+    {
+        if args.first().map(String::as_str) == Some("bench") {
+            let options = bench::Options::from_args(&args);
+            match server(
+                warmup::ProfileHits::default(),
+                features::FeatureFlags::from_env(),
+                storage::from_settings(settings),
+                settings,
+            ) {
+                Ok(server) => exit_with_bench(bench::run(server, &options, &connection)),
+                Err(error) => {
+                    log::error!("refusing to benchmark: {}", error);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
+    let mode = flag_value(&args, "--mode").or_else(|| flag_value(&args, "--role"));
+    let role = match Role::parse(mode.unwrap_or("all")) {
+        Some(role) => role,
+        None => {
+            log::error!("--mode must be one of api, worker or all");
+            std::process::exit(1);
+        }
+    };
+
+    let features = features::FeatureFlags::from_env();
+    log::info!("features: {:?}", features);
+
+    let images = storage::from_settings(settings);
+    shutdown::listen(settings);
+    if role != Role::Api {
+        jobs::start_workers(
+            config::env_value("WORKER_CONCURRENCY", DEFAULT_WORKER_CONCURRENCY).max(1),
+            images.clone(),
+            settings,
+        );
+        if features.scheduler {
+            scheduler::start(settings);
+        }
+        takedown::start_purger(images.clone(), settings);
+        uploads::start_retrier(images.clone(), settings);
+    }
+
+    if role == Role::Worker {
+        loop {
+            thread::sleep(Duration::from_secs(60));
+        }
+    }
+
+    let hits = warmup::ProfileHits::default();
+    warmup::start(hits.clone(), settings);
+
+    server(hits, features, images, settings)?.launch();
+    Ok(())
+}
+
+// Everything that answers requests, without the background threads.
+fn server(
+    hits: warmup::ProfileHits,
+    features: features::FeatureFlags,
+    images: storage::Images,
+    settings: &config::Settings,
+) -> Result<rocket::Rocket, Error> {
+    let allowed_origins = AllowedOrigins::some_exact(settings.cors_origins.as_slice());
 
     // You can also deserialize this
     let cors = rocket_cors::CorsOptions {
         allowed_origins,
-        allowed_methods: vec![Method::Get, Method::Post]
+        allowed_methods: vec![Method::Get, Method::Post, Method::Put, Method::Delete]
             .into_iter()
             .map(From::from)
             .collect(),
@@ -77,30 +2348,124 @@ fn main() -> Result<(), Error> {
     }
     .to_cors()?;
 
-    rocket::ignite()
+    let mut server = rocket::ignite()
         .mount("/", StaticFiles::from("static"))
-        .mount("/", routes![update, user])
+        .mount(
+            "/",
+            routes![
+                update,
+                update_batch,
+                users,
+                user,
+                sync_preview,
+                user_range,
+                user_stats,
+                history,
+                timeline,
+                activity,
+                changes,
+                global_stats,
+                covers,
+                export,
+                feed,
+                calendar,
+                similar_users,
+                recommendations,
+                compare,
+                job,
+                robots,
+                user_meta,
+                anime_meta,
+                sitemap,
+                sitemap_page,
+                search,
+                search_remote,
+                anime,
+                ingest_anime,
+                profile_slug,
+                preferences,
+                set_preferences,
+                hide_entry,
+                subscribe,
+                unsubscribe,
+                subscriptions,
+                request_takedown,
+                lift_takedown,
+                delete_user,
+                refresh_all,
+                batch,
+                force_sync,
+                sync_failures,
+                sync_errors,
+                reprocess_sync_errors,
+                retry_sync_error,
+                retry_sync_errors,
+                purge_anime,
+                reupload_cover,
+                openapi_spec,
+                docs,
+                status,
+                metrics
+            ],
+        )
+        .mount("/v1", routes![user_v1, subscriptions_v1]);
+    if features.oauth {
+        server = server.mount(
+            "/",
+            routes![
+                anilist_login,
+                anilist_callback,
+                sign_out,
+                sign_out_everywhere
+            ],
+        );
+    }
+    if features.webhooks {
+        server = server.mount("/", routes![register_webhook, remove_webhook]);
+    }
+    if features.graphql {
+        server = server.mount("/", routes![graphql, graphiql]);
+    }
+
+    Ok(server
+        .register(error::catchers())
+        .attach(AdHoc::on_request("Request ID", request_id::on_request))
+        .attach(AdHoc::on_response("Request ID", request_id::on_response))
         .attach(cors)
-        .attach(PgDbConn::fairing())
-        .launch();
+        .attach(AdHoc::on_response("Cache-Control", crawlers::cache_control))
+        .manage(pool::connect(settings))
+        .manage(settings.clone())
+        .manage(graphql::schema())
+        .manage(rate_limit::UpdateLimiter::from_env())
+        .manage(features)
+        .manage(images)
+        .manage(hits))
+}
 
-    Ok(())
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|index| args.get(index + 1))
+        .map(String::as_str)
 }
 
-fn setup_logger() -> Result<(), fern::InitError> {
-    fern::Dispatch::new()
-        .format(move |out, message, record| {
-            out.finish(format_args!(
-                "{}[{}][{}] {}",
-                chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
-                record.level(),
-                record.target(),
-                message
-            ))
-        })
-        .level(log::LevelFilter::Info)
-        .chain(std::io::stdout())
-        .chain(fern::log_file("trx.log")?)
-        .apply()?;
-    Ok(())
+#[cfg(feature = "bench")]
+fn exit_with_bench(result: Result<(), bench::BenchError>) -> ! {
+    match result {
+        Ok(_) => std::process::exit(0),
+        Err(error) => {
+            log::error!("benchmark failed: {}", error);
+            std::process::exit(1)
+        }
+    }
+}
+
+fn exit_with(result: Result<(), dump::DumpError>) -> ! {
+    match result {
+        Ok(_) => std::process::exit(0),
+        Err(error) => {
+            log::error!("{}", error);
+            std::process::exit(1)
+        }
+    }
 }
@@ -0,0 +1,72 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use serde_derive::Deserialize;
+
+// Jikan's profile of a MyAnimeList user.
+#[derive(Deserialize)]
+pub struct UserResponse {
+    pub data: User,
+}
+
+#[derive(Deserialize)]
+pub struct User {
+    pub mal_id: i32,
+    pub username: String,
+    pub images: Images,
+}
+
+#[derive(Deserialize)]
+pub struct Images {
+    pub jpg: Image,
+}
+
+#[derive(Deserialize)]
+pub struct Image {
+    pub image_url: Option<String>,
+}
+
+// A page of a user's anime list from the MyAnimeList API.
+#[derive(Deserialize)]
+pub struct ListResponse {
+    pub data: Vec<ListNode>,
+    pub paging: Paging,
+}
+
+#[derive(Deserialize)]
+pub struct Paging {
+    pub next: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ListNode {
+    pub node: Anime,
+    pub list_status: ListStatus,
+}
+
+#[derive(Deserialize)]
+pub struct Anime {
+    pub id: i32,
+}
+
+#[derive(Deserialize)]
+pub struct ListStatus {
+    // watching, completed, on_hold, dropped or plan_to_watch.
+    pub status: Option<String>,
+    // 0 to 10, 0 when the user gave no score.
+    pub score: Option<i16>,
+    pub num_episodes_watched: Option<i32>,
+    pub num_times_rewatched: Option<i32>,
+    // RFC 3339.
+    pub updated_at: Option<String>,
+    // YYYY-MM-DD, or only the year and month when the user left out the rest.
+    pub start_date: Option<String>,
+    pub finish_date: Option<String>,
+    // Empty when the user left none.
+    pub comments: Option<String>,
+}
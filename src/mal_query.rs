@@ -0,0 +1,194 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Lists from MyAnimeList. Profiles come from Jikan, which finds users by name, and lists from
+// MyAnimeList's own API, which needs MAL_CLIENT_ID. Entries are turned into AniList's shape with
+// AniList's data of each anime, so everything after fetching works the same for both sources.
+// Anime AniList doesn't know are left out.
+
+use crate::anilist_query::{self, AnilistError};
+use crate::{anilist_models, config, mal_models};
+use chrono::DateTime;
+use log::warn;
+use reqwest::StatusCode;
+use serde_json::from_str;
+use std::collections::HashMap;
+use std::time::Instant;
+
+const JIKAN_URL: &str = "https://api.jikan.moe/v4";
+
+const MAL_API_URL: &str = "https://api.myanimelist.net/v2";
+
+// The most MyAnimeList returns per page.
+const PER_PAGE: i32 = 1000;
+
+// Guards against paging that never ends.
+const MAX_PAGES: usize = 50;
+
+// The user is returned under the id they are stored with, see providers::user_id.
+pub fn get_user(username: &str) -> Result<Option<mal_models::User>, AnilistError> {
+    let response = anilist_query::client()
+        .get(&format!("{}/users/{}", JIKAN_URL, username))
+        .send()
+        .map_err(AnilistError::Unreachable)?;
+    match response.status() {
+        StatusCode::NOT_FOUND => Ok(None),
+        status if !status.is_success() => Err(AnilistError::Mal(format!(
+            "Jikan responded with status {}",
+            status
+        ))),
+        _ => {
+            let res_text = response.text().map_err(AnilistError::Unreachable)?;
+            let json: mal_models::UserResponse =
+                from_str(res_text.as_ref()).map_err(AnilistError::InvalidResponse)?;
+            Ok(Some(json.data))
+        }
+    }
+}
+
+pub fn get_lists(
+    username: &str,
+    deadline: Option<Instant>,
+) -> Result<Vec<anilist_models::MediaList>, AnilistError> {
+    let client_id = match &config::settings().mal_client_id {
+        Some(client_id) => client_id,
+        None => return Err(AnilistError::Mal("MAL_CLIENT_ID is not set".to_owned())),
+    };
+
+    let mut nodes = Vec::new();
+    let mut url = Some(format!(
+        "{}/users/{}/animelist?fields=list_status{{start_date,finish_date,num_times_rewatched,comments}}&nsfw=true&limit={}",
+        MAL_API_URL, username, PER_PAGE
+    ));
+    for _ in 0..MAX_PAGES {
+        let page_url = match url.take() {
+            Some(page_url) => page_url,
+            None => break,
+        };
+        if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+            return Err(AnilistError::DeadlineExceeded);
+        }
+        let response = anilist_query::client()
+            .get(&page_url)
+            .header("X-MAL-CLIENT-ID", client_id.as_str())
+            .send()
+            .map_err(AnilistError::Unreachable)?;
+        match response.status() {
+            StatusCode::FORBIDDEN => return Err(AnilistError::PrivateList),
+            status if !status.is_success() => {
+                return Err(AnilistError::Mal(format!(
+                    "MyAnimeList responded with status {}",
+                    status
+                )))
+            }
+            _ => (),
+        }
+        let res_text = response.text().map_err(AnilistError::Unreachable)?;
+        let json: mal_models::ListResponse =
+            from_str(res_text.as_ref()).map_err(AnilistError::InvalidResponse)?;
+        nodes.extend(json.data);
+        url = json.paging.next;
+    }
+
+    let mal_ids: Vec<i32> = nodes.iter().map(|node| node.node.id).collect();
+    let media: HashMap<i32, anilist_models::Media> =
+        anilist_query::get_media_by_mal_ids(&mal_ids, deadline)?
+            .into_iter()
+            .filter_map(|media| media.id_mal.map(|id_mal| (id_mal, media)))
+            .collect();
+
+    let mut lists: Vec<anilist_models::MediaList> = Vec::new();
+    for node in nodes {
+        let media = match media.get(&node.node.id) {
+            Some(media) => media.clone(),
+            None => {
+                warn!(
+                    "mal_id={} on the list of mal_user={} is unknown to AniList",
+                    node.node.id, username
+                );
+                continue;
+            }
+        };
+        let entry = entry(node.list_status, media);
+        let name = list_name(entry.status.as_deref());
+        match lists.iter_mut().find(|list| list.name == name) {
+            Some(list) => list.entries.push(entry),
+            None => lists.push(anilist_models::MediaList {
+                name: name.to_owned(),
+                is_custom_list: false,
+                entries: vec![entry],
+            }),
+        }
+    }
+    Ok(lists)
+}
+
+fn entry(
+    list_status: mal_models::ListStatus,
+    media: anilist_models::Media,
+) -> anilist_models::Entry {
+    anilist_models::Entry {
+        // AniList's raw scores go up to 100.
+        score_raw: list_status.score.map(|score| score * 10),
+        status: list_status
+            .status
+            .as_deref()
+            .and_then(status)
+            .map(str::to_owned),
+        progress: list_status.num_episodes_watched,
+        repeat: list_status.num_times_rewatched,
+        updated_at: list_status
+            .updated_at
+            .and_then(|updated_at| DateTime::parse_from_rfc3339(&updated_at).ok())
+            .map(|updated_at| updated_at.timestamp()),
+        started_at: date(list_status.start_date.as_deref()),
+        completed_at: date(list_status.finish_date.as_deref()),
+        notes: list_status.comments,
+        // MyAnimeList has no custom lists.
+        custom_lists: None,
+        // Lists from MyAnimeList are read without the user's token, so nothing is private.
+        private: None,
+        hidden_from_status_lists: None,
+        media,
+    }
+}
+
+// AniList's status for MyAnimeList's.
+fn status(status: &str) -> Option<&'static str> {
+    match status {
+        "watching" => Some("CURRENT"),
+        "completed" => Some("COMPLETED"),
+        "on_hold" => Some("PAUSED"),
+        "dropped" => Some("DROPPED"),
+        "plan_to_watch" => Some("PLANNING"),
+        _ => None,
+    }
+}
+
+// The name AniList gives the list of entries with the status.
+fn list_name(status: Option<&str>) -> &'static str {
+    match status {
+        Some("CURRENT") => "Watching",
+        Some("COMPLETED") => "Completed",
+        Some("PAUSED") => "Paused",
+        Some("DROPPED") => "Dropped",
+        _ => "Planning",
+    }
+}
+
+fn date(date: Option<&str>) -> anilist_models::Date {
+    let mut parts = date
+        .unwrap_or("")
+        .split('-')
+        .map(|part| part.parse::<i32>().ok());
+    anilist_models::Date {
+        year: parts.next().flatten(),
+        month: parts.next().flatten(),
+        day: parts.next().flatten(),
+    }
+}
@@ -0,0 +1,277 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Serialized list responses of the most requested users, kept in process so popular profiles skip
+// both the snapshot and the list join. Entries remember the ETag they were built for and are only
+// served while it is still the current one, which covers syncs run by other processes. Syncs in
+// this process drop the user's entries right away. LIST_CACHE_USERS bounds how many users are kept,
+// 0 turns the cache off, and LIST_CACHE_BYTES bounds the size of all cached bodies together, so a
+// few very large lists can't take up the memory. Other backends, like one shared between
+// instances, go behind ListCache.
+
+use crate::config;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+const DEFAULT_USERS: usize = 200;
+
+const DEFAULT_BYTES: usize = 64 * 1024 * 1024;
+
+// Different query strings kept per user, the least recently used goes first.
+const QUERIES_PER_USER: usize = 8;
+
+pub trait ListCache: Send + Sync {
+    // The body cached for the query, if it was built for the given ETag.
+    fn get(&self, user_id: i32, query: &str, etag: &str) -> Option<Vec<u8>>;
+    fn put(&self, user_id: i32, query: &str, etag: &str, body: &[u8]);
+    fn invalidate(&self, user_id: i32);
+}
+
+struct Entry {
+    etag: String,
+    body: Vec<u8>,
+    used_at: Instant,
+}
+
+#[derive(Default)]
+struct UserEntries {
+    queries: HashMap<String, Entry>,
+}
+
+impl UserEntries {
+    fn used_at(&self) -> Option<Instant> {
+        self.queries.values().map(|entry| entry.used_at).max()
+    }
+}
+
+#[derive(Default)]
+struct Entries {
+    users: HashMap<i32, UserEntries>,
+    // Length of all cached bodies together.
+    bytes: usize,
+}
+
+impl Entries {
+    fn remove_query(&mut self, user_id: i32, query: &str) {
+        if let Some(user) = self.users.get_mut(&user_id) {
+            if let Some(entry) = user.queries.remove(query) {
+                self.bytes -= entry.body.len();
+            }
+            if user.queries.is_empty() {
+                self.users.remove(&user_id);
+            }
+        }
+    }
+
+    fn remove_user(&mut self, user_id: i32) {
+        if let Some(user) = self.users.remove(&user_id) {
+            self.bytes -= user
+                .queries
+                .values()
+                .map(|entry| entry.body.len())
+                .sum::<usize>();
+        }
+    }
+
+    // The least recently used query of any user.
+    fn least_recent_query(&self) -> Option<(i32, String)> {
+        self.users
+            .iter()
+            .flat_map(|(user_id, user)| {
+                user.queries
+                    .iter()
+                    .map(move |(query, entry)| (entry.used_at, *user_id, query))
+            })
+            .min()
+            .map(|(_, user_id, query)| (user_id, query.clone()))
+    }
+}
+
+pub struct InMemory {
+    users: usize,
+    max_bytes: usize,
+    entries: Mutex<Entries>,
+}
+
+impl InMemory {
+    pub fn new(users: usize, max_bytes: usize) -> InMemory {
+        InMemory {
+            users,
+            max_bytes,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+}
+
+impl ListCache for InMemory {
+    fn get(&self, user_id: i32, query: &str, etag: &str) -> Option<Vec<u8>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.users.get_mut(&user_id)?.queries.get_mut(query)?;
+        if entry.etag != etag {
+            entries.remove_query(user_id, query);
+            return None;
+        }
+        entry.used_at = Instant::now();
+        Some(entry.body.clone())
+    }
+
+    // Bodies larger than the whole cache aren't kept.
+    fn put(&self, user_id: i32, query: &str, etag: &str, body: &[u8]) {
+        if self.users == 0 || body.len() > self.max_bytes {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove_query(user_id, query);
+        let user = entries.users.entry(user_id).or_default();
+        user.queries.insert(
+            query.to_owned(),
+            Entry {
+                etag: etag.to_owned(),
+                body: body.to_vec(),
+                used_at: Instant::now(),
+            },
+        );
+        let oldest_query = if user.queries.len() > QUERIES_PER_USER {
+            least_recent(&user.queries, |entry| Some(entry.used_at))
+        } else {
+            None
+        };
+        entries.bytes += body.len();
+        if let Some(oldest) = oldest_query {
+            entries.remove_query(user_id, &oldest);
+        }
+
+        if entries.users.len() > self.users {
+            if let Some(oldest) = least_recent(&entries.users, UserEntries::used_at) {
+                entries.remove_user(oldest);
+            }
+        }
+        while entries.bytes > self.max_bytes {
+            match entries.least_recent_query() {
+                Some((user_id, query)) => entries.remove_query(user_id, &query),
+                None => break,
+            }
+        }
+    }
+
+    fn invalidate(&self, user_id: i32) {
+        self.entries.lock().unwrap().remove_user(user_id);
+    }
+}
+
+fn least_recent<K: Clone, V>(
+    map: &HashMap<K, V>,
+    used_at: impl Fn(&V) -> Option<Instant>,
+) -> Option<K> {
+    map.iter()
+        .min_by_key(|(_, value)| used_at(value))
+        .map(|(key, _)| key.clone())
+}
+
+static CACHE: OnceLock<Box<dyn ListCache>> = OnceLock::new();
+
+fn cache() -> &'static dyn ListCache {
+    CACHE
+        .get_or_init(|| {
+            Box::new(InMemory::new(
+                config::env_value("LIST_CACHE_USERS", DEFAULT_USERS),
+                config::env_value("LIST_CACHE_BYTES", DEFAULT_BYTES),
+            ))
+        })
+        .as_ref()
+}
+
+pub fn get(user_id: i32, query: &str, etag: &str) -> Option<Vec<u8>> {
+    cache().get(user_id, query, etag)
+}
+
+pub fn put(user_id: i32, query: &str, etag: &str, body: &[u8]) {
+    cache().put(user_id, query, etag, body)
+}
+
+pub fn invalidate(user_id: i32) {
+    cache().invalidate(user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    // Lets entries used one after the other tell which was used last.
+    fn tick() {
+        thread::sleep(Duration::from_millis(2));
+    }
+
+    fn bytes(cache: &InMemory) -> usize {
+        cache.entries.lock().unwrap().bytes
+    }
+
+    #[test]
+    fn serves_bodies_for_their_etag_only() {
+        let cache = InMemory::new(10, 1024);
+        cache.put(1, "", "v1", b"list");
+        assert_eq!(cache.get(1, "", "v1"), Some(b"list".to_vec()));
+        assert_eq!(cache.get(1, "", "v2"), None);
+        assert_eq!(cache.get(1, "", "v1"), None);
+        assert_eq!(bytes(&cache), 0);
+    }
+
+    #[test]
+    fn evicts_the_least_recent_bodies_past_the_byte_bound() {
+        let cache = InMemory::new(10, 100);
+        cache.put(1, "", "v1", &[0; 40]);
+        tick();
+        cache.put(2, "", "v1", &[0; 40]);
+        tick();
+        assert!(cache.get(1, "", "v1").is_some());
+        tick();
+        cache.put(3, "", "v1", &[0; 40]);
+
+        assert!(cache.get(1, "", "v1").is_some());
+        assert!(cache.get(2, "", "v1").is_none());
+        assert!(cache.get(3, "", "v1").is_some());
+        assert_eq!(bytes(&cache), 80);
+    }
+
+    #[test]
+    fn skips_bodies_larger_than_the_cache() {
+        let cache = InMemory::new(10, 100);
+        cache.put(1, "", "v1", &[0; 40]);
+        cache.put(2, "", "v1", &[0; 101]);
+        assert!(cache.get(1, "", "v1").is_some());
+        assert!(cache.get(2, "", "v1").is_none());
+        assert_eq!(bytes(&cache), 40);
+    }
+
+    #[test]
+    fn replacing_and_invalidating_give_back_their_bytes() {
+        let cache = InMemory::new(10, 100);
+        cache.put(1, "", "v1", &[0; 40]);
+        cache.put(1, "", "v2", &[0; 30]);
+        assert_eq!(bytes(&cache), 30);
+        cache.put(1, "?sort=score", "v2", &[0; 20]);
+        assert_eq!(bytes(&cache), 50);
+        cache.invalidate(1);
+        assert_eq!(bytes(&cache), 0);
+    }
+
+    #[test]
+    fn keeps_at_most_the_given_users() {
+        let cache = InMemory::new(1, 100);
+        cache.put(1, "", "v1", &[0; 10]);
+        tick();
+        cache.put(2, "", "v1", &[0; 10]);
+        assert!(cache.get(1, "", "v1").is_none());
+        assert!(cache.get(2, "", "v1").is_some());
+        assert_eq!(bytes(&cache), 10);
+    }
+}
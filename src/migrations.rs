@@ -0,0 +1,169 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use log::{info, warn};
+use rocket_contrib::databases::postgres::Connection;
+use std::fmt;
+
+// Latest schema migration this binary was written against. A database without the
+// schema_migrations table counts as version 0.
+pub const SCHEMA_VERSION: i64 = 25;
+
+// The SQL files in migrations/, built into the binary. Versions are the file name prefixes and the
+// last one has to match SCHEMA_VERSION. Applied migrations are never edited, changes go into a new
+// file.
+const MIGRATIONS: &[(i64, &str)] = &[
+    (1, include_str!("../migrations/0001_core.sql")),
+    (2, include_str!("../migrations/0002_sync.sql")),
+    (3, include_str!("../migrations/0003_accounts.sql")),
+    (4, include_str!("../migrations/0004_search.sql")),
+    (5, include_str!("../migrations/0005_taste.sql")),
+    (6, include_str!("../migrations/0006_cover_variants.sql")),
+    (7, include_str!("../migrations/0007_sync_cadence.sql")),
+    (8, include_str!("../migrations/0008_request_ids.sql")),
+    (9, include_str!("../migrations/0009_sync_errors.sql")),
+    (10, include_str!("../migrations/0010_sync_error_ids.sql")),
+    (11, include_str!("../migrations/0011_cover_thumbnails.sql")),
+    (12, include_str!("../migrations/0012_pending_uploads.sql")),
+    (13, include_str!("../migrations/0013_providers.sql")),
+    (14, include_str!("../migrations/0014_activities.sql")),
+    (15, include_str!("../migrations/0015_list_notes.sql")),
+    (16, include_str!("../migrations/0016_list_changes.sql")),
+    (17, include_str!("../migrations/0017_mal_names.sql")),
+    (18, include_str!("../migrations/0018_private_entries.sql")),
+    (19, include_str!("../migrations/0019_visible_users.sql")),
+    (20, include_str!("../migrations/0020_lookup_jobs.sql")),
+    (21, include_str!("../migrations/0021_anime_updated_at.sql")),
+    (22, include_str!("../migrations/0022_partial_dates.sql")),
+    (23, include_str!("../migrations/0023_batch_jobs.sql")),
+    (
+        24,
+        include_str!("../migrations/0024_remote_search_expiry.sql"),
+    ),
+    (25, include_str!("../migrations/0025_session_hashes.sql")),
+];
+
+// Namespace of the advisory lock held while migrating, jobs uses 1 for its queue locks.
+const MIGRATION_LOCK_NAMESPACE: i32 = 2;
+
+#[derive(Debug)]
+pub enum SchemaError {
+    Database(postgres::Error),
+    Outdated { database: i64, expected: i64 },
+    Newer { database: i64, expected: i64 },
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchemaError::Database(error) => {
+                write!(f, "could not read the schema version: {}", error)
+            }
+            SchemaError::Outdated { database, expected } => write!(
+                f,
+                "database schema is at version {} but this build needs version {}, run the \
+                 migrations first",
+                database, expected
+            ),
+            SchemaError::Newer { database, expected } => write!(
+                f,
+                "database schema is at version {} which is newer than version {} known to this \
+                 build, start with --allow-newer-db if the newer migrations are backwards \
+                 compatible",
+                database, expected
+            ),
+        }
+    }
+}
+
+// Applies the migrations the database doesn't have yet, each in its own transaction, and returns
+// how many ran. Instances starting at the same time wait for each other, so every migration runs
+// once.
+pub fn migrate(connection: &Connection) -> Result<usize, postgres::Error> {
+    connection.execute(
+        "SELECT pg_advisory_lock($1, 0)",
+        &[&MIGRATION_LOCK_NAMESPACE],
+    )?;
+    let result = apply_pending(connection);
+    connection.execute(
+        "SELECT pg_advisory_unlock($1, 0)",
+        &[&MIGRATION_LOCK_NAMESPACE],
+    )?;
+    result
+}
+
+fn apply_pending(connection: &Connection) -> Result<usize, postgres::Error> {
+    connection.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (version BIGINT PRIMARY KEY, applied_at \
+         TIMESTAMPTZ NOT NULL DEFAULT now())",
+    )?;
+    let current = database_version(connection)?;
+
+    let mut applied = 0;
+    for (version, sql) in MIGRATIONS.iter().filter(|(version, _)| *version > current) {
+        let transaction = connection.transaction()?;
+        transaction.batch_execute(sql)?;
+        transaction.execute(
+            "INSERT INTO schema_migrations (version, applied_at) VALUES ($1, now())",
+            &[version],
+        )?;
+        transaction.commit()?;
+        info!("applied schema migration {}", version);
+        applied += 1;
+    }
+
+    if applied == 0 {
+        info!("database schema is up to date at version {}", current);
+    }
+    Ok(applied)
+}
+
+// Refuses to serve against a schema the binary doesn't match. A newer schema is allowed on request
+// so old instances can keep serving during a rolling deploy.
+pub fn check_schema_version(connection: &Connection, allow_newer: bool) -> Result<(), SchemaError> {
+    let database = database_version(connection).map_err(SchemaError::Database)?;
+
+    if database < SCHEMA_VERSION {
+        return Err(SchemaError::Outdated {
+            database,
+            expected: SCHEMA_VERSION,
+        });
+    }
+
+    if database > SCHEMA_VERSION {
+        if !allow_newer {
+            return Err(SchemaError::Newer {
+                database,
+                expected: SCHEMA_VERSION,
+            });
+        }
+        warn!(
+            "database schema version {} is newer than expected version {}, continuing because of \
+             --allow-newer-db",
+            database, SCHEMA_VERSION
+        );
+        return Ok(());
+    }
+
+    info!("database schema is at expected version {}", database);
+    Ok(())
+}
+
+fn database_version(connection: &Connection) -> Result<i64, postgres::Error> {
+    let rows = connection.query("SELECT to_regclass('schema_migrations') IS NOT NULL", &[])?;
+    let exists: bool = rows.get(0).get(0);
+    if !exists {
+        return Ok(0);
+    }
+
+    let rows = connection.query(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_migrations",
+        &[],
+    )?;
+    Ok(rows.get(0).get(0))
+}
@@ -6,11 +6,17 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use chrono::NaiveDate;
+use crate::dates::{DateRepr, PartialDate};
+use crate::features::FeatureFlags;
+use crate::fields::Fieldset;
+use async_graphql::{Enum, SimpleObject};
+use chrono::{DateTime, NaiveDate, Utc};
 use serde_derive::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 //#[table_name = "users"]
+#[serde(rename_all = "camelCase")]
 pub struct User {
     pub user_id: i32,
     pub name: String,
@@ -25,13 +31,36 @@ pub struct Anime {
     pub description: String,
     pub cover_s3: String,
     pub cover_anilist: String,
+    // Higher resolution copy for retina displays, None when AniList has no extra large cover.
+    pub cover_xl_s3: Option<String>,
+    // Small WebP copy for the list grid.
+    pub cover_thumb_s3: Option<String>,
     pub average: Option<i16>,
     pub native: Option<String>,
     pub romaji: Option<String>,
     pub english: Option<String>,
+    pub genres: Vec<String>,
+    pub tags: Vec<String>,
+    pub episodes: Option<i32>,
+    pub season: Option<String>,
+    pub season_year: Option<i32>,
+    // TV, TV_SHORT, MOVIE, SPECIAL, OVA, ONA or MUSIC.
+    pub format: Option<String>,
+    // Main animation studio.
+    pub studio: Option<String>,
 }
 
+// An image whose upload failed, see pending_uploads.
 #[derive(Debug, Clone)]
+pub struct PendingUpload {
+    pub image_type: String,
+    pub id: i32,
+    pub source_url: String,
+    // Retries that failed so far.
+    pub attempts: i32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 //#[table_name = "lists"]
 pub struct ListItem {
     pub user_id: i32,
@@ -39,40 +68,921 @@ pub struct ListItem {
     pub user_title: Option<String>,
     pub start_day: Option<NaiveDate>,
     pub end_day: Option<NaiveDate>,
+    // Set instead of start_day and end_day when AniList only has the year or month.
+    pub start_partial: Option<PartialDate>,
+    pub end_partial: Option<PartialDate>,
     pub score: Option<i16>,
+    pub status: Option<String>,
+    pub progress: Option<i32>,
+    pub repeat: Option<i32>,
+    pub notes: Option<String>,
+    pub custom_lists: Vec<String>,
+    // Private on AniList or left out of its status lists. Stored for syncs to compare against,
+    // never served.
+    pub private: bool,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, SimpleObject)]
+#[serde(rename_all = "camelCase")]
+pub struct RestResponse {
+    pub users: ResponseList,
+    pub data_freshness: DataFreshness,
+    // Entries on the whole list, regardless of the page returned.
+    pub total: i64,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListSort {
+    EndDay,
+    Score,
+    Title,
+}
+
+impl ListSort {
+    pub fn parse(value: &str) -> Option<ListSort> {
+        match value {
+            "end_day" => Some(ListSort::EndDay),
+            "score" => Some(ListSort::Score),
+            "title" => Some(ListSort::Title),
+            _ => None,
+        }
+    }
+}
+
+// Which part of a list to load and in what order. The default is the whole list, most recently
+// completed first.
 #[derive(Debug, Clone)]
-//#[table_name = "lists"]
-pub struct ListItemMap {
-    pub user: User,
-    pub anime: Anime,
-    pub list_item: ListItem,
+pub struct ListQuery {
+    pub sort: ListSort,
+    pub descending: bool,
+    pub page: i64,
+    pub per_page: Option<i64>,
+    pub filter: ListFilter,
+    pub preferences: Preferences,
+    pub date_repr: DateRepr,
+    // Entry fields to return, all of them when None.
+    pub fields: Option<Fieldset>,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct RestResponse {
-    pub users: ResponseList,
+// Conditions entries have to meet to be listed, all optional.
+#[derive(Debug, Clone, Default)]
+pub struct ListFilter {
+    pub min_score: Option<i16>,
+    // Completion date bounds, both included.
+    pub completed_from: Option<NaiveDate>,
+    pub completed_to: Option<NaiveDate>,
+    // Part of any of the anime's titles or the user's title for it.
+    pub title: Option<String>,
+}
+
+impl ListFilter {
+    pub fn is_empty(&self) -> bool {
+        self.min_score.is_none()
+            && self.completed_from.is_none()
+            && self.completed_to.is_none()
+            && self.title.is_none()
+    }
+}
+
+impl Default for ListQuery {
+    fn default() -> ListQuery {
+        ListQuery {
+            sort: ListSort::EndDay,
+            descending: true,
+            page: 1,
+            per_page: None,
+            filter: ListFilter::default(),
+            preferences: Preferences::default(),
+            date_repr: DateRepr::default(),
+            fields: None,
+        }
+    }
+}
+
+// How a user wants their list shown. List routes use the owner's preferences, query parameters
+// override them per request. The snake_case aliases keep requests written for the legacy naming
+// working.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+#[serde(default)]
+pub struct Preferences {
+    #[serde(alias = "title_language")]
+    pub title_language: TitleLanguage,
+    #[serde(alias = "date_format")]
+    pub date_format: DateFormat,
+    // Whether entries AniList marks as adult are listed.
+    #[serde(alias = "show_adult")]
+    pub show_adult: bool,
+    // How often the scheduler syncs the list. Stored on the user row, list routes ignore it.
+    #[serde(alias = "sync_cadence")]
+    pub sync_cadence: SyncCadence,
+}
+
+impl Default for Preferences {
+    fn default() -> Preferences {
+        Preferences {
+            title_language: TitleLanguage::UserPreferred,
+            date_format: DateFormat::Iso,
+            show_adult: true,
+            sync_cadence: SyncCadence::Daily,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TitleLanguage {
+    // Whatever AniList picks, stored as the entry's user_title.
+    UserPreferred,
+    Romaji,
+    English,
+    Native,
+}
+
+impl TitleLanguage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TitleLanguage::UserPreferred => "user_preferred",
+            TitleLanguage::Romaji => "romaji",
+            TitleLanguage::English => "english",
+            TitleLanguage::Native => "native",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<TitleLanguage> {
+        match value {
+            "user_preferred" => Some(TitleLanguage::UserPreferred),
+            "romaji" => Some(TitleLanguage::Romaji),
+            "english" => Some(TitleLanguage::English),
+            "native" => Some(TitleLanguage::Native),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DateFormat {
+    // 2023-01-31
+    Iso,
+    // 01/31/2023
+    Us,
+    // 31/01/2023
+    Eu,
+    // January 31, 2023
+    Long,
+}
+
+impl DateFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DateFormat::Iso => "iso",
+            DateFormat::Us => "us",
+            DateFormat::Eu => "eu",
+            DateFormat::Long => "long",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<DateFormat> {
+        match value {
+            "iso" => Some(DateFormat::Iso),
+            "us" => Some(DateFormat::Us),
+            "eu" => Some(DateFormat::Eu),
+            "long" => Some(DateFormat::Long),
+            _ => None,
+        }
+    }
+
+    pub fn format(&self, day: NaiveDate) -> String {
+        let pattern = match self {
+            DateFormat::Iso => "%Y-%m-%d",
+            DateFormat::Us => "%m/%d/%Y",
+            DateFormat::Eu => "%d/%m/%Y",
+            DateFormat::Long => "%B %-d, %Y",
+        };
+        day.format(pattern).to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncCadence {
+    Daily,
+    Weekly,
+    // Only synced when someone asks for it.
+    Manual,
+}
+
+impl SyncCadence {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SyncCadence::Daily => "daily",
+            SyncCadence::Weekly => "weekly",
+            SyncCadence::Manual => "manual",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<SyncCadence> {
+        match value {
+            "daily" => Some(SyncCadence::Daily),
+            "weekly" => Some(SyncCadence::Weekly),
+            "manual" => Some(SyncCadence::Manual),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceStatus {
+    pub version: String,
+    pub upstream_status: UpstreamStatus,
+    pub features: FeatureFlags,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, SimpleObject)]
+#[serde(rename_all = "camelCase")]
+pub struct DataFreshness {
+    pub last_synced_at: Option<DateTime<Utc>>,
+    pub last_attempt_at: Option<DateTime<Utc>>,
+    pub upstream_status: UpstreamStatus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema, Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamStatus {
+    Up,
+    Degraded,
+    Down,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, SimpleObject)]
+#[serde(rename_all = "camelCase")]
 pub struct ResponseList {
     pub id: String,
     pub avatar: String,
+    // Set when the last sync held back a large deletion until a forced update confirms it.
+    pub needs_confirmation: bool,
     pub list: Vec<ResponseItem>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, ToSchema, SimpleObject)]
+#[serde(rename_all = "camelCase")]
+pub struct AnimeDetail {
+    pub id: i32,
+    pub slug: Option<String>,
+    pub romaji: Option<String>,
+    pub english: Option<String>,
+    pub native: Option<String>,
+    pub description: String,
+    pub cover: String,
+    // Twice the resolution of cover, for srcset.
+    pub cover_xl: Option<String>,
+    pub average: Option<i16>,
+    pub genres: Vec<String>,
+    pub tags: Vec<String>,
+    pub episodes: Option<i32>,
+    pub season: Option<String>,
+    pub season_year: Option<i32>,
+    pub format: Option<String>,
+    pub studio: Option<String>,
+    // Tracked users with the anime on their list.
+    pub watchers: Vec<Watcher>,
+}
+
+#[derive(Serialize, ToSchema, SimpleObject)]
+#[serde(rename_all = "camelCase")]
+pub struct Watcher {
+    pub id: String,
+    pub avatar: String,
+    pub score: Option<i16>,
+    pub status: Option<String>,
+    #[serde(with = "crate::dates::iso")]
+    pub start_day: Option<NaiveDate>,
+    #[serde(with = "crate::dates::iso")]
+    pub end_day: Option<NaiveDate>,
+}
+
+// Values an entry had until a sync replaced them.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEntry {
+    pub anime_id: i32,
+    pub romaji: Option<String>,
+    pub english: Option<String>,
+    pub native: Option<String>,
+    pub score: Option<i16>,
+    pub status: Option<String>,
+    #[serde(with = "crate::dates::iso")]
+    pub start_day: Option<NaiveDate>,
+    #[serde(with = "crate::dates::iso")]
+    pub end_day: Option<NaiveDate>,
+    pub replaced_at: DateTime<Utc>,
+}
+
+// What a sync did to an entry.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ListChangeOperation {
+    Added,
+    Updated,
+    Removed,
+}
+
+impl ListChangeOperation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListChangeOperation::Added => "added",
+            ListChangeOperation::Updated => "updated",
+            ListChangeOperation::Removed => "removed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<ListChangeOperation> {
+        match value {
+            "added" => Some(ListChangeOperation::Added),
+            "updated" => Some(ListChangeOperation::Updated),
+            "removed" => Some(ListChangeOperation::Removed),
+            _ => None,
+        }
+    }
+}
+
+// An entry a sync added, updated or removed.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ListChange {
+    pub id: i64,
+    pub anime_id: i32,
+    // None for anime that are no longer stored.
+    pub romaji: Option<String>,
+    pub english: Option<String>,
+    pub native: Option<String>,
+    // The sync that made the change.
+    pub job_id: i32,
+    pub operation: ListChangeOperation,
+    // The entry's title, dates, score, status, progress, rewatches, notes and custom lists, before
+    // is missing for added entries and after for removed ones.
+    #[schema(value_type = Option<Object>)]
+    pub before: Option<serde_json::Value>,
+    #[schema(value_type = Option<Object>)]
+    pub after: Option<serde_json::Value>,
+    pub changed_at: DateTime<Utc>,
+}
+
+// An update to a list entry on AniList.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Activity {
+    pub activity_id: i32,
+    pub anime_id: i32,
+    // None for anime that are no longer stored.
+    pub romaji: Option<String>,
+    pub english: Option<String>,
+    pub native: Option<String>,
+    // AniList's wording, like "watched episode" or "completed".
+    pub status: String,
+    // The episodes the update covers, the same one for a single episode.
+    pub episode_from: Option<i32>,
+    pub episode_to: Option<i32>,
+    pub created_at: DateTime<Utc>,
+}
+
+// Entries of a list by when they were completed, for the history graph.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Timeline {
+    // month or year.
+    pub period: String,
+    // Entries with a completion date.
+    pub total: i64,
+    // Completed entries without a completion date, they are in no period.
+    pub undated: i64,
+    // Periods without completions are left out.
+    pub periods: Vec<TimelinePeriod>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TimelinePeriod {
+    // First day of the month or year.
+    pub start: NaiveDate,
+    // Entries completed within the period.
+    pub count: i64,
+    // Entries completed up to the end of the period.
+    pub cumulative: i64,
+}
+
+// Changes to a list since an earlier response. as_of is the since value for the next request.
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ListDelta {
+    pub id: String,
+    pub since: DateTime<Utc>,
+    pub as_of: DateTime<Utc>,
+    pub changed: Vec<ResponseItem>,
+    // anime_ids of entries removed from the list.
+    pub removed: Vec<i32>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, SimpleObject)]
+#[serde(rename_all = "camelCase")]
 pub struct ResponseItem {
     pub user_title: Option<String>,
+    // Title in the preferred language, falling back to any title the anime has.
+    pub display_title: Option<String>,
+    #[serde(with = "crate::dates::iso")]
     pub start_day: Option<NaiveDate>,
+    #[serde(with = "crate::dates::iso")]
     pub end_day: Option<NaiveDate>,
+    // 2023 or 2023-05 when AniList only knows the year or month, start_day or end_day is unset then.
+    #[schema(value_type = Option<String>)]
+    pub start_partial: Option<PartialDate>,
+    #[schema(value_type = Option<String>)]
+    pub end_partial: Option<PartialDate>,
+    // The days in the preferred date format, partial ones as they are.
+    pub display_start_day: Option<String>,
+    pub display_end_day: Option<String>,
     pub score: Option<i16>,
+    // AniList list status: CURRENT, PLANNING, COMPLETED, DROPPED, PAUSED or REPEATING.
+    pub status: Option<String>,
+    // Episodes watched so far, of the current rewatch for REPEATING entries.
+    pub progress: Option<i32>,
+    // Completed rewatches.
+    pub repeat: Option<i32>,
+    // The user's own notes on the entry.
+    pub notes: Option<String>,
+    // Names of the user's custom lists the entry is on, in the order the user arranged them.
+    pub custom_lists: Vec<String>,
     pub average: Option<i16>,
     pub native: Option<String>,
     pub romaji: Option<String>,
     pub english: Option<String>,
     pub description: String,
     pub cover: String,
+    // Twice the resolution of cover, for srcset.
+    pub cover_xl: Option<String>,
+    // Small WebP copy of cover for the list grid. Generated after the row is stored, so it can be
+    // missing for a moment, or for good when AniList's cover couldn't be decoded.
+    pub cover_thumb: Option<String>,
     pub id: i32,
+    // Set on the anime's next sync for rows stored before slugs existed.
+    pub slug: Option<String>,
+    pub genres: Vec<String>,
+    pub tags: Vec<String>,
+    pub episodes: Option<i32>,
+    pub season: Option<String>,
+    pub season_year: Option<i32>,
+    pub format: Option<String>,
+    pub studio: Option<String>,
+}
+
+impl ResponseItem {
+    pub fn apply_preferences(&mut self, preferences: &Preferences) {
+        let titles = match preferences.title_language {
+            TitleLanguage::UserPreferred => {
+                [&self.user_title, &self.romaji, &self.english, &self.native]
+            }
+            TitleLanguage::Romaji => [&self.romaji, &self.user_title, &self.english, &self.native],
+            TitleLanguage::English => [&self.english, &self.user_title, &self.romaji, &self.native],
+            TitleLanguage::Native => [&self.native, &self.user_title, &self.romaji, &self.english],
+        };
+        self.display_title = titles.iter().find_map(|title| (*title).clone());
+        self.display_start_day = self
+            .start_day
+            .map(|day| preferences.date_format.format(day))
+            .or_else(|| self.start_partial.map(|date| date.to_string()));
+        self.display_end_day = self
+            .end_day
+            .map(|day| preferences.date_format.format(day))
+            .or_else(|| self.end_partial.map(|date| date.to_string()));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum ChangeKind {
+    Added,
+    Updated,
+    Completed,
+    Removed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangeEvent {
+    pub user_id: i32,
+    pub anime_id: i32,
+    pub title: Option<String>,
+    pub kind: ChangeKind,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncPreview {
+    pub adds: PreviewChanges,
+    pub updates: PreviewChanges,
+    pub deletes: PreviewChanges,
+    // Whether the deletions would trip the safety threshold and need a forced update.
+    pub needs_confirmation: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PreviewChanges {
+    pub count: usize,
+    pub samples: Vec<PreviewItem>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PreviewItem {
+    pub anime_id: i32,
+    pub user_title: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchResult {
+    pub id: i32,
+    pub romaji: Option<String>,
+    pub english: Option<String>,
+    pub native: Option<String>,
+    pub cover: String,
+    // 1.0 for titles containing the query, the full text rank scaled to 0..1 for word matches and
+    // trigram similarity for fuzzy fallback matches.
+    pub match_quality: f32,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteSearchResult {
+    pub id: i32,
+    pub romaji: Option<String>,
+    pub english: Option<String>,
+    pub native: Option<String>,
+    // AniList's cover URL, stored anime may not have been uploaded yet.
+    pub cover: String,
+    pub format: Option<String>,
+    pub season_year: Option<i32>,
+    pub average: Option<i16>,
+    // Whether the anime is stored locally.
+    pub local: bool,
+    // Tracked users with the anime on their list.
+    pub local_users: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl JobState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Succeeded => "succeeded",
+            JobState::Failed => "failed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<JobState> {
+        match value {
+            "queued" => Some(JobState::Queued),
+            "running" => Some(JobState::Running),
+            "succeeded" => Some(JobState::Succeeded),
+            "failed" => Some(JobState::Failed),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Job {
+    pub job_id: i32,
+    // None for a lookup that hasn't found its user.
+    pub user_id: Option<i32>,
+    // "sync", or "lookup" for a name of a batch update that still has to be found on AniList.
+    pub kind: String,
+    pub state: JobState,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    // X-Request-Id of the request that queued the job.
+    pub request_id: Option<String>,
+    // The name a lookup looks for.
+    pub username: Option<String>,
+    // The sync a lookup queued, or the one that was already queued or running.
+    pub sync_job_id: Option<i32>,
+    // Entries this sync left out because they couldn't be written, see /admin/sync-errors.
+    pub quarantined: i64,
+    // Entries this sync stored with missing or broken data.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<SyncWarning>,
+}
+
+// What became of one username of POST /users/batch.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchUpdateResult {
+    // As it was sent.
+    pub username: String,
+    pub status: BatchUpdateStatus,
+    // The lookup that was queued, see /jobs/{job_id}. Once it has found the user it points at
+    // their sync.
+    pub job_id: Option<i32>,
+    // Why nothing was queued.
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchUpdateStatus {
+    Queued,
+    RateLimited,
+    // The database failed.
+    Failed,
+}
+
+// Progress of a batch of jobs queued together, e.g. a refresh of every user.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchProgress {
+    pub batch_id: i32,
+    pub kind: String,
+    pub created_at: DateTime<Utc>,
+    pub total: i64,
+    pub queued: i64,
+    pub running: i64,
+    pub succeeded: i64,
+    pub failed: i64,
+    // Jobs finished per minute recently.
+    pub jobs_per_minute: Option<f64>,
+    // None once nothing is pending, or while nothing finishes.
+    pub eta: Option<DateTime<Utc>>,
+    // The first failed jobs of the batch.
+    pub failures: Vec<BatchFailure>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncFailure {
+    pub job_id: i32,
+    pub user_id: i32,
+    pub name: Option<String>,
+    pub error: String,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub request_id: Option<String>,
+}
+
+// Outcome of replaying quarantined entries from their stored payloads.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct QuarantineRetry {
+    pub retried: i64,
+    // Entries that were written and left the quarantine.
+    pub healed: i64,
+    // Entries that still can't be written, with the error of this attempt.
+    pub remaining: Vec<QuarantinedEntry>,
+}
+
+// Entries syncs left out because they couldn't be written. Later syncs retry them.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Quarantine {
+    pub entries: i64,
+    pub users: i64,
+    // Most recently quarantined first.
+    pub recent: Vec<QuarantinedEntry>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct QuarantinedEntry {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: Option<String>,
+    pub anime_id: i32,
+    // Last sync that quarantined it.
+    pub job_id: i32,
+    pub error: String,
+    // The entry as AniList sent it.
+    #[schema(value_type = Object)]
+    pub payload: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchFailure {
+    pub job_id: i32,
+    pub user_id: i32,
+    pub name: Option<String>,
+    pub error: String,
+    pub request_id: Option<String>,
+}
+
+#[derive(Serialize, ToSchema, SimpleObject)]
+#[serde(rename_all = "camelCase")]
+pub struct UserStats {
+    pub id: String,
+    pub entries: i64,
+    pub completed: i64,
+    pub scored: i64,
+    // Mean of the scores that are set, on AniList's 100 point scale.
+    pub mean_score: Option<f64>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, ToSchema, SimpleObject)]
+#[serde(rename_all = "camelCase")]
+pub struct GlobalStats {
+    pub users: i64,
+    pub entries: i64,
+    pub most_watched: Vec<GlobalAnime>,
+    // Highest mean user score among anime enough users have scored.
+    pub top_rated: Vec<GlobalAnime>,
+}
+
+#[derive(Serialize, ToSchema, SimpleObject)]
+#[serde(rename_all = "camelCase")]
+pub struct GlobalAnime {
+    pub id: i32,
+    pub romaji: Option<String>,
+    pub english: Option<String>,
+    pub native: Option<String>,
+    pub cover: String,
+    // AniList's average score.
+    pub average: Option<i16>,
+    // Tracked users with the anime on their list, and how many of them scored it.
+    pub watchers: i64,
+    pub scored: i64,
+    pub mean_score: Option<f64>,
+    // How much higher tracked users score it than AniList does.
+    pub score_delta: Option<f64>,
+}
+
+// Whether a stored profile may be served.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Visibility {
+    Public,
+    // Made private on AniList.
+    Private,
+    // A takedown was requested, the data is purged once the grace period ends.
+    TakenDown,
+}
+
+// Returned after signing in with AniList. The token goes in "Authorization: Bearer <token>" until
+// it runs out after SESSION_TTL_DAYS or is revoked through DELETE /auth/session(s).
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct OAuthSession {
+    pub token: String,
+    pub user_id: i32,
+    pub user: String,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Takedown {
+    pub id: String,
+    pub requested_at: DateTime<Utc>,
+    pub purge_after: DateTime<Utc>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackedUser {
+    pub id: String,
+    pub slug: Option<String>,
+    pub avatar: String,
+    pub entries: i64,
+    pub last_synced_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SimilarUser {
+    pub id: String,
+    pub avatar: String,
+    // Cosine similarity of the users' taste embeddings, 1 for identical taste.
+    pub similarity: f64,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Recommendation {
+    pub id: i32,
+    pub romaji: Option<String>,
+    pub english: Option<String>,
+    pub native: Option<String>,
+    pub cover: String,
+    // Similarity weighted mean of the scores similar users gave, on AniList's 100 point scale.
+    pub predicted_score: Option<f64>,
+    // Number of similar users who scored it.
+    pub recommended_by: i64,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Comparison {
+    pub user: String,
+    pub other: String,
+    pub shared: Vec<SharedAnime>,
+    // Pearson correlation of the scores both users gave, from -1 to 1. None when fewer than two
+    // shared titles are scored by both or one of them gave every title the same score.
+    pub affinity: Option<f64>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SharedAnime {
+    pub id: i32,
+    pub title: Option<String>,
+    pub score: Option<i16>,
+    pub other_score: Option<i16>,
+    // score minus other_score, when both are set.
+    pub score_delta: Option<i16>,
+}
+
+// Why an entry may look incomplete after a sync.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum WarningKind {
+    MissingCover,
+    CoverDownloadFailed,
+    MissingTitle,
+    InvalidDate,
+    SaveFailed,
+}
+
+impl WarningKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WarningKind::MissingCover => "missing_cover",
+            WarningKind::CoverDownloadFailed => "cover_download_failed",
+            WarningKind::MissingTitle => "missing_title",
+            WarningKind::InvalidDate => "invalid_date",
+            WarningKind::SaveFailed => "save_failed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<WarningKind> {
+        match value {
+            "missing_cover" => Some(WarningKind::MissingCover),
+            "cover_download_failed" => Some(WarningKind::CoverDownloadFailed),
+            "missing_title" => Some(WarningKind::MissingTitle),
+            "invalid_date" => Some(WarningKind::InvalidDate),
+            "save_failed" => Some(WarningKind::SaveFailed),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncWarning {
+    pub anime_id: i32,
+    pub kind: WarningKind,
+    pub detail: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct SlugRequest {
+    // None removes the slug.
+    pub slug: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct WebhookRequest {
+    pub url: String,
+    // Key for the HMAC-SHA256 signature of every delivery.
+    pub secret: String,
+}
+
+// The secret is never sent back.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    pub created_at: DateTime<Utc>,
+}
+
+// Body of a webhook delivery.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookPayload {
+    // sync.succeeded or sync.failed
+    pub event: String,
+    pub job_id: i32,
+    pub user: Option<String>,
+    pub state: JobState,
+    pub error: Option<String>,
+    pub finished_at: DateTime<Utc>,
 }
@@ -0,0 +1,130 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+// Folds a title or search query into the form stored in anime.search_title so that spelling
+// variants of the same romanization compare equal, e.g. "Shōjo", "Shoujo" and "Shojo". Only Latin
+// letters lose their accents, the dakuten of が is part of the letter.
+pub fn normalize(text: &str) -> String {
+    let stripped: String = strip_latin_marks(text)
+        .chars()
+        .flat_map(char::to_lowercase)
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect();
+
+    let words: Vec<String> = stripped.split_whitespace().map(fold_long_vowels).collect();
+    words.join(" ")
+}
+
+// Compatibility forms are folded as well, half-width ｶﾞ becomes ガ.
+fn strip_latin_marks(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut after_latin = false;
+    for c in text.nfkd() {
+        if is_combining_mark(c) {
+            if after_latin {
+                continue;
+            }
+        } else {
+            after_latin = is_latin(c);
+        }
+        stripped.push(c);
+    }
+    stripped.nfc().collect()
+}
+
+// Latin letters as they are left by decomposition, the accented ones are split off their marks.
+fn is_latin(c: char) -> bool {
+    matches!(c, 'A'..='Z' | 'a'..='z' | '\u{00C0}'..='\u{024F}' | '\u{1E00}'..='\u{1EFF}')
+}
+
+// All known titles of a show in one normalized string, used as its search index entry.
+pub fn search_title(titles: &[&Option<String>]) -> String {
+    let normalized: Vec<String> = titles
+        .iter()
+        .filter_map(|title| title.as_ref())
+        .map(|title| normalize(title))
+        .filter(|title| !title.is_empty())
+        .collect();
+    normalized.join(" ")
+}
+
+// URL-safe name for an anime, e.g. "shingeki-no-kyojin-16498". Characters without an ASCII form
+// are dropped, titles without any leave just the id. The id keeps slugs unique and lets a slug
+// from before a title change still resolve.
+pub fn slug(title: &Option<String>, id: i32) -> String {
+    let stripped: String = title
+        .as_ref()
+        .map(|title| title.as_str())
+        .unwrap_or("")
+        .nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                ' '
+            }
+        })
+        .collect();
+
+    let mut words: Vec<String> = stripped.split_whitespace().map(String::from).collect();
+    words.push(id.to_string());
+    words.join("-")
+}
+
+// Id at the end of a slug, or the whole value when it is a plain numeric id.
+pub fn slug_id(slug: &str) -> Option<i32> {
+    slug.rsplit('-').next().and_then(|id| id.parse().ok())
+}
+
+// Long vowels are romanized as "ou", "oo", "uu" or a doubled vowel depending on who wrote the
+// title, so they are collapsed to the single vowel.
+fn fold_long_vowels(word: &str) -> String {
+    let mut folded = String::with_capacity(word.len());
+    let mut chars = word.chars().peekable();
+
+    while let Some(current) = chars.next() {
+        folded.push(current);
+        if let Some(&next) = chars.peek() {
+            let long_vowel = match current {
+                'o' => next == 'o' || next == 'u',
+                'a' | 'i' | 'u' | 'e' => next == current,
+                _ => false,
+            };
+            if long_vowel {
+                chars.next();
+            }
+        }
+    }
+
+    folded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn romanization_variants_compare_equal() {
+        assert_eq!(normalize("Shōjo"), "shojo");
+        assert_eq!(normalize("Shoujo"), "shojo");
+        assert_eq!(normalize("SHOJO"), "shojo");
+        assert_eq!(normalize("Pokémon: Mewtwo"), "pokemon mewtwo");
+    }
+
+    #[test]
+    fn kana_keep_their_dakuten() {
+        assert_eq!(normalize("がっこうぐらし"), "がっこうぐらし");
+        assert_ne!(normalize("が"), normalize("か"));
+        assert_eq!(normalize("パン"), "パン");
+        assert_eq!(normalize("ｶﾞﾝﾀﾞﾑ"), "ガンダム");
+    }
+}
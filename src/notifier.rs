@@ -0,0 +1,56 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::{database, models};
+use log::info;
+use rocket_contrib::databases::postgres::Connection;
+
+pub trait Notifier {
+    fn notify(&self, subscriber: &models::User, event: &models::ChangeEvent);
+}
+
+// Default notifier until there is a delivery channel for subscribers, it only records what would
+// have been sent.
+pub struct LogNotifier;
+
+impl Notifier for LogNotifier {
+    fn notify(&self, subscriber: &models::User, event: &models::ChangeEvent) {
+        info!(
+            "notifying user_id={} that user_id={} {:?} anime_id={} ({})",
+            subscriber.user_id,
+            event.user_id,
+            event.kind,
+            event.anime_id,
+            event
+                .title
+                .as_ref()
+                .map(String::as_str)
+                .unwrap_or("untitled")
+        );
+    }
+}
+
+// Events always come from a single user's sync. Subscribers only care about finished shows,
+// everything else stays internal to the sync.
+pub fn fan_out(events: &[models::ChangeEvent], connection: &Connection, notifier: &dyn Notifier) {
+    let completed: Vec<&models::ChangeEvent> = events
+        .iter()
+        .filter(|event| event.kind == models::ChangeKind::Completed)
+        .collect();
+
+    if completed.is_empty() {
+        return;
+    }
+
+    let subscribers = database::get_subscribers(completed[0].user_id, connection);
+    for event in completed {
+        for subscriber in subscribers.iter() {
+            notifier.notify(subscriber, event);
+        }
+    }
+}
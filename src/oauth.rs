@@ -0,0 +1,140 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Sign in with AniList. Signing in links the AniList account: its access token is kept, sealed,
+// so syncs see lists that are private as a whole and private scores too, and the caller gets a
+// session token for the routes that act on their behalf. Entries marked private on AniList are
+// synced but never shown here.
+
+use crate::{anilist_models, anilist_query, config, database, jobs, models, providers, storage};
+use chrono::{Duration, Utc};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use reqwest::Url;
+use rocket_contrib::databases::postgres::Connection;
+use std::fmt;
+
+static AUTHORIZE_URL: &'static str = "https://anilist.co/api/v2/oauth/authorize";
+
+// Holds the state parameter between the redirect to AniList and the callback.
+pub const STATE_COOKIE: &str = "anilist_oauth_state";
+
+const TOKEN_LEN: usize = 43;
+
+// Days a session lasts before the user has to sign in again.
+const DEFAULT_SESSION_TTL_DAYS: i32 = 30;
+
+pub struct Config {
+    pub client_id: String,
+    pub client_secret: String,
+    // Has to match the redirect URL registered for the client on AniList exactly.
+    pub redirect_uri: String,
+}
+
+#[derive(Debug)]
+pub enum SignInError {
+    Upstream(anilist_query::AnilistError),
+    TakenDown,
+    Database,
+}
+
+impl fmt::Display for SignInError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignInError::Upstream(error) => write!(f, "{}", error),
+            SignInError::TakenDown => write!(f, "account is being removed"),
+            SignInError::Database => write!(f, "sign in could not be saved"),
+        }
+    }
+}
+
+// None unless ANILIST_CLIENT_ID, ANILIST_CLIENT_SECRET, ANILIST_REDIRECT_URI and
+// TOKEN_ENCRYPTION_KEY are all set.
+pub fn config(settings: &config::Settings) -> Option<Config> {
+    let var = config::env_parsed::<String>;
+
+    // Access tokens are never stored in the clear.
+    settings.token_key?;
+    Some(Config {
+        client_id: var("ANILIST_CLIENT_ID")?,
+        client_secret: var("ANILIST_CLIENT_SECRET")?,
+        redirect_uri: var("ANILIST_REDIRECT_URI")?,
+    })
+}
+
+pub fn authorize_url(config: &Config, state: &str) -> String {
+    Url::parse_with_params(
+        AUTHORIZE_URL,
+        &[
+            ("client_id", config.client_id.as_str()),
+            ("redirect_uri", config.redirect_uri.as_str()),
+            ("response_type", "code"),
+            ("state", state),
+        ],
+    )
+    .unwrap()
+    .to_string()
+}
+
+// Random token for sessions and the state parameter.
+pub fn new_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LEN)
+        .map(char::from)
+        .collect()
+}
+
+// Trades the code for the user's access token, tracks the user if they weren't yet and queues a
+// sync so entries that were hidden from the public query show up.
+pub fn sign_in(
+    config: &Config,
+    code: &str,
+    images: &storage::Images,
+    connection: &Connection,
+) -> Result<models::OAuthSession, SignInError> {
+    let token = anilist_query::exchange_code(&anilist_models::TokenRequest {
+        grant_type: "authorization_code",
+        client_id: &config.client_id,
+        client_secret: &config.client_secret,
+        redirect_uri: &config.redirect_uri,
+        code,
+    })
+    .map_err(SignInError::Upstream)?;
+    let viewer = anilist_query::get_viewer(&token.access_token).map_err(SignInError::Upstream)?;
+
+    if database::get_visibility(&viewer.name, connection) == models::Visibility::TakenDown {
+        return Err(SignInError::TakenDown);
+    }
+    database::update_user_profile(
+        viewer.clone(),
+        providers::Provider::AniList,
+        images,
+        connection,
+    );
+
+    let expires_at = Utc::now() + Duration::seconds(token.expires_in);
+    let session = new_token();
+    if !database::save_anilist_token(viewer.id, &token.access_token, expires_at, connection)
+        || !database::create_session(
+            viewer.id,
+            &session,
+            config::env_value("SESSION_TTL_DAYS", DEFAULT_SESSION_TTL_DAYS).max(1),
+            connection,
+        )
+    {
+        return Err(SignInError::Database);
+    }
+    jobs::queue_sync(viewer.id, false, None, connection);
+
+    Ok(models::OAuthSession {
+        token: session,
+        user_id: viewer.id,
+        user: viewer.name,
+    })
+}
@@ -0,0 +1,189 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// OpenAPI description of the JSON and download routes, served as /openapi.json together with a
+// Swagger UI at /docs. Errors are application/problem+json bodies. Crawler routes (robots, sitemaps,
+// meta pages) are left out. Schemas use the camelCase field names of /v1, see the description for the
+// unversioned routes.
+
+use crate::{error, features, models, response};
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "anihistory",
+        description = "Completed anime history of AniList users.\n\nField names are camelCase under /v1. Unversioned routes predate that policy and answer with the same fields in snake_case, e.g. seasonYear is season_year there."
+    ),
+    paths(
+        crate::users,
+        crate::user,
+        crate::user_range,
+        crate::history,
+        crate::timeline,
+        crate::activity,
+        crate::changes,
+        crate::user_stats,
+        crate::covers,
+        crate::feed,
+        crate::calendar,
+        crate::export,
+        crate::similar_users,
+        crate::recommendations,
+        crate::global_stats,
+        crate::compare,
+        crate::profile_slug,
+        crate::preferences,
+        crate::set_preferences,
+        crate::hide_entry,
+        crate::register_webhook,
+        crate::remove_webhook,
+        crate::user_v1,
+        crate::update,
+        crate::update_batch,
+        crate::anilist_login,
+        crate::anilist_callback,
+        crate::sign_out,
+        crate::sign_out_everywhere,
+        crate::request_takedown,
+        crate::lift_takedown,
+        crate::delete_user,
+        crate::force_sync,
+        crate::sync_failures,
+        crate::sync_errors,
+        crate::reprocess_sync_errors,
+        crate::retry_sync_error,
+        crate::retry_sync_errors,
+        crate::purge_anime,
+        crate::reupload_cover,
+        crate::refresh_all,
+        crate::batch,
+        crate::job,
+        crate::sync_preview,
+        crate::search,
+        crate::search_remote,
+        crate::anime,
+        crate::ingest_anime,
+        crate::subscribe,
+        crate::unsubscribe,
+        crate::subscriptions,
+        crate::subscriptions_v1,
+        crate::status,
+        crate::metrics,
+    ),
+    components(schemas(
+        error::Problem,
+        models::ServiceStatus,
+        features::FeatureFlags,
+        models::User,
+        models::RestResponse,
+        models::DataFreshness,
+        models::UpstreamStatus,
+        models::ResponseList,
+        models::ResponseItem,
+        models::ListDelta,
+        models::AnimeDetail,
+        models::Watcher,
+        models::HistoryEntry,
+        models::Timeline,
+        models::Activity,
+        models::ListChangeOperation,
+        models::ListChange,
+        models::TimelinePeriod,
+        models::SyncPreview,
+        models::PreviewChanges,
+        models::PreviewItem,
+        models::SearchResult,
+        models::RemoteSearchResult,
+        models::JobState,
+        models::Job,
+        models::BatchUpdateResult,
+        models::BatchUpdateStatus,
+        models::SyncFailure,
+        models::Quarantine,
+        models::QuarantineRetry,
+        models::QuarantinedEntry,
+        models::BatchProgress,
+        models::BatchFailure,
+        models::SyncWarning,
+        models::WarningKind,
+        models::UserStats,
+        models::GlobalStats,
+        models::GlobalAnime,
+        models::OAuthSession,
+        models::Takedown,
+        models::TrackedUser,
+        models::SimilarUser,
+        models::Recommendation,
+        models::Comparison,
+        models::SharedAnime,
+        models::SlugRequest,
+        models::Preferences,
+        models::TitleLanguage,
+        models::SyncCadence,
+        models::DateFormat,
+        models::WebhookRequest,
+        models::Webhook,
+        models::WebhookPayload,
+        response::ListPage,
+        response::TrackedUserPage,
+        response::UserPage,
+        response::Meta,
+        response::Pagination,
+        response::Links,
+    )),
+    modifiers(&SecuritySchemes)
+)]
+pub struct ApiDoc;
+
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            // Session tokens come from signing in with AniList, the admin token is ADMIN_TOKEN.
+            components.add_security_scheme(
+                "session_token",
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+            );
+            components.add_security_scheme(
+                "admin_token",
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+            );
+        }
+    }
+}
+
+pub fn spec() -> String {
+    // Only fails on types serde can't represent, which the derives never produce.
+    ApiDoc::openapi().to_pretty_json().unwrap()
+}
+
+// Swagger UI loads from a CDN, the service only has to hand out the spec.
+pub fn swagger_ui() -> String {
+    r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>anihistory API</title>
+<link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+<div id="swagger-ui"></div>
+<script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+<script>
+window.onload = function () {
+    SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });
+};
+</script>
+</body>
+</html>
+"##
+    .to_owned()
+}
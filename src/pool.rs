@@ -0,0 +1,157 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// The connection pool requests take their database connection from. DB_POOL_MAX and
+// DB_POOL_MIN_IDLE size it, a request waits DB_ACQUIRE_TIMEOUT_SECS for a connection before it is
+// answered with 503, and DB_STATEMENT_TIMEOUT_MS cancels statements that run longer. Statements
+// taking longer than SLOW_QUERY_MS are logged by the database with their text and duration, only it
+// sees how long a statement ran rather than how long a request kept its connection. Background
+// threads open their own connections and aren't bound by any of it.
+
+use crate::config;
+use log::{error, warn};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Outcome, State};
+use rocket_contrib::databases::postgres::{Connection, Error};
+use rocket_contrib::databases::r2d2::{self, CustomizeConnection, PooledConnection};
+use rocket_contrib::databases::r2d2_postgres::{PostgresConnectionManager, TlsMode};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+const DEFAULT_MAX: u32 = 10;
+
+const DEFAULT_ACQUIRE_TIMEOUT_SECS: u64 = 5;
+
+const DEFAULT_SLOW_QUERY_MS: u64 = 500;
+
+// Whether the database refused to log slow statements, which is only worth saying once.
+static SLOW_LOG_REFUSED: AtomicBool = AtomicBool::new(false);
+
+pub struct Pool {
+    pool: r2d2::Pool<PostgresConnectionManager>,
+}
+
+// Connections, idle ones and ones handed out, for GET /metrics.
+pub struct PoolState {
+    pub max: u32,
+    pub connections: u32,
+    pub idle: u32,
+}
+
+impl PoolState {
+    pub fn active(&self) -> u32 {
+        self.connections - self.idle
+    }
+}
+
+impl Pool {
+    pub fn state(&self) -> PoolState {
+        let state = self.pool.state();
+        PoolState {
+            max: self.pool.max_size(),
+            connections: state.connections,
+            idle: state.idle_connections,
+        }
+    }
+}
+
+// Settings of every new connection, they hold for the connection's lifetime.
+#[derive(Debug)]
+struct Session {
+    statement_timeout: Option<u64>,
+    slow_ms: u64,
+}
+
+impl CustomizeConnection<Connection, Error> for Session {
+    fn on_acquire(&self, connection: &mut Connection) -> Result<(), Error> {
+        if let Some(timeout) = self.statement_timeout {
+            connection.batch_execute(&format!("SET statement_timeout = {}", timeout))?;
+        }
+        // Changing the setting takes a superuser or a grant, without one the server's own setting
+        // stays in place.
+        let slow_log = format!("SET log_min_duration_statement = {}", self.slow_ms);
+        if let Err(error) = connection.batch_execute(&slow_log) {
+            if !SLOW_LOG_REFUSED.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "the database won't log statements slower than {}ms. Error: {}",
+                    self.slow_ms, error
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+// Stops the process when the pool can't be set up, main has already reached the database by then.
+pub fn connect(settings: &config::Settings) -> Pool {
+    let max = config::env_parsed::<u64>("DB_POOL_MAX")
+        .unwrap_or(u64::from(DEFAULT_MAX))
+        .max(1) as u32;
+    let builder = r2d2::Pool::builder()
+        .max_size(max)
+        .min_idle(
+            config::env_parsed::<u64>("DB_POOL_MIN_IDLE").map(|min| min.min(u64::from(max)) as u32),
+        )
+        .connection_timeout(Duration::from_secs(
+            config::env_parsed::<u64>("DB_ACQUIRE_TIMEOUT_SECS")
+                .unwrap_or(DEFAULT_ACQUIRE_TIMEOUT_SECS),
+        ))
+        .connection_customizer(Box::new(Session {
+            statement_timeout: config::env_parsed::<u64>("DB_STATEMENT_TIMEOUT_MS")
+                .filter(|timeout| *timeout > 0),
+            slow_ms: config::env_parsed::<u64>("SLOW_QUERY_MS").unwrap_or(DEFAULT_SLOW_QUERY_MS),
+        }));
+
+    let pool = PostgresConnectionManager::new(settings.database_url.as_str(), TlsMode::None)
+        .map_err(|error| error.to_string())
+        .and_then(|manager| builder.build(manager).map_err(|error| error.to_string()));
+    match pool {
+        Ok(pool) => Pool { pool },
+        Err(error) => {
+            error!(
+                "refusing to start: creating the connection pool failed: {}",
+                error
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+// A pooled connection for the length of a request.
+pub struct PgDbConn {
+    connection: PooledConnection<PostgresConnectionManager>,
+}
+
+impl Deref for PgDbConn {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.connection
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for PgDbConn {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let pool = request.guard::<State<Pool>>()?;
+        match pool.pool.get() {
+            Ok(connection) => Outcome::Success(PgDbConn { connection }),
+            Err(error) => {
+                error!(
+                    "error getting a database connection for {}. Error: {}",
+                    request.uri().path(),
+                    error
+                );
+                Outcome::Failure((Status::ServiceUnavailable, ()))
+            }
+        }
+    }
+}
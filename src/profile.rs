@@ -0,0 +1,99 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Vanity profile URLs. A user can pick a slug that /users/<slug> resolves to instead of their
+// AniList name, which then redirects to the slug.
+
+use crate::database;
+use rocket_contrib::databases::postgres::Connection;
+use std::fmt;
+
+const MIN_SLUG_LENGTH: usize = 3;
+const MAX_SLUG_LENGTH: usize = 32;
+
+// Words that are, or are likely to become, path segments of their own.
+const RESERVED_SLUGS: &[&str] = &[
+    "admin",
+    "anime",
+    "api",
+    "auth",
+    "batch",
+    "jobs",
+    "login",
+    "logout",
+    "me",
+    "profile",
+    "search",
+    "settings",
+    "subscriptions",
+    "users",
+    "v1",
+];
+
+#[derive(Debug)]
+pub enum SlugError {
+    Invalid,
+    Reserved,
+    Taken,
+    Database,
+}
+
+impl fmt::Display for SlugError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SlugError::Invalid => write!(
+                f,
+                "slugs are {} to {} lowercase letters, digits or inner dashes",
+                MIN_SLUG_LENGTH, MAX_SLUG_LENGTH
+            ),
+            SlugError::Reserved => write!(f, "this slug is reserved"),
+            SlugError::Taken => write!(f, "this slug is already used by another user"),
+            SlugError::Database => write!(f, "the slug could not be saved"),
+        }
+    }
+}
+
+// Sets or, given None, clears the user's slug. A slug may not match another user's slug or
+// AniList name, since both resolve through the same routes.
+pub fn set_slug(
+    user_id: i32,
+    slug: Option<&str>,
+    connection: &Connection,
+) -> Result<(), SlugError> {
+    if let Some(slug) = slug {
+        validate(slug)?;
+        match database::slug_taken(slug, user_id, connection) {
+            Some(false) => (),
+            Some(true) => return Err(SlugError::Taken),
+            None => return Err(SlugError::Database),
+        }
+    }
+
+    if database::set_user_slug(user_id, slug, connection) {
+        Ok(())
+    } else {
+        Err(SlugError::Database)
+    }
+}
+
+fn validate(slug: &str) -> Result<(), SlugError> {
+    let valid_length = slug.len() >= MIN_SLUG_LENGTH && slug.len() <= MAX_SLUG_LENGTH;
+    let valid_chars = slug
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if !valid_length || !valid_chars || slug.starts_with('-') || slug.ends_with('-') {
+        return Err(SlugError::Invalid);
+    }
+
+    // All digits would read like a user or job id.
+    if RESERVED_SLUGS.contains(&slug) || slug.chars().all(|c| c.is_ascii_digit()) {
+        return Err(SlugError::Reserved);
+    }
+
+    Ok(())
+}
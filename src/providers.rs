@@ -0,0 +1,140 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Sites lists are synced from. Every user row remembers its provider and the id the provider knows
+// the user by. AniList users are stored under their AniList id, MyAnimeList users under their
+// negated MyAnimeList id, so ids of both sources never collide. Names are shared the same way,
+// MyAnimeList users are stored as "mal:" and their name so a name finds a single user.
+
+use crate::anilist_query::{self, AnilistError};
+use crate::{anilist_models, mal_query};
+use std::time::Instant;
+
+const MAL_PREFIX: &str = "mal:";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Provider {
+    AniList,
+    Mal,
+}
+
+impl Provider {
+    pub fn parse(value: &str) -> Option<Provider> {
+        match value.to_lowercase().as_str() {
+            "anilist" => Some(Provider::AniList),
+            "mal" => Some(Provider::Mal),
+            _ => None,
+        }
+    }
+
+    // As stored in users.provider.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Provider::AniList => "anilist",
+            Provider::Mal => "mal",
+        }
+    }
+
+    pub fn source(&self) -> &'static dyn ListProvider {
+        match self {
+            Provider::AniList => &AniList,
+            Provider::Mal => &Mal,
+        }
+    }
+
+    // The id a user the provider knows by external_id is stored under.
+    pub fn user_id(&self, external_id: i32) -> i32 {
+        match self {
+            Provider::AniList => external_id,
+            Provider::Mal => -external_id,
+        }
+    }
+
+    pub fn external_id(&self, user_id: i32) -> i32 {
+        match self {
+            Provider::AniList => user_id,
+            Provider::Mal => -user_id,
+        }
+    }
+
+    // The name a user the provider knows by username is stored under.
+    pub fn stored_name(&self, username: &str) -> String {
+        match self {
+            Provider::AniList => username.to_owned(),
+            Provider::Mal => format!("{}{}", MAL_PREFIX, self.remote_name(username)),
+        }
+    }
+
+    // The name the provider knows a stored user by, stored names are taken as they are too.
+    pub fn remote_name<'a>(&self, name: &'a str) -> &'a str {
+        match self {
+            Provider::AniList => name,
+            Provider::Mal => name.strip_prefix(MAL_PREFIX).unwrap_or(name),
+        }
+    }
+}
+
+pub trait ListProvider: Sync {
+    // The user with the name, with the id and name they are stored under.
+    fn get_user(&self, username: &str) -> Result<Option<anilist_models::User>, AnilistError>;
+
+    // The user's lists in AniList's shape. The access token only means something to AniList.
+    fn get_lists(
+        &self,
+        external_id: i32,
+        username: &str,
+        deadline: Option<Instant>,
+        token: Option<&str>,
+    ) -> Result<Vec<anilist_models::MediaList>, AnilistError>;
+}
+
+pub struct AniList;
+
+impl ListProvider for AniList {
+    fn get_user(&self, username: &str) -> Result<Option<anilist_models::User>, AnilistError> {
+        anilist_query::get_id(username)
+    }
+
+    fn get_lists(
+        &self,
+        external_id: i32,
+        _: &str,
+        deadline: Option<Instant>,
+        token: Option<&str>,
+    ) -> Result<Vec<anilist_models::MediaList>, AnilistError> {
+        anilist_query::get_lists(external_id, deadline, token)
+    }
+}
+
+pub struct Mal;
+
+impl ListProvider for Mal {
+    fn get_user(&self, username: &str) -> Result<Option<anilist_models::User>, AnilistError> {
+        Ok(
+            mal_query::get_user(Provider::Mal.remote_name(username))?.map(|user| {
+                anilist_models::User {
+                    id: Provider::Mal.user_id(user.mal_id),
+                    name: Provider::Mal.stored_name(&user.username),
+                    avatar: anilist_models::Avatar {
+                        large: user.images.jpg.image_url.unwrap_or_default(),
+                    },
+                }
+            }),
+        )
+    }
+
+    fn get_lists(
+        &self,
+        _: i32,
+        username: &str,
+        deadline: Option<Instant>,
+        _: Option<&str>,
+    ) -> Result<Vec<anilist_models::MediaList>, AnilistError> {
+        mal_query::get_lists(Provider::Mal.remote_name(username), deadline)
+    }
+}
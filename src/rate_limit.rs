@@ -0,0 +1,208 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Token buckets for the update routes, which anyone can call and which cost AniList requests and
+// S3 uploads. A request needs a token from the bucket of its IP address and from the bucket of the
+// username it updates, UPDATE_LIMIT_PER_IP and UPDATE_LIMIT_PER_USER a minute by default. A batch
+// update costs the address a token per username, as much as updating them one by one. Buckets live
+// in memory, every API process counts on its own.
+
+use crate::config;
+use crate::error::AppError;
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Outcome, State};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+const DEFAULT_PER_IP: u32 = 10;
+
+const DEFAULT_PER_USER: u32 = 2;
+
+// Full buckets are dropped once this many are kept, a full bucket is the same as none.
+const PRUNE_AT: usize = 10_000;
+
+// Address of the caller. X-Real-IP is only taken from one of TRUSTED_PROXIES, anyone else could
+// send a new one with every request and never run out of tokens.
+pub struct ClientIp(Option<IpAddr>);
+
+impl ClientIp {
+    // Callers without a known address share a bucket.
+    fn key(&self) -> String {
+        self.0
+            .map_or_else(|| "unknown".to_owned(), |ip| ip.to_string())
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientIp {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let peer = request.remote().map(|address| address.ip());
+        let trusted = |peer: &IpAddr| {
+            request
+                .guard::<State<config::Settings>>()
+                .succeeded()
+                .map_or(false, |settings| settings.trusted_proxies.contains(peer))
+        };
+        let ip = match peer {
+            Some(peer) if trusted(&peer) => request.real_ip().or(Some(peer)),
+            peer => peer,
+        };
+        Outcome::Success(ClientIp(ip))
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+struct Buckets {
+    // Tokens a bucket holds when full, also the number refilled each minute.
+    capacity: f64,
+    buckets: HashMap<String, Bucket>,
+}
+
+impl Buckets {
+    fn new(per_minute: u32) -> Buckets {
+        Buckets {
+            capacity: f64::from(per_minute.max(1)),
+            buckets: HashMap::new(),
+        }
+    }
+
+    // Tokens in the key's bucket now.
+    fn refill(&mut self, key: &str, now: Instant) -> f64 {
+        let capacity = self.capacity;
+        let bucket = self.buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: capacity,
+            refilled_at: now,
+        });
+        let minutes = now.duration_since(bucket.refilled_at).as_secs_f64() / 60.0;
+        bucket.tokens = (bucket.tokens + minutes * capacity).min(capacity);
+        bucket.refilled_at = now;
+        bucket.tokens
+    }
+
+    // Seconds until the key's bucket has a token again, given the tokens it holds.
+    fn wait_secs(&self, tokens: f64) -> u64 {
+        ((1.0 - tokens) * 60.0 / self.capacity).ceil().max(1.0) as u64
+    }
+
+    fn take(&mut self, key: &str) {
+        if let Some(bucket) = self.buckets.get_mut(key) {
+            bucket.tokens -= 1.0;
+        }
+    }
+
+    fn prune(&mut self, now: Instant) {
+        if self.buckets.len() < PRUNE_AT {
+            return;
+        }
+        let capacity = self.capacity;
+        self.buckets.retain(|_, bucket| {
+            let minutes = now.duration_since(bucket.refilled_at).as_secs_f64() / 60.0;
+            bucket.tokens + minutes * capacity < capacity
+        });
+    }
+}
+
+pub struct UpdateLimiter {
+    by_ip: Mutex<Buckets>,
+    by_user: Mutex<Buckets>,
+}
+
+impl UpdateLimiter {
+    pub fn from_env() -> UpdateLimiter {
+        UpdateLimiter {
+            by_ip: Mutex::new(Buckets::new(config::env_value(
+                "UPDATE_LIMIT_PER_IP",
+                DEFAULT_PER_IP,
+            ))),
+            by_user: Mutex::new(Buckets::new(config::env_value(
+                "UPDATE_LIMIT_PER_USER",
+                DEFAULT_PER_USER,
+            ))),
+        }
+    }
+
+    // Takes a token from both buckets, or none when either is empty so a caller held back by one
+    // limit doesn't use up the other.
+    pub fn check(&self, client: &ClientIp, username: &str) -> Result<(), AppError> {
+        let now = Instant::now();
+        let mut by_ip = self.by_ip.lock().unwrap();
+        let mut by_user = self.by_user.lock().unwrap();
+        by_ip.prune(now);
+        by_user.prune(now);
+
+        let ip = client.key();
+        let user = username.to_lowercase();
+
+        let ip_tokens = by_ip.refill(&ip, now);
+        if ip_tokens < 1.0 {
+            return Err(ip_limited(&by_ip, ip_tokens));
+        }
+        let user_tokens = by_user.refill(&user, now);
+        if user_tokens < 1.0 {
+            return Err(AppError::RateLimited(
+                "This user was updated moments ago, try again later".to_owned(),
+                Some(by_user.wait_secs(user_tokens)),
+            ));
+        }
+
+        by_ip.take(&ip);
+        by_user.take(&user);
+        Ok(())
+    }
+
+    // Takes a token from both buckets for each username, in their order, until the address runs
+    // out. Tells which of the usernames may be updated, or why not. An address that can't update
+    // any of them is refused outright.
+    pub fn check_batch(
+        &self,
+        client: &ClientIp,
+        usernames: &[String],
+    ) -> Result<Vec<Result<(), String>>, AppError> {
+        let now = Instant::now();
+        let mut by_ip = self.by_ip.lock().unwrap();
+        let mut by_user = self.by_user.lock().unwrap();
+        by_ip.prune(now);
+        by_user.prune(now);
+
+        let ip = client.key();
+        let ip_tokens = by_ip.refill(&ip, now);
+        if ip_tokens < 1.0 {
+            return Err(ip_limited(&by_ip, ip_tokens));
+        }
+
+        Ok(usernames
+            .iter()
+            .map(|username| {
+                let user = username.to_lowercase();
+                if by_ip.refill(&ip, now) < 1.0 {
+                    return Err("Too many updates from this address, try again later".to_owned());
+                }
+                if by_user.refill(&user, now) < 1.0 {
+                    return Err("This user was updated moments ago, try again later".to_owned());
+                }
+                by_ip.take(&ip);
+                by_user.take(&user);
+                Ok(())
+            })
+            .collect())
+    }
+}
+
+fn ip_limited(by_ip: &Buckets, tokens: f64) -> AppError {
+    AppError::RateLimited(
+        "Too many updates from this address, try again later".to_owned(),
+        Some(by_ip.wait_secs(tokens)),
+    )
+}
@@ -0,0 +1,160 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Catalog search proxied to AniList. Raw results are cached per query so repeated searches don't
+// spend the rate limit syncs depend on, and local data is merged in fresh on every request.
+// Results expire after REMOTE_SEARCH_TTL_SECS and are deleted when the next search is cached, so
+// the cache holds at most the searches of one TTL at REMOTE_SEARCH_PER_MINUTE.
+
+use crate::{anilist_models, anilist_query, config, models};
+use log::error;
+use rocket_contrib::databases::postgres::Connection;
+use std::collections::HashMap;
+use std::fmt;
+
+const DEFAULT_TTL_SECS: i64 = 3600;
+
+// AniList allows 90 requests a minute for the whole service, most of which belong to syncs.
+const DEFAULT_PER_MINUTE: i64 = 20;
+
+#[derive(Debug)]
+pub enum RemoteSearchError {
+    RateLimited,
+    Upstream(anilist_query::AnilistError),
+}
+
+impl fmt::Display for RemoteSearchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RemoteSearchError::RateLimited => {
+                write!(f, "too many remote searches, try again later")
+            }
+            RemoteSearchError::Upstream(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+pub fn search(
+    query: &str,
+    connection: &Connection,
+) -> Result<Vec<models::RemoteSearchResult>, RemoteSearchError> {
+    let key = query.trim().to_lowercase();
+    if key.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let media = match cached_media(&key, connection) {
+        Some(media) => media,
+        None => {
+            if recent_fetches(connection) >= per_minute() {
+                return Err(RemoteSearchError::RateLimited);
+            }
+            let media = anilist_query::search_media(&key).map_err(RemoteSearchError::Upstream)?;
+            save_media(&key, &media, connection);
+            media
+        }
+    };
+
+    let ids: Vec<i32> = media.iter().map(|item| item.id).collect();
+    let local = local_counts(&ids, connection);
+
+    Ok(media
+        .into_iter()
+        .map(|item| {
+            let users = local.get(&item.id).cloned();
+            models::RemoteSearchResult {
+                id: item.id,
+                romaji: item.title.romaji,
+                english: item.title.english,
+                native: item.title.native,
+                cover: item.cover_image.large,
+                format: item.format,
+                season_year: item.season_year,
+                average: item.average_score,
+                local: users.is_some(),
+                local_users: users.unwrap_or(0),
+            }
+        })
+        .collect())
+}
+
+fn cached_media(key: &str, connection: &Connection) -> Option<Vec<anilist_models::SearchMedia>> {
+    let stmt = connection.prepare_cached("SELECT body FROM remote_search_cache WHERE query = $1 AND fetched_at > now() - make_interval(secs => $2)").unwrap();
+
+    let ttl = ttl_secs() as f64;
+    let body: String = match stmt.query(&[&key, &ttl]) {
+        Ok(rows) => rows.iter().next()?.get(0),
+        Err(error) => {
+            error!(
+                "error reading remote search cache for query={}. Error: {}",
+                key, error
+            );
+            return None;
+        }
+    };
+
+    serde_json::from_str(&body).ok()
+}
+
+fn save_media(key: &str, media: &[anilist_models::SearchMedia], connection: &Connection) {
+    let stmt = connection.prepare_cached("INSERT INTO remote_search_cache (query, body, fetched_at) VALUES ($1, $2, now()) ON CONFLICT (query) DO UPDATE SET body = excluded.body, fetched_at = excluded.fetched_at").unwrap();
+
+    let body = serde_json::to_string(media).unwrap();
+    if let Err(error) = stmt.execute(&[&key, &body]) {
+        error!(
+            "error saving remote search cache for query={}. Error: {}",
+            key, error
+        );
+    }
+
+    let stmt = connection
+        .prepare_cached(
+            "DELETE FROM remote_search_cache WHERE fetched_at <= now() - make_interval(secs => $1)",
+        )
+        .unwrap();
+    // Rows of the last minute still count the fetches against the limit.
+    let expired = ttl_secs().max(60) as f64;
+    if let Err(error) = stmt.execute(&[&expired]) {
+        error!("error deleting expired remote searches. Error: {}", error);
+    }
+}
+
+// Every fetch refreshes its cache row, so the rows touched in the last minute are the requests
+// made to AniList in that minute.
+fn recent_fetches(connection: &Connection) -> i64 {
+    let stmt = connection.prepare_cached("SELECT count(*) FROM remote_search_cache WHERE fetched_at > now() - interval '1 minute'").unwrap();
+
+    match stmt.query(&[]) {
+        Ok(rows) => rows.get(0).get(0),
+        Err(error) => {
+            error!("error counting remote searches. Error: {}", error);
+            0
+        }
+    }
+}
+
+// Tracked users per anime, for anime stored locally.
+fn local_counts(ids: &[i32], connection: &Connection) -> HashMap<i32, i64> {
+    let stmt = connection.prepare_cached("SELECT a.anime_id, count(u.user_id) FROM anime AS a LEFT JOIN public_lists AS l ON l.anime_id = a.anime_id LEFT JOIN visible_users AS u ON l.user_id = u.user_id WHERE a.anime_id = ANY($1) GROUP BY a.anime_id").unwrap();
+
+    match stmt.query(&[&ids]) {
+        Ok(rows) => rows.iter().map(|row| (row.get(0), row.get(1))).collect(),
+        Err(error) => {
+            error!("error counting local users for search. Error: {}", error);
+            HashMap::new()
+        }
+    }
+}
+
+fn ttl_secs() -> i64 {
+    config::env_value("REMOTE_SEARCH_TTL_SECS", DEFAULT_TTL_SECS)
+}
+
+fn per_minute() -> i64 {
+    config::env_value("REMOTE_SEARCH_PER_MINUTE", DEFAULT_PER_MINUTE)
+}
@@ -0,0 +1,72 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Gives every request an ID, the caller's X-Request-Id when it sends a usable one. The ID is put
+// back into the request headers for handlers and error responses, echoed in the response, written
+// on every log line while the request is handled and stored on the sync jobs it queues.
+
+use crate::logging;
+use log::info;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use rocket::data::Data;
+use rocket::http::Header;
+use rocket::{Request, Response};
+use std::time::Instant;
+
+pub const HEADER: &str = "X-Request-Id";
+
+// Longer IDs from callers are replaced, they end up on every log line.
+const MAX_LEN: usize = 64;
+
+const GENERATED_LEN: usize = 16;
+
+struct Started(Instant);
+
+pub fn on_request(request: &mut Request, _: &Data) {
+    let id = match request.headers().get_one(HEADER) {
+        Some(id) if is_valid(id) => id.to_owned(),
+        _ => generate(),
+    };
+    request.replace_header(Header::new(HEADER, id.clone()));
+    request.local_cache(|| Started(Instant::now()));
+    logging::set_request_id(Some(id));
+}
+
+// Rocket answers a request on a single thread, so the ID set in on_request is still the current one.
+pub fn on_response(request: &Request, response: &mut Response) {
+    if let Some(id) = request.headers().get_one(HEADER) {
+        response.set_raw_header(HEADER, id.to_owned());
+    }
+
+    let Started(started) = request.local_cache(|| Started(Instant::now()));
+    info!(
+        "{} {} answered {} in {}ms",
+        request.method(),
+        request.uri(),
+        response.status(),
+        started.elapsed().as_millis()
+    );
+    logging::set_request_id(None);
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+fn generate() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(GENERATED_LEN)
+        .map(char::from)
+        .collect()
+}
@@ -0,0 +1,175 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::models;
+use chrono::{DateTime, Utc};
+use rocket::http::ContentType;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use utoipa::ToSchema;
+
+// Shape shared by every list-style /v1 endpoint. Like every response model its fields are
+// camelCase, unversioned routes answer through Legacy instead.
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+#[aliases(
+    ListPage = Envelope<models::ResponseList>,
+    TrackedUserPage = Envelope<Vec<models::TrackedUser>>,
+    UserPage = Envelope<Vec<models::User>>
+)]
+pub struct Envelope<T> {
+    pub data: T,
+    pub meta: Meta,
+    pub links: Links,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Meta {
+    pub pagination: Option<Pagination>,
+    pub last_synced_at: Option<DateTime<Utc>>,
+    pub freshness: Option<models::DataFreshness>,
+    pub sort: Option<String>,
+    // Entries with sync warnings, counted per warning kind.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warnings: Option<BTreeMap<String, i64>>,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Pagination {
+    pub page: i64,
+    pub per_page: i64,
+    pub total: i64,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Links {
+    #[serde(rename = "self")]
+    pub self_link: String,
+    pub next: Option<String>,
+    pub prev: Option<String>,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(data: T, self_link: String) -> Envelope<T> {
+        Envelope {
+            data,
+            meta: Meta {
+                pagination: None,
+                last_synced_at: None,
+                freshness: None,
+                sort: None,
+                warnings: None,
+            },
+            links: Links {
+                self_link,
+                next: None,
+                prev: None,
+            },
+        }
+    }
+
+    pub fn paginated(mut self, page: i64, per_page: i64, total: i64) -> Envelope<T> {
+        let base = self.links.self_link.clone();
+        if page.saturating_mul(per_page) < total {
+            self.links.next = Some(page_link(&base, page + 1, per_page));
+        }
+        if page > 1 {
+            self.links.prev = Some(page_link(&base, page - 1, per_page));
+        }
+
+        self.meta.pagination = Some(Pagination {
+            page,
+            per_page,
+            total,
+        });
+        self
+    }
+
+    pub fn freshness(mut self, freshness: models::DataFreshness) -> Envelope<T> {
+        self.meta.last_synced_at = freshness.last_synced_at;
+        self.meta.freshness = Some(freshness);
+        self
+    }
+
+    pub fn warnings(mut self, warnings: BTreeMap<String, i64>) -> Envelope<T> {
+        if !warnings.is_empty() {
+            self.meta.warnings = Some(warnings);
+        }
+        self
+    }
+
+    pub fn sorted(mut self, sort: String) -> Envelope<T> {
+        self.meta.sort = Some(sort);
+        self
+    }
+}
+
+fn page_link(base: &str, page: i64, per_page: i64) -> String {
+    let separator = if base.contains('?') { '&' } else { '?' };
+    format!("{}{}page={}&per_page={}", base, separator, page, per_page)
+}
+
+// JSON with the snake_case field names unversioned routes have always used. Every key is renamed,
+// so models holding maps with data as keys, or JSON from elsewhere, have to be sent as plain Json.
+pub struct Legacy<T>(pub T);
+
+impl<'r, T: serde::Serialize> Responder<'r> for Legacy<T> {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(ContentType::JSON)
+            .sized_body(Cursor::new(legacy_json(&self.0)))
+            .ok()
+    }
+}
+
+// Serializes straight to bytes and renames the keys in one pass over them, large lists never
+// exist as a tree of JSON values.
+pub fn legacy_json<T: serde::Serialize>(value: &T) -> Vec<u8> {
+    snake_case_keys(&serde_json::to_vec(value).unwrap())
+}
+
+// Compact JSON has no whitespace, so a string directly followed by a colon is a key.
+fn snake_case_keys(json: &[u8]) -> Vec<u8> {
+    let mut renamed = Vec::with_capacity(json.len() + json.len() / 16);
+    let mut index = 0;
+    while index < json.len() {
+        if json[index] != b'"' {
+            renamed.push(json[index]);
+            index += 1;
+            continue;
+        }
+
+        let start = index;
+        index += 1;
+        while json[index] != b'"' {
+            index += if json[index] == b'\\' { 2 } else { 1 };
+        }
+        index += 1;
+
+        let string = &json[start..index];
+        if json.get(index) == Some(&b':') {
+            for &byte in string {
+                if byte.is_ascii_uppercase() {
+                    renamed.push(b'_');
+                    renamed.push(byte.to_ascii_lowercase());
+                } else {
+                    renamed.push(byte);
+                }
+            }
+        } else {
+            renamed.extend_from_slice(string);
+        }
+    }
+    renamed
+}
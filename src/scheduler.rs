@@ -0,0 +1,101 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Periodically queues a sync for every known user whose sync cadence says they are due, so lists
+// stay fresh without anyone pressing update. The job workers bound how many of them run at once.
+// Disabled unless REFRESH_INTERVAL_SECS is set, which also bounds how closely cadences are kept.
+//
+// Profiles nobody looks at don't need fresh lists as badly as popular ones. Daily users are synced
+// every REFRESH_MAX_INTERVAL_SECS when their profile wasn't viewed lately, more often the more it
+// was, down to every REFRESH_MIN_INTERVAL_SECS. Weekly users are never synced more than weekly.
+
+use crate::config::Settings;
+use crate::{config, database, jobs, warmup};
+use log::{error, info};
+use rocket_contrib::databases::postgres::Connection;
+use std::thread;
+use std::time::Duration;
+
+const DEFAULT_MIN_INTERVAL_SECS: u64 = 60 * 60;
+
+const DEFAULT_MAX_INTERVAL_SECS: u64 = 24 * 60 * 60;
+
+pub fn start(settings: &'static Settings) {
+    let interval: u64 = config::env_value("REFRESH_INTERVAL_SECS", 0);
+    if interval == 0 {
+        info!("scheduled refresh is disabled");
+        return;
+    }
+
+    let max_interval = config::env_value("REFRESH_MAX_INTERVAL_SECS", DEFAULT_MAX_INTERVAL_SECS);
+    let min_interval =
+        config::env_value("REFRESH_MIN_INTERVAL_SECS", DEFAULT_MIN_INTERVAL_SECS).min(max_interval);
+
+    info!(
+        "refreshing due users every {}s, syncing each every {}s to {}s depending on views",
+        interval, min_interval, max_interval
+    );
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(interval));
+        let connection = match database::connect(settings) {
+            Ok(connection) => connection,
+            // Users due now are picked up by the next run.
+            Err(error) => {
+                error!("error connecting to refresh due users. Error: {}", error);
+                continue;
+            }
+        };
+        // A user coming due before the next run is synced now rather than a whole interval late.
+        let user_ids = database::get_due_user_ids(
+            interval as i64,
+            min_interval as i64,
+            max_interval as i64,
+            warmup::window_hours(),
+            &connection,
+        );
+        queue_batch("scheduled", &user_ids, &connection);
+    });
+}
+
+// Syncs everyone regardless of their cadence, for operators.
+pub fn refresh_all(connection: &Connection) -> Option<i32> {
+    queue_batch("refresh", &database::get_user_ids(connection), connection)
+}
+
+// Syncs the users with quarantined entries, after whatever kept them from being written is fixed.
+pub fn reprocess_quarantined(connection: &Connection) -> Option<i32> {
+    queue_batch(
+        "reprocess",
+        &database::get_quarantined_user_ids(connection),
+        connection,
+    )
+}
+
+// Queues the syncs as one batch, so an operator can follow the refresh under /admin/jobs.
+fn queue_batch(kind: &str, user_ids: &[i32], connection: &Connection) -> Option<i32> {
+    let batch_id = jobs::create_batch(kind, connection)?;
+
+    // Users with a sync already in flight keep that job rather than getting a second one.
+    let queued = user_ids
+        .iter()
+        .filter(
+            |user_id| match jobs::queue_sync(**user_id, false, Some(batch_id), connection) {
+                Some((_, created)) => created,
+                None => false,
+            },
+        )
+        .count();
+    info!(
+        "{} batch_id={} queued {} of {} users",
+        kind,
+        batch_id,
+        queued,
+        user_ids.len()
+    );
+    Some(batch_id)
+}
@@ -16,6 +16,57 @@ table! {
         native -> Nullable<Text>,
         romaji -> Nullable<Text>,
         english -> Nullable<Text>,
+        search_title -> Text,
+        slug -> Nullable<Text>,
+        genres -> Array<Text>,
+        tags -> Array<Text>,
+        episodes -> Nullable<Int4>,
+        season -> Nullable<Text>,
+        season_year -> Nullable<Int4>,
+        format -> Nullable<Text>,
+        studio -> Nullable<Text>,
+        cover_version -> Int4,
+        mal_id -> Nullable<Int4>,
+        is_adult -> Bool,
+        search_document -> Tsvector,
+        cover_xl_s3 -> Nullable<Text>,
+        cover_thumb_s3 -> Nullable<Text>,
+        updated_at -> Timestamptz,
+    }
+}
+
+// The jobs of each batch, including queued jobs a batch joined instead of queueing another.
+table! {
+    batch_jobs (batch_id, job_id) {
+        batch_id -> Int4,
+        job_id -> Int4,
+    }
+}
+
+table! {
+    job_batches (batch_id) {
+        batch_id -> Int4,
+        kind -> Text,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    jobs (job_id) {
+        job_id -> Int4,
+        user_id -> Nullable<Int4>,
+        kind -> Text,
+        state -> Text,
+        force -> Bool,
+        error -> Nullable<Text>,
+        claimed_by -> Nullable<Text>,
+        batch_id -> Nullable<Int4>,
+        created_at -> Timestamptz,
+        started_at -> Nullable<Timestamptz>,
+        finished_at -> Nullable<Timestamptz>,
+        request_id -> Nullable<Text>,
+        username -> Nullable<Text>,
+        sync_job_id -> Nullable<Int4>,
     }
 }
 
@@ -27,6 +78,103 @@ table! {
         start_day -> Nullable<Date>,
         end_day -> Nullable<Date>,
         score -> Nullable<Int2>,
+        status -> Nullable<Text>,
+        updated_at -> Timestamptz,
+        anilist_updated_at -> Nullable<Int8>,
+        progress -> Nullable<Int4>,
+        repeat -> Nullable<Int4>,
+        notes -> Nullable<Text>,
+        custom_lists -> Array<Text>,
+        private -> Bool,
+        start_partial -> Nullable<Text>,
+        end_partial -> Nullable<Text>,
+    }
+}
+
+// Previous values of list entries, appended whenever a sync changes them.
+table! {
+    list_history (history_id) {
+        history_id -> Int8,
+        user_id -> Int4,
+        anime_id -> Int4,
+        score -> Nullable<Int2>,
+        status -> Nullable<Text>,
+        start_day -> Nullable<Date>,
+        end_day -> Nullable<Date>,
+        replaced_at -> Timestamptz,
+    }
+}
+
+// What syncs added, updated and removed, with the entry's values before and after.
+table! {
+    list_changes (change_id) {
+        change_id -> Int8,
+        user_id -> Int4,
+        anime_id -> Int4,
+        job_id -> Int4,
+        operation -> Text,
+        before -> Nullable<Text>,
+        after -> Nullable<Text>,
+        changed_at -> Timestamptz,
+    }
+}
+
+// Entries the user removed here, which syncs leave out even though they are still on AniList.
+table! {
+    hidden_entries (user_id, anime_id) {
+        user_id -> Int4,
+        anime_id -> Int4,
+        hidden_at -> Timestamptz,
+    }
+}
+
+table! {
+    list_tombstones (user_id, anime_id) {
+        user_id -> Int4,
+        anime_id -> Int4,
+        deleted_at -> Timestamptz,
+    }
+}
+
+// secret signs the deliveries, see webhooks.
+table! {
+    webhooks (webhook_id) {
+        webhook_id -> Int4,
+        user_id -> Int4,
+        url -> Text,
+        secret -> Text,
+        created_at -> Timestamptz,
+    }
+}
+
+// embedding is a pgvector vector(19), one dimension per genre in taste::GENRES.
+table! {
+    user_embeddings (user_id) {
+        user_id -> Int4,
+        embedding -> Vector,
+        updated_at -> Timestamptz,
+    }
+}
+
+// title_language is a models::TitleLanguage, date_format a models::DateFormat.
+table! {
+    user_preferences (user_id) {
+        user_id -> Int4,
+        title_language -> Text,
+        date_format -> Text,
+        show_adult -> Bool,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    user_stats (user_id) {
+        user_id -> Int4,
+        entries -> Int8,
+        completed -> Int8,
+        scored -> Int8,
+        score_sum -> Int8,
+        updated_at -> Timestamptz,
     }
 }
 
@@ -36,10 +184,178 @@ table! {
         name -> Text,
         avatar_s3 -> Text,
         avatar_anilist -> Text,
+        sync_needs_confirmation -> Bool,
+        last_synced_at -> Nullable<Timestamptz>,
+        last_sync_attempt_at -> Nullable<Timestamptz>,
+        slug -> Nullable<Text>,
+        restricted -> Bool,
+        takedown_requested_at -> Nullable<Timestamptz>,
+        // A models::SyncCadence.
+        sync_cadence -> Text,
+        // anilist or mal, see providers::Provider.
+        provider -> Text,
+        external_id -> Int4,
+    }
+}
+
+// Raw AniList search results by lowercased query.
+table! {
+    remote_search_cache (query) {
+        query -> Text,
+        body -> Text,
+        fetched_at -> Timestamptz,
+    }
+}
+
+// Requests to each profile per hour, see warmup.
+table! {
+    profile_hits (user_id, hour) {
+        user_id -> Int4,
+        hour -> Timestamptz,
+        hits -> Int4,
+    }
+}
+
+table! {
+    response_cache (user_id) {
+        user_id -> Int4,
+        body -> Bytea,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    schema_migrations (version) {
+        version -> Int8,
+        applied_at -> Timestamptz,
+    }
+}
+
+// AniList list activity, one row per update.
+table! {
+    activities (activity_id) {
+        activity_id -> Int4,
+        user_id -> Int4,
+        anime_id -> Int4,
+        status -> Text,
+        episode_from -> Nullable<Int4>,
+        episode_to -> Nullable<Int4>,
+        created_at -> Timestamptz,
+    }
+}
+
+// Tokens from signing in with AniList, see oauth.
+table! {
+    anilist_tokens (user_id) {
+        user_id -> Int4,
+        sealed_token -> Bytea,
+        expires_at -> Timestamptz,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    sessions (token_hash) {
+        token_hash -> Text,
+        user_id -> Int4,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+    }
+}
+
+table! {
+    sync_warnings (user_id, anime_id, kind) {
+        user_id -> Int4,
+        anime_id -> Int4,
+        kind -> Text,
+        detail -> Text,
+        job_id -> Int4,
+        created_at -> Timestamptz,
+    }
+}
+
+// Entries syncs couldn't write, with AniList's JSON of each, until a later sync writes them.
+table! {
+    sync_errors (user_id, anime_id) {
+        sync_error_id -> Int4,
+        user_id -> Int4,
+        anime_id -> Int4,
+        job_id -> Int4,
+        payload -> Text,
+        error -> Text,
+        created_at -> Timestamptz,
+    }
+}
+
+// Images whose upload failed, until a retry stores them.
+table! {
+    pending_uploads (image_type, id) {
+        image_type -> Text,
+        id -> Int4,
+        source_url -> Text,
+        attempts -> Int4,
+        last_error -> Text,
+        next_attempt_at -> Timestamptz,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    subscriptions (subscriber_id, target_id) {
+        subscriber_id -> Int4,
+        target_id -> Int4,
+        created_at -> Timestamptz,
     }
 }
 
+joinable!(activities -> users (user_id));
+joinable!(batch_jobs -> job_batches (batch_id));
+joinable!(batch_jobs -> jobs (job_id));
+joinable!(hidden_entries -> users (user_id));
+joinable!(jobs -> users (user_id));
+joinable!(list_history -> anime (anime_id));
+joinable!(list_history -> users (user_id));
+joinable!(list_changes -> jobs (job_id));
+joinable!(list_changes -> users (user_id));
+joinable!(list_tombstones -> users (user_id));
 joinable!(lists -> anime (anime_id));
 joinable!(lists -> users (user_id));
+joinable!(profile_hits -> users (user_id));
+joinable!(response_cache -> users (user_id));
+joinable!(anilist_tokens -> users (user_id));
+joinable!(sessions -> users (user_id));
+joinable!(jobs -> job_batches (batch_id));
+joinable!(sync_errors -> jobs (job_id));
+joinable!(sync_errors -> users (user_id));
+joinable!(sync_warnings -> jobs (job_id));
+joinable!(user_embeddings -> users (user_id));
+joinable!(user_preferences -> users (user_id));
+joinable!(user_stats -> users (user_id));
+joinable!(webhooks -> users (user_id));
 
-allow_tables_to_appear_in_same_query!(anime, lists, users,);
+allow_tables_to_appear_in_same_query!(
+    activities,
+    anilist_tokens,
+    anime,
+    batch_jobs,
+    hidden_entries,
+    job_batches,
+    jobs,
+    list_changes,
+    list_history,
+    list_tombstones,
+    lists,
+    pending_uploads,
+    profile_hits,
+    remote_search_cache,
+    response_cache,
+    sessions,
+    subscriptions,
+    sync_errors,
+    sync_warnings,
+    user_embeddings,
+    user_preferences,
+    user_stats,
+    users,
+    webhooks,
+);
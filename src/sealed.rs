@@ -0,0 +1,37 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// AniList access tokens at rest. They are sealed with AES-256-GCM under TOKEN_ENCRYPTION_KEY, so a
+// copy of the database alone can't be used to read anyone's private entries. Sealed tokens start
+// with the random nonce they were sealed with.
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+
+const NONCE_LEN: usize = 12;
+
+pub fn seal(key: &[u8; 32], token: &str) -> Option<Vec<u8>> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher.encrypt(&nonce, token.as_bytes()).ok()?;
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    Some(sealed)
+}
+
+// None when the token was sealed with another key or has been tampered with.
+pub fn open(key: &[u8; 32], sealed: &[u8]) -> Option<String> {
+    if sealed.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let token = cipher.decrypt(Nonce::from_slice(nonce), ciphertext).ok()?;
+    String::from_utf8(token).ok()
+}
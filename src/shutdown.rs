@@ -0,0 +1,105 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Exits cleanly on SIGTERM or SIGINT. Workers stop claiming jobs and running syncs stop before
+// writing anything, then the syncs, S3 uploads and webhook deliveries under way get
+// SHUTDOWN_TIMEOUT_SECS to finish. Syncs still running after that are put back in the queue.
+// Rocket 0.4 can't close its listener, requests in flight are cut off when the process exits.
+
+use crate::config::Settings;
+use crate::{config, database, jobs};
+use log::{error, info, warn};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const DEFAULT_TIMEOUT_SECS: u64 = 60;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Background tasks under way, and the condition signalled when the last one finishes.
+static RUNNING: Mutex<usize> = Mutex::new(0);
+static DRAINED: Condvar = Condvar::new();
+
+// Held for as long as a background task that shutdown should wait for runs.
+pub struct Task(());
+
+impl Task {
+    pub fn start() -> Task {
+        *RUNNING.lock().unwrap() += 1;
+        Task(())
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        let mut running = RUNNING.lock().unwrap();
+        *running -= 1;
+        if *running == 0 {
+            DRAINED.notify_all();
+        }
+    }
+}
+
+// Whether new work should be left alone.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+pub fn listen(settings: &'static Settings) {
+    let mut signals = match Signals::new(&[SIGTERM, SIGINT]) {
+        Ok(signals) => signals,
+        Err(error) => {
+            error!("error listening for shutdown signals. Error: {}", error);
+            return;
+        }
+    };
+
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            REQUESTED.store(true, Ordering::SeqCst);
+            let timeout = timeout();
+            info!(
+                "received signal {}, waiting up to {}s for background tasks",
+                signal,
+                timeout.as_secs()
+            );
+
+            let remaining = {
+                let running = RUNNING.lock().unwrap();
+                let (running, _) = DRAINED
+                    .wait_timeout_while(running, timeout, |running| *running > 0)
+                    .unwrap();
+                *running
+            };
+            if remaining > 0 {
+                warn!(
+                    "{} background tasks still running, exiting anyway",
+                    remaining
+                );
+                match database::connect(settings) {
+                    Ok(connection) => jobs::requeue_running(&connection),
+                    // Other instances requeue the jobs once they have gone stale.
+                    Err(error) => {
+                        error!("error connecting to requeue running jobs. Error: {}", error)
+                    }
+                }
+            }
+            info!("shut down");
+            std::process::exit(0);
+        }
+    });
+}
+
+fn timeout() -> Duration {
+    let secs = config::env_value("SHUTDOWN_TIMEOUT_SECS", DEFAULT_TIMEOUT_SECS);
+    Duration::from_secs(secs)
+}
@@ -0,0 +1,178 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Sitemaps of the frontend's profile and anime pages. Small sites get a single sitemap, larger
+// ones an index pointing at one sitemap per page of users or anime.
+
+use crate::{config, database, markup};
+use chrono::{DateTime, Utc};
+use log::error;
+use rocket_contrib::databases::postgres::Connection;
+
+// The most URLs a single sitemap may hold.
+const URLS_PER_SITEMAP: i64 = 50_000;
+
+const DEFAULT_BASE_URL: &str = "https://anihistory.moe";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Section {
+    Users,
+    Anime,
+}
+
+impl Section {
+    pub fn parse(value: &str) -> Option<Section> {
+        match value {
+            "users" => Some(Section::Users),
+            "anime" => Some(Section::Anime),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Section::Users => "users",
+            Section::Anime => "anime",
+        }
+    }
+}
+
+// The sitemap itself, or an index of per-section pages once there are too many URLs.
+pub fn root(connection: &Connection) -> Option<String> {
+    let users = count(Section::Users, connection)?;
+    let anime = count(Section::Anime, connection)?;
+
+    if users + anime <= URLS_PER_SITEMAP {
+        let mut entries = urls(Section::Users, 1, connection)?;
+        entries.extend(urls(Section::Anime, 1, connection)?);
+        return Some(urlset(&entries));
+    }
+
+    let base = base_url();
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<sitemapindex xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n",
+    );
+    for (section, total) in &[(Section::Users, users), (Section::Anime, anime)] {
+        let pages = (total + URLS_PER_SITEMAP - 1) / URLS_PER_SITEMAP;
+        for page in 1..=pages {
+            xml.push_str(&format!(
+                "  <sitemap><loc>{}/sitemaps/{}/{}.xml</loc></sitemap>\n",
+                markup::escape(&api_url(&base)),
+                section.as_str(),
+                page
+            ));
+        }
+    }
+    xml.push_str("</sitemapindex>\n");
+    Some(xml)
+}
+
+// A page of the index, None past the last page.
+pub fn page(section: Section, page: i64, connection: &Connection) -> Option<String> {
+    let entries = urls(section, page, connection)?;
+    if entries.is_empty() && page > 1 {
+        return None;
+    }
+    Some(urlset(&entries))
+}
+
+fn urlset(urls: &[(String, Option<DateTime<Utc>>)]) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n",
+    );
+    for (loc, lastmod) in urls {
+        xml.push_str(&format!("  <url><loc>{}</loc>", markup::escape(loc)));
+        if let Some(lastmod) = lastmod {
+            xml.push_str(&format!(
+                "<lastmod>{}</lastmod>",
+                lastmod.format("%Y-%m-%dT%H:%M:%SZ")
+            ));
+        }
+        xml.push_str("</url>\n");
+    }
+    xml.push_str("</urlset>\n");
+    xml
+}
+
+fn count(section: Section, connection: &Connection) -> Option<i64> {
+    let query = match section {
+        Section::Users => "SELECT count(*) FROM visible_users",
+        Section::Anime => "SELECT count(*) FROM anime",
+    };
+    let stmt = connection.prepare_cached(query).unwrap();
+
+    match stmt.query(&[]) {
+        Ok(rows) => Some(rows.get(0).get(0)),
+        Err(error) => {
+            error!(
+                "error counting {} for sitemap. Error: {}",
+                section.as_str(),
+                error
+            );
+            None
+        }
+    }
+}
+
+// Page URLs with the time their content last changed. Anime change whenever someone's entry for
+// them does.
+fn urls(
+    section: Section,
+    page: i64,
+    connection: &Connection,
+) -> Option<Vec<(String, Option<DateTime<Utc>>)>> {
+    let query = match section {
+        Section::Users => "SELECT COALESCE(slug, name), last_synced_at FROM visible_users ORDER BY user_id LIMIT $1 OFFSET $2",
+        Section::Anime => "SELECT COALESCE(a.slug, a.anime_id::text), (SELECT max(l.updated_at) FROM public_lists AS l WHERE l.anime_id = a.anime_id) FROM anime AS a ORDER BY a.anime_id LIMIT $1 OFFSET $2",
+    };
+    let stmt = connection.prepare_cached(query).unwrap();
+
+    let base = base_url();
+    // A page past any offset doesn't exist.
+    let offset = database::page_offset(page, URLS_PER_SITEMAP)?;
+    match stmt.query(&[&URLS_PER_SITEMAP, &offset]) {
+        Ok(rows) => Some(
+            rows.iter()
+                .map(|row| {
+                    let path: String = row.get(0);
+                    (
+                        format!("{}/{}/{}", base, section.as_str(), path),
+                        row.get(1),
+                    )
+                })
+                .collect(),
+        ),
+        Err(error) => {
+            error!(
+                "error listing {} for sitemap. Error: {}",
+                section.as_str(),
+                error
+            );
+            None
+        }
+    }
+}
+
+// Where crawlers find the sitemap.
+pub fn sitemap_url() -> String {
+    format!("{}/sitemap.xml", api_url(&base_url()))
+}
+
+// Frontend pages the sitemap points at.
+pub fn base_url() -> String {
+    config::env_value("SITEMAP_BASE_URL", DEFAULT_BASE_URL.to_owned())
+        .trim_end_matches('/')
+        .to_owned()
+}
+
+// Where the frontend proxies this API, so index entries resolve on the same domain.
+fn api_url(base: &str) -> String {
+    config::env_parsed::<String>("SITEMAP_API_URL")
+        .map(|url| url.trim_end_matches('/').to_owned())
+        .unwrap_or_else(|| base.to_owned())
+}
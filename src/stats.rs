@@ -0,0 +1,179 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Per-user list statistics kept in user_stats. Syncs add up how each entry they write or delete
+// changes the totals and apply the difference once at the end, so reading stats never has to
+// scan the list. Global stats are aggregated on request, across the users in visible_users.
+
+use crate::models;
+use log::error;
+use rocket_contrib::databases::postgres::Connection;
+
+const GLOBAL_LIMIT: i64 = 25;
+
+// Users that must have scored an anime before its mean user score is ranked.
+const MIN_SCORED: i64 = 3;
+
+#[derive(Debug, Default)]
+pub struct StatsDelta {
+    entries: i64,
+    completed: i64,
+    scored: i64,
+    score_sum: i64,
+}
+
+impl StatsDelta {
+    // Records an entry changing from old to new, where None means it wasn't or isn't on the list.
+    pub fn record(&mut self, old: Option<&models::ListItem>, new: Option<&models::ListItem>) {
+        if let Some(old) = old {
+            self.add(old, -1);
+        }
+        if let Some(new) = new {
+            self.add(new, 1);
+        }
+    }
+
+    fn add(&mut self, item: &models::ListItem, sign: i64) {
+        // Stats are public, private entries don't count.
+        if item.private {
+            return;
+        }
+        self.entries += sign;
+        if item.status.as_ref().map(String::as_str) == Some("COMPLETED") {
+            self.completed += sign;
+        }
+        // AniList stores unscored entries as 0.
+        if let Some(score) = item.score.filter(|score| *score > 0) {
+            self.scored += sign;
+            self.score_sum += sign * score as i64;
+        }
+    }
+}
+
+// Applies a sync's changes. Users without stats yet get them computed from their list instead,
+// which already contains the changes.
+pub fn apply(user_id: i32, delta: &StatsDelta, connection: &Connection) {
+    let stmt = connection.prepare_cached("UPDATE user_stats SET entries = entries + $2, completed = completed + $3, scored = scored + $4, score_sum = score_sum + $5, updated_at = now() WHERE user_id = $1").unwrap();
+
+    match stmt.execute(&[
+        &user_id,
+        &delta.entries,
+        &delta.completed,
+        &delta.scored,
+        &delta.score_sum,
+    ]) {
+        Ok(0) => rebuild(user_id, connection),
+        Ok(_) => (),
+        Err(error) => error!(
+            "error updating stats for user_id={}. Error: {}",
+            user_id, error
+        ),
+    }
+}
+
+// Recomputes the user's stats from scratch, used for new users and by forced syncs to correct
+// any drift.
+pub fn rebuild(user_id: i32, connection: &Connection) {
+    let stmt = connection.prepare_cached("INSERT INTO user_stats (user_id, entries, completed, scored, score_sum, updated_at) SELECT $1, count(*), count(*) FILTER (WHERE status = 'COMPLETED'), count(*) FILTER (WHERE score > 0), COALESCE(sum(score) FILTER (WHERE score > 0), 0), now() FROM public_lists WHERE user_id = $1 ON CONFLICT (user_id) DO UPDATE SET entries = excluded.entries, completed = excluded.completed, scored = excluded.scored, score_sum = excluded.score_sum, updated_at = excluded.updated_at").unwrap();
+
+    if let Err(error) = stmt.execute(&[&user_id]) {
+        error!(
+            "error rebuilding stats for user_id={}. Error: {}",
+            user_id, error
+        );
+    }
+}
+
+pub fn get_stats(user: &models::User, connection: &Connection) -> Option<models::UserStats> {
+    let stmt = connection.prepare_cached("SELECT entries, completed, scored, score_sum, updated_at FROM user_stats WHERE user_id = $1").unwrap();
+
+    for _ in 0..2 {
+        match stmt.query(&[&user.user_id]) {
+            Ok(rows) => {
+                if let Some(row) = rows.iter().next() {
+                    let scored: i64 = row.get(2);
+                    let score_sum: i64 = row.get(3);
+                    return Some(models::UserStats {
+                        id: user.name.clone(),
+                        entries: row.get(0),
+                        completed: row.get(1),
+                        scored,
+                        mean_score: if scored > 0 {
+                            Some(score_sum as f64 / scored as f64)
+                        } else {
+                            None
+                        },
+                        updated_at: row.get(4),
+                    });
+                }
+            }
+            Err(error) => {
+                error!(
+                    "error getting stats for user_id={}. Error: {}",
+                    user.user_id, error
+                );
+                return None;
+            }
+        }
+        rebuild(user.user_id, connection);
+    }
+
+    None
+}
+
+pub fn get_global_stats(connection: &Connection) -> Option<models::GlobalStats> {
+    let stmt = connection
+        .prepare_cached("SELECT (SELECT count(*) FROM visible_users), (SELECT count(*) FROM public_lists AS l INNER JOIN visible_users AS u ON l.user_id = u.user_id)")
+        .unwrap();
+
+    let (users, entries) = match stmt.query(&[]) {
+        Ok(rows) => {
+            let row = rows.get(0);
+            (row.get(0), row.get(1))
+        }
+        Err(error) => {
+            error!("error counting global totals. Error: {}", error);
+            return None;
+        }
+    };
+
+    let most_watched = connection.prepare_cached("SELECT a.anime_id, a.romaji, a.english, a.native, a.cover_s3, a.average, count(*), count(NULLIF(l.score, 0)), avg(NULLIF(l.score, 0))::float8 FROM public_lists AS l INNER JOIN visible_users AS u ON l.user_id = u.user_id INNER JOIN anime AS a ON l.anime_id = a.anime_id GROUP BY a.anime_id ORDER BY count(*) DESC, a.anime_id LIMIT $1").unwrap();
+    let top_rated = connection.prepare_cached("SELECT a.anime_id, a.romaji, a.english, a.native, a.cover_s3, a.average, count(*), count(NULLIF(l.score, 0)), avg(NULLIF(l.score, 0))::float8 FROM public_lists AS l INNER JOIN visible_users AS u ON l.user_id = u.user_id INNER JOIN anime AS a ON l.anime_id = a.anime_id GROUP BY a.anime_id HAVING count(NULLIF(l.score, 0)) >= $2 ORDER BY avg(NULLIF(l.score, 0)) DESC, count(NULLIF(l.score, 0)) DESC, a.anime_id LIMIT $1").unwrap();
+
+    let most_watched = most_watched.query(&[&GLOBAL_LIMIT]);
+    let top_rated = top_rated.query(&[&GLOBAL_LIMIT, &MIN_SCORED]);
+    match (most_watched, top_rated) {
+        (Ok(most_watched), Ok(top_rated)) => Some(models::GlobalStats {
+            users,
+            entries,
+            most_watched: most_watched.iter().map(|row| global_anime(&row)).collect(),
+            top_rated: top_rated.iter().map(|row| global_anime(&row)).collect(),
+        }),
+        (Err(error), _) | (_, Err(error)) => {
+            error!("error aggregating global stats. Error: {}", error);
+            None
+        }
+    }
+}
+
+fn global_anime(row: &postgres::rows::Row) -> models::GlobalAnime {
+    let average: Option<i16> = row.get(5);
+    let mean_score: Option<f64> = row.get(8);
+    models::GlobalAnime {
+        id: row.get(0),
+        romaji: row.get(1),
+        english: row.get(2),
+        native: row.get(3),
+        cover: row.get(4),
+        average,
+        watchers: row.get(6),
+        scored: row.get(7),
+        mean_score,
+        score_delta: mean_score.and_then(|mean| average.map(|average| mean - f64::from(average))),
+    }
+}
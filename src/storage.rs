@@ -0,0 +1,189 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Where copies of covers and avatars are kept, chosen by IMAGE_STORE, and the public URLs they are
+// served from. S3 is what anihistory.moe runs on, the local store writes below IMAGE_DIR so the
+// service can be hosted without AWS. With CLOUDFRONT_DISTRIBUTION_ID set, S3 keys that are
+// replaced or deleted are invalidated in that distribution, so the CDN doesn't keep serving them.
+// main builds the store once and hands it to the routes as managed state and to the background
+// threads, everything that stores or deletes images takes it as an argument.
+
+use crate::config::{self, ImageStoreKind, Settings};
+use log::error;
+use rusoto_cloudfront::{
+    CloudFront, CloudFrontClient, CreateInvalidationRequest, InvalidationBatch, Paths,
+};
+use rusoto_core::Region;
+use rusoto_s3::{DeleteObjectRequest, HeadObjectRequest, PutObjectRequest, S3Client, S3};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Shared with the threads uploads run on.
+pub type Images = Arc<dyn ImageStore>;
+
+pub trait ImageStore: Send + Sync {
+    // Replaces whatever is stored under the key.
+    fn put(&self, key: &str, content: Vec<u8>, mime: &str) -> Result<(), String>;
+    // Images that aren't stored count as deleted.
+    fn delete(&self, key: &str) -> Result<(), String>;
+}
+
+// Public URL of the image stored under the key.
+pub fn url(key: &str) -> String {
+    format!("{}/{}", config::settings().image_base_url, key)
+}
+
+pub struct S3Store {
+    client: S3Client,
+    bucket: String,
+    cdn: Option<Cdn>,
+}
+
+impl S3Store {
+    fn exists(&self, key: &str) -> bool {
+        let request = HeadObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_owned(),
+            ..HeadObjectRequest::default()
+        };
+        self.client.head_object(request).sync().is_ok()
+    }
+}
+
+impl ImageStore for S3Store {
+    fn put(&self, key: &str, content: Vec<u8>, mime: &str) -> Result<(), String> {
+        // New keys aren't cached anywhere yet, only overwrites need an invalidation.
+        let overwrite = self.cdn.is_some() && self.exists(key);
+        let request = PutObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_owned(),
+            body: Some(content.into()),
+            content_type: Some(mime.to_owned()),
+            acl: Some("public-read".to_owned()),
+            ..PutObjectRequest::default()
+        };
+        self.client
+            .put_object(request)
+            .sync()
+            .map_err(|error| error.to_string())?;
+        if let (true, Some(cdn)) = (overwrite, &self.cdn) {
+            cdn.invalidate(key);
+        }
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), String> {
+        let request = DeleteObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_owned(),
+            ..DeleteObjectRequest::default()
+        };
+        self.client
+            .delete_object(request)
+            .sync()
+            .map_err(|error| error.to_string())?;
+        if let Some(cdn) = &self.cdn {
+            cdn.invalidate(key);
+        }
+        Ok(())
+    }
+}
+
+// The CloudFront distribution serving the bucket.
+struct Cdn {
+    client: CloudFrontClient,
+    distribution_id: String,
+}
+
+impl Cdn {
+    // The image is already stored, a failed invalidation only leaves the old copy cached until it
+    // expires.
+    fn invalidate(&self, key: &str) {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_nanos());
+        let request = CreateInvalidationRequest {
+            distribution_id: self.distribution_id.clone(),
+            invalidation_batch: InvalidationBatch {
+                caller_reference: format!("{}-{}", key, nanos),
+                paths: Paths {
+                    items: Some(vec![format!("/{}", key)]),
+                    quantity: 1,
+                },
+            },
+        };
+        if let Err(error) = self.client.create_invalidation(request).sync() {
+            error!("error invalidating {} in CloudFront. Error: {}", key, error);
+        }
+    }
+}
+
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl ImageStore for LocalStore {
+    // The file system has nowhere to keep the type, the extension stands in for it.
+    fn put(&self, key: &str, content: Vec<u8>, _: &str) -> Result<(), String> {
+        let path = self.root.join(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|error| error.to_string())?;
+        }
+        fs::write(path, content).map_err(|error| error.to_string())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), String> {
+        match fs::remove_file(self.root.join(key)) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error.to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub fn from_settings(settings: &Settings) -> Images {
+    match settings.image_store {
+        ImageStoreKind::S3 => Arc::new(S3Store {
+            client: S3Client::new(settings.s3_region.clone()),
+            bucket: settings.s3_bucket.clone(),
+            // CloudFront's API is global and only answers in us-east-1.
+            cdn: settings.cloudfront_distribution_id.clone().map(|id| Cdn {
+                client: CloudFrontClient::new(Region::UsEast1),
+                distribution_id: id,
+            }),
+        }),
+        ImageStoreKind::Local => Arc::new(LocalStore {
+            root: PathBuf::from(&settings.image_dir),
+        }),
+    }
+}
+
+// Keeps images in memory, for tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryStore {
+    pub images: std::sync::Mutex<std::collections::HashMap<String, (Vec<u8>, String)>>,
+}
+
+#[cfg(test)]
+impl ImageStore for MemoryStore {
+    fn put(&self, key: &str, content: Vec<u8>, mime: &str) -> Result<(), String> {
+        self.images
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), (content, mime.to_owned()));
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), String> {
+        self.images.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
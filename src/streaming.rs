@@ -0,0 +1,175 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Downloads produced on a worker thread while the response is being sent. The worker hands chunks
+// over a bounded channel, so memory stays at a few chunks however large the file gets. A worker
+// that stops without finishing the download fails the response instead of cutting the file short.
+// At most STREAM_MAX_WORKERS downloads are produced at once, further ones are turned away.
+
+use crate::config;
+use rocket::http::ContentType;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+
+// Chunks buffered between the worker and the response before the worker waits.
+const BUFFERED_CHUNKS: usize = 4;
+
+const DEFAULT_MAX_WORKERS: usize = 8;
+
+// Seconds a turned away client is asked to wait.
+pub const RETRY_AFTER_SECS: u64 = 10;
+
+static WORKERS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug)]
+pub enum DownloadError {
+    // The user isn't tracked.
+    NotFound,
+    // As many downloads as allowed are being produced already.
+    Busy,
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DownloadError::NotFound => write!(f, "User not found"),
+            DownloadError::Busy => write!(
+                f,
+                "Too many downloads are being prepared, try again shortly"
+            ),
+        }
+    }
+}
+
+pub struct Download {
+    content_type: ContentType,
+    file_name: String,
+    reader: ChannelReader,
+}
+
+impl<'r> Responder<'r> for Download {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(self.content_type)
+            .raw_header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", self.file_name),
+            )
+            .streamed_body(self.reader)
+            .ok()
+    }
+}
+
+// The worker's end of a download. Sends fail once the client is gone.
+pub struct Sender {
+    // None marks the end of the download.
+    sender: SyncSender<Option<Vec<u8>>>,
+    _worker: Worker,
+}
+
+// A worker slot, given back when the worker drops its sender.
+struct Worker;
+
+impl Worker {
+    fn start() -> Option<Worker> {
+        let max = config::env_value("STREAM_MAX_WORKERS", DEFAULT_MAX_WORKERS);
+        WORKERS
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |workers| {
+                if workers < max {
+                    Some(workers + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| Worker)
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        WORKERS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Sender {
+    pub fn send(&self, chunk: Vec<u8>) -> Result<(), ()> {
+        self.sender.send(Some(chunk)).map_err(|_| ())
+    }
+
+    // Dropping the sender without finishing fails the download.
+    pub fn finish(self) -> Result<(), ()> {
+        self.sender.send(None).map_err(|_| ())
+    }
+}
+
+// The sender for the worker and the download it feeds, unless too many are being produced.
+pub fn download(
+    content_type: ContentType,
+    file_name: String,
+) -> Result<(Sender, Download), DownloadError> {
+    let worker = Worker::start().ok_or(DownloadError::Busy)?;
+    let (sender, receiver) = sync_channel(BUFFERED_CHUNKS);
+    let download = Download {
+        content_type,
+        file_name,
+        reader: ChannelReader {
+            receiver,
+            chunk: Vec::new(),
+            position: 0,
+            finished: false,
+        },
+    };
+    Ok((
+        Sender {
+            sender,
+            _worker: worker,
+        },
+        download,
+    ))
+}
+
+// Reads chunks off the channel until the worker finishes.
+struct ChannelReader {
+    receiver: Receiver<Option<Vec<u8>>>,
+    chunk: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position >= self.chunk.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            match self.receiver.recv() {
+                Ok(Some(chunk)) => {
+                    self.chunk = chunk;
+                    self.position = 0;
+                }
+                Ok(None) => self.finished = true,
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "download stopped before it was complete",
+                    ))
+                }
+            }
+        }
+
+        let read = buf.len().min(self.chunk.len() - self.position);
+        buf[..read].copy_from_slice(&self.chunk[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
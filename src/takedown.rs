@@ -0,0 +1,103 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Takedown requests. A requested takedown hides the profile from every public route right away,
+// and once the grace period is over its data is purged for good. Lifting the takedown within the
+// grace period restores the profile as it was.
+
+use crate::config::Settings;
+use crate::{cache, config, database, models, storage};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use log::{error, info};
+use rocket_contrib::databases::postgres::Connection;
+use std::thread;
+use std::time::Duration;
+
+const DEFAULT_GRACE_DAYS: i64 = 30;
+
+const PURGE_INTERVAL_SECS: u64 = 3600;
+
+// None when the user isn't tracked. Repeated requests keep the original request time.
+pub fn request(name: &str, connection: &Connection) -> Option<models::Takedown> {
+    let stmt = connection.prepare_cached("UPDATE users SET takedown_requested_at = COALESCE(takedown_requested_at, now()) WHERE name = $1 RETURNING user_id, name, takedown_requested_at").unwrap();
+
+    match stmt.query(&[&name]) {
+        Ok(rows) => rows.iter().next().map(|row| {
+            let user_id: i32 = row.get(0);
+            cache::invalidate_snapshot(user_id, connection);
+
+            let requested_at: DateTime<Utc> = row.get(2);
+            info!("takedown requested for user_id={}", user_id);
+            models::Takedown {
+                id: row.get(1),
+                requested_at,
+                purge_after: requested_at + ChronoDuration::days(grace_days()),
+            }
+        }),
+        Err(error) => {
+            error!(
+                "error requesting takedown for user_name={}. Error: {}",
+                name, error
+            );
+            None
+        }
+    }
+}
+
+// Whether a pending takedown was lifted.
+pub fn lift(name: &str, connection: &Connection) -> bool {
+    let stmt = connection.prepare_cached("UPDATE users SET takedown_requested_at = NULL WHERE name = $1 AND takedown_requested_at IS NOT NULL").unwrap();
+
+    match stmt.execute(&[&name]) {
+        Ok(updated) => updated > 0,
+        Err(error) => {
+            error!(
+                "error lifting takedown for user_name={}. Error: {}",
+                name, error
+            );
+            false
+        }
+    }
+}
+
+pub fn start_purger(images: storage::Images, settings: &'static Settings) {
+    thread::spawn(move || loop {
+        match database::connect(settings) {
+            Ok(connection) => purge_expired(&images, &connection),
+            // Expired takedowns stay expired, the next pass purges them.
+            Err(error) => error!(
+                "error connecting to purge expired takedowns. Error: {}",
+                error
+            ),
+        }
+        thread::sleep(Duration::from_secs(PURGE_INTERVAL_SECS));
+    });
+}
+
+fn purge_expired(images: &storage::Images, connection: &Connection) {
+    let stmt = connection.prepare_cached("SELECT user_id, avatar_s3 FROM users WHERE takedown_requested_at < now() - make_interval(days => $1)").unwrap();
+
+    let grace_days = grace_days() as i32;
+    let expired: Vec<(i32, String)> = match stmt.query(&[&grace_days]) {
+        Ok(rows) => rows.iter().map(|row| (row.get(0), row.get(1))).collect(),
+        Err(error) => {
+            error!("error finding expired takedowns. Error: {}", error);
+            return;
+        }
+    };
+
+    for (user_id, avatar) in expired {
+        if database::purge_user(user_id, &avatar, images, connection) {
+            info!("purged user_id={} after takedown", user_id);
+        }
+    }
+}
+
+fn grace_days() -> i64 {
+    config::env_value("TAKEDOWN_GRACE_DAYS", DEFAULT_GRACE_DAYS)
+}
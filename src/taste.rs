@@ -0,0 +1,180 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Taste embeddings for recommendations. Every user gets a vector with one dimension per AniList
+// genre, stored with pgvector so similar users are a nearest neighbor lookup instead of comparing
+// every pair of lists.
+
+use crate::models;
+use log::error;
+use rocket_contrib::databases::postgres::Connection;
+use std::collections::HashMap;
+
+// AniList's fixed set of genres, in embedding order. Changing it means recomputing every
+// embedding and the vector column's dimension.
+const GENRES: &[&str] = &[
+    "Action",
+    "Adventure",
+    "Comedy",
+    "Drama",
+    "Ecchi",
+    "Fantasy",
+    "Hentai",
+    "Horror",
+    "Mahou Shoujo",
+    "Mecha",
+    "Music",
+    "Mystery",
+    "Psychological",
+    "Romance",
+    "Sci-Fi",
+    "Slice of Life",
+    "Sports",
+    "Supernatural",
+    "Thriller",
+];
+
+// Users whose lists feed recommendations.
+const NEIGHBORS: i64 = 20;
+
+const RESULT_LIMIT: i64 = 25;
+
+// Recomputes the user's embedding from their list. Each genre's value is the share of entries
+// with that genre plus how much higher or lower than their average the user scores it.
+pub fn refresh(user_id: i32, connection: &Connection) {
+    let stmt = connection.prepare_cached("SELECT a.genres, NULLIF(l.score, 0) FROM public_lists AS l INNER JOIN anime AS a ON l.anime_id = a.anime_id WHERE l.user_id = $1").unwrap();
+
+    let entries: Vec<(Vec<String>, Option<i16>)> = match stmt.query(&[&user_id]) {
+        Ok(rows) => rows.iter().map(|row| (row.get(0), row.get(1))).collect(),
+        Err(error) => {
+            error!(
+                "error reading list for taste of user_id={}. Error: {}",
+                user_id, error
+            );
+            return;
+        }
+    };
+    if entries.is_empty() {
+        return;
+    }
+
+    let scores: Vec<f64> = entries
+        .iter()
+        .filter_map(|(_, score)| score.map(f64::from))
+        .collect();
+    let mean_score = if scores.is_empty() {
+        0.0
+    } else {
+        scores.iter().sum::<f64>() / scores.len() as f64
+    };
+
+    let mut counts: HashMap<&str, f64> = HashMap::new();
+    let mut deviations: HashMap<&str, (f64, f64)> = HashMap::new();
+    for (genres, score) in &entries {
+        for genre in genres {
+            *counts.entry(genre.as_str()).or_insert(0.0) += 1.0;
+            if let Some(score) = score {
+                let deviation = deviations.entry(genre.as_str()).or_insert((0.0, 0.0));
+                deviation.0 += (f64::from(*score) - mean_score) / 100.0;
+                deviation.1 += 1.0;
+            }
+        }
+    }
+
+    let mut embedding: Vec<f64> = GENRES
+        .iter()
+        .map(|genre| {
+            let share = counts.get(genre).unwrap_or(&0.0) / entries.len() as f64;
+            let affinity = match deviations.get(genre) {
+                Some((sum, count)) => sum / count,
+                None => 0.0,
+            };
+            share + affinity
+        })
+        .collect();
+
+    // Unit length, so cosine distance only compares taste and not list size.
+    let length = embedding
+        .iter()
+        .map(|value| value * value)
+        .sum::<f64>()
+        .sqrt();
+    if length == 0.0 {
+        return;
+    }
+    for value in embedding.iter_mut() {
+        *value /= length;
+    }
+
+    let literal = format!(
+        "[{}]",
+        embedding
+            .iter()
+            .map(|value| value.to_string())
+            .collect::<Vec<String>>()
+            .join(",")
+    );
+    let stmt = connection.prepare_cached("INSERT INTO user_embeddings (user_id, embedding, updated_at) VALUES ($1, $2::text::vector, now()) ON CONFLICT (user_id) DO UPDATE SET embedding = excluded.embedding, updated_at = excluded.updated_at").unwrap();
+
+    if let Err(error) = stmt.execute(&[&user_id, &literal]) {
+        error!(
+            "error saving taste of user_id={}. Error: {}",
+            user_id, error
+        );
+    }
+}
+
+pub fn similar_users(user_id: i32, connection: &Connection) -> Vec<models::SimilarUser> {
+    let stmt = connection.prepare_cached("SELECT u.name, u.avatar_s3, 1 - (e.embedding <=> me.embedding) FROM user_embeddings AS e INNER JOIN visible_users AS u ON e.user_id = u.user_id, user_embeddings AS me WHERE me.user_id = $1 AND e.user_id <> $1 ORDER BY e.embedding <=> me.embedding LIMIT $2").unwrap();
+
+    match stmt.query(&[&user_id, &RESULT_LIMIT]) {
+        Ok(rows) => rows
+            .iter()
+            .map(|row| models::SimilarUser {
+                id: row.get(0),
+                avatar: row.get(1),
+                similarity: row.get(2),
+            })
+            .collect(),
+        Err(error) => {
+            error!(
+                "error finding similar users for user_id={}. Error: {}",
+                user_id, error
+            );
+            Vec::new()
+        }
+    }
+}
+
+// Anime the closest users scored that aren't on the user's list yet, ranked by their scores
+// weighted with how similar each of them is.
+pub fn recommendations(user_id: i32, connection: &Connection) -> Vec<models::Recommendation> {
+    let stmt = connection.prepare_cached("WITH neighbors AS (SELECT e.user_id, 1 - (e.embedding <=> me.embedding) AS similarity FROM user_embeddings AS e INNER JOIN visible_users AS u ON e.user_id = u.user_id, user_embeddings AS me WHERE me.user_id = $1 AND e.user_id <> $1 ORDER BY e.embedding <=> me.embedding LIMIT $2) SELECT a.anime_id, a.romaji, a.english, a.native, a.cover_s3, sum(n.similarity * l.score) / NULLIF(sum(n.similarity), 0) AS predicted, count(*) FROM neighbors AS n INNER JOIN public_lists AS l ON l.user_id = n.user_id INNER JOIN anime AS a ON l.anime_id = a.anime_id WHERE l.score > 0 AND n.similarity > 0 AND NOT EXISTS (SELECT 1 FROM public_lists AS mine WHERE mine.user_id = $1 AND mine.anime_id = l.anime_id) GROUP BY a.anime_id ORDER BY predicted DESC NULLS LAST, count(*) DESC, a.anime_id LIMIT $3").unwrap();
+
+    match stmt.query(&[&user_id, &NEIGHBORS, &RESULT_LIMIT]) {
+        Ok(rows) => rows
+            .iter()
+            .map(|row| models::Recommendation {
+                id: row.get(0),
+                romaji: row.get(1),
+                english: row.get(2),
+                native: row.get(3),
+                cover: row.get(4),
+                predicted_score: row.get(5),
+                recommended_by: row.get(6),
+            })
+            .collect(),
+        Err(error) => {
+            error!(
+                "error finding recommendations for user_id={}. Error: {}",
+                user_id, error
+            );
+            Vec::new()
+        }
+    }
+}
@@ -0,0 +1,84 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Retries the uploads of covers and avatars that failed, so rows don't keep pointing at images the
+// store doesn't have. Failed uploads wait in pending_uploads, each retry downloads the image from
+// AniList again. The wait doubles with every failed retry, from UPLOAD_RETRY_BASE_SECS up to
+// UPLOAD_RETRY_MAX_SECS, and uploads are retried until they succeed.
+
+use crate::config::Settings;
+use crate::{config, database, shutdown, storage};
+use log::{error, info, warn};
+use rocket_contrib::databases::postgres::Connection;
+use std::thread;
+use std::time::Duration;
+
+const POLL_SECS: u64 = 60;
+
+// Uploads claimed per poll.
+const BATCH_SIZE: i64 = 20;
+
+// How long a claimed upload is left alone by other instances, longer than a batch takes.
+const LEASE_SECS: i64 = 15 * 60;
+
+const DEFAULT_BASE_SECS: i64 = 60;
+
+const DEFAULT_MAX_SECS: i64 = 6 * 60 * 60;
+
+pub fn start_retrier(images: storage::Images, settings: &'static Settings) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(POLL_SECS));
+        if !shutdown::requested() {
+            match database::connect(settings) {
+                Ok(connection) => retry_due(&images, &connection),
+                // Due uploads wait for the next poll.
+                Err(error) => error!("error connecting to retry uploads. Error: {}", error),
+            }
+        }
+    });
+}
+
+fn retry_due(images: &storage::Images, connection: &Connection) {
+    for upload in database::claim_pending_uploads(BATCH_SIZE, LEASE_SECS, connection) {
+        if shutdown::requested() {
+            // Claimed uploads come due again once their lease runs out.
+            return;
+        }
+        let task = shutdown::Task::start();
+        match database::retry_upload(&upload, images, connection) {
+            Ok(()) => {
+                info!(
+                    "stored the {} image for id={} after {} failed retries",
+                    upload.image_type, upload.id, upload.attempts
+                );
+                database::finish_pending_upload(&upload.image_type, upload.id, connection);
+            }
+            Err(error) => {
+                let delay = backoff_secs(upload.attempts);
+                warn!(
+                    "retrying the {} image for id={} failed, next retry in {}s. Error: {}",
+                    upload.image_type, upload.id, delay, error
+                );
+                database::reschedule_pending_upload(
+                    &upload.image_type,
+                    upload.id,
+                    &error,
+                    delay,
+                    connection,
+                );
+            }
+        }
+        drop(task);
+    }
+}
+
+fn backoff_secs(attempts: i32) -> i64 {
+    let base = config::env_value("UPLOAD_RETRY_BASE_SECS", DEFAULT_BASE_SECS).max(1);
+    let max = config::env_value("UPLOAD_RETRY_MAX_SECS", DEFAULT_MAX_SECS).max(base);
+    base.saturating_mul(1 << attempts.clamp(0, 30)).min(max)
+}
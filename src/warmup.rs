@@ -0,0 +1,113 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Rebuilds the snapshots of the most requested profiles on startup, before any traffic arrives, so
+// a deploy doesn't send all of them through the list join at once. Profile requests are counted in
+// memory and added to hourly buckets once a minute, the ranking covers the last
+// WARM_WINDOW_HOURS of them.
+
+use crate::config::Settings;
+use crate::{cache, config, database};
+use log::{error, info};
+use rocket_contrib::databases::postgres::Connection;
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const FLUSH_INTERVAL_SECS: u64 = 60;
+
+const DEFAULT_WARM_PROFILES: i64 = 50;
+
+const DEFAULT_WINDOW_HOURS: i32 = 24;
+
+// Requests per profile name since the last flush, shared between the route and the flush thread.
+#[derive(Clone, Default)]
+pub struct ProfileHits(Arc<Mutex<HashMap<String, i32>>>);
+
+impl ProfileHits {
+    pub fn record(&self, name: &str) {
+        let mut hits = self.0.lock().unwrap();
+        *hits.entry(name.to_owned()).or_insert(0) += 1;
+    }
+
+    fn take(&self) -> HashMap<String, i32> {
+        mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+// Blocks until the snapshots are rebuilt, then keeps flushing the counts in the background.
+// WARM_CACHE_PROFILES=0 skips the rebuild, the counting goes on regardless.
+pub fn start(hits: ProfileHits, settings: &'static Settings) {
+    let profiles = config::env_value("WARM_CACHE_PROFILES", DEFAULT_WARM_PROFILES);
+    if profiles > 0 {
+        match database::connect(settings) {
+            Ok(connection) => warm(profiles, &connection),
+            Err(error) => error!(
+                "error connecting to warm the snapshots, they are built on first request. Error: {}",
+                error
+            ),
+        }
+    }
+
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(FLUSH_INTERVAL_SECS));
+        match database::connect(settings) {
+            Ok(connection) => flush(&hits, &connection),
+            // The counts stay in memory until a flush gets through.
+            Err(error) => error!("error connecting to count requests. Error: {}", error),
+        }
+    });
+}
+
+fn warm(profiles: i64, connection: &Connection) {
+    let stmt = connection.prepare_cached("SELECT user_id FROM profile_hits WHERE hour > now() - make_interval(hours => $1) GROUP BY user_id ORDER BY sum(hits) DESC LIMIT $2").unwrap();
+
+    let user_ids: Vec<i32> = match stmt.query(&[&window_hours(), &profiles]) {
+        Ok(rows) => rows.iter().map(|row| row.get(0)).collect(),
+        Err(error) => {
+            error!(
+                "error getting the most requested profiles. Error: {}",
+                error
+            );
+            return;
+        }
+    };
+
+    // Rebuilt rather than kept, a snapshot from before the deploy may be missing fields.
+    for user_id in user_ids.iter() {
+        cache::refresh_snapshot(*user_id, connection);
+    }
+    info!("warmed the snapshots of {} profiles", user_ids.len());
+}
+
+fn flush(hits: &ProfileHits, connection: &Connection) {
+    let stmt = connection.prepare_cached("INSERT INTO profile_hits (user_id, hour, hits) SELECT user_id, date_trunc('hour', now()), $2 FROM users WHERE name = $1 ON CONFLICT (user_id, hour) DO UPDATE SET hits = profile_hits.hits + excluded.hits").unwrap();
+
+    for (name, count) in hits.take() {
+        if let Err(error) = stmt.execute(&[&name, &count]) {
+            error!(
+                "error counting requests for user_name={}. Error: {}",
+                name, error
+            );
+        }
+    }
+
+    let prune = connection
+        .prepare_cached("DELETE FROM profile_hits WHERE hour < now() - make_interval(hours => $1)")
+        .unwrap();
+    if let Err(error) = prune.execute(&[&window_hours()]) {
+        error!("error pruning profile hits. Error: {}", error);
+    }
+}
+
+// Also the window the scheduler weighs views over.
+pub fn window_hours() -> i32 {
+    config::env_value("WARM_WINDOW_HOURS", DEFAULT_WINDOW_HOURS)
+}
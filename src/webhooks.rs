@@ -0,0 +1,427 @@
+/*
+ * Copyright (c) 2018, Tyler Bratton
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Outbound webhooks. Users register callback URLs that get a JSON payload whenever one of their
+// syncs finishes or fails. Every delivery is signed with the webhook's secret: the
+// X-Anihistory-Signature header holds "sha256=" followed by the hex HMAC-SHA256 of the body.
+// Nothing is delivered while FEATURE_WEBHOOKS is off.
+// URLs have to resolve to public addresses only, when they are registered and again on every
+// delivery, which goes to the addresses that were checked. Redirects aren't followed, so a webhook
+// can't be used to reach the service's own network.
+
+use crate::features::FeatureFlags;
+use crate::{database, models, shutdown};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use reqwest::blocking::Client;
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use rocket_contrib::databases::postgres::Connection;
+use sha2::Sha256;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+// Deliveries sent before giving up on a webhook that doesn't answer with a 2xx status.
+const MAX_ATTEMPTS: u32 = 5;
+
+const BASE_BACKOFF_SECS: u64 = 2;
+
+const DELIVERY_TIMEOUT_SECS: u64 = 10;
+
+// Limits what a single user can make the service call.
+const MAX_WEBHOOKS_PER_USER: i64 = 5;
+
+const MIN_SECRET_LEN: usize = 16;
+
+#[derive(Debug)]
+pub enum WebhookError {
+    InvalidUrl,
+    // The URL's host resolves to a loopback, private, link-local or otherwise internal address.
+    PrivateAddress,
+    SecretTooShort,
+    TooMany,
+    Database,
+}
+
+impl fmt::Display for WebhookError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WebhookError::InvalidUrl => write!(f, "url must be an absolute https URL"),
+            WebhookError::PrivateAddress => {
+                write!(f, "url must only resolve to public addresses")
+            }
+            WebhookError::SecretTooShort => write!(
+                f,
+                "secret must be at least {} characters long",
+                MIN_SECRET_LEN
+            ),
+            WebhookError::TooMany => write!(
+                f,
+                "no more than {} webhooks can be registered",
+                MAX_WEBHOOKS_PER_USER
+            ),
+            WebhookError::Database => write!(f, "webhook could not be saved"),
+        }
+    }
+}
+
+pub fn register(
+    user_id: i32,
+    request: &models::WebhookRequest,
+    connection: &Connection,
+) -> Result<models::Webhook, WebhookError> {
+    let url = match Url::parse(&request.url) {
+        Ok(url) if url.scheme() == "https" && url.host_str().is_some() => url,
+        _ => return Err(WebhookError::InvalidUrl),
+    };
+    resolve_public(&url)?;
+    if request.secret.len() < MIN_SECRET_LEN {
+        return Err(WebhookError::SecretTooShort);
+    }
+
+    // The count and the insert share a statement, so concurrent requests can't both slip in.
+    let stmt = connection.prepare_cached("INSERT INTO webhooks (user_id, url, secret, created_at) SELECT $1, $2, $3, now() WHERE (SELECT count(*) FROM webhooks WHERE user_id = $1) < $4 RETURNING webhook_id, url, created_at").unwrap();
+
+    match stmt.query(&[
+        &user_id,
+        &request.url,
+        &request.secret,
+        &MAX_WEBHOOKS_PER_USER,
+    ]) {
+        Ok(rows) => match rows.iter().next() {
+            Some(row) => Ok(models::Webhook {
+                id: row.get(0),
+                url: row.get(1),
+                created_at: row.get(2),
+            }),
+            None => Err(WebhookError::TooMany),
+        },
+        Err(error) => {
+            error!(
+                "error registering webhook for user_id={}. Error: {}",
+                user_id, error
+            );
+            Err(WebhookError::Database)
+        }
+    }
+}
+
+// Whether the user had a webhook with the id.
+pub fn remove(user_id: i32, webhook_id: i32, connection: &Connection) -> bool {
+    let stmt = connection
+        .prepare_cached("DELETE FROM webhooks WHERE webhook_id = $1 AND user_id = $2")
+        .unwrap();
+
+    match stmt.execute(&[&webhook_id, &user_id]) {
+        Ok(deleted) => deleted > 0,
+        Err(error) => {
+            error!(
+                "error removing webhook_id={} for user_id={}. Error: {}",
+                webhook_id, user_id, error
+            );
+            false
+        }
+    }
+}
+
+// Sends the outcome of a finished sync job to the user's webhooks. Deliveries run on their own
+// threads, a slow receiver never holds up the job worker.
+pub fn sync_finished(
+    job_id: i32,
+    user_id: i32,
+    state: models::JobState,
+    error: Option<&str>,
+    connection: &Connection,
+) {
+    if !FeatureFlags::from_env().webhooks {
+        return;
+    }
+
+    let stmt = connection
+        .prepare_cached("SELECT webhook_id, url, secret FROM webhooks WHERE user_id = $1")
+        .unwrap();
+
+    let targets: Vec<(i32, String, String)> = match stmt.query(&[&user_id]) {
+        Ok(rows) => rows
+            .iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2)))
+            .collect(),
+        Err(error) => {
+            error!(
+                "error getting webhooks for user_id={}. Error: {}",
+                user_id, error
+            );
+            return;
+        }
+    };
+    if targets.is_empty() {
+        return;
+    }
+
+    let event = match state {
+        models::JobState::Succeeded => "sync.succeeded",
+        _ => "sync.failed",
+    };
+    let payload = models::WebhookPayload {
+        event: event.to_owned(),
+        job_id,
+        user: database::get_user_by_id(user_id, connection).map(|user| user.name),
+        state,
+        error: error.map(str::to_owned),
+        finished_at: Utc::now(),
+    };
+    let body = match serde_json::to_vec(&payload) {
+        Ok(body) => body,
+        Err(error) => {
+            error!(
+                "error serializing webhook payload for job_id={}. Error: {}",
+                job_id, error
+            );
+            return;
+        }
+    };
+
+    for (webhook_id, url, secret) in targets {
+        let body = body.clone();
+        let task = shutdown::Task::start();
+        thread::spawn(move || {
+            deliver(webhook_id, &url, &secret, event, &body);
+            drop(task);
+        });
+    }
+}
+
+// The host's addresses, as long as every one of them is public.
+fn resolve_public(url: &Url) -> Result<Vec<SocketAddr>, WebhookError> {
+    let port = url
+        .port_or_known_default()
+        .ok_or(WebhookError::InvalidUrl)?;
+    let host = url.host_str().ok_or(WebhookError::InvalidUrl)?;
+    // IPv6 hosts keep their brackets.
+    let addresses: Vec<SocketAddr> = match host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => (host, port)
+            .to_socket_addrs()
+            .map_err(|_| WebhookError::InvalidUrl)?
+            .collect(),
+    };
+    if addresses.is_empty() {
+        return Err(WebhookError::InvalidUrl);
+    }
+    if !addresses.iter().all(|address| is_public(address.ip())) {
+        return Err(WebhookError::PrivateAddress);
+    }
+    Ok(addresses)
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match embedded_v4(ip) {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+// The IPv4 address behind IPv6 ranges that reach IPv4 hosts, which has to pass the IPv4 check.
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let v4 = |high: u16, low: u16| Ipv4Addr::from((u32::from(high) << 16) | u32::from(low));
+    match segments {
+        // ::ffff:0:0/96, IPv4-mapped.
+        [0, 0, 0, 0, 0, 0xffff, high, low]
+        // ::/96, IPv4-compatible. :: and ::1 come out as 0.0.0.0 and 0.0.0.1, neither is public.
+        | [0, 0, 0, 0, 0, 0, high, low]
+        // 64:ff9b::/96, NAT64.
+        | [0x64, 0xff9b, 0, 0, 0, 0, high, low] => Some(v4(high, low)),
+        // 2002::/16, 6to4.
+        [0x2002, high, low, ..] => Some(v4(high, low)),
+        _ => None,
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    !(ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // 0.0.0.0/8, "this network".
+        || octets[0] == 0
+        // 100.64.0.0/10, carrier-grade NAT.
+        || (octets[0] == 100 && octets[1] & 0xc0 == 64)
+        // 192.0.0.0/24, protocol assignments.
+        || (octets[0] == 192 && octets[1] == 0 && octets[2] == 0)
+        // 198.18.0.0/15, benchmarking.
+        || (octets[0] == 198 && octets[1] & 0xfe == 18)
+        // 240.0.0.0/4, reserved.
+        || octets[0] >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // fc00::/7, unique local.
+        || first & 0xfe00 == 0xfc00
+        // fe80::/10, link-local.
+        || first & 0xffc0 == 0xfe80
+        // 2001:db8::/32, documentation.
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8)
+        // 64:ff9b:1::/48, local-use NAT64, where the IPv4 address may sit anywhere.
+        || (first == 0x64 && ip.segments()[1] == 0xff9b && ip.segments()[2] == 1))
+}
+
+fn deliver(webhook_id: i32, url: &str, secret: &str, event: &str, body: &[u8]) {
+    // Checked again, the host may point somewhere else by now. The client connects to the
+    // addresses checked here instead of resolving the host a second time.
+    let parsed = match Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(error) => {
+            error!(
+                "webhook_id={} has an invalid url. Error: {}",
+                webhook_id, error
+            );
+            return;
+        }
+    };
+    let addresses = match resolve_public(&parsed) {
+        Ok(addresses) => addresses,
+        Err(error) => {
+            warn!(
+                "not delivering {} to webhook_id={}: {}",
+                event, webhook_id, error
+            );
+            return;
+        }
+    };
+    let mut builder = Client::builder()
+        .timeout(Duration::from_secs(DELIVERY_TIMEOUT_SECS))
+        .redirect(Policy::none());
+    if let Some(domain) = parsed.domain() {
+        for address in addresses {
+            builder = builder.resolve(domain, address);
+        }
+    }
+    let client = match builder.build() {
+        Ok(client) => client,
+        Err(error) => {
+            error!("error building webhook client. Error: {}", error);
+            return;
+        }
+    };
+    let signature = format!("sha256={}", sign(secret, body));
+
+    for attempt in 0..MAX_ATTEMPTS {
+        if attempt > 0 {
+            thread::sleep(Duration::from_secs(BASE_BACKOFF_SECS << (attempt - 1)));
+        }
+
+        let response = client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .header("X-Anihistory-Event", event)
+            .header("X-Anihistory-Signature", signature.as_str())
+            .body(body.to_vec())
+            .send();
+
+        match response {
+            Ok(response) if response.status().is_success() => {
+                info!("delivered {} to webhook_id={}", event, webhook_id);
+                return;
+            }
+            Ok(response) => warn!(
+                "webhook_id={} answered {} with status={} ({}/{})",
+                webhook_id,
+                event,
+                response.status(),
+                attempt + 1,
+                MAX_ATTEMPTS
+            ),
+            Err(error) => warn!(
+                "error delivering {} to webhook_id={} ({}/{}). Error: {}",
+                event,
+                webhook_id,
+                attempt + 1,
+                MAX_ATTEMPTS,
+                error
+            ),
+        }
+    }
+
+    error!(
+        "giving up delivering {} to webhook_id={} after {} attempts",
+        event, webhook_id, MAX_ATTEMPTS
+    );
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    // HMAC takes keys of any length.
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public(ip: &str) -> bool {
+        is_public(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn private_ipv4_hosts_are_refused_behind_every_ipv6_prefix() {
+        for prefix in &["::ffff:", "::", "64:ff9b::"] {
+            for host in &["127.0.0.1", "10.0.0.1", "169.254.169.254"] {
+                let ip = format!("{}{}", prefix, host);
+                assert!(!public(&ip), "{} is public", ip);
+            }
+            assert!(public(&format!("{}93.184.216.34", prefix)));
+        }
+    }
+
+    #[test]
+    fn private_ipv4_hosts_are_refused_behind_6to4() {
+        // 127.0.0.1, 10.0.0.1 and 169.254.169.254.
+        for ip in &["2002:7f00:1::", "2002:a00:1::1", "2002:a9fe:a9fe:1::1"] {
+            assert!(!public(ip), "{} is public", ip);
+        }
+        assert!(public("2002:5db8:d822::1"));
+    }
+
+    #[test]
+    fn ipv6_hosts_are_checked_by_their_own_ranges() {
+        for ip in &[
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "2001:db8::1",
+            "64:ff9b:1::a00:1",
+        ] {
+            assert!(!public(ip), "{} is public", ip);
+        }
+        assert!(public("2606:4700:4700::1111"));
+    }
+}